@@ -0,0 +1,7 @@
+//! Shared library build of [`rulloc`] with the `capi` feature enabled. All the
+//! exported functions live in the `rulloc` crate itself, this crate only
+//! exists so that the `cdylib` is not built for every user of the `rlib`.
+//! See the `capi` module of `rulloc` for the details.
+
+// Nothing references the crate, but the exported symbols must be linked.
+extern crate rulloc;
@@ -0,0 +1,72 @@
+//! Loads the shared library into a C program with `LD_PRELOAD` and checks
+//! that the exported functions replace the ones from libc.
+
+#![cfg(all(target_os = "linux", not(miri)))]
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// C program that goes through every exported function. Our
+/// `malloc_usable_size` returns exactly the requested size while the glibc
+/// one rounds it up, that's how we know the library was actually loaded.
+const STUB: &str = r#"
+#include <malloc.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+int main(void) {
+    char *buffer = malloc(13);
+    if (buffer == NULL || malloc_usable_size(buffer) != 13) return 1;
+    memset(buffer, 7, 13);
+
+    buffer = realloc(buffer, 4096);
+    if (buffer == NULL || buffer[12] != 7) return 2;
+    free(buffer);
+
+    uint64_t *zeroed = calloc(16, sizeof(uint64_t));
+    for (int i = 0; i < 16; i++) if (zeroed[i] != 0) return 3;
+    free(zeroed);
+
+    void *aligned = NULL;
+    if (posix_memalign(&aligned, 4096, 100) != 0 || (uintptr_t) aligned % 4096 != 0) return 4;
+    free(aligned);
+
+    return 0;
+}
+"#;
+
+/// The cdylib is built next to the test executable or one level above,
+/// depending on whether Cargo uplifted it.
+fn shared_library() -> PathBuf {
+    let deps = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+
+    [
+        deps.join("librulloc_capi.so"),
+        deps.parent().unwrap().join("librulloc_capi.so"),
+    ]
+    .into_iter()
+    .find(|path| path.exists())
+    .expect("librulloc_capi.so not found, is the crate built as a cdylib?")
+}
+
+#[test]
+fn c_program_uses_exported_functions() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let source = dir.join("capi_stub.c");
+    let program = dir.join("capi_stub");
+    fs::write(&source, STUB).unwrap();
+
+    let status = Command::new("cc")
+        .arg(&source)
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("C compiler not available");
+    assert!(status.success());
+
+    let status = Command::new(&program)
+        .env("LD_PRELOAD", shared_library())
+        .status()
+        .unwrap();
+    assert!(status.success(), "C stub exited with {status:?}");
+}
@@ -0,0 +1,166 @@
+//! Bucket size recommendations based on the sizes actually requested. Bucket
+//! sizes are fixed at compile time, but workloads change over time, so with
+//! [`crate::Rulloc::with_adaptive_sizes`] the allocator keeps a histogram of
+//! requested sizes where each bin covers one power of 2:
+//!
+//! ```text
+//! bin      0    1    2     3     4      5      6     ...     63
+//! sizes    1    2   3-4   5-8   9-16  17-32  33-64   ...   > 2^62
+//! count    0    0    0    12     40    900     3     ...     0
+//! ```
+//!
+//! Every few allocations the histogram is turned into `N` bucket sizes by
+//! splitting the requests into `N` groups of roughly the same count: bucket
+//! `i` ends at the bin where the cumulative count reaches `(i + 1) / N` of
+//! 99% of all requests. The last percent is left to the dynamic bucket so
+//! that a few huge outliers can't drag the last fixed size with them. Live
+//! sizes are never changed, since blocks already allocated depend on them,
+//! the result is only a recommendation for the next build.
+//!
+//! After each recommendation every bin is halved, so a request counts half as
+//! much with every interval that goes by. Otherwise a long running program
+//! would keep recommending sizes for whatever it did at startup, because old
+//! requests would always outnumber the new ones.
+
+use crate::alignment::POINTER_SIZE;
+
+/// One bin per power of 2 that fits in `usize`.
+const BINS: usize = usize::BITS as usize;
+
+/// Percentage of requests covered by the recommended fixed size buckets.
+const COVERED_PERCENT: u128 = 99;
+
+/// Histogram of requested sizes and the last recommendation computed from it.
+pub(crate) struct AdaptiveSizes<const N: usize> {
+    /// Allocations between recommendations, 0 if disabled.
+    interval: usize,
+    /// Number of requests per power of 2 bin, halved after every
+    /// recommendation.
+    histogram: [usize; BINS],
+    /// Requests recorded since the last recommendation.
+    pending: usize,
+    /// Last recommendation, `None` until `interval` requests are recorded.
+    recommended: Option<[usize; N]>,
+}
+
+impl<const N: usize> AdaptiveSizes<N> {
+    /// Disabled, nothing is recorded.
+    pub const fn new() -> Self {
+        Self {
+            interval: 0,
+            histogram: [0; BINS],
+            pending: 0,
+            recommended: None,
+        }
+    }
+
+    /// Recomputes the recommendation every `interval` requests, or disables
+    /// recording if `interval` is 0.
+    pub fn set_interval(&mut self, interval: usize) {
+        self.interval = interval;
+    }
+
+    /// Forgets every request recorded so far and the last recommendation,
+    /// but keeps recording at the same interval.
+    pub fn reset(&mut self) {
+        self.histogram = [0; BINS];
+        self.pending = 0;
+        self.recommended = None;
+    }
+
+    /// Last recommendation computed, see the module documentation.
+    #[inline]
+    pub fn recommended(&self) -> Option<[usize; N]> {
+        self.recommended
+    }
+
+    /// Counts one request of `size` bytes.
+    #[inline]
+    pub fn record(&mut self, size: usize) {
+        if self.interval == 0 {
+            return;
+        }
+
+        let bin = size
+            .checked_next_power_of_two()
+            .map_or(BINS - 1, |power| power.trailing_zeros() as usize);
+        self.histogram[bin] += 1;
+        self.pending += 1;
+
+        if self.pending >= self.interval {
+            self.pending = 0;
+            self.recommended = Some(self.recommend());
+            self.histogram.iter_mut().for_each(|count| *count /= 2);
+        }
+    }
+
+    /// Splits the histogram in `N` groups of requests, see the module
+    /// documentation. Sizes are strictly ascending and at least pointer
+    /// sized, so they are always valid bucket sizes.
+    fn recommend(&self) -> [usize; N] {
+        let total: u128 = self.histogram.iter().map(|count| *count as u128).sum();
+        let mut sizes = [0; N];
+        let mut bin = 0;
+        let mut cumulative = self.histogram[0] as u128;
+        let mut previous = 0;
+
+        for (i, size) in sizes.iter_mut().enumerate() {
+            let target = (total * COVERED_PERCENT * (i as u128 + 1)).div_ceil(100 * N as u128);
+            while cumulative < target && bin < BINS - 1 {
+                bin += 1;
+                cumulative += self.histogram[bin] as u128;
+            }
+
+            // Several groups can end in the same bin if it's very popular,
+            // but sizes must be strictly ascending.
+            *size = (1 << bin).max(POINTER_SIZE);
+            if *size <= previous {
+                *size = previous.saturating_mul(2);
+            }
+            previous = *size;
+        }
+
+        sizes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommend_follows_distribution() {
+        let mut adaptive = AdaptiveSizes::<3>::new();
+        adaptive.record(24);
+        assert_eq!(adaptive.recommended(), None);
+
+        adaptive.set_interval(300);
+        for size in [24, 200, 3000] {
+            for _ in 0..100 {
+                adaptive.record(size);
+            }
+        }
+        assert_eq!(adaptive.recommended(), Some([32, 256, 4096]));
+
+        // Requests of one size only still give ascending sizes.
+        let mut adaptive = AdaptiveSizes::<3>::new();
+        adaptive.set_interval(10);
+        (0..10).for_each(|_| adaptive.record(1));
+        assert_eq!(adaptive.recommended(), Some([8, 16, 32]));
+    }
+
+    #[test]
+    fn old_requests_decay() {
+        let mut adaptive = AdaptiveSizes::<1>::new();
+        adaptive.set_interval(100);
+
+        (0..1000).for_each(|_| adaptive.record(24));
+        assert_eq!(adaptive.recommended(), Some([32]));
+
+        // Without decay the first phase would still be 10 times larger than
+        // the second one after the same number of requests.
+        (0..1000).for_each(|_| adaptive.record(3000));
+        assert_eq!(adaptive.recommended(), Some([4096]));
+        assert!(adaptive.histogram[5] < 2);
+    }
+}
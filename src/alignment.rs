@@ -3,7 +3,7 @@
 //! provided by the caller or return an error if it doesn't. See
 //! [`AlignmentBackPointer`] to understand how we deal with this.
 
-use std::{alloc::Layout, mem, ptr::NonNull};
+use std::{alloc::Layout, cmp, mem, ptr::NonNull};
 
 use crate::{
     block::{Block, MIN_BLOCK_SIZE},
@@ -271,6 +271,40 @@ pub(crate) fn minimum_block_size_needed_for(layout: Layout) -> usize {
     size
 }
 
+/// Returns the exact block size needed to allocate `layout` in a block whose
+/// content starts at `address`. [`minimum_block_size_needed_for`] has to
+/// account for the worst case padding because it doesn't know where the
+/// block is located, but once we know the address we only need the padding
+/// that [`next_aligned`] will actually introduce:
+///
+/// ```text
+/// Worst case (padding = align):
+///
+/// +--------------+----------------------+------------------+------------+
+/// | Block header |  Padding (align)     | Content (size)   | Split off  |
+/// +--------------+----------------------+------------------+------------+
+///
+/// Exact (padding = distance to next aligned address):
+///
+/// +--------------+---------+------------------+-------------------------+
+/// | Block header | Padding | Content (size)   | Split off (more space)  |
+/// +--------------+---------+------------------+-------------------------+
+/// ```
+///
+/// The result is never greater than [`minimum_block_size_needed_for`], so
+/// blocks found or created with that size can always fit the exact size, and
+/// the rest of the block can be split off and reused.
+pub(crate) unsafe fn block_size_needed_at(address: NonNull<u8>, layout: Layout) -> usize {
+    if layout.align() <= POINTER_SIZE {
+        return minimum_block_size_needed_for(layout);
+    }
+
+    let size = layout.size() + layout.padding_needed_for(POINTER_SIZE);
+    let padding = padding_needed_to_align(address, layout.align());
+
+    cmp::max(size + padding, MIN_BLOCK_SIZE)
+}
+
 /// Returns the minimum block size needed to allocate `layout` without taking
 /// `layout.align()` into consideration. This is useful for reallocations,
 /// where an address might be already aligned so no need to account for
@@ -344,4 +378,34 @@ mod tests {
             assert_eq!(minimum_block_size_needed_for(layout), expected);
         }
     }
+
+    #[test]
+    fn exact_block_size() {
+        let base = 16 * 4096;
+
+        // (size, align, content offset from page aligned base, expected size)
+        let layouts = [
+            (1, 8, 8, MIN_BLOCK_SIZE),
+            (1, 16, 8, MIN_BLOCK_SIZE),
+            (1, 16, 0, 16 + POINTER_SIZE),
+            (14, 16, 8, 16 + 8),
+            (14, 16, 16, 16 + 16),
+            (13, 32, 24, 16 + 8),
+            (13, 32, 8, 16 + 24),
+            (512, 64, 56, 512 + 8),
+            (1, 4096, 88, 4096 - 88 + POINTER_SIZE),
+        ];
+
+        for (size, align, offset, expected) in layouts {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let address = NonNull::<u8>::dangling()
+                .as_ptr()
+                .map_addr(|_| base + offset);
+            let address = NonNull::new(address).unwrap();
+            let exact = unsafe { block_size_needed_at(address, layout) };
+
+            assert_eq!(exact, expected);
+            assert!(exact <= minimum_block_size_needed_for(layout));
+        }
+    }
 }
@@ -1,10 +1,64 @@
+#[cfg(feature = "fault-injection")]
+use std::sync::atomic::AtomicUsize;
 use std::{
     alloc::{AllocError, Allocator, GlobalAlloc, Layout},
+    array::TryFromSliceError,
+    cmp,
+    ffi::c_int,
+    io,
+    mem::{ManuallyDrop, MaybeUninit},
     ptr::{self, NonNull},
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        PoisonError,
+    },
+    time::Instant,
 };
 
-use crate::{bucket::Bucket, realloc::Realloc, AllocResult};
+#[cfg(feature = "leak-tracking")]
+use crate::leak::LeakTracker;
+#[cfg(feature = "tracy")]
+use crate::tracy;
+use crate::{
+    adaptive::AdaptiveSizes,
+    alignment,
+    block::{Block, MIN_BLOCK_SIZE},
+    bucket::Bucket,
+    canary::{Canary, CANARY_SIZE},
+    exec::ExecRegions,
+    failure::{AllocFailure, ConfigFrozen, LastFailure, UnmapFailure, UnmapFailures},
+    fit::{FirstFit, FitPolicy},
+    freelist::FreeListOrder,
+    generation::CheckedPtr,
+    header::Header,
+    interior::InteriorPointerPolicy,
+    lock::Lock,
+    platform::{self, Protection},
+    realloc::{Realloc, ReallocMethod, ShrinkPolicy},
+    region::RegionInfo,
+    report::HeapReport,
+    secure::SecureBuffer,
+    slab::Slab,
+    source::MmapSource,
+    stats::{Counters, FullStats, Stats},
+    AllocResult, Pointer,
+};
+#[cfg(feature = "timing")]
+use crate::{
+    timing::{LatencyHistogram, LatencyRecorder},
+    window::{self, WindowRecorder, WindowedStats},
+};
+
+/// Where [`Rulloc::allocate_in`] should place an allocation.
+#[derive(Clone, Copy)]
+enum Placement {
+    /// Bucket chosen by size, see [`InternalAllocator::bucket_index_of`].
+    BySize,
+    /// Given bucket, see [`Rulloc::allocate_in_bucket`].
+    Bucket(usize),
+    /// Region of the given address if possible, see [`Rulloc::allocate_near`].
+    Near(NonNull<u8>),
+}
 
 /// This is the main allocator, it contains multiple allocation buckets for
 /// different sizes. Once you've read [`crate::header`], [`crate::block`],
@@ -58,34 +112,128 @@ use crate::{bucket::Bucket, realloc::Realloc, AllocResult};
 ///
 /// # Drop
 ///
-/// This struct doesn't implement [`Drop`] because region unmapping is
-/// implemented by [`Bucket`]. If we don't implement [`Drop`], the compiler will
-/// just call [`Drop::drop`] on all the struct members one by one, so all the
-/// buckets will be dropped automatically.
-struct InternalAllocator<const N: usize> {
+/// Region unmapping is implemented by [`Bucket`], so this struct only
+/// implements [`Drop`] with the `leak-check` feature to look for blocks that
+/// are still in use. Either way, the compiler calls [`Drop::drop`] on all the
+/// struct members one by one afterwards, so all the buckets will be dropped
+/// automatically.
+struct InternalAllocator<const N: usize, P = FirstFit> {
     /// Size of each bucket, in bytes.
     sizes: [usize; N],
     /// Fixed size buckets.
-    buckets: [Bucket; N],
+    buckets: [Bucket<P>; N],
     /// Any allocation request of `size > sizes[N - 1]` will use this bucket.
-    dyn_bucket: Bucket,
+    /// If `N == 0` there are no fixed size buckets at all, so every request
+    /// ends up here.
+    dyn_bucket: Bucket<P>,
+    /// Executable regions, which don't belong to any bucket. See
+    /// [`crate::exec`].
+    exec: ExecRegions,
+    /// Headerless slots that replace `buckets[0]` for tiny allocations when
+    /// enabled. See [`crate::slab`] and [`Rulloc::with_slab`].
+    slab: Slab,
+    /// Histogram of requested sizes, see [`Rulloc::with_adaptive_sizes`].
+    adaptive: AdaptiveSizes<N>,
+    /// Whether shrinking can move blocks to smaller buckets, see
+    /// [`Rulloc::with_shrink_policy`].
+    shrink_policy: ShrinkPolicy,
+    /// Maximum number of bytes that all buckets together can map. See
+    /// [`Rulloc::with_memory_limit`].
+    limit: usize,
+    /// Bucket index and region address where the next step of
+    /// [`Rulloc::defragment_until`] starts, see [`Bucket::defragment_step`].
+    defrag_cursor: (usize, Pointer<u8>),
 }
 
 impl<const N: usize> InternalAllocator<N> {
-    /// Builds a new allocator configured with the given bucket sizes.
+    /// Builds a new allocator configured with the given bucket sizes, which
+    /// must be strictly ascending. Otherwise some buckets would never be used,
+    /// since [`Self::bucket_index_of`] returns the first bucket that can fit
+    /// the request. This is checked at compile time when called in a const
+    /// context.
     pub const fn with_bucket_sizes(sizes: [usize; N]) -> Self {
+        assert!(N < u16::MAX as usize, "too many buckets");
+
+        let mut i = 1;
+        while i < N {
+            assert!(
+                sizes[i - 1] < sizes[i],
+                "bucket sizes must be strictly ascending"
+            );
+            i += 1;
+        }
+
         const BUCKET: Bucket = Bucket::new();
+        let mut buckets = [BUCKET; N];
+        let mut dyn_bucket = Bucket::new();
+
+        let mut i = 0;
+        while i < N {
+            buckets[i].set_index(i as u16);
+            i += 1;
+        }
+        dyn_bucket.set_index(N as u16);
+
         InternalAllocator::<N> {
             sizes,
-            buckets: [BUCKET; N],
-            dyn_bucket: Bucket::new(),
+            buckets,
+            dyn_bucket,
+            exec: ExecRegions::new(),
+            slab: Slab::new(),
+            adaptive: AdaptiveSizes::new(),
+            shrink_policy: ShrinkPolicy::Migrate,
+            limit: usize::MAX,
+            defrag_cursor: (0, None),
+        }
+    }
+
+    /// Same as [`Self::with_bucket_sizes`] but every fixed size bucket is a
+    /// pool of blocks of its size, see [`Bucket::set_pool`].
+    pub const fn pool_mode(sizes: [usize; N]) -> Self {
+        let mut allocator = Self::with_bucket_sizes(sizes);
+
+        let mut i = 0;
+        while i < N {
+            allocator.buckets[i].set_pool(sizes[i]);
+            i += 1;
+        }
+
+        allocator
+    }
+}
+
+impl<const N: usize, P: FitPolicy> InternalAllocator<N, P> {
+    /// Replaces the [`FitPolicy`] of all buckets. Each bucket gets its own
+    /// clone of `policy`.
+    fn replace_policy<Q: FitPolicy + Clone>(self, policy: Q) -> InternalAllocator<N, Q> {
+        // Same as [`Bucket::replace_policy`], we can't move fields out of a
+        // type that might implement Drop, but all of them are moved to the
+        // new allocator so there's nothing left to drop.
+        let allocator = ManuallyDrop::new(self);
+        unsafe {
+            InternalAllocator {
+                sizes: allocator.sizes,
+                buckets: ptr::read(&allocator.buckets)
+                    .map(|bucket| bucket.replace_policy(policy.clone())),
+                dyn_bucket: ptr::read(&allocator.dyn_bucket).replace_policy(policy),
+                exec: ptr::read(&allocator.exec),
+                slab: ptr::read(&allocator.slab),
+                adaptive: ptr::read(&allocator.adaptive),
+                shrink_policy: allocator.shrink_policy,
+                limit: allocator.limit,
+                defrag_cursor: allocator.defrag_cursor,
+            }
         }
     }
 
     /// Returns the index of the [`Bucket`] where `layout` should be allocated.
+    /// Pools can't deal with alignment padding, so requests with stricter
+    /// alignment skip them.
     fn bucket_index_of(&self, layout: Layout) -> usize {
         for (i, size) in self.sizes.iter().enumerate() {
-            if layout.size() <= *size {
+            if layout.size() <= *size
+                && (layout.align() <= alignment::POINTER_SIZE || !self.buckets[i].is_pool())
+            {
                 return i;
             }
         }
@@ -93,8 +241,17 @@ impl<const N: usize> InternalAllocator<N> {
         self.buckets.len()
     }
 
+    /// Returns a reference to the [`Bucket`] at `index`.
+    fn bucket(&self, index: usize) -> &Bucket<P> {
+        if index == self.buckets.len() {
+            &self.dyn_bucket
+        } else {
+            &self.buckets[index]
+        }
+    }
+
     /// Returns a mutable reference to the [`Bucket`] at `index`.
-    fn bucket_mut(&mut self, index: usize) -> &mut Bucket {
+    fn bucket_mut(&mut self, index: usize) -> &mut Bucket<P> {
         if index == self.buckets.len() {
             &mut self.dyn_bucket
         } else {
@@ -102,29 +259,412 @@ impl<const N: usize> InternalAllocator<N> {
         }
     }
 
-    /// Returns a mutable reference to the [`Bucket`] where `layout` should be
-    /// allocated.
+    /// Iterator over all buckets, including [`Self::dyn_bucket`].
+    fn buckets_mut(&mut self) -> impl Iterator<Item = &mut Bucket<P>> {
+        self.buckets
+            .iter_mut()
+            .chain(std::iter::once(&mut self.dyn_bucket))
+    }
+
+    /// See [`Rulloc::region_for`].
+    unsafe fn region_for(&self, address: NonNull<u8>) -> Option<RegionInfo> {
+        self.buckets
+            .iter()
+            .chain(std::iter::once(&self.dyn_bucket))
+            .find_map(|bucket| bucket.region_for(address))
+    }
+
+    /// Total number of content bytes in free blocks of all buckets and free
+    /// slots of the slab.
+    fn free_bytes(&self) -> usize {
+        self.buckets.iter().fold(
+            self.dyn_bucket.free_bytes() + self.slab.free_bytes(),
+            |total, bucket| total + bucket.free_bytes(),
+        )
+    }
+
+    /// Total number of bytes mapped by all buckets and the slab.
+    fn bytes_mapped(&self) -> usize {
+        self.buckets.iter().fold(
+            self.dyn_bucket.bytes_mapped() + self.slab.bytes_mapped(),
+            |total, bucket| total + bucket.bytes_mapped(),
+        )
+    }
+
+    /// Total number of deallocations with mismatched layouts in all buckets.
+    #[cfg(feature = "layout-tracking")]
+    fn layout_mismatches(&self) -> usize {
+        self.buckets
+            .iter()
+            .fold(self.dyn_bucket.layout_mismatches(), |total, bucket| {
+                total + bucket.layout_mismatches()
+            })
+    }
+
+    /// Total number of free blocks in all buckets.
+    fn num_free_blocks(&self) -> usize {
+        self.buckets
+            .iter()
+            .fold(self.dyn_bucket.num_free_blocks(), |total, bucket| {
+                total + bucket.num_free_blocks()
+            })
+    }
+
+    /// Total number of blocks in all buckets, free or not.
+    fn num_blocks(&self) -> usize {
+        self.buckets
+            .iter()
+            .fold(self.dyn_bucket.num_blocks(), |total, bucket| {
+                total + bucket.num_blocks()
+            })
+    }
+
+    /// Total number of regions mapped by all buckets and the slab.
+    fn num_regions(&self) -> usize {
+        self.buckets.iter().fold(
+            self.dyn_bucket.num_regions() + self.slab.num_regions(),
+            |total, bucket| total + bucket.num_regions(),
+        )
+    }
+
+    /// Buckets don't know about each other, so before a bucket maps new
+    /// regions we tell it how many bytes it can map without exceeding
+    /// [`Self::limit`] given what the other buckets have already mapped.
+    /// Nothing to do if there's no limit, which is the common case.
+    fn enforce_limit(&mut self, index: usize) {
+        if self.limit == usize::MAX {
+            return;
+        }
+
+        let mapped_by_others = self.bytes_mapped() - self.bucket(index).bytes_mapped();
+        let limit = self.limit.saturating_sub(mapped_by_others);
+        self.bucket_mut(index).set_limit(limit);
+    }
+
+    /// See [`Bucket::take_fresh`]. `address` must have been returned by the
+    /// last allocation, which is the one whose contents are described. Slab
+    /// slots are never fresh.
+    pub unsafe fn take_fresh(&mut self, address: NonNull<u8>, layout: Layout) -> Option<u8> {
+        if self.is_in_slab(address, layout) {
+            return None;
+        }
+
+        let block = Header::<Block>::from_allocated_pointer(address, layout);
+        self.bucket_mut(block.as_ref().data.bucket as usize)
+            .take_fresh()
+    }
+
+    /// Reason of the last failed operation, see [`crate::failure`]. Only one
+    /// bucket (or the slab) is involved in each failure and the reason is
+    /// taken right after the operation, so there's at most one pending
+    /// failure.
+    pub fn take_failure(&mut self) -> Option<AllocFailure> {
+        self.slab
+            .take_failure()
+            .or_else(|| self.buckets_mut().find_map(Bucket::take_failure))
+    }
+
+    /// Unmap failures of all the buckets and the slab since the last call,
+    /// see [`crate::failure`]. Unlike allocation failures, one operation can
+    /// unmap multiple regions of different buckets, so they're all merged.
+    pub fn take_unmap_failures(&mut self) -> UnmapFailures {
+        let mut failures = self.slab.take_unmap_failures();
+        for bucket in self.buckets_mut() {
+            failures.merge(bucket.take_unmap_failures());
+        }
+
+        failures
+    }
+
+    /// Adds the size of `layout` to [`Region::requested`] for the block
+    /// allocated at `address`, which can't be a slot of the slab. Must be
+    /// called for every allocation served by a bucket, and
+    /// [`Self::discharge`] for every deallocation, so that blocks dropped
+    /// all at once can be discounted, see [`Rulloc::clear_bucket`].
+    #[inline]
+    unsafe fn charge(address: NonNull<u8>, layout: Layout) {
+        let mut region = Header::<Block>::from_allocated_pointer(address, layout)
+            .as_ref()
+            .data
+            .region;
+        let requested = &mut region.as_mut().data.requested;
+        *requested = requested.wrapping_add(layout.size());
+    }
+
+    /// Opposite of [`Self::charge`].
+    #[inline]
+    unsafe fn discharge(address: NonNull<u8>, layout: Layout) {
+        let mut region = Header::<Block>::from_allocated_pointer(address, layout)
+            .as_ref()
+            .data
+            .region;
+        let requested = &mut region.as_mut().data.requested;
+        *requested = requested.wrapping_sub(layout.size());
+    }
+
+    /// Allocates `layout` in the bucket at `index` and charges it, see
+    /// [`Self::charge`].
     #[inline]
-    fn dispatch(&mut self, layout: Layout) -> &mut Bucket {
-        self.bucket_mut(self.bucket_index_of(layout))
+    unsafe fn allocate_charged(&mut self, index: usize, layout: Layout) -> AllocResult {
+        let address = self.bucket_mut(index).allocate(layout)?;
+        Self::charge(address.cast(), layout);
+        Ok(address)
     }
 
     /// Returns an address where `layout.size()` bytes can be safely written or
     /// [`AllocError`] if it fails to allocate.
     #[inline]
     pub unsafe fn allocate(&mut self, layout: Layout) -> AllocResult {
-        self.dispatch(layout).allocate(layout)
+        self.adaptive.record(layout.size());
+        let index = self.bucket_index_of(layout);
+        if index == 0 && self.slab.fits(layout) {
+            return self.allocate_in_slab();
+        }
+        self.enforce_limit(index);
+        self.allocate_charged(index, layout)
+    }
+
+    /// Same as [`Self::allocate`] but tries the region of `hint` first, see
+    /// [`Bucket::allocate_near`].
+    #[inline]
+    pub unsafe fn allocate_near(&mut self, layout: Layout, hint: NonNull<u8>) -> AllocResult {
+        self.adaptive.record(layout.size());
+        let index = self.bucket_index_of(layout);
+        if index == 0 && self.slab.fits(layout) {
+            return self.allocate_in_slab();
+        }
+        self.enforce_limit(index);
+        let address = self.bucket_mut(index).allocate_near(layout, hint)?;
+        Self::charge(address.cast(), layout);
+        Ok(address)
+    }
+
+    /// Maps `counts[i]` regions in the fixed size bucket `i`, see
+    /// [`Bucket::prewarm`]. Stops at the first bucket that fails to map.
+    pub unsafe fn prewarm(&mut self, counts: [usize; N]) -> Result<(), AllocError> {
+        for (index, count) in counts.into_iter().enumerate() {
+            self.enforce_limit(index);
+            let size = self.sizes[index];
+            self.buckets[index].prewarm(count, size)?;
+        }
+
+        Ok(())
+    }
+
+    /// See [`Bucket::allocate_batch`].
+    #[inline]
+    pub unsafe fn allocate_batch(
+        &mut self,
+        layout: Layout,
+        count: usize,
+        addresses: &mut Vec<NonNull<u8>>,
+    ) -> Result<(), AllocError> {
+        (0..count).for_each(|_| self.adaptive.record(layout.size()));
+        let index = self.bucket_index_of(layout);
+        self.enforce_limit(index);
+        let start = addresses.len();
+        self.bucket_mut(index)
+            .allocate_batch(layout, count, addresses)?;
+        for address in &addresses[start..] {
+            Self::charge(*address, layout);
+        }
+        Ok(())
+    }
+
+    /// Allocates `layout` in the bucket at `index` instead of the one chosen by
+    /// [`Self::bucket_index_of`]. The caller must check that the bucket can
+    /// hold `layout`.
+    #[inline]
+    pub unsafe fn allocate_in_bucket(&mut self, index: usize, layout: Layout) -> AllocResult {
+        self.adaptive.record(layout.size());
+        if index == 0 && self.slab.fits(layout) {
+            return self.allocate_in_slab();
+        }
+        self.enforce_limit(index);
+        self.allocate_charged(index, layout)
+    }
+
+    /// Runs one step of [`Rulloc::defragment_until`] at
+    /// [`Self::defrag_cursor`] and moves the cursor forward. Returns `false`
+    /// once every bucket has been processed, the cursor then goes back to
+    /// the first bucket so the next call starts a new pass.
+    pub unsafe fn defragment_step(&mut self) -> bool {
+        let (index, from) = self.defrag_cursor;
+
+        self.defrag_cursor = match self.bucket_mut(index).defragment_step(from) {
+            Some(next) => (index, Some(next)),
+            None if index < N => (index + 1, None),
+            None => (0, None),
+        };
+
+        self.defrag_cursor != (0, None)
+    }
+
+    /// Returns one slot of [`Self::slab`]. Same as [`Self::enforce_limit`],
+    /// the slab can only map what the buckets have left.
+    unsafe fn allocate_in_slab(&mut self) -> AllocResult {
+        let limit = self
+            .limit
+            .saturating_sub(self.bytes_mapped() - self.slab.bytes_mapped());
+        let address = self.slab.allocate(limit).ok_or(AllocError)?;
+
+        Ok(NonNull::slice_from_raw_parts(
+            address,
+            self.slab.slot_size(),
+        ))
+    }
+
+    /// Whether `address` was allocated with `layout` in [`Self::slab`]. Slab
+    /// slots have no header, so this must be checked before reading one.
+    #[inline]
+    fn is_in_slab(&self, address: NonNull<u8>, layout: Layout) -> bool {
+        self.slab.fits(layout) && self.slab.contains(address)
+    }
+
+    /// Number of bytes that can be written at `address`, which was allocated
+    /// with `layout`, up to the end of its block or slab slot. With
+    /// `layout-tracking` the block now remembers a layout of that size, see
+    /// [`Rulloc::try_grow_to_capacity`].
+    pub unsafe fn grow_to_capacity(&mut self, address: NonNull<u8>, layout: Layout) -> usize {
+        if self.is_in_slab(address, layout) {
+            return self.slab.slot_size();
+        }
+
+        let mut block = Header::<Block>::from_allocated_pointer(address, layout);
+        let end = Header::content_address_of(block)
+            .as_ptr()
+            .add(block.as_ref().size());
+        let capacity = end.addr() - address.as_ptr().addr();
+        Self::discharge(address, layout);
+        Self::charge(
+            address,
+            Layout::from_size_align_unchecked(capacity, layout.align()),
+        );
+
+        #[cfg(feature = "layout-tracking")]
+        {
+            block.as_mut().data.layout =
+                Some(Layout::from_size_align_unchecked(capacity, layout.align()));
+        }
+        #[cfg(not(feature = "layout-tracking"))]
+        let _ = &mut block;
+
+        capacity
+    }
+
+    /// Whether `address` points into memory managed by this allocator, either
+    /// a slot of the slab or any region of any bucket. Only addresses are
+    /// compared, nothing is read from `address` itself, so this is safe to
+    /// call with any pointer before trusting its block header.
+    ///
+    /// Debug builds walk through the regions of every bucket, see
+    /// [`Self::region_for`]. That's too slow for every deallocation, so
+    /// release builds only check that `address` is within the range spanned
+    /// by the regions of some bucket, see [`Bucket::spans`]. Pointers that
+    /// fall in a gap between two regions are not caught there.
+    #[inline]
+    unsafe fn owns(&self, address: NonNull<u8>, layout: Layout) -> bool {
+        if self.is_in_slab(address, layout) {
+            return true;
+        }
+
+        if cfg!(debug_assertions) {
+            self.region_for(address).is_some()
+        } else {
+            self.buckets
+                .iter()
+                .chain(std::iter::once(&self.dyn_bucket))
+                .any(|bucket| bucket.spans(address))
+        }
+    }
+
+    /// Alignment that the allocation at `address` was made with, if it's not
+    /// the alignment of `layout`. Only checked in debug builds, see
+    /// [`Bucket::allocated_align`], release builds always return `None`.
+    /// Slab slots don't record anything.
+    unsafe fn misaligned(&self, address: NonNull<u8>, layout: Layout) -> Option<usize> {
+        #[cfg(debug_assertions)]
+        if !self.is_in_slab(address, layout) {
+            return self
+                .buckets
+                .iter()
+                .chain(std::iter::once(&self.dyn_bucket))
+                .find_map(|bucket| bucket.allocated_align(address, layout))
+                .filter(|align| *align != layout.align());
+        }
+
+        #[cfg(not(debug_assertions))]
+        let _ = (address, layout);
+
+        None
+    }
+
+    /// Address of the allocation that contains `address`. It's only different
+    /// if `address` is an interior pointer and the bucket that owns it is
+    /// [`InteriorPointerPolicy::Lenient`], with [`InteriorPointerPolicy::Panic`]
+    /// this panics instead. The bucket is found by address and not by reading
+    /// the block header, which is garbage for interior pointers. Release
+    /// builds, slab slots and [`InteriorPointerPolicy::Trust`] return
+    /// `address` as is.
+    #[inline]
+    pub unsafe fn resolve(&self, address: NonNull<u8>, layout: Layout) -> NonNull<u8> {
+        #[cfg(debug_assertions)]
+        if !self.is_in_slab(address, layout) {
+            let resolved = self
+                .buckets
+                .iter()
+                .chain(std::iter::once(&self.dyn_bucket))
+                .find_map(|bucket| bucket.resolve_interior_pointer(address, layout));
+
+            if let Some(resolved) = resolved {
+                return resolved;
+            }
+        }
+
+        #[cfg(not(debug_assertions))]
+        let _ = layout;
+
+        address
+    }
+
+    /// Bucket that owns the allocation at `address`, or `None` if it's a
+    /// slot of the slab. See [`crate::tracy`].
+    #[cfg(feature = "tracy")]
+    unsafe fn bucket_of(&self, address: NonNull<u8>, layout: Layout) -> Option<usize> {
+        if self.is_in_slab(address, layout) {
+            return None;
+        }
+
+        let address = self.resolve(address, layout);
+        let block = Header::<Block>::from_allocated_pointer(address, layout);
+        Some(block.as_ref().data.bucket as usize)
     }
 
     /// Deallocates the memory block at `address`.
     #[inline]
     pub unsafe fn deallocate(&mut self, address: NonNull<u8>, layout: Layout) {
-        // We can find the bucket that has allocated the pointer because we also
-        // know the layout. If the allocator trait changes and the layout is
-        // no longer required, we can still obtain the block header given any
-        // valid address and check the size to find the bucket. Let's hope it
-        // doesn't change though, layouts are useful information for allocators!
-        self.dispatch(layout).deallocate(address, layout)
+        if self.is_in_slab(address, layout) {
+            return self.slab.deallocate(address);
+        }
+
+        let address = self.resolve(address, layout);
+
+        // The layout would be enough to find the bucket if all allocations
+        // went through [`Self::bucket_index_of`], but blocks placed with
+        // [`Self::allocate_in_bucket`] can be anywhere, so we read the index
+        // stored in the block header instead.
+        let block = Header::<Block>::from_allocated_pointer(address, layout);
+        let index = block.as_ref().data.bucket as usize;
+        Self::discharge(address, layout);
+
+        // Blocks that fill their fixed size bucket exactly are most likely
+        // part of a pool of objects of the same type, so they should be
+        // reused as soon as possible, see [`Bucket::recycle`].
+        if index < N && layout.size() == self.sizes[index] {
+            self.bucket_mut(index).recycle(address, layout)
+        } else {
+            self.bucket_mut(index).deallocate(address, layout)
+        }
     }
 
     /// Reallocation algorithm. Whether shrinking or growing, we'll try to
@@ -133,25 +673,157 @@ impl<const N: usize> InternalAllocator<N> {
     /// different bucket, we'll move the user contents there. Otherwise just
     /// delegate the call to the current bucket and handle reallocation
     /// internally.
+    ///
+    /// Note that moving to another bucket takes precedence over in place
+    /// reallocation. If a small allocation grows past `sizes[N - 1]` it will
+    /// always be moved to [`Self::dyn_bucket`], even if the block right after
+    /// it is free and big enough, so fixed size buckets never contain blocks
+    /// larger than their size. Blocks placed with [`Self::allocate_in_bucket`]
+    /// are moved to the bucket where `new_layout` would normally go as well.
+    /// The only exception is [`ShrinkPolicy::Stable`], which keeps shrunk
+    /// blocks in their current bucket.
     pub unsafe fn reallocate(&mut self, realloc: &Realloc) -> AllocResult {
-        let current_bucket = self.bucket_index_of(realloc.old_layout);
-        let ideal_bucket = self.bucket_index_of(realloc.new_layout);
+        if self.is_in_slab(realloc.address, realloc.old_layout) {
+            return self.reallocate_from_slab(realloc);
+        }
+
+        let address = self.resolve(realloc.address, realloc.old_layout);
+        if address != realloc.address {
+            let realloc = Realloc::new(
+                address,
+                realloc.old_layout,
+                realloc.new_layout,
+                realloc.method,
+            );
+            return self.reallocate(&realloc);
+        }
+
+        // The region might be unmapped if the block moves, so the old size
+        // is discharged first and charged back if reallocation fails.
+        Self::discharge(realloc.address, realloc.old_layout);
+        let result = self.reallocate_uncharged(realloc);
+        match result {
+            Ok(address) => Self::charge(address.cast(), realloc.new_layout),
+            Err(_) => Self::charge(realloc.address, realloc.old_layout),
+        }
+
+        result
+    }
+
+    /// Rest of [`Self::reallocate`] for blocks that have a header, without
+    /// charging anything.
+    unsafe fn reallocate_uncharged(&mut self, realloc: &Realloc) -> AllocResult {
+        // Now we know there's a header, see [`Realloc::with_requested_size`].
+        #[cfg(feature = "layout-tracking")]
+        let realloc = &realloc.with_requested_size();
+
+        let current_bucket = realloc.block.as_ref().data.bucket as usize;
+        let ideal_bucket = match realloc.method {
+            ReallocMethod::Shrink if self.shrink_policy == ShrinkPolicy::Stable => current_bucket,
+            _ => self.bucket_index_of(realloc.new_layout),
+        };
+        self.enforce_limit(ideal_bucket);
+
+        let new_address = if current_bucket == ideal_bucket {
+            self.bucket_mut(current_bucket).reallocate(realloc)?
+        } else {
+            let new_address = self.bucket_mut(ideal_bucket).allocate(realloc.new_layout)?;
+            ptr::copy_nonoverlapping(
+                realloc.address.as_ptr(),
+                new_address.as_mut_ptr(),
+                realloc.count(),
+            );
+            self.bucket_mut(current_bucket)
+                .deallocate(realloc.address, realloc.old_layout);
+            new_address
+        };
+
+        // Exact shrinks report exactly what was requested, see
+        // [`Bucket::set_exact_shrink`].
+        if matches!(realloc.method, ReallocMethod::Shrink)
+            && self.bucket(ideal_bucket).exact_shrink()
+        {
+            return Ok(NonNull::slice_from_raw_parts(
+                new_address.cast(),
+                realloc.new_layout.size(),
+            ));
+        }
 
-        if current_bucket == ideal_bucket {
-            return self.bucket_mut(current_bucket).reallocate(realloc);
+        Ok(new_address)
+    }
+
+    /// Reallocation of a slot of [`Self::slab`]. If the new layout still fits
+    /// in one slot there's nothing to do, otherwise the contents are moved to
+    /// wherever [`Self::allocate`] places the new layout.
+    unsafe fn reallocate_from_slab(&mut self, realloc: &Realloc) -> AllocResult {
+        if self.slab.fits(realloc.new_layout) {
+            return Ok(NonNull::slice_from_raw_parts(
+                realloc.address,
+                self.slab.slot_size(),
+            ));
         }
 
-        let new_address = self.bucket_mut(ideal_bucket).allocate(realloc.new_layout)?;
+        let new_address = self.allocate(realloc.new_layout)?;
         ptr::copy_nonoverlapping(
             realloc.address.as_ptr(),
             new_address.as_mut_ptr(),
             realloc.count(),
         );
-        self.bucket_mut(current_bucket)
-            .deallocate(realloc.address, realloc.old_layout);
+        self.slab.deallocate(realloc.address);
 
         Ok(new_address)
     }
+
+    /// Panics if any two regions or any two blocks overlap in address space,
+    /// which would mean that some pointer arithmetic went wrong. Blocks are
+    /// checked against their neighbours by each bucket, so here we only need
+    /// to check regions of different buckets against each other. Each region
+    /// list is already sorted by address, so sorting the concatenation of all
+    /// lists is almost linear. Only used for testing.
+    #[cfg(test)]
+    pub(crate) unsafe fn verify_no_overlap(&self) {
+        let mut ranges = Vec::new();
+
+        for bucket in self.buckets.iter().chain(std::iter::once(&self.dyn_bucket)) {
+            bucket.verify_no_overlap(&mut ranges);
+        }
+
+        ranges.sort_by_key(|range| range.start);
+
+        for pair in ranges.windows(2) {
+            assert!(
+                pair[0].end <= pair[1].start,
+                "regions {:#x?} and {:#x?} overlap",
+                pair[0],
+                pair[1]
+            );
+        }
+    }
+
+    /// See [`Bucket::generation_of`]. Slab slots have no generation, they
+    /// are always 0.
+    #[cfg(debug_assertions)]
+    unsafe fn generation_of(&self, address: NonNull<u8>, layout: Layout) -> usize {
+        if self.is_in_slab(address, layout) {
+            return 0;
+        }
+
+        self.bucket(self.bucket_index_of(layout))
+            .generation_of(address, layout)
+    }
+
+    /// See [`Bucket::is_stale`]. Slab slots can only be detected as stale
+    /// while they are free, once reused by another allocation they look
+    /// valid again.
+    #[cfg(debug_assertions)]
+    unsafe fn is_stale(&self, address: NonNull<u8>, generation: usize, layout: Layout) -> bool {
+        if self.is_in_slab(address, layout) {
+            return !self.slab.is_allocated(address);
+        }
+
+        self.bucket(self.bucket_index_of(layout))
+            .is_stale(address, generation)
+    }
 }
 
 /// This struct exposes the public interface by implementing
@@ -219,44 +891,117 @@ impl<const N: usize> InternalAllocator<N> {
 ///     assert_eq!(*num, 5);
 /// }
 /// ```
-pub struct Rulloc<const N: usize = 3> {
+///
+/// ## Global and explicit allocator at the same time
+///
+/// The same static instance can be the global allocator and also be passed
+/// explicitly to collections, because `&'static Rulloc` implements
+/// [`Allocator`] as well. This is useful to group some allocations together,
+/// for example with a different bucket configuration than the rest of the
+/// program:
+///
+/// ```no_run
+/// #![feature(allocator_api)]
+///
+/// use rulloc::Rulloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: Rulloc = Rulloc::with_default_config();
+///
+/// fn main() {
+///     // Allocated through GlobalAlloc.
+///     let global = vec![1, 2, 3];
+///     // Allocated through Allocator, same instance.
+///     let mut explicit = Vec::new_in(&GLOBAL);
+///     explicit.extend_from_slice(&global);
+/// }
+/// ```
+///
+/// This works because the allocator never allocates memory while holding its
+/// lock. If it did, the global allocator would be called again on the same
+/// thread and try to acquire the lock that the thread already holds, which
+/// is a deadlock. That's why anything that needs memory, such as leak
+/// tracking backtraces or the vector returned by [`Rulloc::allocate_batch`],
+/// is done before acquiring the lock or after releasing it.
+pub struct Rulloc<const N: usize = 3, P = FirstFit> {
     /// Currently we use a global [`Mutex`] to access the allocator, but here
     /// are some ideas to further optimize multithreaded allocations:
     ///
     /// 1. Use one [`Mutex`] per [`Bucket`]. That way different size allocations
-    /// don't have to wait on each other. Note that reallocations might try to
-    /// "move" a pointer from one [`Bucket`] to another if the requested new
-    /// size changes drastically. If each [`Bucket`] has its own lock, we have
-    /// to handle deadlocks properly with [`Mutex::try_lock`].
+    ///    don't have to wait on each other. Note that reallocations might try
+    ///    to "move" a pointer from one [`Bucket`] to another if the requested
+    ///    new size changes drastically. If each [`Bucket`] has its own lock, we
+    ///    have to handle deadlocks properly with [`Mutex::try_lock`].
     ///
     /// 2. Use a fixed number of allocators and distribute requests from
-    /// different threads between them (round-robin, for example). Each
-    /// allocator could have a global [`Mutex`] or one [`Mutex`] per [`Bucket`]
-    /// like mentioned above.
+    ///    different threads between them (round-robin, for example). Each
+    ///    allocator could have a global [`Mutex`] or one [`Mutex`] per
+    ///    [`Bucket`] like mentioned above.
     ///
     /// 3. Don't use any [`Mutex`] at all, have one entire allocator per thread.
-    /// Conceptually, we would need a mapping of [`std::thread::ThreadId`] to
-    /// [`InternalAllocator`]. Instead of using general data structures that
-    /// need to allocate memory, such as hash maps, we could use a fixed size
-    /// array and store a tuple of `(ThreadId, Bucket)`. Each allocation will
-    /// perform a linear scan to find the [`Bucket`] where we should allocate.
-    /// This is technically O(n) but as long as we don't have thousands of
-    /// threads it won't be an issue. If we end up needing to allocate memory
-    /// for ourselves, we can just use [`crate::platform::request_memory`]. The
-    /// issue with this approach is that we have to deal with threads that
-    /// deallocate memory which was not allocated by themselves, so we need more
-    /// than a simple mapping.
-    allocator: Mutex<InternalAllocator<N>>,
+    ///    Conceptually, we would need a mapping of [`std::thread::ThreadId`] to
+    ///    [`InternalAllocator`]. Instead of using general data structures that
+    ///    need to allocate memory, such as hash maps, we could use a fixed size
+    ///    array and store a tuple of `(ThreadId, Bucket)`. Each allocation will
+    ///    perform a linear scan to find the [`Bucket`] where we should
+    ///    allocate. This is technically O(n) but as long as we don't have
+    ///    thousands of threads it won't be an issue. If we end up needing to
+    ///    allocate memory for ourselves, we can just use
+    ///    [`crate::platform::request_memory`]. The issue with this approach is
+    ///    that we have to deal with threads that deallocate memory which was
+    ///    not allocated by themselves, so we need more than a simple mapping.
+    allocator: Lock<InternalAllocator<N, P>>,
+    /// Counters that can be read without acquiring the lock. See
+    /// [`Rulloc::poll_stats`].
+    counters: Counters,
+    /// Reason of the last failed allocation, see [`Rulloc::last_error`].
+    failures: LastFailure,
+    /// Redzone canaries, `None` if disabled. See [`crate::canary`].
+    pub(crate) canary: Option<Canary>,
+    /// Whether runtime setters are rejected, see [`Rulloc::freeze_config`].
+    frozen: AtomicBool,
+    /// Number of upcoming allocations that will fail on purpose, see
+    /// [`Rulloc::set_fail_next`].
+    #[cfg(feature = "fault-injection")]
+    fail_next: AtomicUsize,
+    /// Backtraces of live allocations.
+    #[cfg(feature = "leak-tracking")]
+    leaks: LeakTracker,
+    /// Latency of allocations and deallocations.
+    #[cfg(feature = "timing")]
+    latencies: LatencyRecorder,
+    /// Allocations and deallocations per second.
+    #[cfg(feature = "timing")]
+    windows: WindowRecorder,
 }
 
-unsafe impl<const N: usize> Sync for Rulloc<N> {}
+// Raw pointers make the compiler assume that Rulloc can't cross threads, but
+// every region it points to is owned by the allocator and only touched while
+// holding the lock, so it can be moved to another thread and shared between
+// threads. The fit policy is used mutably behind the lock as well, which is
+// why it only needs to be Send. Everything else is atomic or thread safe on
+// its own.
+unsafe impl<const N: usize, P: Send> Send for Rulloc<N, P> {}
+unsafe impl<const N: usize, P: Send> Sync for Rulloc<N, P> {}
 
 impl Rulloc {
     /// Default configuration includes 3 buckets of sizes 128, 1024 and 8192.
     /// See [`Rulloc::<N>::with_bucket_sizes`] for details.
     pub const fn with_default_config() -> Self {
         Self {
-            allocator: Mutex::new(InternalAllocator::with_bucket_sizes([128, 1024, 8192])),
+            allocator: Lock::new(InternalAllocator::with_bucket_sizes([128, 1024, 8192])),
+            counters: Counters::new(),
+            failures: LastFailure::new(),
+            canary: None,
+            frozen: AtomicBool::new(false),
+            #[cfg(feature = "fault-injection")]
+            fail_next: AtomicUsize::new(0),
+            #[cfg(feature = "leak-tracking")]
+            leaks: LeakTracker::new(),
+            #[cfg(feature = "timing")]
+            latencies: LatencyRecorder::new(),
+            #[cfg(feature = "timing")]
+            windows: WindowRecorder::new(window::monotonic_seconds),
         }
     }
 }
@@ -292,212 +1037,5626 @@ impl<const N: usize> Rulloc<N> {
     /// assert!(p2.len() >= 100);
     /// assert!(p3.len() >= 210);
     /// assert!(p4.len() >= 512);
+    ///
+    /// for (p, size) in [(p1, 64), (p2, 100), (p3, 210), (p4, 512)] {
+    ///     unsafe { rulloc.deallocate(p.cast(), Layout::from_size_align(size, 8).unwrap()) };
+    /// }
     /// ```
-    pub fn with_bucket_sizes(sizes: [usize; N]) -> Self {
-        Self {
-            allocator: Mutex::new(InternalAllocator::with_bucket_sizes(sizes)),
-        }
+    ///
+    /// # Dynamic bucket only
+    ///
+    /// `N = 0` is also a valid configuration. There won't be any fixed size
+    /// buckets, so all allocations are served by the dynamic bucket regardless
+    /// of their size. This is useful for workloads where sizes are so random
+    /// that segregating them doesn't reduce fragmentation at all:
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<0>::with_bucket_sizes([]);
+    /// let p = rulloc.allocate(Layout::from_size_align(16, 8).unwrap()).unwrap();
+    /// assert!(p.len() >= 16);
+    /// unsafe { rulloc.deallocate(p.cast(), Layout::from_size_align(16, 8).unwrap()) };
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `sizes` is not strictly ascending. This is a `const fn`, so when the
+    /// allocator is built in a const context, such as a `static` global
+    /// allocator, invalid sizes are rejected at compile time:
+    ///
+    /// ```rust,compile_fail
+    /// use rulloc::Rulloc;
+    ///
+    /// static ALLOCATOR: Rulloc<3> = Rulloc::<3>::with_bucket_sizes([8, 4, 2]);
+    /// ```
+    pub const fn with_bucket_sizes(sizes: [usize; N]) -> Self {
+        Self::with_allocator(InternalAllocator::with_bucket_sizes(sizes))
     }
-}
 
-impl Default for Rulloc {
-    fn default() -> Self {
-        Rulloc::with_default_config()
+    /// Same as [`Self::with_bucket_sizes`] but the sizes are given as a slice,
+    /// which is useful when they are computed at runtime or read from a
+    /// configuration file. The number of buckets is still part of the type,
+    /// so this fails if `sizes` doesn't contain exactly `N` elements.
+    ///
+    /// # Panics
+    ///
+    /// If `sizes` is not strictly ascending, see [`Self::with_bucket_sizes`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let sizes: Vec<usize> = (6..9).map(|shift| 1 << shift).collect();
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes_from_slice(&sizes).unwrap();
+    ///
+    /// assert!(Rulloc::<4>::with_bucket_sizes_from_slice(&sizes).is_err());
+    /// ```
+    pub fn with_bucket_sizes_from_slice(sizes: &[usize]) -> Result<Self, TryFromSliceError> {
+        sizes.try_into().map(Self::with_bucket_sizes)
     }
-}
 
-unsafe impl<const N: usize> Allocator for Rulloc<N> {
-    fn allocate(&self, layout: Layout) -> AllocResult {
-        unsafe {
-            match self.allocator.lock() {
-                Ok(mut allocator) => allocator.allocate(layout),
-                Err(_) => Err(AllocError),
-            }
-        }
+    /// Builds an allocator tuned for throughput instead of memory usage.
+    /// Every fixed size bucket becomes a pool of blocks of exactly its size:
+    /// new regions are carved into blocks up front and after that allocating
+    /// and deallocating just pop and push the head of a free list, without
+    /// searching, splitting or merging anything. Since blocks are never
+    /// merged, regions mapped by pools are never returned to the kernel until
+    /// the allocator is dropped, so memory usage only grows to the peak of
+    /// the workload.
+    ///
+    /// Requests larger than the last size or aligned to more than the
+    /// pointer size are served by the dynamic bucket, which works as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// static POOL: Rulloc<3> = Rulloc::<3>::pool_mode([32, 64, 128]);
+    ///
+    /// let layout = Layout::array::<u8>(40).unwrap();
+    /// unsafe {
+    ///     let first = POOL.allocate(layout).unwrap();
+    ///     assert_eq!(first.len(), 64);
+    ///     POOL.deallocate(first.cast(), layout);
+    ///
+    ///     // Last in, first out.
+    ///     let second = POOL.allocate(layout).unwrap();
+    ///     assert_eq!(first.cast::<u8>(), second.cast::<u8>());
+    ///     POOL.deallocate(second.cast(), layout);
+    /// }
+    /// ```
+    pub const fn pool_mode(sizes: [usize; N]) -> Self {
+        Self::with_allocator(InternalAllocator::pool_mode(sizes))
     }
 
-    unsafe fn deallocate(&self, address: NonNull<u8>, layout: Layout) {
-        if let Ok(mut allocator) = self.allocator.lock() {
-            allocator.deallocate(address, layout)
+    /// Wraps `allocator` with default settings for everything else.
+    const fn with_allocator(allocator: InternalAllocator<N>) -> Self {
+        Self {
+            allocator: Lock::new(allocator),
+            counters: Counters::new(),
+            failures: LastFailure::new(),
+            canary: None,
+            frozen: AtomicBool::new(false),
+            #[cfg(feature = "fault-injection")]
+            fail_next: AtomicUsize::new(0),
+            #[cfg(feature = "leak-tracking")]
+            leaks: LeakTracker::new(),
+            #[cfg(feature = "timing")]
+            latencies: LatencyRecorder::new(),
+            #[cfg(feature = "timing")]
+            windows: WindowRecorder::new(window::monotonic_seconds),
         }
     }
+}
 
-    unsafe fn shrink(
-        &self,
-        address: NonNull<u8>,
-        old_layout: Layout,
-        new_layout: Layout,
-    ) -> AllocResult {
-        match self.allocator.lock() {
-            Ok(mut allocator) => {
-                allocator.reallocate(&Realloc::shrink(address, old_layout, new_layout))
-            }
-            Err(_) => Err(AllocError),
-        }
+impl<const N: usize, P: FitPolicy> Rulloc<N, P> {
+    /// Calls `configure` with the internal allocator. Used for builder
+    /// methods, we don't need to lock anything because we own `self`.
+    fn configure(mut self, configure: impl FnOnce(&mut InternalAllocator<N, P>)) -> Self {
+        configure(
+            self.allocator
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        self
     }
 
-    unsafe fn grow(
-        &self,
-        address: NonNull<u8>,
-        old_layout: Layout,
-        new_layout: Layout,
-    ) -> AllocResult {
-        match self.allocator.lock() {
-            Ok(mut allocator) => {
-                allocator.reallocate(&Realloc::grow(address, old_layout, new_layout))
+    /// Changes the strategy used to find free blocks, see [`FitPolicy`]. Each
+    /// bucket gets its own clone of `policy`. The default is [`FirstFit`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::{BestFit, Rulloc};
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_fit_policy(BestFit);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(layout).unwrap();
+    ///     rulloc.deallocate(address.cast(), layout);
+    /// }
+    /// ```
+    pub fn with_fit_policy<Q: FitPolicy + Clone>(self, policy: Q) -> Rulloc<N, Q> {
+        let allocator = self
+            .allocator
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        Rulloc {
+            allocator: Lock::new(allocator.replace_policy(policy)),
+            counters: self.counters,
+            failures: self.failures,
+            canary: self.canary,
+            frozen: self.frozen,
+            #[cfg(feature = "fault-injection")]
+            fail_next: self.fail_next,
+            #[cfg(feature = "leak-tracking")]
+            leaks: self.leaks,
+            #[cfg(feature = "timing")]
+            latencies: self.latencies,
+            #[cfg(feature = "timing")]
+            windows: self.windows,
+        }
+    }
+
+    /// Places a redzone after each allocation and panics on deallocation or
+    /// reallocation if it was overwritten, which means there was a buffer
+    /// overflow. The value stored in the redzone is derived from `seed` and
+    /// the address of each allocation. Use [`Self::with_random_canary_seed`]
+    /// if you don't need reproducible values.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// static ALLOCATOR: Rulloc = Rulloc::with_default_config().with_canary_seed(0x5EED);
+    /// ```
+    pub const fn with_canary_seed(mut self, seed: u64) -> Self {
+        self.canary = Some(Canary::with_seed(seed));
+        self
+    }
+
+    /// Same as [`Self::with_canary_seed`] but using a random seed.
+    pub fn with_random_canary_seed(mut self) -> Self {
+        self.canary = Some(Canary::random());
+        self
+    }
+
+    /// Replaces the clock used by [`Self::windowed_stats`]. `clock` must
+    /// return the number of whole seconds elapsed since any fixed point in
+    /// time and must never go backwards. By default it's based on
+    /// [`std::time::Instant`], this is mostly useful for testing. Only
+    /// available with the `timing` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_stats_clock(|| 0);
+    /// ```
+    #[cfg(feature = "timing")]
+    pub fn with_stats_clock(mut self, clock: fn() -> u64) -> Self {
+        self.windows = WindowRecorder::new(clock);
+        self
+    }
+
+    /// Retains the free block created after shrinking an allocation in place
+    /// so that if the allocation grows again it can reclaim the space without
+    /// copying its contents to a different address. This optimizes the common
+    /// pattern of shrinking and then growing a [`Vec`] or similar collection.
+    /// Only a few tails are retained per bucket, older tails are released when
+    /// the cache is full.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_shrink_tail_cache(true);
+    /// let (big, small) = (Layout::array::<u8>(4096).unwrap(), Layout::array::<u8>(2048).unwrap());
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(big).unwrap();
+    ///     let shrunk = rulloc.shrink(address.cast(), big, small).unwrap();
+    ///     let grown = rulloc.grow(shrunk.cast(), small, big).unwrap();
+    ///     assert_eq!(address.cast::<u8>(), grown.cast::<u8>());
+    ///     rulloc.deallocate(grown.cast(), big);
+    /// }
+    /// ```
+    pub fn with_shrink_tail_cache(self, enabled: bool) -> Self {
+        self.configure(|allocator| unsafe {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_tail_cache(enabled))
+        })
+    }
+
+    /// Changes the position where freed blocks are inserted in the free list
+    /// of each bucket, see [`FreeListOrder`]. The default is
+    /// [`FreeListOrder::Fifo`]. Buckets built with [`Self::pool_mode`] ignore
+    /// this setting.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::{FreeListOrder, Rulloc};
+    ///
+    /// let rulloc = Rulloc::default().with_free_list_order(FreeListOrder::AddressOrdered);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// let [first, second, third] = [(); 3].map(|_| rulloc.allocate(layout).unwrap());
+    ///
+    /// unsafe {
+    ///     rulloc.deallocate(second.cast(), layout);
+    ///     rulloc.deallocate(first.cast(), layout);
+    ///
+    ///     // Lowest address first, even though it was freed last.
+    ///     let reused = rulloc.allocate(layout).unwrap();
+    ///     assert_eq!(reused.cast::<u8>(), first.cast::<u8>());
+    ///
+    ///     rulloc.deallocate(reused.cast(), layout);
+    ///     rulloc.deallocate(third.cast(), layout);
+    /// }
+    /// ```
+    pub fn with_free_list_order(self, order: FreeListOrder) -> Self {
+        self.configure(|allocator| unsafe {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_free_list_order(order))
+        })
+    }
+
+    /// Chooses whether [`Allocator::shrink`] moves allocations to the bucket
+    /// of their new size or keeps them where they are, see [`ShrinkPolicy`].
+    /// [`ShrinkPolicy::Stable`] trades some memory for pointer stability:
+    /// shrunk allocations keep their address, but small allocations can end
+    /// up in the blocks of larger buckets.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::{Rulloc, ShrinkPolicy};
+    ///
+    /// let rulloc = Rulloc::default().with_shrink_policy(ShrinkPolicy::Stable);
+    /// let (old, new) = (Layout::array::<u8>(900).unwrap(), Layout::array::<u8>(100).unwrap());
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(old).unwrap();
+    ///     let shrunk = rulloc.shrink(address.cast(), old, new).unwrap();
+    ///     assert_eq!(shrunk.cast::<u8>(), address.cast::<u8>());
+    ///     rulloc.deallocate(shrunk.cast(), new);
+    /// }
+    /// ```
+    pub fn with_shrink_policy(self, policy: ShrinkPolicy) -> Self {
+        self.configure(|allocator| allocator.shrink_policy = policy)
+    }
+
+    /// Optimizes [`Allocator::shrink`] for allocations that won't grow again,
+    /// like a `Vec` converted into a `Box<[T]>`. The returned slice is exactly
+    /// as long as the new layout, and the bytes that the allocation no longer
+    /// needs are given back to the bucket even if they are too few to form a
+    /// block on their own, as long as the next block is free. Without this,
+    /// shrinking keeps any slack that can't be split off, which is fine for
+    /// collections that might reuse it later. Shrunk tails are not retained
+    /// if this is enabled, see [`Self::with_shrink_tail_cache`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default().with_exact_shrink(true);
+    /// let (old, new) = (Layout::array::<u8>(100).unwrap(), Layout::array::<u8>(77).unwrap());
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(old).unwrap();
+    ///     let shrunk = rulloc.shrink(address.cast(), old, new).unwrap();
+    ///     assert_eq!(shrunk.len(), 77);
+    ///     rulloc.deallocate(shrunk.cast(), new);
+    /// }
+    /// ```
+    pub fn with_exact_shrink(self, enabled: bool) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_exact_shrink(enabled))
+        })
+    }
+
+    /// Rounds small requests up to fixed size classes (16, 32, 48, 64 and so
+    /// on up to 512 bytes) and keeps freed blocks of each class in their own
+    /// list, so that the next request of the same class reuses one of them
+    /// without searching or splitting. Tails cut off by shrinking an
+    /// allocation in place are retained too when their size matches a class
+    /// exactly. Only applies to the fixed size buckets, the dynamic bucket
+    /// handles requests of any size.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::with_default_config().with_size_classes(true);
+    /// let layout = Layout::array::<u8>(20).unwrap();
+    ///
+    /// unsafe {
+    ///     let first = rulloc.allocate(layout).unwrap();
+    ///     assert_eq!(first.len(), 32);
+    ///     rulloc.deallocate(first.cast(), layout);
+    ///
+    ///     let second = rulloc.allocate(layout).unwrap();
+    ///     assert_eq!(first.cast::<u8>(), second.cast::<u8>());
+    ///     rulloc.deallocate(second.cast(), layout);
+    /// }
+    /// ```
+    pub fn with_size_classes(self, enabled: bool) -> Self {
+        self.configure(|allocator| unsafe {
+            allocator
+                .buckets
+                .iter_mut()
+                .for_each(|bucket| bucket.set_size_classes(enabled))
+        })
+    }
+
+    /// Serves requests of up to `slot_size` bytes aligned to pointer size or
+    /// less from a slab of fixed size slots instead of the first bucket. Slots
+    /// have no header at all, the slab only keeps one bit per slot, so tiny
+    /// allocations cost their own size instead of their size plus a block
+    /// header. See [`crate::slab`]. `slot_size` is rounded up to pointer size
+    /// and 0 disables the slab, which is the default.
+    ///
+    /// # Panics
+    ///
+    /// If there are no fixed size buckets, if `slot_size` is larger than the
+    /// first bucket or if it's larger than 64 bytes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::with_default_config().with_slab(8);
+    /// let layout = Layout::new::<u64>();
+    ///
+    /// unsafe {
+    ///     let first = rulloc.allocate(layout).unwrap();
+    ///     let second = rulloc.allocate(layout).unwrap();
+    ///     // Consecutive slots, no header in between.
+    ///     assert_eq!(second.cast::<u8>().as_ptr() as usize - first.cast::<u8>().as_ptr() as usize, 8);
+    ///     rulloc.deallocate(first.cast(), layout);
+    ///     rulloc.deallocate(second.cast(), layout);
+    /// }
+    /// ```
+    pub fn with_slab(self, slot_size: usize) -> Self {
+        self.configure(|allocator| {
+            assert!(
+                N > 0,
+                "the slab replaces the first bucket, but there are no buckets"
+            );
+            assert!(
+                slot_size <= allocator.sizes[0],
+                "slab slots of {slot_size} bytes don't fit in the first bucket of {} bytes",
+                allocator.sizes[0]
+            );
+            allocator.slab.set_slot_size(slot_size);
+        })
+    }
+
+    /// Records the size of every allocation in a histogram and recomputes
+    /// recommended bucket sizes every `interval` allocations, see
+    /// [`crate::adaptive`] for the heuristic. The recommendation is returned
+    /// by [`Self::recommended_sizes`] but never applied, live blocks depend
+    /// on the current sizes, so it's meant for tuning the next build. An
+    /// `interval` of 0 disables recording, which is the default. Older
+    /// allocations count less and less, so the recommendation follows the
+    /// current workload rather than the whole history.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_adaptive_sizes(100);
+    /// let layout = Layout::array::<u8>(24).unwrap();
+    ///
+    /// for _ in 0..100 {
+    ///     let address = rulloc.allocate(layout).unwrap();
+    ///     unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// }
+    ///
+    /// assert_eq!(rulloc.recommended_sizes(), Some([32, 64, 128]));
+    /// ```
+    pub fn with_adaptive_sizes(self, interval: usize) -> Self {
+        self.configure(|allocator| allocator.adaptive.set_interval(interval))
+    }
+
+    /// Bucket sizes recommended for the sizes allocated so far, or `None` if
+    /// [`Self::with_adaptive_sizes`] is disabled or hasn't seen enough
+    /// allocations yet.
+    pub fn recommended_sizes(&self) -> Option<[usize; N]> {
+        self.allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .adaptive
+            .recommended()
+    }
+
+    /// Checks that deallocated pointers point to the start of an allocation
+    /// and not somewhere inside of it. Only available in debug builds, see
+    /// [`InteriorPointerPolicy`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::{InteriorPointerPolicy, Rulloc};
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192])
+    ///     .with_interior_pointer_policy(InteriorPointerPolicy::Panic);
+    /// ```
+    pub fn with_interior_pointer_policy(self, policy: InteriorPointerPolicy) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_interior_pointer_policy(policy))
+        })
+    }
+
+    /// Aligns the base address of every region mapped by the allocator to
+    /// `align` bytes, which must be a power of 2. Values smaller than the page
+    /// size have no effect because regions are always page aligned.
+    ///
+    /// # Panics
+    ///
+    /// If `align` is not a power of 2.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192])
+    ///     .with_region_alignment(64 * 1024)
+    ///     .with_cache_coloring(64);
+    /// ```
+    pub fn with_region_alignment(self, align: usize) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_region_alignment(align))
+        })
+    }
+
+    /// Shifts the first block of each new region by `color_stride` more bytes
+    /// than the previous region (wrapping around at page size) so that blocks
+    /// at the beginning of different regions don't map to the same cache sets.
+    /// The stride must be a multiple of the pointer size, usually the cache
+    /// line size is a good choice. See [`Self::with_region_alignment`] for an
+    /// example.
+    pub fn with_cache_coloring(self, color_stride: usize) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_color_stride(color_stride))
+        })
+    }
+
+    /// Maps `bytes` of inaccessible memory, rounded up to the page size, after
+    /// every region. Writing past the end of the last allocation of a region
+    /// then faults instead of corrupting whatever comes next, which is most
+    /// useful for large allocations since they usually take the whole region.
+    /// This is much cheaper than one guard page per allocation, but overruns
+    /// into the next block of the same region are not detected. Guard pages
+    /// count as mapped memory in [`Stats::bytes_mapped`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_region_guard_bytes(4096);
+    /// ```
+    pub fn with_region_guard_bytes(self, bytes: usize) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_region_guard(bytes))
+        })
+    }
+
+    /// Asks the kernel to back regions of 2MB or more with transparent huge
+    /// pages using
+    /// [`madvise(MADV_HUGEPAGE)`](https://man7.org/linux/man-pages/man2/madvise.2.html).
+    /// This reduces TLB misses for large allocations without the need to
+    /// reserve huge pages in advance like `MAP_HUGETLB` does. It's only a
+    /// hint, so it's ignored if THP is disabled and it has no effect on
+    /// platforms other than Linux.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_thp_hint(true);
+    /// ```
+    pub fn with_thp_hint(self, enabled: bool) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_huge_pages(enabled))
+        })
+    }
+
+    /// ORs `extra` into the flags of every `mmap` call made by this
+    /// allocator, which by default are `MAP_PRIVATE | MAP_ANONYMOUS`. This is
+    /// an escape hatch for the options that the other builder methods don't
+    /// model, such as `MAP_NORESERVE`, `MAP_POPULATE` or `MAP_32BIT`. It
+    /// applies to the regions of all buckets and the slab, and to the memory
+    /// returned by [`Self::allocate_pages_aligned`], [`Self::allocate_exec`]
+    /// and [`Self::allocate_secure`]. It has no effect on platforms without
+    /// `mmap`, like Windows.
+    ///
+    /// Flags are passed to the kernel as they are, nothing is validated. An
+    /// invalid flag makes every allocation fail, and flags that change what
+    /// `mmap` does can break the allocator in subtle ways. For example,
+    /// `MAP_SHARED` makes regions shared with child processes, `MAP_FIXED`
+    /// and `MAP_FIXED_NOREPLACE` are meaningless without an address hint,
+    /// which we never give, and `MAP_HUGETLB` makes mappings fail unless
+    /// their length is a multiple of the huge page size. Check the
+    /// [`mmap` manual](https://man7.org/linux/man-pages/man2/mmap.2.html)
+    /// before using anything here.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// # #[cfg(target_os = "linux")]
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_mmap_flags(libc::MAP_NORESERVE);
+    /// ```
+    pub fn with_mmap_flags(self, extra: c_int) -> Self {
+        self.configure(|allocator| {
+            allocator.slab.set_mmap_flags(extra);
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_mmap_flags(extra))
+        })
+    }
+
+    /// Requests the regions of the bucket at `index` to `source` instead of
+    /// mapping anonymous memory, see [`MmapSource`]. Indices work like in
+    /// [`Self::allocate_in_bucket`], `N` being the dynamic bucket, and each
+    /// bucket can have a different source, so memory of different kinds can
+    /// back different allocation sizes:
+    ///
+    /// ```text
+    /// with_bucket_source(0, &DRAM)   -> small objects in fast memory
+    /// with_bucket_source(N, &PMEM)   -> large buffers in persistent memory
+    /// ```
+    ///
+    /// Regions of custom sources are always returned one by one and as a
+    /// whole, so they are not coalesced when the allocator is dropped and
+    /// [`Self::shrink_regions`] skips them. [`Self::with_mmap_flags`] doesn't
+    /// apply to them, while guard pages, alignment and huge pages hints still
+    /// do. Memory that doesn't belong to any bucket, like the slab or
+    /// [`Self::allocate_pages_aligned`], is still mapped as usual. Regions
+    /// obtained through [`Self::into_raw_parts`] belong to their source.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than `N`.
+    ///
+    /// # Examples
+    ///
+    /// See [`MmapSource`].
+    pub fn with_bucket_source(self, index: usize, source: &'static dyn MmapSource) -> Self {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        self.configure(|allocator| allocator.bucket_mut(index).set_source(Some(source)))
+    }
+
+    /// Marks the pages of freed blocks as cold with
+    /// [`madvise(MADV_COLD)`](https://man7.org/linux/man-pages/man2/madvise.2.html),
+    /// so under memory pressure the kernel reclaims free memory retained by
+    /// the allocator before memory in use. Unlike
+    /// [`Self::drain_free_lists`], contents are preserved and nothing is
+    /// reclaimed if there's no pressure, so reusing the blocks stays cheap.
+    /// Only a hint, it requires Linux 5.4 or newer and has no effect on other
+    /// platforms.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_cold_free_cache(true);
+    /// ```
+    pub fn with_cold_free_cache(self, enabled: bool) -> Self {
+        self.configure(|allocator| {
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_cold_free_cache(enabled))
+        })
+    }
+
+    /// Fills every freshly mapped region with `pattern` instead of leaving
+    /// the zeroes given by the kernel. Code that reads memory it never wrote
+    /// often works by accident because new regions are zeroed, this makes it
+    /// break loudly instead. Meant for testing, it's off by default and
+    /// [`Allocator::allocate_zeroed`] still returns zeroed memory.
+    ///
+    /// Only new regions are scrubbed. Blocks reused from the free list
+    /// contain whatever was written to them before, which is also not zero.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default().with_scrub_pattern(0xAA);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(layout).unwrap();
+    ///     assert!(address.as_ref().iter().all(|byte| *byte == 0xAA));
+    ///     rulloc.deallocate(address.cast(), layout);
+    /// }
+    /// ```
+    pub fn with_scrub_pattern(self, pattern: u8) -> Self {
+        self.configure(|allocator| {
+            allocator.slab.set_scrub_pattern(Some(pattern));
+            allocator
+                .buckets_mut()
+                .for_each(|bucket| bucket.set_scrub_pattern(Some(pattern)))
+        })
+    }
+
+    /// Limits the number of bytes that the allocator can map in total,
+    /// including headers. Once the limit is reached, allocations that can't
+    /// be served from free blocks fail and [`Self::last_error`] returns
+    /// [`AllocFailure::LimitExceeded`]. Memory returned by
+    /// [`Self::allocate_pages_aligned`] and [`Self::allocate_exec`] doesn't
+    /// count towards the limit since it's not managed by any bucket.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::{AllocFailure, Rulloc};
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_memory_limit(1 << 20);
+    /// let layout = Layout::array::<u8>(2 << 20).unwrap();
+    ///
+    /// assert!(rulloc.allocate(layout).is_err());
+    /// assert_eq!(rulloc.last_error(), Some(AllocFailure::LimitExceeded));
+    /// ```
+    pub fn with_memory_limit(self, limit: usize) -> Self {
+        self.configure(|allocator| allocator.limit = limit)
+    }
+
+    /// Maps `counts[i]` regions for the fixed size bucket `i` right away
+    /// instead of waiting for the first allocations. Each region can hold at
+    /// least one block of the bucket size, so allocations are served from
+    /// warm free lists and the cost of `mmap` is paid during startup, not
+    /// while the program is running. The dynamic bucket has no fixed size,
+    /// so it's not included.
+    ///
+    /// Regions are mapped with the configuration given so far, so this
+    /// should be the last call of the builder chain. Unlike the other
+    /// builder methods it can't be used in a `const` context, like a
+    /// `static` global allocator. If a region can't be mapped, for example
+    /// because of [`Self::with_memory_limit`], the rest are skipped and
+    /// mapped on demand as usual. Pre-mapped regions are just like any other
+    /// region afterwards, so they are unmapped once all of their blocks have
+    /// been allocated and freed again, except for pools (see
+    /// [`Self::pool_mode`]).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_initial_regions([4, 1, 0]);
+    /// assert_eq!(rulloc.region_count(0), 4);
+    /// assert_eq!(rulloc.region_count(1), 1);
+    /// assert_eq!(rulloc.region_count(2), 0);
+    ///
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// assert_eq!(rulloc.region_count(0), 4);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn with_initial_regions(mut self, counts: [usize; N]) -> Self {
+        let allocator = self
+            .allocator
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Failing is fine, see above.
+        let _ = unsafe { allocator.prewarm(counts) };
+        self.counters
+            .set_mapped(allocator.bytes_mapped(), allocator.num_regions());
+
+        self
+    }
+
+    /// Allocates `layout` in the bucket at `index`, regardless of the bucket
+    /// that would normally be chosen for its size. Indices `0..N` are the
+    /// fixed size buckets in the order given to [`Self::with_bucket_sizes`]
+    /// and `N` is the bucket used for allocations larger than all of them.
+    /// This is useful to keep certain objects close to each other in memory,
+    /// away from other allocations of the same size. The pointer can be
+    /// deallocated with [`Allocator::deallocate`] as usual, the bucket is
+    /// stored in the block header. Reallocating it moves it back to the
+    /// bucket where the new layout would normally go.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+    /// let layout = Layout::array::<u8>(16).unwrap();
+    ///
+    /// // Would normally go to the first bucket.
+    /// let address = rulloc.allocate_in_bucket(2, layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `index > N` or the bucket at `index` can't hold `layout.size()`
+    /// bytes.
+    pub fn allocate_in_bucket(&self, index: usize, layout: Layout) -> AllocResult {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        let inner_layout = self.inner_layout(layout)?;
+
+        // Don't panic while holding the lock, it would poison it.
+        let bucket_size = {
+            let allocator = self
+                .allocator
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            allocator.sizes.get(index).copied().unwrap_or(usize::MAX)
+        };
+
+        assert!(
+            inner_layout.size() <= bucket_size,
+            "bucket {index} can't hold {} bytes, its size is {bucket_size}",
+            layout.size()
+        );
+
+        self.allocate_in(Placement::Bucket(index), layout, false)
+    }
+
+    /// Allocates `layout` in the same region as `hint` if that region has a
+    /// free block that can fit it, otherwise it's allocated as usual. Objects
+    /// that point to each other, like the nodes of a graph, are faster to
+    /// traverse when they share cache lines and pages, and this is a way to
+    /// ask for that:
+    ///
+    /// ```text
+    ///           hint        new
+    ///             |          |
+    /// +--------+--v----+-----v-+-------+
+    /// | Region | Block | Free  | Block |
+    /// +--------+-------+-------+-------+
+    /// ```
+    ///
+    /// `hint` is only used to find a region, so it doesn't need to be the
+    /// start of an allocation and it's fine if it's dangling or belongs to
+    /// someone else, in which case it's ignored. Regions only contain blocks
+    /// of one bucket, so the hint is also ignored when `layout` goes to a
+    /// different bucket than `hint` did, when the bucket is a pool (see
+    /// [`Self::pool_mode`]) or uses [`Self::with_size_classes`], and when
+    /// the allocation fits in the slab (see [`Self::with_slab`]).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// let parent = rulloc.allocate(layout).unwrap();
+    /// let child = rulloc.allocate_near(layout, parent.cast()).unwrap();
+    ///
+    /// assert_eq!(
+    ///     rulloc.region_for(parent.cast()),
+    ///     rulloc.region_for(child.cast())
+    /// );
+    ///
+    /// unsafe {
+    ///     rulloc.deallocate(child.cast(), layout);
+    ///     rulloc.deallocate(parent.cast(), layout);
+    /// }
+    /// ```
+    pub fn allocate_near(&self, layout: Layout, hint: NonNull<u8>) -> AllocResult {
+        self.allocate_in(Placement::Near(hint), layout, false)
+    }
+
+    /// Allocates `count` blocks that can fit `layout` each with one single
+    /// call. All the blocks are carved consecutively from one new region, so
+    /// they are guaranteed to be close to each other in memory, which is nice
+    /// for object pools. Each address must be deallocated separately with
+    /// [`Allocator::deallocate`] as if it had been returned by
+    /// [`Allocator::allocate`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<[u64; 4]>();
+    /// let slots = rulloc.allocate_batch(layout, 16).unwrap();
+    /// assert_eq!(slots.len(), 16);
+    ///
+    /// for slot in slots {
+    ///     unsafe { rulloc.deallocate(slot, layout) };
+    /// }
+    /// ```
+    pub fn allocate_batch(
+        &self,
+        layout: Layout,
+        count: usize,
+    ) -> Result<Vec<NonNull<u8>>, AllocError> {
+        let inner_layout = self.inner_layout(layout)?;
+
+        // Can't allocate while holding the lock, the vector might be using us
+        // as the global allocator.
+        let mut addresses = Vec::with_capacity(count);
+
+        unsafe {
+            let unmap_failures = match self.allocator.lock() {
+                Ok(mut allocator) => {
+                    allocator
+                        .allocate_batch(inner_layout, count, &mut addresses)
+                        .map_err(|_| self.failed(&mut allocator))?;
+                    for _ in 0..count {
+                        self.counters.record_allocation(layout.size());
+                    }
+                    #[cfg(feature = "tracy")]
+                    for address in &addresses {
+                        tracy::emit_alloc(
+                            address.as_ptr(),
+                            layout.size(),
+                            allocator.bucket_of(*address, inner_layout),
+                        );
+                    }
+                    self.sync_mapped(&mut allocator)
+                }
+                Err(_) => return Err(AllocError),
+            };
+            unmap_failures.report();
+
+            if let Some(canary) = self.canary {
+                for address in &addresses {
+                    canary.write(*address, layout.size());
+                }
+            }
+        }
+
+        #[cfg(feature = "leak-tracking")]
+        for address in &addresses {
+            self.leaks.track(*address, layout.size());
+        }
+
+        Ok(addresses)
+    }
+
+    /// Allocates an array of `len` elements of type `T` and returns it as a
+    /// slice of uninitialized elements, so callers don't have to build the
+    /// [`Layout`] and cast the result themselves. The memory must be
+    /// deallocated with [`Allocator::deallocate`] using
+    /// `Layout::array::<T>(len)`. Fails if that layout overflows.
+    ///
+    /// If the array takes no memory at all, because `len` is 0 or `T` is
+    /// zero sized, nothing is allocated and the slice points to
+    /// [`NonNull::dangling`], which is aligned for `T`. That pointer can
+    /// still be given back to [`Allocator::deallocate`], which ignores it,
+    /// or to [`Allocator::grow`], which allocates for real.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let array = rulloc.allocate_array::<u32>(8).unwrap();
+    ///
+    /// unsafe {
+    ///     for element in &mut *array.as_ptr() {
+    ///         element.write(7);
+    ///     }
+    ///     rulloc.deallocate(array.cast(), Layout::array::<u32>(8).unwrap());
+    /// }
+    /// ```
+    pub fn allocate_array<T>(&self, len: usize) -> Result<NonNull<[MaybeUninit<T>]>, AllocError> {
+        let layout = Layout::array::<T>(len).map_err(|_| AllocError)?;
+
+        if layout.size() == 0 {
+            return Ok(NonNull::slice_from_raw_parts(NonNull::dangling(), len));
+        }
+
+        let address = self.allocate(layout)?;
+
+        Ok(NonNull::slice_from_raw_parts(address.cast(), len))
+    }
+
+    /// Uses a [`std::sync::RwLock`] instead of a [`std::sync::Mutex`] to
+    /// protect the allocator internals. Allocations, deallocations and
+    /// reallocations still need exclusive access, but introspection methods
+    /// such as [`Self::free_bytes`] can run concurrently. Only worth it if
+    /// introspection is frequent compared to allocations, otherwise the
+    /// mutex is cheaper.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_read_write_lock();
+    /// assert_eq!(rulloc.free_bytes(), 0);
+    /// ```
+    pub fn with_read_write_lock(self) -> Self {
+        Self {
+            allocator: self.allocator.into_shared(),
+            ..self
+        }
+    }
+
+    /// Number of bytes in free blocks, which can be reused for allocations
+    /// without requesting more memory from the kernel. Headers are not
+    /// included. This has to walk the free lists of all buckets, so it's
+    /// slower than [`Self::poll_stats`], but it only needs shared access if
+    /// the allocator was built [`Self::with_read_write_lock`].
+    pub fn free_bytes(&self) -> usize {
+        self.allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .free_bytes()
+    }
+
+    /// Number of mapped bytes that are neither allocated by the user nor
+    /// available in free blocks. This is the cost of the allocator metadata:
+    ///
+    /// - Region and block headers.
+    /// - Padding added to satisfy alignment constraints or minimum block
+    ///   sizes, and canaries if enabled.
+    /// - Bytes that can't be used because they don't fit a block header.
+    ///
+    /// In other words, [`Stats::bytes_mapped`] minus
+    /// [`Stats::bytes_allocated`] minus [`Self::free_bytes`]. Lots of small
+    /// allocations have a high overhead because every block needs a header,
+    /// see [`Self::overhead_ratio`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u8>();
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// assert!(rulloc.bytes_overhead() > 0);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// assert_eq!(rulloc.bytes_overhead(), 0);
+    /// ```
+    pub fn bytes_overhead(&self) -> usize {
+        // Counters are only written while holding the lock, so this is
+        // consistent with the buckets.
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let bytes_allocated = self.counters.snapshot().bytes_allocated;
+
+        // The counters only know the sizes requested by the user, which never
+        // exceed their blocks, but a miscount shouldn't be able to panic.
+        allocator
+            .bytes_mapped()
+            .saturating_sub(bytes_allocated + allocator.free_bytes())
+    }
+
+    /// [`Self::bytes_overhead`] divided by [`Stats::bytes_mapped`], or 0 if
+    /// nothing is mapped. Useful for comparing configurations.
+    pub fn overhead_ratio(&self) -> f64 {
+        let bytes_mapped = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bytes_mapped();
+
+        if bytes_mapped == 0 {
+            return 0.0;
+        }
+
+        self.bytes_overhead() as f64 / bytes_mapped as f64
+    }
+
+    /// Memory pressure response. Tells the kernel that it can reclaim the
+    /// physical pages of free blocks (see [`Bucket::purge`]), starting from
+    /// the largest blocks, until the bytes in free blocks that have not been
+    /// purged drop to `target_bytes` or there's nothing else to purge.
+    /// Returns the number of bytes purged by this call.
+    ///
+    /// Regions whose blocks are all free are already returned to the kernel
+    /// on deallocation, so this only deals with free blocks in regions that
+    /// are still in use. Purged blocks are still mapped and can be reused,
+    /// they are just not backed by physical memory until they are written
+    /// again. Blocks purged by a previous call are skipped until they are
+    /// reused or merged, so calling this again only counts newly released
+    /// bytes.
+    ///
+    /// Only blocks in the free lists are considered. Blocks retained by the
+    /// size class caches, the tail cache or object pools are not free from the
+    /// point of view of the bucket, so their memory is never purged here, see
+    /// [`Self::with_size_classes`] and [`Self::pool_mode`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// assert_eq!(rulloc.drain_free_lists(0), 0);
+    /// ```
+    pub fn drain_free_lists(&self, target_bytes: usize) -> usize {
+        let num_free_blocks = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .num_free_blocks();
+
+        // Can't allocate while holding the lock. If more free blocks appear
+        // in the meantime, they'll be ignored.
+        let mut blocks = Vec::with_capacity(num_free_blocks);
+
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return 0;
+        };
+
+        allocator
+            .buckets_mut()
+            .for_each(|bucket| bucket.free_blocks_into(&mut blocks));
+
+        blocks.sort_unstable_by_key(|block| cmp::Reverse(unsafe { block.as_ref().size() }));
+
+        let already_purged: usize = blocks
+            .iter()
+            .map(|block| unsafe { Bucket::<P>::purged_bytes(*block) })
+            .sum();
+
+        let mut free_bytes = allocator.free_bytes() - already_purged;
+        let mut purged = 0;
+
+        for block in blocks {
+            if free_bytes <= target_bytes {
+                break;
+            }
+
+            let bytes = unsafe { Bucket::<P>::purge(block) };
+            purged += bytes;
+            free_bytes -= bytes;
+        }
+
+        purged
+    }
+
+    /// Unmaps the free pages at the end of regions that are still in use. A
+    /// region is mapped to fit the allocation that requested it, so after a
+    /// large allocation is shrunk or the blocks at the end of a region are
+    /// deallocated, most of the region might be free but still mapped because
+    /// a few blocks at the beginning are in use. Unlike
+    /// [`Self::drain_free_lists`], this actually returns the pages to the
+    /// kernel and reduces [`Self::total_mapped_bytes`]. See
+    /// [`Bucket::shrink_regions`] for details. Returns the number of bytes
+    /// unmapped.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let large = Layout::array::<u8>(64 * 1024).unwrap();
+    /// let small = Layout::array::<u8>(16 * 1024).unwrap();
+    ///
+    /// unsafe {
+    ///     let address = rulloc.allocate(large).unwrap();
+    ///     let address = rulloc.shrink(address.cast(), large, small).unwrap();
+    ///     assert!(rulloc.shrink_regions() > 0);
+    ///     rulloc.deallocate(address.cast(), small);
+    /// }
+    /// ```
+    pub fn shrink_regions(&self) -> usize {
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return 0;
+        };
+
+        let unmapped = allocator
+            .buckets_mut()
+            .map(|bucket| unsafe { bucket.shrink_regions() })
+            .sum();
+
+        let unmap_failures = self.sync_mapped(&mut allocator);
+        drop(allocator);
+        unmap_failures.report();
+
+        unmapped
+    }
+
+    /// Same work as calling [`Self::shrink_bucket`] with nothing to keep on
+    /// every bucket, but split in small steps so that it can be done within
+    /// a time budget, like the spare time at the end of a frame. Each step
+    /// processes one region: it's unmapped if all of its blocks are free or
+    /// the free pages at its end are unmapped otherwise, see
+    /// [`Self::shrink_regions`]. The first step of each bucket also releases
+    /// the blocks retained by [`Self::with_size_classes`].
+    ///
+    /// Steps run until `deadline`, at least one step always runs so that
+    /// some progress is made even if the deadline has already passed. The
+    /// position is saved, so the next call resumes where this one stopped.
+    /// Returns `true` if there's more work left, or `false` once all the
+    /// buckets have been processed, in which case the next call starts over.
+    /// The lock is held during the whole call, so deadlines should be short.
+    ///
+    /// ```text
+    ///              defragment_until()     defragment_until()
+    ///                       |                     |
+    ///                       v                     v
+    /// +----------+----------+----------+----------+----------+
+    /// | Region 1 | Region 2 | Region 3 | Region 4 | Region 5 |
+    /// +----------+----------+----------+----------+----------+
+    ///   Bucket 0                         Bucket 1
+    /// ```
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    ///
+    /// // Once per frame, with whatever time is left.
+    /// let deadline = Instant::now() + Duration::from_micros(500);
+    /// while rulloc.defragment_until(deadline) {}
+    /// ```
+    pub fn defragment_until(&self, deadline: Instant) -> bool {
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return false;
+        };
+
+        let more = loop {
+            let more = unsafe { allocator.defragment_step() };
+            if !more || Instant::now() >= deadline {
+                break more;
+            }
+        };
+
+        let unmap_failures = self.sync_mapped(&mut allocator);
+        drop(allocator);
+        unmap_failures.report();
+
+        more
+    }
+
+    /// Returns how many blocks that can fit `layout` are currently available
+    /// in the free list of the bucket where `layout` would be allocated. If
+    /// this returns `K`, the next `K` allocations of `layout` won't request
+    /// memory from the kernel as long as nobody else allocates in the
+    /// meantime. Large free blocks are counted once even though they might
+    /// be split to serve multiple allocations, so this is a lower bound.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    /// assert_eq!(rulloc.available_in_bucket(layout), 0);
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// // Region was returned to the kernel.
+    /// assert_eq!(rulloc.available_in_bucket(layout), 0);
+    /// ```
+    pub fn available_in_bucket(&self, layout: Layout) -> usize {
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let layout = match self.canary {
+            Some(canary) => match canary.padded(layout) {
+                Ok(padded) => padded,
+                Err(_) => return 0,
+            },
+            None => layout,
+        };
+
+        allocator
+            .bucket(allocator.bucket_index_of(layout))
+            .available(layout)
+    }
+
+    /// Number of bytes that an allocation of `layout` would receive, which
+    /// is the length of the slice returned by [`Allocator::allocate`] and
+    /// [`Allocator::allocate_at_least`]. Blocks are rounded up to pointer
+    /// size or to size classes if [`Self::with_size_classes`] is enabled, so
+    /// this is usually greater than `layout.size()` and collections can use
+    /// the extra capacity right away instead of growing later. Nothing is
+    /// allocated.
+    ///
+    /// This is what a block split at the exact size needed contains. When a
+    /// free block is too small to be split, the allocation takes all of it
+    /// and receives more than this, never less.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(20).unwrap();
+    /// assert_eq!(rulloc.capacity_hint(layout), 24);
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// assert_eq!(address.len(), 24);
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn capacity_hint(&self, layout: Layout) -> usize {
+        // The canary goes right after the requested size, so the user can't
+        // get any extra capacity.
+        if self.canary.is_some() {
+            return layout.size();
+        }
+
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        allocator
+            .bucket(allocator.bucket_index_of(layout))
+            .capacity_hint(layout)
+    }
+
+    /// Same as [`Self::capacity_hint`] but for an allocation that already
+    /// exists. Returns the number of bytes that can be written at `address`
+    /// without reallocating, which is everything up to the end of its block:
+    ///
+    /// ```text
+    ///          address
+    ///             |
+    /// +--------+--v---------------------+-------+
+    /// | Header | old_layout.size() | ... | Next  |
+    /// +--------+------------------------+-------+
+    ///             <------ capacity ----->
+    /// ```
+    ///
+    /// From now on the allocation is considered to be of that size, so
+    /// [`Stats::bytes_allocated`] grows accordingly and containers can use
+    /// the extra bytes right away, like a [`Vec`] that finds free headroom
+    /// without copying anything. Blocks can't grow past their end here, use
+    /// [`Allocator::grow`] for that. With canaries enabled (see
+    /// [`Self::with_canary_seed`]) the canary sits right after the requested
+    /// bytes, so there's no capacity to gain and `old_layout.size()` is
+    /// returned.
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been allocated by this allocator with
+    /// `old_layout`. Afterwards it must be deallocated or reallocated with a
+    /// layout of the returned size and the same alignment, like
+    /// [`Allocator::grow`] requires after growing.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(100).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// unsafe {
+    ///     let capacity = rulloc.try_grow_to_capacity(address.cast(), layout);
+    ///     assert!(capacity >= 100);
+    ///
+    ///     let layout = Layout::array::<u8>(capacity).unwrap();
+    ///     address.cast::<u8>().as_ptr().write_bytes(1, capacity);
+    ///     rulloc.deallocate(address.cast(), layout);
+    /// }
+    /// ```
+    pub unsafe fn try_grow_to_capacity(&self, address: NonNull<u8>, old_layout: Layout) -> usize {
+        if self.canary.is_some() || Self::is_dangling(address, old_layout) {
+            return old_layout.size();
+        }
+
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return old_layout.size();
+        };
+
+        let capacity = allocator.grow_to_capacity(address, old_layout);
+        self.counters
+            .record_reallocation(old_layout.size(), capacity);
+        #[cfg(feature = "tracy")]
+        {
+            let bucket = allocator.bucket_of(address, old_layout);
+            tracy::emit_free(address.as_ptr(), bucket);
+            tracy::emit_alloc(address.as_ptr(), capacity, bucket);
+        }
+        drop(allocator);
+
+        #[cfg(feature = "leak-tracking")]
+        self.leaks.retrack(address, address, capacity);
+
+        capacity
+    }
+
+    /// Number of bytes that can be used at `address`, which is the content
+    /// size of its block, like `malloc_usable_size` in glibc. This is at least
+    /// the size requested when allocating and usually the length of the
+    /// slice returned by [`Allocator::allocate`], so callers can use the
+    /// slack capacity without reallocating. The block header is right before
+    /// `address`, so this doesn't search anything.
+    ///
+    /// With [`Self::with_canary_seed`] the canary is written right after the
+    /// requested size, so the bytes after it must not be used even though
+    /// they are part of the block.
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been returned by this allocator for a layout
+    /// aligned to pointer size or less and must not be deallocated yet.
+    /// Stricter alignments place padding between the header and the
+    /// address, so the header can't be found without the layout.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(20).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// unsafe {
+    ///     assert_eq!(rulloc.block_size(address.cast()), 24);
+    ///     rulloc.deallocate(address.cast(), layout);
+    /// }
+    /// ```
+    pub unsafe fn block_size(&self, address: NonNull<u8>) -> usize {
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Slab slots have no header, see [`crate::slab`].
+        if allocator.slab.contains(address) {
+            return allocator.slab.slot_size();
+        }
+
+        Header::<Block>::from_content_address(address)
+            .as_ref()
+            .size()
+    }
+
+    /// Maps `n` pages directly from the kernel, bypassing buckets entirely,
+    /// with the base address aligned to `align` bytes. This is useful for
+    /// buffers that need stronger alignment than the page size, for example
+    /// a 2MB buffer aligned to 2MB so that it can be backed by one huge page.
+    /// Just like [`Self::with_region_alignment`], we map more than needed and
+    /// return the unaligned parts to the kernel, so the result is one clean
+    /// mapping of exactly `n` pages.
+    ///
+    /// Pages don't have any headers and they are not tracked by the
+    /// allocator, so they don't count towards [`Stats::bytes_mapped`] and
+    /// they must be returned with [`Self::deallocate_pages_aligned`] using the
+    /// same `n` and `align`.
+    ///
+    /// # Panics
+    ///
+    /// If `align` is not a power of 2.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let align = 64 * 1024;
+    /// let pages = rulloc.allocate_pages_aligned(4, align).unwrap();
+    /// assert_eq!(pages.cast::<u8>().as_ptr() as usize % align, 0);
+    ///
+    /// unsafe { rulloc.deallocate_pages_aligned(pages.cast(), 4, align) };
+    /// ```
+    pub fn allocate_pages_aligned(&self, n: usize, align: usize) -> AllocResult {
+        assert!(
+            align.is_power_of_two(),
+            "page alignment must be a power of 2"
+        );
+
+        let length = n
+            .checked_mul(platform::page_size())
+            .ok_or_else(|| self.failures.record(AllocFailure::SizeOverflow))?;
+        let mmap_flags = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .dyn_bucket
+            .mmap_flags();
+        let address = unsafe { platform::request_aligned_memory(length, align, mmap_flags) };
+
+        address
+            .map(|address| NonNull::slice_from_raw_parts(address, length))
+            .map_err(|errno| self.failures.record(AllocFailure::MmapFailed(errno)))
+    }
+
+    /// Returns pages obtained from [`Self::allocate_pages_aligned`] to the
+    /// kernel.
+    ///
+    /// # Safety
+    ///
+    /// `address`, `n` and `align` must be exactly the values used when
+    /// calling [`Self::allocate_pages_aligned`], and the pages must not be
+    /// used after this call. In debug builds, `align` not being a power of 2
+    /// panics before anything is unmapped.
+    pub unsafe fn deallocate_pages_aligned(&self, address: NonNull<u8>, n: usize, align: usize) {
+        debug_assert!(
+            align.is_power_of_two(),
+            "page alignment must be a power of 2"
+        );
+
+        self.check_unmapped(platform::return_aligned_memory(
+            address,
+            n * platform::page_size(),
+            align,
+        ));
+    }
+
+    /// Maps memory where at least `size` bytes of machine code can be written,
+    /// for JIT compilers. The memory is read-write until
+    /// [`Self::make_executable`] is called, and it must be deallocated with
+    /// [`Self::deallocate_exec`]. Executable memory doesn't go through
+    /// buckets, every call maps a new region with an additional page for
+    /// the header, see [`crate::exec`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let code = rulloc.allocate_exec(64).unwrap();
+    ///
+    /// unsafe {
+    ///     code.cast::<u8>().as_ptr().write(0xC3); // ret on x86
+    ///     rulloc.make_executable(code.cast(), 1).unwrap();
+    ///     rulloc.deallocate_exec(code.cast());
+    /// }
+    /// ```
+    pub fn allocate_exec(&self, size: usize) -> AllocResult {
+        let mut allocator = self.allocator.lock().map_err(|_| AllocError)?;
+
+        unsafe {
+            let mmap_flags = allocator.dyn_bucket.mmap_flags();
+            let address = allocator
+                .exec
+                .allocate(size, mmap_flags)
+                .ok_or(AllocError)?;
+            let capacity = allocator.exec.capacity(address);
+
+            Ok(NonNull::slice_from_raw_parts(address, capacity))
+        }
+    }
+
+    /// Makes the first `size` bytes of the region returned by
+    /// [`Self::allocate_exec`] read-execute. Protection works on entire
+    /// pages, so the rest of the page that contains the last byte becomes
+    /// read-execute as well.
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been returned by [`Self::allocate_exec`] and not
+    /// deallocated yet.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is larger than the allocated region.
+    pub unsafe fn make_executable(&self, address: NonNull<u8>, size: usize) -> io::Result<()> {
+        self.allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .exec
+            .make_executable(address, size)
+    }
+
+    /// Inverse of [`Self::make_executable`], makes the code writable again
+    /// and not executable so that it can be patched.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Self::make_executable`], and no thread can be executing the
+    /// code at the moment.
+    pub unsafe fn make_writable(&self, address: NonNull<u8>, size: usize) -> io::Result<()> {
+        self.allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .exec
+            .make_writable(address, size)
+    }
+
+    /// Unmaps a region returned by [`Self::allocate_exec`].
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been returned by [`Self::allocate_exec`], it must
+    /// not be used again and no thread can be executing its code.
+    pub unsafe fn deallocate_exec(&self, address: NonNull<u8>) {
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return;
+        };
+
+        let result = allocator.exec.deallocate(address);
+        drop(allocator);
+        self.check_unmapped(result);
+    }
+
+    /// Makes the memory of an allocation read only, so that any write to it
+    /// faults. Useful as a trip wire for objects that are initialized once
+    /// and must never change afterwards. Unlike [`Self::freeze_config`],
+    /// which only affects the allocator itself, this only affects one
+    /// allocation. Reads keep working as usual.
+    ///
+    /// Protection works on entire pages, so the allocation must span whole
+    /// pages and nothing else: `address` must be page aligned and
+    /// `layout.size()` a multiple of the page size. Otherwise other
+    /// allocations or block headers sharing the first or last page would
+    /// become read only as well. Page aligned layouts of whole pages, like
+    /// the ones in the example below, always satisfy this.
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been allocated by this allocator with `layout`,
+    /// and it must be made writable again with [`Self::unprotect`] before
+    /// being deallocated or reallocated.
+    ///
+    /// # Panics
+    ///
+    /// If `address` is not page aligned or `layout.size()` is not a non-zero
+    /// multiple of the page size.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::from_size_align(4096, 4096).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap().cast::<u8>();
+    ///
+    /// unsafe {
+    ///     address.as_ptr().write_bytes(1, layout.size());
+    ///     rulloc.protect(address, layout).unwrap();
+    ///     assert_eq!(*address.as_ptr(), 1);
+    ///
+    ///     rulloc.unprotect(address, layout).unwrap();
+    ///     rulloc.deallocate(address, layout);
+    /// }
+    /// ```
+    pub unsafe fn protect(&self, address: NonNull<u8>, layout: Layout) -> io::Result<()> {
+        Self::set_protection(address, layout, Protection::ReadOnly)
+    }
+
+    /// Inverse of [`Self::protect`], makes the allocation writable again.
+    ///
+    /// # Safety
+    ///
+    /// `address` must have been allocated by this allocator with `layout`.
+    ///
+    /// # Panics
+    ///
+    /// Same as [`Self::protect`].
+    pub unsafe fn unprotect(&self, address: NonNull<u8>, layout: Layout) -> io::Result<()> {
+        Self::set_protection(address, layout, Protection::ReadWrite)
+    }
+
+    /// Common code for [`Self::protect`] and [`Self::unprotect`]. The pages
+    /// only belong to one allocation, so there's no need to lock.
+    unsafe fn set_protection(
+        address: NonNull<u8>,
+        layout: Layout,
+        protection: Protection,
+    ) -> io::Result<()> {
+        let page_size = platform::page_size();
+        assert!(
+            address.as_ptr() as usize % page_size == 0,
+            "can't protect {address:?}, it's not page aligned"
+        );
+        assert!(
+            layout.size() > 0 && layout.size() % page_size == 0,
+            "can't protect {} bytes, it's not a multiple of the page size",
+            layout.size()
+        );
+
+        if platform::protect(address, layout.size(), protection) {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Maps a page aligned buffer of `size` bytes for secrets such as key
+    /// material. The pages are locked in physical memory so they are never
+    /// swapped out, and they are zeroed when allocated and again when the
+    /// buffer is dropped, right before they are unlocked and returned to the
+    /// kernel. Just like [`Self::allocate_exec`], the buffer gets its own
+    /// mapping and doesn't go through buckets, see [`crate::secure`].
+    ///
+    /// Fails if the kernel refuses to lock the pages, which happens when the
+    /// process would exceed its locked memory limit (`RLIMIT_MEMLOCK` on
+    /// Unix).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let mut key = rulloc.allocate_secure(32).unwrap();
+    /// key.copy_from_slice(&[7; 32]);
+    ///
+    /// // Zeroed, unlocked and unmapped.
+    /// drop(key);
+    /// ```
+    pub fn allocate_secure(&self, size: usize) -> io::Result<SecureBuffer> {
+        let mmap_flags = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .dyn_bucket
+            .mmap_flags();
+        SecureBuffer::new(size, mmap_flags)
+    }
+
+    /// Same as [`Allocator::allocate`] but the returned pointer remembers the
+    /// generation of its block, so that [`Self::deallocate_checked`] can
+    /// detect if it was already freed and the memory was given to somebody
+    /// else. See [`crate::generation`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::alloc::Layout;
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    /// let address = rulloc.allocate_checked(layout).unwrap();
+    /// unsafe { address.as_ptr().write(42) };
+    /// unsafe { rulloc.deallocate_checked(address) };
+    /// ```
+    pub fn allocate_checked(&self, layout: Layout) -> Result<CheckedPtr, AllocError> {
+        let address = self.allocate(layout)?.cast();
+
+        #[cfg(debug_assertions)]
+        let generation = unsafe {
+            let inner_layout = match self.canary {
+                Some(canary) => canary.padded(layout).unwrap_unchecked(),
+                None => layout,
+            };
+            let allocator = self
+                .allocator
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            allocator.generation_of(address, inner_layout)
+        };
+
+        #[cfg(not(debug_assertions))]
+        let generation = 0;
+
+        Ok(CheckedPtr {
+            address,
+            layout,
+            generation,
+        })
+    }
+
+    /// Deallocates a pointer returned by [`Self::allocate_checked`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Allocator::deallocate`], except that in debug builds passing
+    /// a pointer that was already deallocated is detected as long as its
+    /// region is still mapped or it has been reused.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if `ptr` is stale, which means that it has already
+    /// been deallocated and the memory might belong to another allocation.
+    pub unsafe fn deallocate_checked(&self, ptr: CheckedPtr) {
+        #[cfg(debug_assertions)]
+        {
+            let inner_layout = match self.canary {
+                Some(canary) => canary.padded(ptr.layout).unwrap_unchecked(),
+                None => ptr.layout,
+            };
+
+            // Don't panic while holding the lock, it would poison it.
+            let is_stale = {
+                let allocator = self
+                    .allocator
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+
+                allocator.is_stale(ptr.address, ptr.generation, inner_layout)
+            };
+
+            assert!(
+                !is_stale,
+                "deallocating stale pointer {:?} of generation {}, it was already freed",
+                ptr.address, ptr.generation
+            );
+        }
+
+        self.deallocate(ptr.address, ptr.layout)
+    }
+
+    /// Returns all the memory of the bucket at `index` to the kernel without
+    /// touching other buckets. Indices `0..N` are the fixed size buckets in
+    /// the order given to [`Self::with_bucket_sizes`] and `N` is the bucket
+    /// used for allocations larger than all of them. This is useful for
+    /// workloads that go through phases, where one size class is used a lot
+    /// in one phase and not at all in the next one.
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that none of the pointers allocated in this
+    /// bucket will be used again, not even for deallocation. Pointers
+    /// allocated in other buckets are not affected.
+    ///
+    /// Blocks that were still in use count as deallocated in [`Stats`], and
+    /// with the `leak-tracking` feature they are no longer reported as leaks.
+    ///
+    /// # Panics
+    ///
+    /// If `index > N`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<2>::with_bucket_sizes([64, 1024]);
+    /// let small = rulloc.allocate(Layout::array::<u8>(32).unwrap()).unwrap();
+    /// let medium = rulloc.allocate(Layout::array::<u8>(512).unwrap()).unwrap();
+    ///
+    /// // Small allocations are not needed anymore.
+    /// unsafe { rulloc.clear_bucket(0) };
+    ///
+    /// // Medium ones are still valid.
+    /// unsafe { rulloc.deallocate(medium.cast(), Layout::array::<u8>(512).unwrap()) };
+    /// ```
+    pub unsafe fn clear_bucket(&self, index: usize) {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        if let Ok(mut allocator) = self.allocator.lock() {
+            // The tracker allocates with the system allocator and doesn't
+            // capture anything here, so it's fine to call it while locked.
+            #[cfg(feature = "leak-tracking")]
+            for region in allocator.bucket(index).regions().iter() {
+                self.leaks
+                    .untrack_range(region.cast(), region.as_ref().total_size());
+            }
+
+            let (in_use, requested) = allocator.bucket_mut(index).clear();
+            self.record_dropped(in_use, requested);
+            let unmap_failures = self.sync_mapped(&mut allocator);
+            drop(allocator);
+            unmap_failures.report();
+        }
+    }
+
+    /// Frees every allocation of one region at once, for arena style usage
+    /// where a whole region worth of objects dies together. `region` is
+    /// obtained from [`Self::region_for`] with any pointer of the region.
+    /// The region becomes one big free block and stays mapped, so it can be
+    /// filled again right away, see [`Bucket::free_region`]. Nothing is
+    /// merged block by block, but every block of the region is visited once
+    /// to unlink the ones that were already free from the free list.
+    ///
+    /// Just like [`Self::clear_bucket`], the blocks that were still in use
+    /// count as deallocated in [`Stats`].
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that none of the pointers allocated in the
+    /// region will be used again, not even for deallocation. Blocks of the
+    /// region must not be protected, see [`Self::protect`].
+    ///
+    /// # Panics
+    ///
+    /// If `region` doesn't describe a region of this allocator that is still
+    /// mapped, or if it belongs to a pool, see [`Self::pool_mode`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(32).unwrap();
+    ///
+    /// let first = rulloc.allocate(layout).unwrap();
+    /// for _ in 0..10 {
+    ///     rulloc.allocate(layout).unwrap();
+    /// }
+    ///
+    /// let region = rulloc.region_for(first.cast()).unwrap();
+    /// unsafe { rulloc.free_region(region) };
+    /// assert_eq!(rulloc.region_for(first.cast()).unwrap().num_blocks, 1);
+    /// ```
+    pub unsafe fn free_region(&self, region: RegionInfo) {
+        assert!(
+            region.bucket <= N,
+            "bucket index {} out of range, there are {N} + 1 buckets",
+            region.bucket
+        );
+
+        if let Ok(mut allocator) = self.allocator.lock() {
+            let bucket = allocator.bucket_mut(region.bucket);
+            let header = bucket
+                .region_at(region.address)
+                .expect("region doesn't belong to this allocator");
+            let (in_use, requested) = bucket.free_region(header);
+            self.record_dropped(in_use, requested);
+            let unmap_failures = self.sync_mapped(&mut allocator);
+            drop(allocator);
+            unmap_failures.report();
+        }
+
+        #[cfg(feature = "leak-tracking")]
+        self.leaks.untrack_range(region.address, region.length);
+    }
+
+    /// Returns free memory of the bucket at `index` to the kernel until the
+    /// bytes in its free blocks drop to `keep_bytes`, preferring whole empty
+    /// regions over parts of regions that are still in use. Unlike
+    /// [`Self::clear_bucket`], allocations are not affected at all. See
+    /// [`Bucket::shrink_to`] for details. Returns the number of bytes
+    /// unmapped.
+    ///
+    /// # Panics
+    ///
+    /// If `index > N`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<1>::pool_mode([64]);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    ///
+    /// // Pools keep their regions mapped until asked otherwise.
+    /// assert!(rulloc.shrink_bucket(0, 0) > 0);
+    /// assert_eq!(rulloc.total_mapped_bytes(), 0);
+    /// ```
+    pub fn shrink_bucket(&self, index: usize, keep_bytes: usize) -> usize {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        let Ok(mut allocator) = self.allocator.lock() else {
+            return 0;
+        };
+
+        let unmapped = unsafe { allocator.bucket_mut(index).shrink_to(keep_bytes) };
+        let unmap_failures = self.sync_mapped(&mut allocator);
+        drop(allocator);
+        unmap_failures.report();
+
+        unmapped
+    }
+
+    /// Marks the bucket at `index` as sensitive. Blocks deallocated from a
+    /// sensitive bucket are zeroed before they are reused or returned to the
+    /// kernel, which is useful when only some sizes hold secrets (key
+    /// buffers, for example). Zeroing on every deallocation is expensive, so
+    /// other buckets are not affected. Index `N` is the dynamic bucket and
+    /// index 0 includes the slab, see [`Self::with_slab`].
+    ///
+    /// Returns [`ConfigFrozen`] without changing anything if
+    /// [`Self::freeze_config`] has been called.
+    ///
+    /// # Panics
+    ///
+    /// If `index > N`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<2>::with_bucket_sizes([32, 1024]);
+    /// rulloc.mark_bucket_sensitive(0).unwrap();
+    ///
+    /// let layout = Layout::array::<u8>(32).unwrap();
+    /// let key = rulloc.allocate(layout).unwrap();
+    /// unsafe {
+    ///     key.cast::<u8>().as_ptr().write_bytes(0xFF, 32);
+    ///     // Contents are zeroed here.
+    ///     rulloc.deallocate(key.cast(), layout);
+    /// }
+    /// ```
+    pub fn mark_bucket_sensitive(&self, index: usize) -> Result<(), ConfigFrozen> {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        self.reconfigure(|allocator| {
+            allocator.bucket_mut(index).set_sensitive(true);
+            if index == 0 {
+                allocator.slab.set_sensitive(true);
+            }
+        })
+    }
+
+    /// Rejects any further changes made through runtime setters such as
+    /// [`Self::mark_bucket_sensitive`], they'll return [`ConfigFrozen`]
+    /// instead. Call this once setup is done so that no thread can change
+    /// the configuration while others are allocating:
+    ///
+    /// ```text
+    ///              freeze_config()
+    /// Configuring ------------------> Running
+    /// ```
+    ///
+    /// There's no way back. Builder methods like [`Self::with_memory_limit`]
+    /// are not affected because they take the allocator by value, so nobody
+    /// else can be using it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use rulloc::{ConfigFrozen, Rulloc};
+    ///
+    /// let rulloc = Rulloc::<2>::with_bucket_sizes([32, 1024]);
+    /// rulloc.mark_bucket_sensitive(0).unwrap();
+    /// rulloc.freeze_config();
+    ///
+    /// assert!(rulloc.is_config_frozen());
+    /// assert_eq!(rulloc.mark_bucket_sensitive(1), Err(ConfigFrozen));
+    /// ```
+    pub fn freeze_config(&self) {
+        // Setters check the flag with the lock held, so once this returns
+        // there's no setter halfway through changing something.
+        let _allocator = self
+            .allocator
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        self.frozen.store(true, Ordering::Release);
+    }
+
+    /// Whether [`Self::freeze_config`] has been called.
+    pub fn is_config_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Acquire)
+    }
+
+    /// Calls `reconfigure` with the internal allocator unless the
+    /// configuration is frozen. Used for runtime setters, builders use
+    /// [`Self::configure`] instead.
+    fn reconfigure(
+        &self,
+        reconfigure: impl FnOnce(&mut InternalAllocator<N, P>),
+    ) -> Result<(), ConfigFrozen> {
+        let mut allocator = self
+            .allocator
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if self.is_config_frozen() {
+            return Err(ConfigFrozen);
+        }
+
+        reconfigure(&mut allocator);
+
+        Ok(())
+    }
+
+    /// Returns a snapshot of the allocator counters without acquiring the
+    /// allocator lock, so it can be called in a loop from a monitoring thread
+    /// without slowing down threads that are allocating memory. See [`Stats`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// let stats = rulloc.poll_stats();
+    /// assert_eq!(stats.allocations, 1);
+    /// assert_eq!(stats.bytes_allocated, 64);
+    /// assert!(stats.bytes_mapped > 0);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// assert_eq!(rulloc.poll_stats().bytes_allocated, 0);
+    /// ```
+    pub fn poll_stats(&self) -> Stats {
+        self.counters.snapshot()
+    }
+
+    /// Zeroes every counter that accumulates over the lifetime of the
+    /// allocator, so that each phase of a benchmark can be measured on its
+    /// own. That includes the allocation and deallocation counts of
+    /// [`Stats`], the hit rates and free counts of every [`BucketStats`],
+    /// the histogram used by [`Self::with_adaptive_sizes`] and, with the
+    /// `timing` feature, the latency histogram and windowed stats.
+    ///
+    /// The heap itself is not touched. Live allocations, regions and free
+    /// lists stay as they are, and so do the counters that describe them,
+    /// like [`Stats::bytes_allocated`] or [`Stats::bytes_mapped`].
+    /// [`Stats::unmap_failures`] is not reset either, the address space it
+    /// counts is still leaked.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// rulloc.reset_stats();
+    /// assert_eq!(rulloc.poll_stats().allocations, 0);
+    /// assert_eq!(rulloc.poll_stats().bytes_allocated, 8);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn reset_stats(&self) {
+        // Counters are written while holding the lock, so resetting them
+        // here can't race with an operation that is counting.
+        if let Ok(mut allocator) = self.allocator.lock() {
+            allocator.buckets_mut().for_each(Bucket::reset_stats);
+            allocator.adaptive.reset();
+            self.counters.reset_lifetime();
+        }
+
+        #[cfg(feature = "timing")]
+        {
+            self.latencies.reset();
+            self.windows.reset();
+        }
+    }
+
+    /// Statistics of every bucket, including the dynamic one, see
+    /// [`FullStats`]. This has to acquire the lock and walk the free lists,
+    /// so it's much slower than [`Self::poll_stats`], but all the values are
+    /// consistent with each other because they are read in the same critical
+    /// section.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// let stats = rulloc.full_stats();
+    /// assert_eq!(stats.buckets[0].regions, 1);
+    /// assert_eq!(stats.dyn_bucket.regions, 0);
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn full_stats(&self) -> FullStats<N> {
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Counters are only written while holding the lock, so reading them
+        // here can't tear with the buckets.
+        let bytes_mapped = allocator.bytes_mapped();
+        let bytes_allocated = self.counters.snapshot().bytes_allocated;
+        let bytes_free = allocator.free_bytes();
+
+        FullStats {
+            buckets: std::array::from_fn(|i| allocator.buckets[i].stats()),
+            dyn_bucket: allocator.dyn_bucket.stats(),
+            sizes: allocator.sizes,
+            bytes_mapped,
+            bytes_allocated,
+            bytes_free,
+            bytes_overhead: bytes_mapped.saturating_sub(bytes_allocated + bytes_free),
+        }
+    }
+
+    /// Structured description of every bucket, region and block, see
+    /// [`HeapReport`]. Useful to write custom visualizers or to check exactly
+    /// where blocks end up. Walking all the blocks takes time proportional to
+    /// the number of blocks, and the lock is held while doing so.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// let report = rulloc.memory_report();
+    /// let region = &report.buckets[0].regions[0];
+    /// assert_eq!(region.blocks[0].size, 64);
+    /// assert!(!region.blocks[0].free);
+    /// assert!(report.buckets[1].regions.is_empty());
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn memory_report(&self) -> HeapReport {
+        loop {
+            let num_blocks = self
+                .allocator
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .num_blocks();
+
+            // Can't allocate while holding the lock. If blocks are split in
+            // the meantime we try again with more capacity.
+            let mut records = Vec::with_capacity(num_blocks);
+
+            let allocator = self
+                .allocator
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            if allocator.num_blocks() > records.capacity() {
+                continue;
+            }
+
+            allocator
+                .buckets
+                .iter()
+                .chain(std::iter::once(&allocator.dyn_bucket))
+                .for_each(|bucket| bucket.block_records_into(&mut records));
+
+            let sizes = allocator.sizes;
+            drop(allocator);
+
+            return HeapReport::from_records(
+                sizes.into_iter().chain(std::iter::once(usize::MAX)),
+                records,
+            );
+        }
+    }
+
+    /// Returns the region that contains `address`, or `None` if the address
+    /// was not given by this allocator. Any address inside the region works,
+    /// not only the ones returned by [`Allocator::allocate`], which is useful
+    /// to find out where some pointer came from while debugging. This walks
+    /// through the regions of every bucket while holding the lock, but each
+    /// list is sorted by address so the search stops early. Regions of
+    /// [`Self::allocate_exec`] are not included.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::{
+    ///     alloc::{Allocator, Layout},
+    ///     ptr::NonNull,
+    /// };
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+    /// let layout = Layout::array::<u8>(512).unwrap();
+    /// let address = rulloc.allocate(layout).unwrap();
+    ///
+    /// let region = rulloc.region_for(address.cast()).unwrap();
+    /// assert_eq!(region.bucket, 1);
+    ///
+    /// let mut unowned = 0u8;
+    /// assert_eq!(rulloc.region_for(NonNull::from(&mut unowned)), None);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    pub fn region_for(&self, address: NonNull<u8>) -> Option<RegionInfo> {
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        unsafe { allocator.region_for(address) }
+    }
+
+    /// Number of regions currently mapped by the bucket at `index`, where
+    /// index `N` is the dynamic bucket. Unlike [`Self::total_regions`] this
+    /// acquires the lock, and it doesn't count slab regions.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > N`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<2>::with_bucket_sizes([8, 64]);
+    /// let layout = Layout::array::<u8>(64).unwrap();
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// assert_eq!(rulloc.region_count(0), 0);
+    /// assert_eq!(rulloc.region_count(1), 1);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// assert_eq!(rulloc.region_count(1), 0);
+    /// ```
+    pub fn region_count(&self, index: usize) -> usize {
+        assert!(
+            index <= N,
+            "bucket index {index} out of range, there are {N} + 1 buckets"
+        );
+
+        let allocator = self
+            .allocator
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        allocator.bucket(index).num_regions()
+    }
+
+    /// Number of regions currently mapped by the dynamic bucket, same as
+    /// `region_count(N)`.
+    pub fn dyn_region_count(&self) -> usize {
+        self.region_count(N)
+    }
+
+    /// Number of regions currently mapped by all buckets, including the
+    /// dynamic one. Just like [`Self::poll_stats`] it doesn't acquire the
+    /// lock, so it's cheap enough to be exported as a metrics gauge.
+    pub fn total_regions(&self) -> usize {
+        self.counters.regions()
+    }
+
+    /// Number of allocations that have not been deallocated yet, including
+    /// slab slots. Doesn't acquire the lock either, see
+    /// [`Stats::live_blocks`]. Together with [`Self::total_regions`] it makes
+    /// a cheap health metric: a block count that keeps growing while
+    /// [`Stats::bytes_allocated`] doesn't suggests a leak of small
+    /// allocations, and regions that keep growing while the block count
+    /// doesn't suggest fragmentation.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// assert_eq!(rulloc.total_live_blocks(), 1);
+    ///
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// assert_eq!(rulloc.total_live_blocks(), 0);
+    /// ```
+    pub fn total_live_blocks(&self) -> usize {
+        self.counters.live_blocks()
+    }
+
+    /// Total length in bytes of all the regions counted by
+    /// [`Self::total_regions`], headers included. Same as
+    /// [`Stats::bytes_mapped`].
+    pub fn total_mapped_bytes(&self) -> usize {
+        self.counters.bytes_mapped()
+    }
+
+    /// Returns why the last failed allocation failed, or `None` if nothing has
+    /// failed yet. The [`Allocator`] trait only allows returning
+    /// [`AllocError`], so this is the only way to tell an out of memory
+    /// condition apart from a limit or an impossible request. Like
+    /// [`Self::poll_stats`], it can be called without acquiring the lock, but
+    /// if multiple threads are allocating at the same time the failure might
+    /// belong to any of them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::{AllocFailure, Rulloc};
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+    ///
+    /// assert!(rulloc.allocate(layout).is_err());
+    /// assert_eq!(rulloc.last_error(), Some(AllocFailure::SizeOverflow));
+    /// ```
+    pub fn last_error(&self) -> Option<AllocFailure> {
+        self.failures.get()
+    }
+
+    /// Returns a human readable report of all the allocations that have not
+    /// been freed yet, including the backtrace captured when each of them was
+    /// allocated. Only available with the `leak-tracking` feature, see
+    /// [`crate::leak`] for details.
+    #[cfg(feature = "leak-tracking")]
+    pub fn leak_report(&self) -> String {
+        self.leaks.report()
+    }
+
+    /// Makes the next `n` allocations fail with [`AllocError`] without
+    /// touching any bucket or mapping anything, which is useful for testing
+    /// how code that uses the allocator handles out of memory conditions.
+    /// Calling this again replaces the previous count, so `set_fail_next(0)`
+    /// cancels pending failures. Reallocations are not affected and injected
+    /// failures are not recorded by [`Self::last_error`]. Only available with
+    /// the `fault-injection` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    ///
+    /// rulloc.set_fail_next(1);
+    /// assert!(rulloc.allocate(layout).is_err());
+    ///
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    /// ```
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fail_next(&self, n: usize) {
+        self.fail_next.store(n, Ordering::Relaxed);
+    }
+
+    /// Returns a snapshot of the latency histogram of all the allocations and
+    /// deallocations made so far. Only available with the `timing` feature,
+    /// see [`crate::timing`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    ///
+    /// assert_eq!(rulloc.latency_histogram().total(), 2);
+    /// ```
+    #[cfg(feature = "timing")]
+    pub fn latency_histogram(&self) -> LatencyHistogram {
+        self.latencies.snapshot()
+    }
+
+    /// Returns the number of allocations and deallocations made during each
+    /// of the last [`crate::NUM_WINDOWS`] seconds, which is enough to plot
+    /// allocation rate without sampling [`Self::poll_stats`] from another
+    /// thread. Only available with the `timing` feature, see
+    /// [`crate::window`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    /// let address = rulloc.allocate(layout).unwrap();
+    /// unsafe { rulloc.deallocate(address.cast(), layout) };
+    ///
+    /// let stats = rulloc.windowed_stats();
+    /// let allocations: usize = stats.windows.iter().map(|window| window.allocations).sum();
+    /// assert_eq!(allocations, 1);
+    /// ```
+    #[cfg(feature = "timing")]
+    pub fn windowed_stats(&self) -> WindowedStats {
+        self.windows.snapshot()
+    }
+
+    /// Consumes the allocator and returns the address and length of every
+    /// memory region it owned, without returning any of them to the kernel.
+    /// The regions still contain whatever was written to them, so this is
+    /// useful for handing a populated heap over to another component. The
+    /// allocator headers are left untouched as well, but they don't mean
+    /// anything once the allocator is gone.
+    ///
+    /// From now on the caller owns the regions and is responsible for
+    /// unmapping them. Each region is one independent mapping, so on Unix it
+    /// can be returned with
+    /// [`munmap`](https://man7.org/linux/man-pages/man2/munmap.2.html) and on
+    /// Windows with
+    /// [`VirtualFree`](https://learn.microsoft.com/en-us/windows/win32/api/memoryapi/nf-memoryapi-virtualfree)
+    /// using `MEM_RELEASE`. Regions are not sorted in any particular order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use std::alloc::{Allocator, Layout};
+    ///
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let layout = Layout::new::<u64>();
+    /// let address = rulloc.allocate(layout).unwrap().cast::<u64>();
+    /// unsafe { address.as_ptr().write(42) };
+    ///
+    /// let bytes_mapped = rulloc.poll_stats().bytes_mapped;
+    /// let regions = rulloc.into_raw_parts();
+    ///
+    /// assert_eq!(regions.iter().map(|(_, length)| length).sum::<usize>(), bytes_mapped);
+    /// assert_eq!(unsafe { address.as_ptr().read() }, 42);
+    /// # #[cfg(unix)]
+    /// # for (address, length) in regions {
+    /// #     unsafe { libc::munmap(address.as_ptr().cast(), length) };
+    /// # }
+    /// ```
+    pub fn into_raw_parts(self) -> Vec<(NonNull<u8>, usize)> {
+        let mut allocator = self
+            .allocator
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Not holding any lock here, so we can allocate.
+        let mut regions = Vec::with_capacity(allocator.num_regions());
+        allocator
+            .buckets_mut()
+            .for_each(|bucket| bucket.take_regions(&mut regions));
+
+        regions
+    }
+
+    /// Whether `address` is the dangling pointer returned by
+    /// [`Self::allocate_array`] for empty arrays, which has no block behind
+    /// it. Blocks are never located at the first page of the address space,
+    /// so no allocation can be mistaken for it.
+    #[inline]
+    fn is_dangling(address: NonNull<u8>, layout: Layout) -> bool {
+        layout.size() == 0 && address.as_ptr().addr() == layout.align()
+    }
+
+    /// Deallocating or reallocating a pointer that was not allocated by us is
+    /// a bug in the caller, so it panics in debug builds. Release builds
+    /// return `false` and the pointer is ignored, reallocations fail. Must be
+    /// called without holding the lock.
+    #[inline]
+    fn accept_owned(address: NonNull<u8>, owned: bool) -> bool {
+        debug_assert!(
+            owned,
+            "{address:?} is not inside any region of this allocator"
+        );
+        owned
+    }
+
+    /// Reports that `address` was deallocated or reallocated with alignment
+    /// `align` but allocated with `allocated`, see
+    /// [`InternalAllocator::misaligned`]. Must be called without holding the
+    /// lock.
+    #[cold]
+    fn alignment_mismatch(address: NonNull<u8>, align: usize, allocated: usize) -> ! {
+        panic!(
+            "{address:?} used with alignment {align}, but it was allocated with alignment \
+             {allocated}"
+        );
+    }
+
+    /// Layout of the block that we actually allocate for `layout`, which is
+    /// larger if canaries are enabled. See [`Canary::padded`].
+    fn inner_layout(&self, layout: Layout) -> Result<Layout, AllocError> {
+        match self.canary {
+            Some(canary) => canary
+                .padded(layout)
+                .map_err(|_| self.failures.record(AllocFailure::SizeOverflow)),
+            None => Ok(layout),
+        }
+    }
+
+    /// Discounts `in_use` blocks dropped without being deallocated, whose
+    /// [`Region::requested`] bytes add up to `requested`, see
+    /// [`Self::clear_bucket`] and [`Self::free_region`].
+    fn record_dropped(&self, in_use: usize, requested: usize) {
+        // Buckets are charged with the padded layout, the counters only know
+        // about the size requested by the user.
+        let padding = match self.canary {
+            Some(_) => in_use * CANARY_SIZE,
+            None => 0,
+        };
+        self.counters
+            .record_dropped(in_use, requested.wrapping_sub(padding));
+    }
+
+    /// Moves the reason of the operation that just failed from `allocator` to
+    /// [`Self::failures`] and returns the error that should be given to the
+    /// caller.
+    fn failed(&self, allocator: &mut InternalAllocator<N, P>) -> AllocError {
+        match allocator.take_failure() {
+            Some(failure) => self.failures.record(failure),
+            None => AllocError,
+        }
+    }
+
+    /// Updates the counters of mapped memory after an operation that might
+    /// have mapped or unmapped regions. Regions that the kernel refused to
+    /// unmap are counted as well, and returned so that the caller can
+    /// [`UnmapFailures::report`] them once the lock is released, the same
+    /// way broken canaries are reported by [`Allocator::deallocate`].
+    fn sync_mapped(&self, allocator: &mut InternalAllocator<N, P>) -> UnmapFailures {
+        self.counters
+            .set_mapped(allocator.bytes_mapped(), allocator.num_regions());
+        let failures = allocator.take_unmap_failures();
+        self.counters.record_unmap_failures(failures.count);
+
+        failures
+    }
+
+    /// Counts and reports the result of unmapping memory that doesn't belong
+    /// to any bucket, see [`Self::sync_mapped`]. Must not be called with the
+    /// lock held.
+    fn check_unmapped(&self, result: Result<(), UnmapFailure>) {
+        let mut failures = UnmapFailures::new();
+        failures.check(result);
+        self.counters.record_unmap_failures(failures.count);
+        failures.report();
+    }
+
+    /// Common code for [`Allocator::allocate`] and [`Self::allocate_in_bucket`].
+    /// `index` is the bucket where `layout` should be allocated, or `None` to
+    /// let [`InternalAllocator::bucket_index_of`] decide.
+    ///
+    /// If `zeroed` is true the first `layout.size()` bytes are zeroed. Memory
+    /// given by the kernel is already zeroed, so when the allocation had to
+    /// map a new anonymous region we only zero the free list node that was
+    /// written at the beginning of the block. Everything after it has never
+    /// been touched, see [`Bucket::take_fresh`]:
+    ///
+    /// ```text
+    /// +--------+--------+-----------+---------------------------------+
+    /// | Region | Header | Free node |        Zeroed by the kernel     |
+    /// +--------+--------+-----------+---------------------------------+
+    ///                   ^
+    ///                   |
+    ///                   +-- Only these bytes need to be zeroed
+    /// ```
+    fn allocate_in(&self, placement: Placement, layout: Layout, zeroed: bool) -> AllocResult {
+        #[cfg(feature = "timing")]
+        let start = std::time::Instant::now();
+
+        #[cfg(feature = "fault-injection")]
+        if self
+            .fail_next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(AllocError);
+        }
+
+        let inner_layout = self.inner_layout(layout)?;
+
+        let (mut address, fresh, scrub, unmap_failures) = unsafe {
+            match self.allocator.lock() {
+                Ok(mut allocator) => {
+                    let scrub = allocator.dyn_bucket.scrub_pattern();
+                    let result = match placement {
+                        Placement::BySize => allocator.allocate(inner_layout),
+                        Placement::Bucket(index) => {
+                            allocator.allocate_in_bucket(index, inner_layout)
+                        }
+                        Placement::Near(hint) => allocator.allocate_near(inner_layout, hint),
+                    };
+                    let address = result.map_err(|_| self.failed(&mut allocator))?;
+                    self.counters.record_allocation(layout.size());
+                    #[cfg(feature = "tracy")]
+                    tracy::emit_alloc(
+                        address.as_mut_ptr(),
+                        layout.size(),
+                        allocator.bucket_of(address.cast(), inner_layout),
+                    );
+                    let unmap_failures = self.sync_mapped(&mut allocator);
+                    let fresh = allocator.take_fresh(address.cast(), inner_layout);
+                    (address, fresh, scrub, unmap_failures)
+                }
+                Err(_) => return Err(AllocError),
+            }
+        };
+
+        unmap_failures.report();
+
+        // Fresh regions only contain the free list links of the first block
+        // besides zeroes or the scrub pattern, so those are the only bytes
+        // that need to be rewritten.
+        let fresh_length = cmp::min(layout.size(), MIN_BLOCK_SIZE);
+        if zeroed {
+            let length = if fresh == Some(0) {
+                fresh_length
+            } else {
+                layout.size()
+            };
+            unsafe { address.as_mut_ptr().write_bytes(0, length) };
+        } else if let Some(pattern) = scrub.filter(|_| fresh.is_some()) {
+            unsafe { address.as_mut_ptr().write_bytes(pattern, fresh_length) };
+        }
+
+        if let Some(canary) = self.canary {
+            address = unsafe { canary.protect(address, layout.size()) };
+        }
+
+        #[cfg(feature = "timing")]
+        {
+            self.latencies.record_since(start);
+            self.windows.record_allocation();
+        }
+
+        // Must be done without holding the lock, see [`crate::leak`].
+        #[cfg(feature = "leak-tracking")]
+        self.leaks.track(address.cast(), layout.size());
+
+        Ok(address)
+    }
+
+    /// Common code for [`Allocator::grow`] and [`Allocator::shrink`].
+    unsafe fn reallocate(&self, realloc: &Realloc) -> AllocResult {
+        let inner_realloc = match self.canary {
+            Some(_) => Realloc {
+                old_layout: self.inner_layout(realloc.old_layout)?,
+                new_layout: self.inner_layout(realloc.new_layout)?,
+                ..*realloc
+            },
+            None => realloc.map(realloc.block),
+        };
+
+        // The old address is the one that was actually allocated, in case
+        // this is an interior pointer, see [`InternalAllocator::resolve`].
+        let (old_address, mut new_address, unmap_failures) = match self.allocator.lock() {
+            Ok(mut allocator) => {
+                // Same as [`Self::deallocate`], pointers we don't own are
+                // rejected before reading their header or canary.
+                if !allocator.owns(realloc.address, inner_realloc.old_layout) {
+                    drop(allocator);
+                    Self::accept_owned(realloc.address, false);
+                    return Err(AllocError);
+                }
+                let old_address = allocator.resolve(realloc.address, inner_realloc.old_layout);
+                if let Some(canary) = self.canary {
+                    if !canary.is_intact(old_address, realloc.old_layout.size()) {
+                        drop(allocator);
+                        Canary::overflow(old_address, realloc.old_layout.size());
+                    }
+                }
+                if let Some(allocated) = allocator.misaligned(old_address, inner_realloc.old_layout)
+                {
+                    drop(allocator);
+                    let align = inner_realloc.old_layout.align();
+                    Self::alignment_mismatch(realloc.address, align, allocated);
+                }
+                #[cfg(feature = "tracy")]
+                let old_bucket = allocator.bucket_of(old_address, inner_realloc.old_layout);
+                let new_address = allocator
+                    .reallocate(&inner_realloc)
+                    .map_err(|_| self.failed(&mut allocator))?;
+                #[cfg(feature = "tracy")]
+                {
+                    tracy::emit_free(old_address.as_ptr(), old_bucket);
+                    tracy::emit_alloc(
+                        new_address.as_mut_ptr(),
+                        realloc.new_layout.size(),
+                        allocator.bucket_of(new_address.cast(), inner_realloc.new_layout),
+                    );
+                }
+                self.counters
+                    .record_reallocation(realloc.old_layout.size(), realloc.new_layout.size());
+                let unmap_failures = self.sync_mapped(&mut allocator);
+                #[cfg(feature = "layout-tracking")]
+                self.counters
+                    .set_layout_mismatches(allocator.layout_mismatches());
+                (old_address, new_address, unmap_failures)
+            }
+            Err(_) => return Err(AllocError),
+        };
+
+        unmap_failures.report();
+
+        if let Some(canary) = self.canary {
+            new_address = canary.protect(new_address, realloc.new_layout.size());
+        }
+
+        #[cfg(feature = "leak-tracking")]
+        self.leaks
+            .retrack(old_address, new_address.cast(), realloc.new_layout.size());
+        #[cfg(not(feature = "leak-tracking"))]
+        let _ = old_address;
+
+        Ok(new_address)
+    }
+}
+
+#[cfg(feature = "leak-check")]
+impl<const N: usize, P> Drop for InternalAllocator<N, P> {
+    fn drop(&mut self) {
+        // Panicking again while unwinding would abort the process.
+        if std::thread::panicking() {
+            return;
+        }
+
+        let sizes: Vec<usize> = self
+            .buckets
+            .iter()
+            .chain(std::iter::once(&self.dyn_bucket))
+            .flat_map(|bucket| unsafe { bucket.live_block_sizes() })
+            .chain(std::iter::repeat_n(
+                self.slab.slot_size(),
+                self.slab.live_slots(),
+            ))
+            .collect();
+
+        if !sizes.is_empty() {
+            panic!(
+                "allocator dropped with {} live allocations, block sizes: {sizes:?}",
+                sizes.len()
+            );
+        }
+    }
+}
+
+impl Default for Rulloc {
+    fn default() -> Self {
+        Rulloc::with_default_config()
+    }
+}
+
+unsafe impl<const N: usize, P: FitPolicy> Allocator for Rulloc<N, P> {
+    fn allocate(&self, layout: Layout) -> AllocResult {
+        self.allocate_in(Placement::BySize, layout, false)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> AllocResult {
+        self.allocate_in(Placement::BySize, layout, true)
+    }
+
+    unsafe fn deallocate(&self, address: NonNull<u8>, layout: Layout) {
+        if Self::is_dangling(address, layout) {
+            return;
+        }
+
+        #[cfg(feature = "timing")]
+        let start = std::time::Instant::now();
+
+        let inner_layout = match self.canary {
+            Some(canary) => canary.padded(layout).unwrap_unchecked(),
+            None => layout,
+        };
+
+        let mut owned = true;
+        let mut resolved = address;
+        let mut intact = true;
+        let mut misaligned = None;
+        let mut unmap_failures = UnmapFailures::new();
+
+        // Pointers that don't belong to any region are rejected before
+        // reading their header or canary, which could be anywhere. In debug
+        // builds that's a bug worth a panic, in release builds the pointer is
+        // ignored, freeing it is impossible anyway. Interior pointers are
+        // resolved next, so that the canary, the leak tracker and the
+        // profiler see the address that was actually allocated. Deallocating
+        // with another alignment would read the header from the wrong place,
+        // so in debug builds the block is located by address first. Broken
+        // canaries and alignment mismatches are reported once the lock is
+        // released.
+        if let Ok(mut allocator) = self.allocator.lock() {
+            owned = allocator.owns(address, inner_layout);
+            if owned {
+                resolved = allocator.resolve(address, inner_layout);
+                intact = self
+                    .canary
+                    .is_none_or(|canary| canary.is_intact(resolved, layout.size()));
+                misaligned = allocator.misaligned(resolved, inner_layout);
+            }
+            if owned && intact && misaligned.is_none() {
+                #[cfg(feature = "tracy")]
+                tracy::emit_free(
+                    resolved.as_ptr(),
+                    allocator.bucket_of(resolved, inner_layout),
+                );
+                allocator.deallocate(resolved, inner_layout);
+                self.counters.record_deallocation(layout.size());
+                unmap_failures = self.sync_mapped(&mut allocator);
+                #[cfg(feature = "layout-tracking")]
+                self.counters
+                    .set_layout_mismatches(allocator.layout_mismatches());
+            }
+        }
+
+        if !Self::accept_owned(address, owned) {
+            return;
+        }
+
+        if !intact {
+            Canary::overflow(resolved, layout.size());
+        }
+
+        unmap_failures.report();
+
+        if let Some(allocated) = misaligned {
+            Self::alignment_mismatch(address, inner_layout.align(), allocated);
+        }
+
+        #[cfg(feature = "timing")]
+        {
+            self.latencies.record_since(start);
+            self.windows.record_deallocation();
+        }
+
+        #[cfg(feature = "leak-tracking")]
+        self.leaks.untrack(resolved);
+    }
+
+    unsafe fn shrink(
+        &self,
+        address: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> AllocResult {
+        self.reallocate(&Realloc::shrink(address, old_layout, new_layout))
+    }
+
+    unsafe fn grow(
+        &self,
+        address: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> AllocResult {
+        if Self::is_dangling(address, old_layout) {
+            return self.allocate(new_layout);
+        }
+
+        self.reallocate(&Realloc::grow(address, old_layout, new_layout))
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        address: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> AllocResult {
+        let new_address = self.grow(address, old_layout, new_layout)?;
+        let zero_from = new_address
+            .as_mut_ptr()
+            .map_addr(|addr| addr + old_layout.size());
+        zero_from.write_bytes(0, new_layout.size() - old_layout.size());
+
+        Ok(new_address)
+    }
+}
+
+unsafe impl GlobalAlloc for Rulloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.allocate(layout) {
+            Ok(address) => address.cast().as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match self.allocate_zeroed(layout) {
+            Ok(address) => address.cast().as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Passing null or misaligned pointers is a bug in the caller, but we
+        // don't want it to become silent UB. Checking null is cheap enough
+        // to do always, alignment is only checked in debug builds.
+        debug_assert!(!ptr.is_null(), "dealloc called with a null pointer");
+        debug_assert!(
+            ptr.addr() % layout.align() == 0,
+            "dealloc called with pointer {ptr:?} not aligned to {}",
+            layout.align()
+        );
+
+        if let Some(address) = NonNull::new(ptr) {
+            self.deallocate(address, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, address: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align(new_size, old_layout.align()).unwrap();
+        let address = NonNull::new_unchecked(address);
+
+        let result = if new_size <= old_layout.size() {
+            self.shrink(address, old_layout, new_layout)
+        } else {
+            self.grow(address, old_layout, new_layout)
+        };
+
+        match result {
+            Ok(new_address) => new_address.as_mut_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync,
+        thread::{self, ThreadId},
+    };
+
+    use super::*;
+    use crate::{
+        block::BLOCK_HEADER_SIZE,
+        bucket::{CACHE_LINE_SIZE, POOL_REGION_BLOCKS},
+        platform::PAGE_SIZE,
+        region::REGION_HEADER_SIZE,
+        stats::BucketStats,
+    };
+
+    #[test]
+    fn internal_allocator_wrapper() {
+        let allocator = Rulloc::with_default_config();
+        unsafe {
+            let layout1 = Layout::array::<u8>(8).unwrap();
+            let mut addr1 = allocator.allocate(layout1).unwrap();
+
+            addr1.as_mut().fill(69);
+
+            let layout2 = Layout::array::<u8>(PAGE_SIZE * 2).unwrap();
+            let mut addr2 = allocator.allocate(layout2).unwrap();
+
+            addr2.as_mut().fill(42);
+
+            for value in addr1.as_ref() {
+                assert_eq!(value, &69);
+            }
+
+            allocator.deallocate(addr1.cast(), layout1);
+
+            for value in addr2.as_ref() {
+                assert_eq!(value, &42);
+            }
+
+            allocator.deallocate(addr2.cast(), layout2);
+        }
+    }
+
+    #[test]
+    fn region_count() {
+        let sizes = [8, 16, 24];
+        let allocator = Rulloc::<3>::with_bucket_sizes(sizes);
+
+        let verify_number_of_regions_per_bucket = |expected: [usize; 3]| {
+            for (i, regions) in expected.into_iter().enumerate() {
+                assert_eq!(allocator.region_count(i), regions);
+            }
+        };
+
+        let layouts = sizes.map(|size| Layout::array::<u8>(size).unwrap());
+        let addresses = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        verify_number_of_regions_per_bucket([1, 1, 1]);
+        assert_eq!(allocator.dyn_region_count(), 0);
+
+        let mut expected = [1, 1, 1];
+        for i in 0..sizes.len() {
+            unsafe { allocator.deallocate(addresses[i].cast(), layouts[i]) };
+            expected[i] = 0;
+            verify_number_of_regions_per_bucket(expected);
+        }
+
+        let layout = Layout::array::<u8>(sizes[2] + 128).unwrap();
+        let address = allocator.allocate(layout).unwrap();
+        verify_number_of_regions_per_bucket([0, 0, 0]);
+        assert_eq!(allocator.dyn_region_count(), 1);
+        assert_eq!(allocator.region_count(3), 1);
+
+        unsafe { allocator.deallocate(address.cast(), layout) };
+        assert_eq!(allocator.dyn_region_count(), 0);
+    }
+
+    #[cfg(all(unix, not(miri)))]
+    #[test]
+    fn protect_faults_on_write() {
+        use std::{os::unix::process::ExitStatusExt, process::Command};
+
+        const CHILD: &str = "RULLOC_PROTECT_CHILD";
+
+        let allocator = Rulloc::with_default_config();
+        let page_size = platform::page_size();
+        let layout = Layout::from_size_align(page_size, page_size).unwrap();
+
+        unsafe {
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            address.as_ptr().write_bytes(69, page_size);
+            allocator.protect(address, layout).unwrap();
+
+            // Reads still work.
+            assert!((0..page_size).all(|i| *address.as_ptr().add(i) == 69));
+
+            if std::env::var_os(CHILD).is_some() {
+                address.as_ptr().add(page_size / 2).write_volatile(0);
+                unreachable!("write to protected allocation didn't fault");
+            }
+
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "allocator::tests::protect_faults_on_write"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap()
+                .status;
+
+            assert!(
+                matches!(status.signal(), Some(libc::SIGSEGV | libc::SIGBUS)),
+                "child exited with {status:?}"
+            );
+
+            allocator.unprotect(address, layout).unwrap();
+            address.as_ptr().write_bytes(42, page_size);
+            allocator.deallocate(address, layout);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    /// Allocations larger than the last fixed size go to the dynamic bucket,
+    /// and deallocation finds them through the bucket index stored in the
+    /// block header, not the layout.
+    #[test]
+    fn deallocate_above_and_below_last_bucket() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024]);
+        let below = Layout::array::<u8>(1024).unwrap();
+        let above = Layout::array::<u8>(1025).unwrap();
+
+        let small = allocator.allocate(below).unwrap();
+        let large = allocator.allocate(above).unwrap();
+        assert_eq!(allocator.region_count(1), 1);
+        assert_eq!(allocator.dyn_region_count(), 1);
+
+        unsafe { allocator.deallocate(large.cast(), above) };
+        assert_eq!(allocator.region_count(1), 1);
+        assert_eq!(allocator.dyn_region_count(), 0);
+
+        unsafe { allocator.deallocate(small.cast(), below) };
+        assert_eq!(allocator.region_count(1), 0);
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn buckets() {
+        unsafe {
+            let sizes = [8, 16, 24];
+            let mut allocator = InternalAllocator::<3>::with_bucket_sizes(sizes);
+
+            macro_rules! verify_number_of_regions_per_bucket {
+                ($expected:expr) => {
+                    for i in 0..sizes.len() {
+                        assert_eq!(allocator.buckets[i].regions().len(), $expected[i]);
+                    }
+                };
+            }
+
+            let layout1 = Layout::array::<u8>(sizes[0]).unwrap();
+            let addr1 = allocator.allocate(layout1).unwrap().cast();
+            verify_number_of_regions_per_bucket!([1, 0, 0]);
+
+            let layout2 = Layout::array::<u8>(sizes[1]).unwrap();
+            let addr2 = allocator.allocate(layout2).unwrap().cast();
+            verify_number_of_regions_per_bucket!([1, 1, 0]);
+
+            let layout3 = Layout::array::<u8>(sizes[2]).unwrap();
+            let addr3 = allocator.allocate(layout3).unwrap().cast();
+            verify_number_of_regions_per_bucket!([1, 1, 1]);
+
+            allocator.deallocate(addr1, layout1);
+            verify_number_of_regions_per_bucket!([0, 1, 1]);
+
+            allocator.deallocate(addr2, layout2);
+            verify_number_of_regions_per_bucket!([0, 0, 1]);
+
+            allocator.deallocate(addr3, layout3);
+            verify_number_of_regions_per_bucket!([0, 0, 0]);
+
+            let layout4 = Layout::array::<u8>(sizes[2] + 128).unwrap();
+            let addr4 = allocator.allocate(layout4).unwrap().cast();
+            verify_number_of_regions_per_bucket!([0, 0, 0]);
+            assert_eq!(allocator.dyn_bucket.regions().len(), 1);
+
+            allocator.deallocate(addr4, layout4);
+            assert_eq!(allocator.dyn_bucket.regions().len(), 0);
+
+            // Now let's try some reallocs
+            let mut realloc_addr = allocator.allocate(layout1).unwrap();
+            let corruption_check = 213;
+            realloc_addr.as_mut().fill(corruption_check);
+
+            realloc_addr = allocator
+                .reallocate(&Realloc::grow(realloc_addr.cast(), layout1, layout2))
+                .unwrap();
+            verify_number_of_regions_per_bucket!([0, 1, 0]);
+
+            realloc_addr = allocator
+                .reallocate(&Realloc::grow(realloc_addr.cast(), layout2, layout3))
+                .unwrap();
+            verify_number_of_regions_per_bucket!([0, 0, 1]);
+
+            for value in &realloc_addr.as_ref()[..layout1.size()] {
+                assert_eq!(*value, corruption_check);
+            }
+
+            allocator.deallocate(realloc_addr.cast(), layout3);
+        }
+    }
+
+    #[test]
+    fn grow_past_largest_bucket_migrates_to_dyn_bucket() {
+        unsafe {
+            let mut allocator = InternalAllocator::<1>::with_bucket_sizes([128]);
+            let old_layout = Layout::array::<u8>(64).unwrap();
+            let new_layout = Layout::array::<u8>(256).unwrap();
+
+            // The rest of the region is free, so growing in place would be
+            // possible if we didn't care about bucket sizes.
+            let address = allocator.allocate(old_layout).unwrap();
+            address.as_mut_ptr().write_bytes(69, old_layout.size());
+            assert_eq!(allocator.buckets[0].regions().len(), 1);
+            assert_eq!(allocator.dyn_bucket.regions().len(), 0);
+
+            let new_address = allocator
+                .reallocate(&Realloc::grow(address.cast(), old_layout, new_layout))
+                .unwrap();
+
+            assert_ne!(address.cast::<u8>(), new_address.cast::<u8>());
+            assert_eq!(allocator.buckets[0].regions().len(), 0);
+            assert_eq!(allocator.dyn_bucket.regions().len(), 1);
+
+            let content = std::slice::from_raw_parts(new_address.as_mut_ptr(), old_layout.size());
+            assert!(content.iter().all(|byte| *byte == 69));
+
+            allocator.deallocate(new_address.cast(), new_layout);
+            assert_eq!(allocator.dyn_bucket.regions().len(), 0);
+        }
+    }
+
+    #[test]
+    fn dyn_bucket_only() {
+        unsafe {
+            let mut allocator = InternalAllocator::<0>::with_bucket_sizes([]);
+
+            // Small and large sizes should all go to the dynamic bucket.
+            let layouts =
+                [1, 8, 128, 1024, PAGE_SIZE * 2].map(|size| Layout::array::<u8>(size).unwrap());
+
+            for layout in layouts {
+                assert_eq!(allocator.bucket_index_of(layout), 0);
+            }
+
+            let mut addrs = layouts.map(|layout| allocator.allocate(layout).unwrap());
+            assert!(allocator.dyn_bucket.regions().len() > 0);
+
+            for (i, addr) in addrs.iter_mut().enumerate() {
+                addr.as_mut().fill(i as u8);
+            }
+
+            // Reallocations can't move pointers to other buckets.
+            let grown = Layout::array::<u8>(PAGE_SIZE * 3).unwrap();
+            addrs[0] = allocator
+                .reallocate(&Realloc::grow(addrs[0].cast(), layouts[0], grown))
+                .unwrap();
+
+            for value in &addrs[0].as_ref()[..layouts[0].size()] {
+                assert_eq!(*value, 0);
+            }
+
+            allocator.deallocate(addrs[0].cast(), grown);
+            for (i, addr) in addrs.iter().enumerate().skip(1) {
+                for value in &addr.as_ref()[..layouts[i].size()] {
+                    assert_eq!(*value, i as u8);
+                }
+                allocator.deallocate(addr.cast(), layouts[i]);
+            }
+
+            assert_eq!(allocator.dyn_bucket.regions().len(), 0);
+        }
+    }
+
+    #[test]
+    fn concurrent_reads_with_read_write_lock() {
+        let allocator = Rulloc::with_default_config().with_read_write_lock();
+        let done = sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !done.load(sync::atomic::Ordering::Relaxed) {
+                        allocator.free_bytes();
+                    }
+                });
+            }
+
+            scope.spawn(|| unsafe {
+                let layout = Layout::array::<u8>(256).unwrap();
+                for _ in 0..100 {
+                    let address = allocator.allocate(layout).unwrap();
+                    assert!(allocator.free_bytes() > 0);
+                    allocator.deallocate(address.cast(), layout);
+                }
+                done.store(true, sync::atomic::Ordering::Relaxed);
+            });
+        });
+
+        verify_no_overlap(&allocator);
+        assert_eq!(allocator.free_bytes(), 0);
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "dealloc called with a null pointer")]
+    fn global_dealloc_null_pointer() {
+        let allocator = Rulloc::with_default_config();
+        unsafe { GlobalAlloc::dealloc(&allocator, ptr::null_mut(), Layout::new::<u64>()) };
+    }
+
+    #[test]
+    fn available_in_bucket() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::array::<u8>(128).unwrap();
+
+        unsafe {
+            let mut addresses: Vec<_> = (0..6)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+
+            // Consume whatever is left in the region.
+            while allocator.available_in_bucket(layout) > 0 {
+                addresses.push(allocator.allocate(layout).unwrap());
+            }
+
+            // Deallocate non-adjacent blocks so that they are not merged.
+            for i in [4, 2, 0] {
+                allocator.deallocate(addresses.remove(i).cast(), layout);
+            }
+            assert_eq!(allocator.available_in_bucket(layout), 3);
+
+            for _ in 0..3 {
+                addresses.push(allocator.allocate(layout).unwrap());
+            }
+            assert_eq!(allocator.available_in_bucket(layout), 0);
+
+            for address in addresses {
+                allocator.deallocate(address.cast(), layout);
+            }
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[cfg(feature = "layout-tracking")]
+    #[test]
+    fn layout_mismatches() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::from_size_align(64, 32).unwrap();
+        let mismatched = Layout::from_size_align(48, 32).unwrap();
+
+        unsafe {
+            let first = allocator.allocate(layout).unwrap().cast();
+            let second = allocator.allocate(layout).unwrap().cast();
+
+            allocator.deallocate(first, layout);
+            assert_eq!(allocator.poll_stats().layout_mismatches, 0);
+
+            // Only the size differs, so the header can still be recovered
+            // and the mismatch doesn't corrupt anything. Different alignments
+            // panic in debug builds, see `deallocate_with_other_alignment`.
+            allocator.deallocate(second, mismatched);
+            assert_eq!(allocator.poll_stats().layout_mismatches, 1);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[cfg(feature = "layout-tracking")]
+    #[test]
+    fn bytes_used() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::array::<u8>(100).unwrap();
+        let grown = Layout::array::<u8>(120).unwrap();
+        let large = Layout::array::<u8>(1000).unwrap();
+        let used = || allocator.full_stats().buckets[0].bytes_used;
+
+        unsafe {
+            let first = allocator.allocate(layout).unwrap();
+            assert!(first.len() > layout.size());
+            assert_eq!(used(), 100);
+
+            let second = allocator.allocate(layout).unwrap();
+            assert_eq!(used(), 200);
+
+            // Grows in place, the block doesn't change but the request does.
+            let second = allocator.grow(second.cast(), layout, grown).unwrap();
+            assert_eq!(used(), 220);
+
+            // Moved to the dynamic bucket.
+            let first = allocator.grow(first.cast(), layout, large).unwrap();
+            assert_eq!(used(), 120);
+            assert_eq!(allocator.full_stats().dyn_bucket.bytes_used, 1000);
+
+            allocator.deallocate(first.cast(), large);
+            allocator.deallocate(second.cast(), grown);
+            assert_eq!(used(), 0);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn into_raw_parts() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024]);
+        let layouts = [
+            Layout::array::<u8>(16).unwrap(),
+            Layout::array::<u8>(512).unwrap(),
+            Layout::array::<u8>(4096).unwrap(),
+        ];
+
+        let addresses = layouts.map(|layout| unsafe {
+            let address = allocator.allocate(layout).unwrap();
+            address.as_mut_ptr().write_bytes(69, layout.size());
+            address
+        });
+
+        let bytes_mapped = allocator.poll_stats().bytes_mapped;
+        let regions = allocator.into_raw_parts();
+
+        // One region per bucket.
+        assert_eq!(regions.len(), 3);
+        assert_eq!(
+            regions.iter().map(|(_, length)| length).sum::<usize>(),
+            bytes_mapped
+        );
+
+        unsafe {
+            // Regions are still mapped and the contents are still there.
+            for (address, layout) in addresses.iter().zip(layouts) {
+                let content = std::slice::from_raw_parts(address.as_mut_ptr(), layout.size());
+                assert!(content.iter().all(|byte| *byte == 69));
+            }
+
+            for (address, length) in regions {
+                crate::platform::return_memory(address, length).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn windowed_stats() {
+        use std::sync::atomic::AtomicU64;
+
+        use crate::window::{Window, NUM_WINDOWS};
+
+        static NOW: AtomicU64 = AtomicU64::new(1000);
+
+        let allocator =
+            Rulloc::<1>::with_bucket_sizes([128]).with_stats_clock(|| NOW.load(Ordering::Relaxed));
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        let churn = |allocations: usize| unsafe {
+            let addresses: Vec<_> = (0..allocations)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            for address in addresses {
+                allocator.deallocate(address.cast(), layout);
+            }
+        };
+
+        churn(3);
+        NOW.store(1001, Ordering::Relaxed);
+        churn(5);
+        NOW.store(1003, Ordering::Relaxed);
+        churn(2);
+
+        let windows = allocator.windowed_stats().windows;
+        let counts = |window: &Window| (window.allocations, window.deallocations);
+        assert_eq!(counts(&windows[NUM_WINDOWS - 1]), (2, 2));
+        assert_eq!(counts(&windows[NUM_WINDOWS - 2]), (0, 0));
+        assert_eq!(counts(&windows[NUM_WINDOWS - 3]), (5, 5));
+        assert_eq!(counts(&windows[NUM_WINDOWS - 4]), (3, 3));
+
+        // Same slot as second 1003, the old counts must be discarded.
+        NOW.store(1003 + NUM_WINDOWS as u64, Ordering::Relaxed);
+        churn(1);
+
+        let windows = allocator.windowed_stats().windows;
+        assert_eq!(counts(&windows[NUM_WINDOWS - 1]), (1, 1));
+        assert_eq!(
+            windows
+                .iter()
+                .map(|window| window.allocations)
+                .sum::<usize>(),
+            1
+        );
+    }
+
+    #[cfg(feature = "timing")]
+    #[test]
+    fn latency_histogram() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        unsafe {
+            // The first allocation maps a region, the rest reuse the free
+            // list of the same region.
+            let first = allocator.allocate(layout).unwrap().cast();
+            let addresses: Vec<_> = (0..8)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            for address in addresses {
+                allocator.deallocate(address.cast(), layout);
+            }
+            allocator.deallocate(first, layout);
+        }
+
+        // 9 allocations and 9 deallocations.
+        let histogram = allocator.latency_histogram();
+        assert_eq!(histogram.total(), 18);
+
+        // Boundaries of the log scale.
+        assert_eq!(
+            LatencyHistogram::bucket_of(std::time::Duration::from_nanos(1)),
+            0
+        );
+        assert_eq!(
+            LatencyHistogram::bucket_of(std::time::Duration::from_nanos(1000)),
+            9
+        );
+        assert_eq!(
+            LatencyHistogram::bucket_of(std::time::Duration::from_secs(60)),
+            31
+        );
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn clear_bucket() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024]);
+        let small = Layout::array::<u8>(32).unwrap();
+        let medium = Layout::array::<u8>(512).unwrap();
+
+        unsafe {
+            for _ in 0..4 {
+                allocator.allocate(small).unwrap();
+            }
+            let address = allocator.allocate(medium).unwrap();
+            address.as_mut_ptr().write_bytes(69, medium.size());
+
+            let mapped_by_medium = {
+                let inner = allocator.allocator.read().unwrap();
+                assert_eq!(inner.buckets[0].regions().len(), 1);
+                inner.buckets[1].bytes_mapped()
+            };
+
+            allocator.clear_bucket(0);
+
+            {
+                let inner = allocator.allocator.read().unwrap();
+                assert_eq!(inner.buckets[0].regions().len(), 0);
+                assert_eq!(inner.buckets[0].bytes_mapped(), 0);
+                assert_eq!(inner.buckets[1].regions().len(), 1);
+            }
+            let stats = allocator.poll_stats();
+            assert_eq!(stats.bytes_mapped, mapped_by_medium);
+            assert_eq!(stats.bytes_allocated, medium.size());
+            assert_eq!(stats.live_blocks, 1);
+            assert_eq!(stats.deallocations, 4);
+            #[cfg(feature = "leak-tracking")]
+            assert!(allocator
+                .leak_report()
+                .starts_with("1 live allocations, 512 bytes"));
+
+            // Bucket 0 still works and bucket 1 contents are untouched.
+            let small_address = allocator.allocate(small).unwrap();
+            allocator.deallocate(small_address.cast(), small);
+
+            let content = std::slice::from_raw_parts(address.as_mut_ptr(), medium.size());
+            assert!(content.iter().all(|byte| *byte == 69));
+            allocator.deallocate(address.cast(), medium);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn clear_bucket_discounts_dropped_blocks() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024]).with_canary_seed(0x5EED);
+        let small = Layout::array::<u8>(16).unwrap();
+
+        unsafe {
+            let mut addresses = Vec::new();
+            for _ in 0..8 {
+                addresses.push(allocator.allocate(small).unwrap().cast::<u8>());
+            }
+
+            // Freed blocks might be retained by the caches, they are not in
+            // use anymore.
+            allocator.deallocate(addresses.pop().unwrap(), small);
+            allocator.deallocate(addresses.pop().unwrap(), small);
+
+            let grown = Layout::array::<u8>(40).unwrap();
+            allocator.grow(addresses[0], small, grown).unwrap();
+
+            assert_eq!(allocator.poll_stats().bytes_allocated, 5 * 16 + 40);
+            allocator.clear_bucket(0);
+
+            let stats = allocator.poll_stats();
+            assert_eq!(stats.bytes_allocated, 0);
+            assert_eq!(stats.live_blocks, 0);
+            assert_eq!(stats.allocations, stats.deallocations);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn thp_hint() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]).with_thp_hint(true);
+        let layout = Layout::array::<u8>(crate::region::HUGE_PAGE_THRESHOLD * 2).unwrap();
+
+        unsafe {
+            let address = allocator.allocate(layout).unwrap();
+            address.as_mut_ptr().write_bytes(69, layout.size());
+            assert!(address.as_ref().iter().all(|byte| *byte == 69));
+            allocator.deallocate(address.cast(), layout);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "stale pointer")]
+    fn stale_checked_pointer() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::array::<u8>(32).unwrap();
+
+        // Keep the region mapped so that the freed block can be reused.
+        let keep_alive = allocator.allocate(layout).unwrap();
+
+        let stale = allocator.allocate_checked(layout).unwrap();
+        unsafe { allocator.deallocate_checked(stale) };
+
+        // Same address but a different generation.
+        let reused = allocator.allocate_checked(layout).unwrap();
+        assert_eq!(reused.as_non_null(), stale.as_non_null());
+        assert_ne!(reused.generation, stale.generation);
+
+        unsafe {
+            allocator.deallocate(keep_alive.cast(), layout);
+            allocator.deallocate_checked(stale);
+        }
+    }
+
+    #[test]
+    fn allocate_pages_aligned() {
+        let allocator = Rulloc::default();
+        let align = 2 * 1024 * 1024;
+        let n = align / platform::page_size();
+
+        unsafe {
+            let pages = allocator.allocate_pages_aligned(n, align).unwrap();
+            assert_eq!(pages.len(), align);
+            assert_eq!(pages.as_mut_ptr() as usize % align, 0);
+
+            pages.as_mut_ptr().write_bytes(69, pages.len());
+
+            // Pages don't go through buckets.
+            assert_eq!(allocator.poll_stats().bytes_mapped, 0);
+
+            let calls = platform::return_memory_calls();
+            allocator.deallocate_pages_aligned(pages.cast(), n, align);
+            assert_eq!(platform::return_memory_calls() - calls, 1);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "page alignment must be a power of 2")]
+    fn allocate_pages_aligned_rejects_non_power_of_two() {
+        let allocator = Rulloc::default();
+        let _ = allocator.allocate_pages_aligned(1, 3 * platform::page_size());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "page alignment must be a power of 2")]
+    fn deallocate_pages_aligned_rejects_non_power_of_two() {
+        let allocator = Rulloc::default();
+        unsafe { allocator.deallocate_pages_aligned(NonNull::dangling(), 1, 3000) };
+    }
+
+    #[test]
+    #[should_panic(expected = "region alignment must be a power of 2")]
+    fn region_alignment_rejects_non_power_of_two() {
+        let _ = Rulloc::default().with_region_alignment(3 * 1024);
+    }
+
+    #[test]
+    fn executable_memory() {
+        let allocator = Rulloc::default();
+
+        unsafe {
+            let code = allocator.allocate_exec(16).unwrap();
+            assert_eq!(code.len(), platform::page_size());
+
+            // mov eax, 42; ret
+            #[cfg(target_arch = "x86_64")]
+            let instructions: &[u8] = &[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3];
+            #[cfg(not(target_arch = "x86_64"))]
+            let instructions: &[u8] = &[0x00; 6];
+
+            let address = code.as_mut_ptr();
+            address.copy_from_nonoverlapping(instructions.as_ptr(), instructions.len());
+
+            allocator
+                .make_executable(code.cast(), instructions.len())
+                .unwrap();
+
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            {
+                let function: extern "C" fn() -> i32 = std::mem::transmute(address);
+                assert_eq!(function(), 42);
+            }
+
+            // Patch the code.
+            allocator
+                .make_writable(code.cast(), instructions.len())
+                .unwrap();
+            address.add(1).write(0x45);
+            allocator
+                .make_executable(code.cast(), instructions.len())
+                .unwrap();
+
+            #[cfg(all(target_arch = "x86_64", not(miri)))]
+            {
+                let function: extern "C" fn() -> i32 = std::mem::transmute(address);
+                assert_eq!(function(), 0x45);
+            }
+
+            // Another region stays mapped until the allocator is dropped.
+            allocator.allocate_exec(platform::page_size() + 1).unwrap();
+            allocator.deallocate_exec(code.cast());
+        }
+    }
+
+    #[test]
+    fn bytes_overhead() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let layout = Layout::new::<u64>();
+        let count = 1000;
+
+        let addresses: Vec<_> = (0..count)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        let (regions, blocks) = {
+            let internal = allocator.allocator.read().unwrap();
+            let regions = internal.buckets[0].regions();
+            let blocks = regions
+                .iter()
+                .map(|region| unsafe { region.as_ref().num_blocks() })
+                .sum::<usize>();
+            (regions.len(), blocks)
+        };
+
+        // Headers plus the padding needed to reach the minimum block size.
+        let expected = regions * REGION_HEADER_SIZE
+            + blocks * BLOCK_HEADER_SIZE
+            + count * (MIN_BLOCK_SIZE - layout.size());
+
+        // The last block of each region might absorb a few bytes that can't
+        // hold another block, that's the only other source of overhead.
+        let overhead = allocator.bytes_overhead();
+        assert!(overhead > 0);
+        assert!(overhead >= expected);
+        assert!(overhead - expected < regions * (BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE));
+
+        let ratio = allocator.overhead_ratio();
+        assert!(ratio > 0.0 && ratio < 1.0);
+
+        for address in addresses {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+
+        assert_eq!(allocator.bytes_overhead(), 0);
+        assert_eq!(allocator.overhead_ratio(), 0.0);
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[cfg(not(miri))]
+    #[test]
+    fn drain_free_lists() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]);
+        let page_size = platform::page_size();
+        let sizes = [16, 8, 4, 2].map(|pages| pages * page_size);
+        let separator = Layout::array::<u8>(256).unwrap();
+
+        unsafe {
+            // Free blocks followed by used ones in the same bucket so that
+            // their regions are not unmapped.
+            let mut free = Vec::new();
+            let mut used = Vec::new();
+            for size in sizes {
+                let layout = Layout::array::<u8>(size).unwrap();
+                free.push((allocator.allocate(layout).unwrap(), layout));
+                used.push(allocator.allocate(separator).unwrap());
+            }
+            for (address, layout) in free.drain(..) {
+                address.as_mut_ptr().write_bytes(69, layout.size());
+                allocator.deallocate(address.cast(), layout);
+            }
+
+            let free_bytes = allocator.free_bytes();
+            let target = free_bytes - 20 * page_size;
+
+            // The largest block alone is not enough, the second one is.
+            let first_purged = allocator.drain_free_lists(target);
+            assert!(free_bytes - first_purged <= target);
+            assert!(first_purged < 24 * page_size);
+
+            // Nothing else to do.
+            assert_eq!(allocator.drain_free_lists(free_bytes), 0);
+            assert_eq!(allocator.drain_free_lists(target), 0);
+
+            // Everything that can be purged. Each block keeps its first and
+            // last page at most, and blocks purged before are not counted
+            // again.
+            let purged = allocator.drain_free_lists(0);
+            assert!(purged < 6 * page_size);
+            assert!(first_purged + purged >= (30 - 2 * sizes.len()) * page_size);
+            assert_eq!(allocator.drain_free_lists(0), 0);
+
+            // Purged blocks can be reused.
+            let layout = Layout::array::<u8>(sizes[0]).unwrap();
+            let address = allocator.allocate(layout).unwrap();
+            address.as_mut_ptr().write_bytes(42, layout.size());
+            assert!(address.as_ref().iter().all(|byte| *byte == 42));
+            allocator.deallocate(address.cast(), layout);
+
+            for address in used {
+                allocator.deallocate(address.cast(), separator);
+            }
+        }
+
+        validate_free_lists(&allocator);
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn const_bucket_sizes() {
+        static ALLOCATOR: Rulloc<3> = Rulloc::<3>::with_bucket_sizes([8, 64, 512]);
+
+        let layout = Layout::array::<u8>(32).unwrap();
+        unsafe {
+            let address = ALLOCATOR.allocate(layout).unwrap();
+            ALLOCATOR.deallocate(address.cast(), layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "bucket sizes must be strictly ascending")]
+    fn unsorted_bucket_sizes() {
+        let sizes = std::hint::black_box([64, 64, 8]);
+        Rulloc::<3>::with_bucket_sizes(sizes);
+    }
+
+    #[test]
+    fn shrink_page_aligned_allocation() {
+        for tail_cache in [false, true] {
+            let allocator =
+                Rulloc::<3>::with_bucket_sizes([64, 128, 256]).with_shrink_tail_cache(tail_cache);
+            let page_size = platform::page_size();
+            let old_layout = Layout::from_size_align(3 * page_size, page_size).unwrap();
+            // Still larger than the last bucket, so it's shrunk in place.
+            let new_layout = Layout::from_size_align(512, page_size).unwrap();
+
+            unsafe {
+                let mut address = allocator.allocate(old_layout).unwrap();
+                for (i, byte) in address.as_mut().iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+
+                let shrunk = allocator
+                    .shrink(address.cast(), old_layout, new_layout)
+                    .unwrap();
+                assert_eq!(shrunk.as_mut_ptr(), address.as_mut_ptr());
+                assert_eq!(shrunk.as_mut_ptr() as usize % page_size, 0);
+                for (i, byte) in shrunk.as_ref()[..new_layout.size()].iter().enumerate() {
+                    assert_eq!(*byte, i as u8);
+                }
+
+                // The back pointer in the padding must still lead to the
+                // block header, otherwise this would corrupt the bucket.
+                allocator.deallocate(shrunk.cast(), new_layout);
+            }
+
+            validate_free_lists(&allocator);
+            verify_buckets_are_empty(allocator);
+        }
+    }
+
+    #[test]
+    fn allocate_in_bucket() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+        let layout = Layout::array::<u8>(16).unwrap();
+
+        let forced = allocator.allocate_in_bucket(2, layout).unwrap();
+        let normal = allocator.allocate(layout).unwrap();
+
+        {
+            let internal = allocator.allocator.lock().unwrap();
+            assert_eq!(internal.buckets[0].regions().len(), 1);
+            assert_eq!(internal.buckets[1].regions().len(), 0);
+            assert_eq!(internal.buckets[2].regions().len(), 1);
+            unsafe {
+                let block = Header::<Block>::from_allocated_pointer(forced.cast(), layout);
+                assert_eq!(
+                    block.as_ref().data.region,
+                    internal.buckets[2].regions().first().unwrap()
+                );
+            }
+        }
+
+        unsafe {
+            allocator.deallocate(forced.cast(), layout);
+            allocator.deallocate(normal.cast(), layout);
+        }
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn adaptive_sizes_see_every_allocation() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_adaptive_sizes(16);
+        let layout = Layout::array::<u8>(24).unwrap();
+
+        let mut addresses = allocator.allocate_batch(layout, 8).unwrap();
+        assert_eq!(allocator.recommended_sizes(), None);
+        for _ in 0..8 {
+            addresses.push(allocator.allocate_in_bucket(2, layout).unwrap().cast());
+        }
+        assert_eq!(allocator.recommended_sizes(), Some([32, 64, 128]));
+
+        for address in addresses {
+            unsafe { allocator.deallocate(address, layout) };
+        }
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't hold")]
+    fn allocate_in_bucket_too_small() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+        let _ = allocator.allocate_in_bucket(0, Layout::array::<u8>(128).unwrap());
+    }
+
+    #[test]
+    fn sensitive_bucket_zeroes_on_free() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        allocator.mark_bucket_sensitive(1).unwrap();
+
+        let small = Layout::array::<u8>(64).unwrap();
+        let medium = Layout::array::<u8>(128).unwrap();
+
+        unsafe {
+            let mut addresses = Vec::new();
+            for layout in [small, medium] {
+                // Keep a second block allocated so the region is not unmapped.
+                let freed = allocator.allocate(layout).unwrap();
+                let kept = allocator.allocate(layout).unwrap();
+                freed.cast::<u8>().as_ptr().write_bytes(0xAB, layout.size());
+                allocator.deallocate(freed.cast(), layout);
+                addresses.push((freed, kept, layout));
+            }
+
+            // The free list node is written over the first bytes of the block.
+            let contents = |address: NonNull<[u8]>, layout: Layout| {
+                &address.as_ref()[MIN_BLOCK_SIZE..layout.size()]
+            };
+
+            let (freed, _, layout) = addresses[0];
+            assert!(contents(freed, layout).iter().all(|byte| *byte == 0xAB));
+
+            let (freed, _, layout) = addresses[1];
+            assert!(contents(freed, layout).iter().all(|byte| *byte == 0));
+
+            for (_, kept, layout) in addresses {
+                allocator.deallocate(kept.cast(), layout);
+            }
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn sensitive_bucket_zeroes_on_shrink() {
+        let big = Layout::array::<u8>(1024).unwrap();
+        let small = Layout::array::<u8>(100).unwrap();
+
+        for tail_cache in [false, true] {
+            let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024])
+                .with_shrink_policy(ShrinkPolicy::Stable)
+                .with_shrink_tail_cache(tail_cache);
+            allocator.mark_bucket_sensitive(1).unwrap();
+
+            unsafe {
+                let address = allocator.allocate(big).unwrap().cast::<u8>();
+                address.as_ptr().write_bytes(0xAB, big.size());
+
+                let shrunk = allocator.shrink(address, big, small).unwrap();
+                assert_eq!(shrunk.cast(), address);
+
+                // The header and the free list node of the block split off
+                // the end are written right after the shrunk block.
+                let contents = std::slice::from_raw_parts(address.as_ptr(), big.size());
+                let tail = shrunk.len() + BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE;
+                assert!(contents[..small.size()].iter().all(|byte| *byte == 0xAB));
+                assert!(contents[small.size()..shrunk.len()]
+                    .iter()
+                    .all(|byte| *byte == 0));
+                assert!(contents[tail..].iter().all(|byte| *byte == 0));
+
+                allocator.deallocate(address, small);
+            }
+
+            verify_buckets_are_empty(allocator);
+        }
+    }
+
+    #[test]
+    fn freeze_config() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        assert!(!allocator.is_config_frozen());
+        assert_eq!(allocator.mark_bucket_sensitive(0), Ok(()));
+
+        allocator.freeze_config();
+        assert!(allocator.is_config_frozen());
+        assert_eq!(allocator.mark_bucket_sensitive(1), Err(ConfigFrozen));
+
+        let allocator = allocator.allocator.into_inner().unwrap();
+        assert!(allocator.bucket(0).is_sensitive());
+        assert!(!allocator.bucket(1).is_sensitive());
+    }
+
+    #[test]
+    fn region_for() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let layouts = [64, 128, 4096].map(|size| Layout::array::<u8>(size).unwrap());
+        let addresses = layouts.map(|layout| allocator.allocate(layout).unwrap());
+
+        for (bucket, address) in addresses.iter().enumerate() {
+            let last_byte = unsafe { address.as_mut_ptr().add(address.len() - 1) };
+
+            for address in [address.cast(), NonNull::new(last_byte).unwrap()] {
+                let region = allocator.region_for(address).unwrap();
+                assert_eq!(region.bucket, bucket);
+                assert_eq!(region.num_blocks, 2);
+                assert!(region.address <= address);
+                assert!(address.as_ptr().addr() < region.address.as_ptr().addr() + region.length);
+            }
+        }
+
+        let mut unowned = 0u8;
+        assert_eq!(allocator.region_for(NonNull::from(&mut unowned)), None);
+
+        unsafe {
+            for (address, layout) in addresses.into_iter().zip(layouts) {
+                allocator.deallocate(address.cast(), layout);
+            }
+        }
+
+        assert_eq!(allocator.region_for(NonNull::from(&mut unowned)), None);
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn shrink_regions() {
+        let page_size = platform::page_size();
+        let large = Layout::array::<u8>(16 * page_size).unwrap();
+        let small = Layout::array::<u8>(256).unwrap();
+
+        for guard in [0, page_size] {
+            let allocator = Rulloc::<0>::with_bucket_sizes([]).with_region_guard_bytes(guard);
+
+            unsafe {
+                // Large region with two small blocks at the beginning.
+                let first = allocator.allocate(large).unwrap();
+                let first = allocator.shrink(first.cast(), large, small).unwrap();
+                let second = allocator.allocate(small).unwrap();
+                first.as_mut_ptr().write_bytes(1, small.size());
+                second.as_mut_ptr().write_bytes(2, small.size());
+
+                let length = allocator.region_for(first.cast()).unwrap().length;
+                let bytes_mapped = allocator.total_mapped_bytes();
+
+                let unmapped = allocator.shrink_regions();
+                assert!(unmapped >= 14 * page_size);
+                assert_eq!(allocator.total_mapped_bytes(), bytes_mapped - unmapped);
+
+                let region = allocator.region_for(second.cast()).unwrap();
+                assert_eq!(region.length, length - unmapped);
+                assert_eq!(region.num_blocks, 3);
+
+                // Nothing left to unmap.
+                assert_eq!(allocator.shrink_regions(), 0);
+
+                assert!(first.as_ref().iter().all(|byte| *byte == 1));
+                assert!(second.as_ref().iter().all(|byte| *byte == 2));
+
+                // Remaining free block can still be used.
+                let third = allocator.allocate(small).unwrap();
+                let third_region = allocator.region_for(third.cast()).unwrap();
+                assert_eq!(third_region.address, region.address);
+                assert_eq!(third_region.length, region.length);
+                third.as_mut_ptr().write_bytes(3, small.size());
+
+                for address in [first, second, third] {
+                    allocator.deallocate(address.cast(), small);
+                }
+            }
+
+            verify_buckets_are_empty(allocator);
+        }
+    }
+
+    #[cfg(feature = "leak-check")]
+    #[test]
+    #[should_panic(expected = "allocator dropped with 2 live allocations, block sizes: [64, 1024]")]
+    fn leak_check() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let freed = Layout::array::<u8>(32).unwrap();
+
+        allocator
+            .allocate(Layout::array::<u8>(64).unwrap())
+            .unwrap();
+        allocator
+            .allocate(Layout::array::<u8>(1024).unwrap())
+            .unwrap();
+        let address = allocator.allocate(freed).unwrap();
+        unsafe { allocator.deallocate(address.cast(), freed) };
+
+        drop(allocator);
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn fail_next() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        allocator.set_fail_next(1);
+        assert!(allocator.allocate(layout).is_err());
+        assert_eq!(allocator.total_regions(), 0);
+        assert_eq!(allocator.last_error(), None);
+
+        let address = allocator.allocate(layout).unwrap();
+        unsafe { allocator.deallocate(address.cast(), layout) };
+
+        allocator.set_fail_next(3);
+        assert!((0..3).all(|_| allocator.allocate(layout).is_err()));
+        allocator.set_fail_next(2);
+        allocator.set_fail_next(0);
+        let address = allocator.allocate(layout).unwrap();
+        unsafe { allocator.deallocate(address.cast(), layout) };
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn pool_prefers_regions_in_use() {
+        let allocator = Rulloc::<2>::pool_mode([64, 128]);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        // First region full and one block in the second. Regions are
+        // rounded up to pages, so they might have more blocks than
+        // `POOL_REGION_BLOCKS`.
+        let mut addresses = vec![allocator.allocate(layout).unwrap().cast::<u8>()];
+        let first_region = allocator.region_for(addresses[0]).unwrap();
+        assert!(first_region.num_blocks >= POOL_REGION_BLOCKS);
+        for _ in 1..first_region.num_blocks {
+            addresses.push(allocator.allocate(layout).unwrap().cast());
+        }
+        let last = allocator.allocate(layout).unwrap().cast::<u8>();
+        let second_region = allocator.region_for(last).unwrap();
+        assert_ne!(first_region.address, second_region.address);
+
+        unsafe {
+            // The first region ends up partially used and the second one
+            // empty, even though its block is the last one freed.
+            for address in addresses.drain(..first_region.num_blocks / 2) {
+                allocator.deallocate(address, layout);
+            }
+            allocator.deallocate(last, layout);
+
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            assert_eq!(allocator.region_for(address), Some(first_region));
+            addresses.push(address);
+
+            // The empty region can be unmapped.
+            assert!(allocator.shrink_bucket(0, 0) > 0);
+            assert_eq!(allocator.full_stats().buckets[0].regions, 1);
+
+            for address in addresses {
+                allocator.deallocate(address, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_bucket() {
+        let allocator = Rulloc::<2>::pool_mode([64, 128]);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        // Enough blocks for a few pool regions.
+        let addresses: Vec<_> = (0..4 * POOL_REGION_BLOCKS)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        let keep = allocator.allocate(layout).unwrap();
+        let other = allocator
+            .allocate(Layout::array::<u8>(128).unwrap())
+            .unwrap();
+
+        for address in addresses {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+
+        // Pools don't return empty regions on their own.
+        let stats = allocator.full_stats();
+        let regions = stats.buckets[0].regions;
+        let cap = stats.buckets[0].free_bytes / 2;
+        assert!(regions > 2);
+
+        let unmapped = allocator.shrink_bucket(0, cap);
+        let stats = allocator.full_stats();
+        assert!(unmapped > 0);
+        assert!(stats.buckets[0].free_bytes <= cap);
+        assert!(stats.buckets[0].regions < regions);
+
+        // Nothing to do if there's enough room.
+        assert_eq!(allocator.shrink_bucket(0, cap), 0);
+        // Other buckets are not affected.
+        assert_eq!(stats.buckets[1].regions, 1);
+
+        unsafe {
+            keep.as_mut_ptr().write_bytes(1, layout.size());
+            let address = allocator.allocate(layout).unwrap();
+            allocator.deallocate(address.cast(), layout);
+            allocator.deallocate(keep.cast(), layout);
+            allocator.deallocate(other.cast(), Layout::array::<u8>(128).unwrap());
+        }
+    }
+
+    #[test]
+    fn with_bucket_sizes_from_slice() {
+        let sizes: Vec<usize> = (1..=4).map(|i| i * 64).collect();
+        let allocator = Rulloc::<4>::with_bucket_sizes_from_slice(&sizes).unwrap();
+        assert_eq!(
+            allocator.allocator.read().unwrap().sizes,
+            [64, 128, 192, 256]
+        );
+
+        assert!(Rulloc::<3>::with_bucket_sizes_from_slice(&sizes).is_err());
+        assert!(Rulloc::<5>::with_bucket_sizes_from_slice(&sizes).is_err());
+    }
+
+    #[test]
+    fn slab() {
+        let layout = Layout::new::<u64>();
+        let count = 4096;
+
+        let fill = |allocator: &Rulloc| unsafe {
+            let addresses: Vec<NonNull<u64>> = (0..count)
+                .map(|i| {
+                    let address = allocator.allocate(layout).unwrap().cast::<u64>();
+                    address.as_ptr().write(i as u64);
+                    address
+                })
+                .collect();
+            let mapped = allocator.total_mapped_bytes();
+            for (i, address) in addresses.iter().enumerate() {
+                assert_eq!(address.as_ptr().read(), i as u64);
+            }
+            (addresses, mapped)
+        };
+
+        let headers = Rulloc::default();
+        let (addresses, with_headers) = fill(&headers);
+        for address in addresses {
+            unsafe { headers.deallocate(address.cast(), layout) };
+        }
+
+        let slab = Rulloc::default().with_slab(8);
+        let (mut addresses, with_slab) = fill(&slab);
+
+        // One header per block is several times the size of the block, a bit
+        // less in release builds which have no generation counters.
+        assert!(
+            with_slab * 3 < with_headers,
+            "{with_slab} vs {with_headers}"
+        );
+
+        unsafe {
+            // Growing past the slot moves the contents to the first bucket.
+            let large = Layout::new::<[u64; 4]>();
+            let grown = slab.grow(addresses[0].cast(), layout, large).unwrap();
+            assert_eq!(grown.cast::<u64>().as_ptr().read(), 0);
+            slab.deallocate(grown.cast(), large);
+            addresses.remove(0);
+
+            for address in addresses {
+                slab.deallocate(address.cast(), layout);
+            }
+        }
+
+        assert_eq!(slab.total_mapped_bytes(), 0);
+        assert_eq!(slab.total_regions(), 0);
+    }
+
+    #[test]
+    fn zero_size_layouts_with_and_without_slab() {
+        for allocator in [Rulloc::default(), Rulloc::default().with_slab(8)] {
+            for align in [1, 2, 4, 8] {
+                let layout = Layout::from_size_align(0, align).unwrap();
+                let address = allocator.allocate(layout).unwrap();
+                assert_eq!(address.cast::<u8>().as_ptr().addr() % align, 0);
+
+                // Still a valid allocation that can grow.
+                let larger = Layout::from_size_align(16, align).unwrap();
+                let grown = unsafe { allocator.grow(address.cast(), layout, larger) }.unwrap();
+                unsafe { allocator.deallocate(grown.cast(), larger) };
+
+                let address = allocator.allocate(layout).unwrap();
+                unsafe { allocator.deallocate(address.cast(), layout) };
+            }
+
+            assert_eq!(allocator.total_regions(), 0);
+            assert_eq!(allocator.total_mapped_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn sensitive_slab() {
+        let layout = Layout::new::<u64>();
+
+        // Slab regions are larger than a page, so they go over the limit.
+        let limited = Rulloc::default()
+            .with_slab(8)
+            .with_memory_limit(platform::page_size());
+        assert!(limited.allocate(layout).is_err());
+        assert_eq!(limited.last_error(), Some(AllocFailure::LimitExceeded));
+
+        let slab = Rulloc::default().with_slab(8);
+        slab.mark_bucket_sensitive(0).unwrap();
+
+        unsafe {
+            let kept = slab.allocate(layout).unwrap().cast::<u64>();
+            let freed = slab.allocate(layout).unwrap().cast::<u64>();
+            freed.as_ptr().write(u64::MAX);
+            slab.deallocate(freed.cast(), layout);
+
+            // The region is still mapped because of `kept`.
+            assert_eq!(freed.as_ptr().read(), 0);
+            slab.deallocate(kept.cast(), layout);
+        }
+    }
+
+    #[test]
+    fn allocate_array() {
+        let allocator = Rulloc::default();
+        let array = allocator.allocate_array::<u64>(32).unwrap();
+        assert_eq!(array.len(), 32);
+        assert_eq!(
+            array.cast::<u64>().as_ptr() as usize % std::mem::align_of::<u64>(),
+            0
+        );
+
+        unsafe {
+            for (i, element) in (*array.as_ptr()).iter_mut().enumerate() {
+                element.write(i as u64);
+            }
+            for (i, element) in (*array.as_ptr()).iter().enumerate() {
+                assert_eq!(element.assume_init(), i as u64);
+            }
+            allocator.deallocate(array.cast(), Layout::array::<u64>(32).unwrap());
+        }
+
+        assert!(allocator.allocate_array::<u64>(usize::MAX).is_err());
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn allocate_empty_array() {
+        let allocator = Rulloc::default();
+        let layout = Layout::array::<u64>(0).unwrap();
+
+        let array = allocator.allocate_array::<u64>(0).unwrap();
+        assert_eq!(array.len(), 0);
+        assert_eq!(array.cast::<u64>(), NonNull::dangling());
+        assert_eq!(allocator.total_regions(), 0);
+        assert_eq!(allocator.poll_stats().allocations, 0);
+
+        unsafe {
+            allocator.deallocate(array.cast(), layout);
+            assert_eq!(allocator.poll_stats().deallocations, 0);
+
+            // Growing allocates for real.
+            let grown = allocator
+                .grow(array.cast(), layout, Layout::array::<u64>(4).unwrap())
+                .unwrap();
+            assert_eq!(allocator.total_regions(), 1);
+            allocator.deallocate(grown.cast(), Layout::array::<u64>(4).unwrap());
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn scrub_pattern() {
+        let allocator = Rulloc::default().with_scrub_pattern(0xAA);
+
+        unsafe {
+            let layout = Layout::array::<u8>(100).unwrap();
+            let address = allocator.allocate(layout).unwrap();
+            assert!(address.as_ref().iter().all(|byte| *byte == 0xAA));
+
+            // Fresh region of the dynamic bucket, still zeroed on request.
+            let large = Layout::array::<u8>(64 * 1024).unwrap();
+            let zeroed = allocator.allocate_zeroed(large).unwrap();
+            assert!(zeroed.as_ref().iter().all(|byte| *byte == 0));
+
+            allocator.deallocate(address.cast(), layout);
+            allocator.deallocate(zeroed.cast(), large);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn block_size() {
+        let allocator = Rulloc::default().with_size_classes(true);
+
+        unsafe {
+            for size in [1, 20, 100, 500, 1000, 5000, 100_000] {
+                let layout = Layout::array::<u8>(size).unwrap();
+                let address = allocator.allocate(layout).unwrap();
+                let block_size = allocator.block_size(address.cast());
+
+                assert!(block_size >= size);
+                assert_eq!(block_size, address.len());
+                assert_eq!(block_size, allocator.capacity_hint(layout));
+
+                // The slack is ours to use.
+                address.as_mut_ptr().write_bytes(7, block_size);
+                allocator.deallocate(address.cast(), layout);
             }
-            Err(_) => Err(AllocError),
+
+            // Size classes round up to 32 bytes.
+            let layout = Layout::array::<u8>(20).unwrap();
+            let address = allocator.allocate(layout).unwrap();
+            assert_eq!(allocator.block_size(address.cast()), 32);
+            allocator.deallocate(address.cast(), layout);
         }
     }
 
-    unsafe fn grow_zeroed(
-        &self,
-        address: NonNull<u8>,
-        old_layout: Layout,
-        new_layout: Layout,
-    ) -> AllocResult {
-        let new_address = self.grow(address, old_layout, new_layout)?;
-        let zero_from = new_address
-            .as_mut_ptr()
-            .map_addr(|addr| addr + old_layout.size());
-        zero_from.write_bytes(0, new_layout.size() - old_layout.size());
+    #[test]
+    fn hit_rate() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let layout = Layout::array::<u8>(64).unwrap();
+        assert_eq!(allocator.full_stats().buckets[0].hit_rate(), 0.0);
 
-        Ok(new_address)
+        // Keep the region mapped, otherwise every allocation would map it
+        // again.
+        let warmup = allocator.allocate(layout).unwrap();
+        let stats = allocator.full_stats().buckets[0];
+        assert_eq!((stats.freelist_hits, stats.region_maps), (0, 1));
+
+        for _ in 0..99 {
+            let address = allocator.allocate(layout).unwrap();
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+
+        let stats = allocator.full_stats().buckets[0];
+        assert_eq!((stats.freelist_hits, stats.region_maps), (99, 1));
+        assert!(stats.hit_rate() >= 0.99);
+
+        unsafe { allocator.deallocate(warmup.cast(), layout) };
+        assert_eq!(allocator.full_stats().buckets[1].hit_rate(), 0.0);
     }
-}
 
-unsafe impl GlobalAlloc for Rulloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        match self.allocate(layout) {
-            Ok(address) => address.cast().as_ptr(),
-            Err(_) => ptr::null_mut(),
+    #[test]
+    fn coalesce_rate() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let layout = Layout::array::<u8>(48).unwrap();
+        let addresses: Vec<_> = (0..20)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        // Interleaved lifetimes, every free leaves a hole between used blocks.
+        for address in addresses.iter().step_by(2) {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+
+        let stats = allocator.full_stats().buckets[0];
+        assert_eq!((stats.coalesced_frees, stats.isolated_frees), (0, 10));
+        assert_eq!(stats.coalesce_rate(), 0.0);
+
+        // Now every block sits between two holes.
+        for address in addresses.iter().skip(1).step_by(2) {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+
+        let stats = allocator.full_stats().buckets[0];
+        assert_eq!((stats.coalesced_frees, stats.isolated_frees), (10, 10));
+        assert_eq!(stats.coalesce_rate(), 0.5);
+    }
+
+    #[test]
+    fn full_stats() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let small = Layout::array::<u8>(64).unwrap();
+        let large = Layout::array::<u8>(1024).unwrap();
+
+        assert!(allocator
+            .full_stats()
+            .iter()
+            .all(|stats| *stats == BucketStats::default()));
+
+        let first = allocator.allocate(small).unwrap();
+        let second = allocator.allocate(large).unwrap();
+        let stats = allocator.full_stats();
+
+        assert_eq!(stats.buckets[0].regions, 1);
+        assert_eq!(stats.buckets[1], BucketStats::default());
+        assert_eq!(stats.dyn_bucket.regions, 1);
+        assert!(stats.dyn_bucket.bytes_mapped > 0);
+        assert_eq!(stats.dyn_bucket.free_blocks, 1);
+        assert!(stats.dyn_bucket.free_bytes > 0);
+        assert_eq!(
+            stats.iter().map(|stats| stats.bytes_mapped).sum::<usize>(),
+            allocator.total_mapped_bytes()
+        );
+
+        unsafe {
+            allocator.deallocate(first.cast(), small);
+            allocator.deallocate(second.cast(), large);
+        }
+
+        assert_eq!(allocator.full_stats().dyn_bucket.regions, 0);
+    }
+
+    #[test]
+    fn full_stats_classes() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 256, 1024]);
+        let layout = Layout::array::<u8>(200).unwrap();
+        let address = allocator.allocate(layout).unwrap();
+
+        let classes: Vec<_> = allocator.full_stats().classes().collect();
+        assert_eq!(classes.len(), 4);
+        assert_eq!(
+            classes.iter().map(|(size, _)| *size).collect::<Vec<_>>(),
+            [64, 256, 1024, usize::MAX]
+        );
+        assert_eq!(classes[1].1.regions, 1);
+        assert!(classes
+            .iter()
+            .enumerate()
+            .all(|(i, (_, stats))| i == 1 || stats.regions == 0));
+
+        unsafe { allocator.deallocate(address.cast(), layout) };
+    }
+
+    #[test]
+    fn memory_report() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        unsafe {
+            let addresses: Vec<_> = (0..3)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            allocator.deallocate(addresses[1].cast(), layout);
+
+            let report = allocator.memory_report();
+            assert_eq!(
+                report
+                    .buckets
+                    .iter()
+                    .map(|bucket| bucket.size)
+                    .collect::<Vec<_>>(),
+                [128, 1024, 8192, usize::MAX]
+            );
+            assert!(report.buckets[1..]
+                .iter()
+                .all(|bucket| bucket.regions.is_empty()));
+
+            let region = &report.buckets[0].regions[0];
+            let info = allocator.region_for(addresses[0].cast()).unwrap();
+            assert_eq!(report.buckets[0].regions.len(), 1);
+            assert_eq!((region.base, region.size), (info.address, info.length));
+            assert_eq!(region.blocks.len(), 4);
+
+            // Three blocks of 64 bytes one after the other, then the rest.
+            let first = region.blocks[0].offset;
+            for (i, block) in region.blocks[..3].iter().enumerate() {
+                assert_eq!(block.offset, first + i * (BLOCK_HEADER_SIZE + 64));
+                assert_eq!(block.size, 64);
+                assert_eq!(block.free, i == 1);
+            }
+            let last = region.blocks[3];
+            assert_eq!(last.offset, first + 3 * (BLOCK_HEADER_SIZE + 64));
+            assert_eq!(last.offset + BLOCK_HEADER_SIZE + last.size, region.size);
+            assert!(last.free);
+
+            allocator.deallocate(addresses[0].cast(), layout);
+            allocator.deallocate(addresses[2].cast(), layout);
+        }
+
+        assert!(allocator.memory_report().buckets[0].regions.is_empty());
+    }
+
+    #[test]
+    fn allocate_near() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([1024]);
+        let layout = Layout::array::<u8>(64).unwrap();
+        let region_of =
+            |address: NonNull<[u8]>| allocator.region_for(address.cast()).unwrap().address;
+
+        unsafe {
+            let first = allocator.allocate(layout).unwrap();
+            let region = region_of(first);
+
+            // Fill the first region so that the next allocation maps another.
+            let mut others = vec![allocator.allocate(layout).unwrap()];
+            while region_of(*others.last().unwrap()) == region {
+                others.push(allocator.allocate(layout).unwrap());
+            }
+            let other_region = region_of(*others.last().unwrap());
+
+            // Normal dispatch takes the head of the free list, which is now
+            // in the second region, but the hint goes back to the first one.
+            let freed = others[0];
+            allocator.deallocate(freed.cast(), layout);
+            let node = allocator.allocate_near(layout, first.cast()).unwrap();
+            assert_eq!(region_of(node), region);
+            assert_eq!(node.cast::<u8>(), freed.cast());
+
+            // No space left near the hint, so it falls back to the free list.
+            let node2 = allocator.allocate_near(layout, first.cast()).unwrap();
+            assert_eq!(region_of(node2), other_region);
+
+            for address in [first, node, node2].iter().chain(&others[1..]) {
+                allocator.deallocate(address.cast(), layout);
+            }
+        }
+
+        assert_eq!(allocator.total_regions(), 0);
+    }
+
+    #[test]
+    fn defragment_until() {
+        // Pools keep empty regions mapped, so there's something to unmap.
+        let allocator = Rulloc::<1>::pool_mode([64]);
+        let layout = Layout::array::<u8>(64).unwrap();
+        let blocks_per_region = {
+            let address = allocator.allocate(layout).unwrap();
+            let region = allocator.region_for(address.cast()).unwrap();
+            unsafe { allocator.deallocate(address.cast(), layout) };
+            region.num_blocks
+        };
+
+        unsafe {
+            let addresses: Vec<_> = (0..3 * blocks_per_region)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            for address in addresses {
+                allocator.deallocate(address.cast(), layout);
+            }
+        }
+        assert_eq!(allocator.region_count(0), 3);
+
+        // A deadline in the past still makes progress, one region at a time.
+        for remaining in [2, 1, 0] {
+            assert!(allocator.defragment_until(Instant::now()));
+            assert_eq!(allocator.region_count(0), remaining);
+            assert_eq!(allocator.total_regions(), remaining);
+        }
+
+        let deadline = Instant::now() + std::time::Duration::from_secs(60);
+        assert!(!allocator.defragment_until(deadline));
+        assert_eq!(allocator.total_mapped_bytes(), 0);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn deallocate_foreign_pointer() {
+        for allocator in [Rulloc::default(), Rulloc::default().with_canary_seed(7)] {
+            let layout = Layout::array::<u8>(64).unwrap();
+            let address = allocator.allocate(layout).unwrap();
+
+            // If the header were read it would say this is a free block of
+            // 0 bytes in bucket 0, which is not something we can recover from.
+            let mut stack = [0u8; 256];
+            let foreign = NonNull::from(&mut stack[128]);
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+                allocator.deallocate(foreign, layout)
+            }));
+
+            let message = *result.unwrap_err().downcast::<String>().unwrap();
+            assert!(message.contains("is not inside any region"), "{message}");
+            assert!(stack.iter().all(|byte| *byte == 0));
+            assert_eq!(allocator.poll_stats().deallocations, 0);
+
+            // The lock is not poisoned.
+            unsafe { allocator.deallocate(address.cast(), layout) };
+            assert_eq!(allocator.total_regions(), 0);
+        }
+    }
+
+    /// [`Allocator::grow`], [`Allocator::shrink`] and [`GlobalAlloc::realloc`]
+    /// all go through [`Rulloc::reallocate`], which rejects foreign pointers
+    /// just like [`Rulloc::deallocate`]. Release builds only check the range
+    /// spanned by each bucket, which a stack address is never part of.
+    #[test]
+    fn reallocate_foreign_pointer() {
+        let allocator = Rulloc::default();
+        let layout = Layout::array::<u8>(64).unwrap();
+        let address = allocator.allocate(layout).unwrap();
+
+        let mut stack = [0u8; 256];
+        let foreign = NonNull::from(&mut stack[128]);
+        let larger = Layout::array::<u8>(128).unwrap();
+        let smaller = Layout::array::<u8>(32).unwrap();
+
+        let attempts: [&dyn Fn() -> bool; 3] = [
+            &|| unsafe { allocator.grow(foreign, layout, larger).is_err() },
+            &|| unsafe { allocator.shrink(foreign, layout, smaller).is_err() },
+            &|| unsafe {
+                GlobalAlloc::realloc(&allocator, foreign.as_ptr(), layout, 128).is_null()
+            },
+        ];
+
+        for attempt in attempts {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(attempt));
+            if cfg!(debug_assertions) {
+                let message = *result.unwrap_err().downcast::<String>().unwrap();
+                assert!(message.contains("is not inside any region"), "{message}");
+            } else {
+                assert!(result.unwrap());
+            }
+        }
+
+        assert!(stack.iter().all(|byte| *byte == 0));
+        assert_eq!(allocator.poll_stats().bytes_allocated, layout.size());
+
+        unsafe { allocator.deallocate(address.cast(), layout) };
+        assert_eq!(allocator.total_regions(), 0);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn deallocate_with_other_alignment() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+
+        // With a stricter alignment the header would be found through a back
+        // pointer that was never written. With a weaker one the header would
+        // be read from the padding. Both at most pointer size read the same
+        // header, which records the alignment used to allocate.
+        for (allocated, used) in [(8, 16), (16, 8), (4, 8)] {
+            let layout = Layout::from_size_align(48, allocated).unwrap();
+            let other = Layout::from_size_align(48, used).unwrap();
+            let larger = Layout::from_size_align(96, used).unwrap();
+            let smaller = Layout::from_size_align(24, used).unwrap();
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            let stats = allocator.poll_stats();
+
+            let attempts: [&dyn Fn(); 3] = [
+                &|| unsafe { allocator.deallocate(address, other) },
+                &|| unsafe {
+                    let _ = allocator.grow(address, other, larger);
+                },
+                &|| unsafe {
+                    let _ = allocator.shrink(address, other, smaller);
+                },
+            ];
+
+            for attempt in attempts {
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(attempt));
+                let message = *result.unwrap_err().downcast::<String>().unwrap();
+                assert!(
+                    message.contains(&format!(
+                        "with alignment {used}, but it was allocated with alignment {allocated}"
+                    )),
+                    "{message}"
+                );
+            }
+            assert_eq!(allocator.poll_stats(), stats);
+
+            unsafe { allocator.deallocate(address, layout) };
+        }
+
+        assert_eq!(allocator.total_regions(), 0);
+    }
+
+    #[test]
+    fn initial_regions() {
+        let allocator =
+            Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_initial_regions([1; 3]);
+
+        for index in 0..3 {
+            assert_eq!(allocator.region_count(index), 1);
+        }
+        assert_eq!(allocator.dyn_region_count(), 0);
+        assert_eq!(allocator.total_regions(), 3);
+        assert!(allocator.total_mapped_bytes() > 0);
+
+        // The biggest size of each bucket fits in the pre-mapped region.
+        for (index, size) in [128, 1024, 8192].into_iter().enumerate() {
+            let layout = Layout::array::<u8>(size).unwrap();
+            let address = allocator.allocate(layout).unwrap();
+            assert_eq!(allocator.region_count(index), 1);
+            assert_eq!(allocator.full_stats().buckets[index].freelist_hits, 1);
+            assert_eq!(allocator.full_stats().buckets[index].region_maps, 1);
+            unsafe { allocator.deallocate(address.cast(), layout) };
         }
+
+        // Pools are carved into blocks up front.
+        let pool = Rulloc::<1>::pool_mode([64]).with_initial_regions([2]);
+        assert_eq!(pool.region_count(0), 2);
+        let layout = Layout::array::<u8>(64).unwrap();
+        let address = pool.allocate(layout).unwrap();
+        let region = pool.region_for(address.cast()).unwrap();
+        assert!(region.num_blocks > 1);
+        unsafe { pool.deallocate(address.cast(), layout) };
+
+        // Limits are honored, the rest is mapped on demand.
+        let limited = Rulloc::<1>::with_bucket_sizes([128])
+            .with_memory_limit(platform::page_size())
+            .with_initial_regions([3]);
+        assert_eq!(limited.region_count(0), 1);
+    }
+
+    #[test]
+    fn send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Rulloc>();
+        assert_send_sync::<Rulloc<3, crate::BestFit>>();
+        assert_send_sync::<CheckedPtr>();
+        assert_send_sync::<RegionInfo>();
+        assert_send_sync::<HeapReport>();
+        assert_send_sync::<SecureBuffer>();
+
+        // Allocators can be moved to another thread along with the
+        // allocations that use them.
+        let mut numbers = Vec::new_in(Rulloc::default());
+        numbers.extend(0..64u64);
+        let sum = std::thread::spawn(move || numbers.iter().sum::<u64>())
+            .join()
+            .unwrap();
+
+        assert_eq!(sum, 63 * 64 / 2);
+    }
+
+    #[test]
+    fn reset_stats() {
+        let allocator = Rulloc::<1>::with_bucket_sizes([128]).with_adaptive_sizes(4);
+        let layout = Layout::array::<u8>(64).unwrap();
+
+        unsafe {
+            let first: Vec<_> = (0..4)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            allocator.deallocate(first[3].cast(), layout);
+            assert!(allocator.recommended_sizes().is_some());
+
+            let mapped = allocator.poll_stats().bytes_mapped;
+            allocator.reset_stats();
+
+            let stats = allocator.poll_stats();
+            assert_eq!((stats.allocations, stats.deallocations), (0, 0));
+            assert_eq!(stats.bytes_allocated, 3 * 64);
+            assert_eq!(stats.bytes_mapped, mapped);
+            assert_eq!(allocator.full_stats().buckets[0].freelist_hits, 0);
+            assert_eq!(allocator.recommended_sizes(), None);
+
+            // Only the second phase is counted, live memory is untouched.
+            let second = allocator.allocate(layout).unwrap();
+            let stats = allocator.poll_stats();
+            assert_eq!((stats.allocations, stats.deallocations), (1, 0));
+            assert_eq!(stats.bytes_allocated, 4 * 64);
+            assert_eq!(allocator.full_stats().buckets[0].freelist_hits, 1);
+
+            allocator.deallocate(second.cast(), layout);
+            for address in &first[..3] {
+                allocator.deallocate(address.cast(), layout);
+            }
+        }
+
+        assert_eq!(allocator.poll_stats().deallocations, 4);
+    }
+
+    #[cfg(feature = "tracy")]
+    #[test]
+    fn tracy_memory_events() {
+        use crate::tracy::mock::{self, Event};
+
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+        let small = Layout::array::<u8>(32).unwrap();
+        let large = Layout::array::<u8>(1024).unwrap();
+        mock::take_events();
+
+        unsafe {
+            let address = allocator.allocate(small).unwrap().cast::<u8>();
+            let moved = allocator.grow(address, small, large).unwrap().cast::<u8>();
+            allocator.deallocate(moved, large);
+
+            let pool = |name: &str| String::from(name);
+            assert_eq!(
+                mock::take_events(),
+                [
+                    Event::Alloc {
+                        address: address.as_ptr().addr(),
+                        size: 32,
+                        pool: pool("rulloc bucket 0"),
+                    },
+                    Event::Free {
+                        address: address.as_ptr().addr(),
+                        pool: pool("rulloc bucket 0"),
+                    },
+                    Event::Alloc {
+                        address: moved.as_ptr().addr(),
+                        size: 1024,
+                        pool: pool("rulloc bucket 3"),
+                    },
+                    Event::Free {
+                        address: moved.as_ptr().addr(),
+                        pool: pool("rulloc bucket 3"),
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn free_region() {
+        let allocator = Rulloc::with_default_config();
+        let layout = Layout::array::<u8>(32).unwrap();
+
+        unsafe {
+            // Everything fits in the first region of the bucket.
+            let addresses: Vec<_> = (0..40)
+                .map(|_| allocator.allocate(layout).unwrap().cast::<u8>())
+                .collect();
+            let region = allocator.region_for(addresses[0]).unwrap();
+            assert_eq!(allocator.full_stats().buckets[0].regions, 1);
+
+            // Some free blocks in the middle have to be unlinked as well.
+            for address in addresses.iter().skip(1).step_by(8) {
+                allocator.deallocate(*address, layout);
+            }
+            assert!(allocator.full_stats().buckets[0].free_blocks > 1);
+
+            allocator.free_region(region);
+
+            let stats = allocator.full_stats();
+            assert_eq!(stats.buckets[0].regions, 1);
+            assert_eq!(stats.buckets[0].free_blocks, 1);
+            assert_eq!(allocator.region_for(addresses[0]).unwrap().num_blocks, 1);
+            assert_eq!(
+                stats.buckets[0].free_bytes,
+                region.length - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE
+            );
+
+            // The region is reused from the start.
+            let reused = allocator.allocate(layout).unwrap();
+            assert_eq!(reused.cast::<u8>(), addresses[0]);
+            allocator.deallocate(reused.cast(), layout);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    fn free_region_updates_counters() {
+        for allocator in [Rulloc::default(), Rulloc::default().with_canary_seed(7)] {
+            let layout = Layout::array::<u8>(32).unwrap();
+            let other = Layout::array::<u8>(100_000).unwrap();
+
+            unsafe {
+                let addresses: Vec<_> = (0..10)
+                    .map(|_| allocator.allocate(layout).unwrap().cast::<u8>())
+                    .collect();
+                let kept = allocator.allocate(other).unwrap();
+                allocator.deallocate(addresses[3], layout);
+                allocator.deallocate(addresses[7], layout);
+
+                let region = allocator.region_for(addresses[0]).unwrap();
+                allocator.free_region(region);
+
+                let stats = allocator.poll_stats();
+                assert_eq!(stats.live_blocks, 1);
+                assert_eq!(stats.bytes_allocated, other.size());
+                assert_eq!(stats.deallocations, 10);
+
+                allocator.deallocate(kept.cast(), other);
+                let stats = allocator.poll_stats();
+                assert_eq!((stats.live_blocks, stats.bytes_allocated), (0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn stable_shrink() {
+        let old = Layout::array::<u8>(900).unwrap();
+        let new = Layout::array::<u8>(100).unwrap();
+
+        for (policy, bucket) in [(ShrinkPolicy::Migrate, 0), (ShrinkPolicy::Stable, 1)] {
+            let allocator =
+                Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_shrink_policy(policy);
+
+            unsafe {
+                let mut address = allocator.allocate(old).unwrap();
+                address.as_mut().fill(69);
+                let shrunk = allocator.shrink(address.cast(), old, new).unwrap();
+
+                assert_eq!(
+                    shrunk.cast::<u8>() == address.cast::<u8>(),
+                    policy == ShrinkPolicy::Stable
+                );
+                assert_eq!(allocator.region_for(shrunk.cast()).unwrap().bucket, bucket);
+                assert!(shrunk.as_ref()[..new.size()].iter().all(|byte| *byte == 69));
+
+                allocator.deallocate(shrunk.cast(), new);
+            }
+
+            verify_buckets_are_empty(allocator);
+        }
+    }
+
+    #[test]
+    fn exact_shrink() {
+        let old = Layout::array::<u8>(100).unwrap();
+        let new = Layout::array::<u8>(93).unwrap();
+
+        for exact in [false, true] {
+            let allocator = Rulloc::<1>::with_bucket_sizes([128]).with_exact_shrink(exact);
+
+            unsafe {
+                let address = allocator.allocate(old).unwrap();
+                let shrunk = allocator.shrink(address.cast(), old, new).unwrap();
+                assert_eq!(shrunk.cast::<u8>(), address.cast::<u8>());
+
+                let block = Header::<Block>::from_allocated_pointer(shrunk.cast(), new);
+                let next = block.as_ref().next.unwrap();
+                assert!(next.as_ref().is_free());
+
+                if exact {
+                    // 3 bytes of padding to reach pointer size are needed,
+                    // the other 8 go to the free block.
+                    assert_eq!(shrunk.len(), new.size());
+                    assert_eq!(block.as_ref().size(), 96);
+                    assert_eq!(
+                        Header::content_address_of(block).as_ptr().add(96),
+                        next.as_ptr().cast()
+                    );
+                } else {
+                    assert_eq!(shrunk.len(), 104);
+                    assert_eq!(block.as_ref().size(), 104);
+                }
+
+                validate_free_lists(&allocator);
+                allocator.deallocate(shrunk.cast(), new);
+            }
+
+            verify_buckets_are_empty(allocator);
+        }
+    }
+
+    #[test]
+    fn unmap_failure() {
+        let allocator = Rulloc::default();
+        let other = Rulloc::default();
+        let layout = Layout::array::<u8>(64).unwrap();
+        let address = allocator.allocate(layout).unwrap();
+        let region = allocator.region_for(address.cast()).unwrap();
+
+        // EINVAL.
+        platform::fail_next_return_memory(22);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+            allocator.deallocate(address.cast(), layout)
+        }));
+
+        // Counted in both builds, debug builds also panic after unlocking.
+        if cfg!(debug_assertions) {
+            let message = result.unwrap_err().downcast::<String>().unwrap();
+            assert_eq!(
+                *message,
+                format!(
+                    "failed to unmap {} bytes at {:?} (errno 22)",
+                    region.length, region.address
+                )
+            );
+        } else {
+            assert!(result.is_ok());
+        }
+        assert_eq!(allocator.poll_stats().unmap_failures, 1);
+        assert_eq!(allocator.poll_stats().regions, 0);
+        assert_eq!(other.poll_stats().unmap_failures, 0);
+
+        // The lock is not poisoned and the failure is only counted once.
+        let address = allocator.allocate(layout).unwrap();
+        unsafe { allocator.deallocate(address.cast(), layout) };
+        assert_eq!(allocator.poll_stats().unmap_failures, 1);
+    }
+
+    #[test]
+    fn capacity_hint_matches_allocation() {
+        let sizes = [1, 7, 20, 33, 100, 500, 1000, 5000];
+        let alignments = [1, 8, 16, 64];
+
+        for size_classes in [false, true] {
+            let allocator =
+                Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_size_classes(size_classes);
+
+            for (size, align) in sizes.into_iter().flat_map(|s| alignments.map(|a| (s, a))) {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let hint = allocator.capacity_hint(layout);
+                let address = allocator.allocate(layout).unwrap();
+                assert_eq!(
+                    hint,
+                    address.len(),
+                    "{layout:?}, size classes: {size_classes}"
+                );
+                unsafe { allocator.deallocate(address.cast(), layout) };
+            }
+        }
+
+        let layout = Layout::array::<u8>(20).unwrap();
+        assert_eq!(
+            Rulloc::default()
+                .with_size_classes(true)
+                .capacity_hint(layout),
+            32
+        );
+        assert_eq!(
+            Rulloc::default().with_canary_seed(1).capacity_hint(layout),
+            20
+        );
+    }
+
+    #[test]
+    fn total_regions_and_mapped_bytes() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]);
+        let small = Layout::array::<u8>(64).unwrap();
+        let large = Layout::array::<u8>(2 * platform::page_size()).unwrap();
+
+        assert_eq!(allocator.total_regions(), 0);
+        assert_eq!(allocator.total_mapped_bytes(), 0);
+
+        let first = allocator.allocate(small).unwrap();
+        assert_eq!(allocator.total_regions(), 1);
+        assert_eq!(allocator.total_mapped_bytes(), platform::page_size());
+
+        let second = allocator.allocate(large).unwrap();
+        assert_eq!(allocator.total_regions(), 2);
+        assert!(allocator.total_mapped_bytes() > 3 * platform::page_size());
+
+        unsafe { allocator.deallocate(first.cast(), small) };
+        assert_eq!(allocator.total_regions(), 1);
+        assert_eq!(
+            allocator.total_mapped_bytes(),
+            allocator.poll_stats().bytes_mapped
+        );
+
+        unsafe { allocator.deallocate(second.cast(), large) };
+        assert_eq!(allocator.total_regions(), 0);
+        assert_eq!(allocator.total_mapped_bytes(), 0);
+    }
+
+    #[test]
+    fn total_live_blocks() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 128]).with_slab(16);
+        let layouts = [8, 64, 100, 4096].map(|size| Layout::array::<u8>(size).unwrap());
+
+        let mut addresses: Vec<_> = layouts
+            .iter()
+            .flat_map(|layout| (0..5).map(|_| (allocator.allocate(*layout).unwrap(), *layout)))
+            .collect();
+        assert_eq!(allocator.total_live_blocks(), 20);
+
+        // Reallocating moves blocks around but doesn't change the count.
+        let (address, layout) = addresses.pop().unwrap();
+        let grown = Layout::array::<u8>(8192).unwrap();
+        let address = unsafe { allocator.grow(address.cast(), layout, grown).unwrap() };
+        addresses.push((address, grown));
+        assert_eq!(allocator.total_live_blocks(), 20);
+
+        allocator.reset_stats();
+        assert_eq!(allocator.total_live_blocks(), 20);
+
+        for (i, (address, layout)) in addresses.into_iter().enumerate() {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+            assert_eq!(allocator.total_live_blocks(), 19 - i);
+        }
+        assert_eq!(allocator.total_regions(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mmap_flags() {
+        // VmFlags of the mapping that contains `address`, see proc(5).
+        fn vm_flags(address: NonNull<u8>) -> String {
+            let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+            let address = address.as_ptr().addr();
+            let mut contains = false;
+
+            for line in smaps.lines() {
+                if let Some((range, _)) = line.split_once(' ') {
+                    if let Some((start, end)) = range.split_once('-') {
+                        let start = usize::from_str_radix(start, 16).unwrap_or(usize::MAX);
+                        let end = usize::from_str_radix(end, 16).unwrap_or(0);
+                        contains = (start..end).contains(&address);
+                    }
+                }
+                if let Some(flags) = line.strip_prefix("VmFlags:") {
+                    if contains {
+                        return flags.to_owned();
+                    }
+                }
+            }
+
+            panic!("no mapping contains {address:#x}");
+        }
+
+        let allocator = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192])
+            .with_slab(16)
+            .with_mmap_flags(libc::MAP_NORESERVE);
+
+        let layouts = [8, 64, 1024, 64 * 1024].map(|size| Layout::array::<u8>(size).unwrap());
+        let addresses = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        let pages = allocator.allocate_pages_aligned(2, 64 * 1024).unwrap();
+
+        // Overcommit mode 2 ignores MAP_NORESERVE.
+        let overcommit = std::fs::read_to_string("/proc/sys/vm/overcommit_memory").unwrap();
+        if overcommit.trim() != "2" {
+            for address in addresses.iter().chain([&pages]) {
+                let flags = vm_flags(address.cast());
+                assert!(flags.split_whitespace().any(|flag| flag == "nr"), "{flags}");
+            }
+        }
+
+        unsafe {
+            for (address, layout) in addresses.into_iter().zip(layouts) {
+                address.cast::<u8>().as_ptr().write_bytes(1, layout.size());
+                allocator.deallocate(address.cast(), layout);
+            }
+            allocator.deallocate_pages_aligned(pages.cast(), 2, 64 * 1024);
+        }
+
+        assert_eq!(allocator.total_regions(), 0);
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.deallocate(NonNull::new_unchecked(ptr), layout)
-    }
+    #[test]
+    fn try_grow_to_capacity() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+        let layout = Layout::array::<u8>(100).unwrap();
+
+        unsafe {
+            let block = allocator.allocate(layout).unwrap();
+            let address = block.cast::<u8>();
+            let neighbour = allocator.allocate(layout).unwrap().cast::<u8>();
+            neighbour.as_ptr().write_bytes(7, layout.size());
 
-    unsafe fn realloc(&self, address: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
-        let new_layout = Layout::from_size_align(new_size, old_layout.align()).unwrap();
-        let address = NonNull::new_unchecked(address);
+            // Rounded up to the alignment of block headers.
+            let capacity = allocator.try_grow_to_capacity(address, layout);
+            assert_eq!(capacity, block.len());
+            assert!(capacity > layout.size());
+            assert_eq!(
+                allocator.poll_stats().bytes_allocated,
+                capacity + layout.size()
+            );
 
-        let result = if old_layout.size() <= new_size {
-            self.shrink(address, old_layout, new_layout)
-        } else {
-            self.grow(address, old_layout, new_layout)
-        };
+            // The extra bytes don't overlap the next block.
+            address.as_ptr().write_bytes(1, capacity);
+            assert!((0..layout.size()).all(|i| *neighbour.as_ptr().add(i) == 7));
 
-        match result {
-            Ok(new_address) => new_address.as_mut_ptr(),
-            Err(_) => ptr::null_mut(),
+            let grown = Layout::from_size_align(capacity, layout.align()).unwrap();
+            allocator.deallocate(address, grown);
+            allocator.deallocate(neighbour, layout);
         }
+
+        assert_eq!(allocator.poll_stats().bytes_allocated, 0);
+        assert_eq!(allocator.total_regions(), 0);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        sync,
-        thread::{self, ThreadId},
-    };
+    #[test]
+    fn bucket_sources() {
+        use std::sync::atomic::AtomicUsize;
 
-    use super::*;
-    use crate::platform::PAGE_SIZE;
+        /// Anonymous memory that remembers what it mapped.
+        struct MockSource {
+            maps: AtomicUsize,
+            unmaps: AtomicUsize,
+            last: AtomicUsize,
+        }
 
-    #[test]
-    fn internal_allocator_wrapper() {
-        let allocator = Rulloc::with_default_config();
-        unsafe {
-            let layout1 = Layout::array::<u8>(8).unwrap();
-            let mut addr1 = allocator.allocate(layout1).unwrap();
+        unsafe impl MmapSource for MockSource {
+            fn map(&self, length: usize, align: usize) -> Result<NonNull<u8>, i32> {
+                let address = unsafe { platform::request_aligned_memory(length, align, 0)? };
+                self.maps.fetch_add(1, Ordering::Relaxed);
+                self.last.store(address.as_ptr().addr(), Ordering::Relaxed);
+                Ok(address)
+            }
 
-            addr1.as_mut().fill(69);
+            unsafe fn unmap(&self, address: NonNull<u8>, length: usize, align: usize) {
+                self.unmaps.fetch_add(1, Ordering::Relaxed);
+                platform::return_aligned_memory(address, length, align).unwrap();
+            }
+        }
 
-            let layout2 = Layout::array::<u8>(PAGE_SIZE * 2).unwrap();
-            let mut addr2 = allocator.allocate(layout2).unwrap();
+        static FAST: MockSource = MockSource {
+            maps: AtomicUsize::new(0),
+            unmaps: AtomicUsize::new(0),
+            last: AtomicUsize::new(0),
+        };
+        static PERSISTENT: MockSource = MockSource {
+            maps: AtomicUsize::new(0),
+            unmaps: AtomicUsize::new(0),
+            last: AtomicUsize::new(0),
+        };
 
-            addr2.as_mut().fill(42);
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256])
+            .with_bucket_source(0, &FAST)
+            .with_bucket_source(3, &PERSISTENT);
 
-            for value in addr1.as_ref() {
-                assert_eq!(value, &69);
-            }
+        let small = Layout::array::<u8>(32).unwrap();
+        let medium = Layout::array::<u8>(100).unwrap();
+        let large = Layout::array::<u8>(64 * 1024).unwrap();
 
-            allocator.deallocate(addr1.cast(), layout1);
+        let small_address = allocator.allocate(small).unwrap().cast::<u8>();
+        let region = allocator.region_for(small_address).unwrap();
+        assert_eq!(FAST.maps.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            FAST.last.load(Ordering::Relaxed),
+            region.address.as_ptr().addr()
+        );
 
-            for value in addr2.as_ref() {
-                assert_eq!(value, &42);
-            }
+        // Buckets without a source still use anonymous mmap.
+        let medium_address = allocator.allocate(medium).unwrap().cast::<u8>();
+        assert_eq!(FAST.maps.load(Ordering::Relaxed), 1);
+        assert_eq!(PERSISTENT.maps.load(Ordering::Relaxed), 0);
 
-            allocator.deallocate(addr2.cast(), layout2);
+        let large_address = allocator.allocate(large).unwrap().cast::<u8>();
+        let region = allocator.region_for(large_address).unwrap();
+        assert_eq!(PERSISTENT.maps.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            PERSISTENT.last.load(Ordering::Relaxed),
+            region.address.as_ptr().addr()
+        );
+
+        // Regions go back to the source they came from.
+        unsafe {
+            allocator.deallocate(small_address, small);
+            allocator.deallocate(medium_address, medium);
+            allocator.deallocate(large_address, large);
         }
+        assert_eq!(FAST.unmaps.load(Ordering::Relaxed), 1);
+        assert_eq!(PERSISTENT.unmaps.load(Ordering::Relaxed), 1);
+        assert_eq!(allocator.total_regions(), 0);
     }
 
+    /// Sources don't have to return zeroed memory, see [`MmapSource`].
     #[test]
-    fn buckets() {
-        unsafe {
-            let sizes = [8, 16, 24];
-            let mut allocator = InternalAllocator::<3>::with_bucket_sizes(sizes);
+    fn allocate_zeroed_from_dirty_source() {
+        /// Anonymous memory filled with garbage, like recycled memory would be.
+        struct DirtySource;
 
-            macro_rules! verify_number_of_regions_per_bucket {
-                ($expected:expr) => {
-                    for i in 0..sizes.len() {
-                        assert_eq!(allocator.buckets[i].regions().len(), $expected[i]);
-                    }
-                };
+        unsafe impl MmapSource for DirtySource {
+            fn map(&self, length: usize, align: usize) -> Result<NonNull<u8>, i32> {
+                let address = unsafe { platform::request_aligned_memory(length, align, 0)? };
+                unsafe { address.as_ptr().write_bytes(0xAB, length) };
+                Ok(address)
             }
 
-            let layout1 = Layout::array::<u8>(sizes[0]).unwrap();
-            let addr1 = allocator.allocate(layout1).unwrap().cast();
-            verify_number_of_regions_per_bucket!([1, 0, 0]);
+            unsafe fn unmap(&self, address: NonNull<u8>, length: usize, align: usize) {
+                platform::return_aligned_memory(address, length, align).unwrap();
+            }
+        }
 
-            let layout2 = Layout::array::<u8>(sizes[1]).unwrap();
-            let addr2 = allocator.allocate(layout2).unwrap().cast();
-            verify_number_of_regions_per_bucket!([1, 1, 0]);
+        static DIRTY: DirtySource = DirtySource;
 
-            let layout3 = Layout::array::<u8>(sizes[2]).unwrap();
-            let addr3 = allocator.allocate(layout3).unwrap().cast();
-            verify_number_of_regions_per_bucket!([1, 1, 1]);
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256])
+            .with_bucket_source(0, &DIRTY)
+            .with_bucket_source(3, &DIRTY);
 
-            allocator.deallocate(addr1, layout1);
-            verify_number_of_regions_per_bucket!([0, 1, 1]);
+        // The first allocation of each bucket maps a new region.
+        let layouts = [32, 64 * 1024].map(|size| Layout::array::<u8>(size).unwrap());
+        for layout in layouts {
+            let address = allocator.allocate_zeroed(layout).unwrap();
+            assert!(unsafe { address.as_ref() }.iter().all(|byte| *byte == 0));
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
 
-            allocator.deallocate(addr2, layout2);
-            verify_number_of_regions_per_bucket!([0, 0, 1]);
+        assert_eq!(allocator.total_regions(), 0);
+    }
 
-            allocator.deallocate(addr3, layout3);
-            verify_number_of_regions_per_bucket!([0, 0, 0]);
+    #[test]
+    fn recycle_uniform_size_blocks() {
+        let allocator = Rulloc::<3>::with_bucket_sizes([64, 128, 256]);
+        let layout = Layout::array::<u8>(64).unwrap();
 
-            let layout4 = Layout::array::<u8>(sizes[2] + 128).unwrap();
-            let addr4 = allocator.allocate(layout4).unwrap().cast();
-            verify_number_of_regions_per_bucket!([0, 0, 0]);
-            assert_eq!(allocator.dyn_bucket.regions().len(), 1);
+        let pool = [(); 8].map(|_| allocator.allocate(layout).unwrap());
+        let blocks_in_region = || {
+            let internal = allocator.allocator.lock().unwrap();
+            unsafe {
+                internal.buckets[0]
+                    .regions()
+                    .first()
+                    .unwrap()
+                    .as_ref()
+                    .num_blocks()
+            }
+        };
+        let num_blocks = blocks_in_region();
 
-            allocator.deallocate(addr4, layout4);
-            assert_eq!(allocator.dyn_bucket.regions().len(), 0);
+        // Objects in the middle of the pool are surrounded by used blocks, so
+        // the same address must be handed out again without merging anything.
+        for _ in 0..100 {
+            for address in &pool[1..7] {
+                unsafe { allocator.deallocate(address.cast(), layout) };
+                let reused = allocator.allocate(layout).unwrap();
+                assert_eq!(reused.cast::<u8>(), address.cast::<u8>());
+                assert_eq!(blocks_in_region(), num_blocks);
+            }
+        }
 
-            // Now let's try some reallocs
-            let mut realloc_addr = allocator.allocate(layout1).unwrap();
-            let corruption_check = 213;
-            realloc_addr.as_mut().fill(corruption_check);
+        validate_free_lists(&allocator);
 
-            realloc_addr = allocator
-                .reallocate(&Realloc::grow(realloc_addr.cast(), layout1, layout2))
-                .unwrap();
-            verify_number_of_regions_per_bucket!([0, 1, 0]);
+        for address in pool {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+        verify_buckets_are_empty(allocator);
+    }
 
-            realloc_addr = allocator
-                .reallocate(&Realloc::grow(realloc_addr.cast(), layout2, layout3))
-                .unwrap();
-            verify_number_of_regions_per_bucket!([0, 0, 1]);
+    #[test]
+    fn last_error() {
+        let page_size = platform::page_size();
+        let allocator =
+            Rulloc::<3>::with_bucket_sizes([8, 16, 24]).with_memory_limit(4 * page_size);
+        assert_eq!(allocator.last_error(), None);
 
-            for value in &realloc_addr.as_ref()[..layout1.size()] {
-                assert_eq!(*value, corruption_check);
-            }
+        // Headers and page rounding can't fit in isize.
+        let too_large = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+        assert!(allocator.allocate(too_large).is_err());
+        assert_eq!(allocator.last_error(), Some(AllocFailure::SizeOverflow));
+
+        // Each bucket maps at least one page, so the fourth bucket can't map
+        // a second page.
+        let layouts = [8, 16, 24, 32].map(|size| Layout::array::<u8>(size).unwrap());
+        let addresses = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        let over_limit = Layout::array::<u8>(page_size).unwrap();
+        assert!(allocator.allocate(over_limit).is_err());
+        assert_eq!(allocator.last_error(), Some(AllocFailure::LimitExceeded));
+
+        for (address, layout) in addresses.into_iter().zip(layouts) {
+            unsafe { allocator.deallocate(address.cast(), layout) };
+        }
+        verify_buckets_are_empty(allocator);
+
+        // No user space address range is this large, so the kernel must
+        // refuse to map it.
+        #[cfg(all(unix, not(miri), target_pointer_width = "64"))]
+        {
+            let allocator = Rulloc::default();
+            let unmappable = Layout::array::<u8>(1 << 48).unwrap();
+            assert!(allocator.allocate(unmappable).is_err());
+            assert_eq!(
+                allocator.last_error(),
+                Some(AllocFailure::MmapFailed(libc::ENOMEM))
+            );
         }
     }
 
-    fn verify_buckets_are_empty(allocator: Rulloc) {
+    fn verify_buckets_are_empty<const N: usize>(allocator: Rulloc<N>) {
         let internal = allocator.allocator.lock().unwrap();
         for bucket in &internal.buckets {
             assert_eq!(bucket.regions().len(), 0);
@@ -505,6 +6664,17 @@ mod tests {
         assert_eq!(internal.dyn_bucket.regions().len(), 0);
     }
 
+    fn verify_no_overlap<const N: usize>(allocator: &Rulloc<N>) {
+        unsafe { allocator.allocator.lock().unwrap().verify_no_overlap() };
+    }
+
+    fn validate_free_lists<const N: usize>(allocator: &Rulloc<N>) {
+        let internal = allocator.allocator.lock().unwrap();
+        for bucket in internal.buckets.iter().chain([&internal.dyn_bucket]) {
+            unsafe { bucket.validate_free_list() };
+        }
+    }
+
     /// We'll make all the threads do only allocs at the same time, then wait
     /// and do only deallocs at the same time.
     #[test]
@@ -539,6 +6709,52 @@ mod tests {
             }
         });
 
+        verify_no_overlap(&allocator);
+        validate_free_lists(&allocator);
+        verify_buckets_are_empty(allocator);
+    }
+
+    /// Each thread works on its own bucket. Buckets never share cache lines,
+    /// see [`Bucket`]. With one global lock the padding can't make this any
+    /// faster yet, so we only check the layout and that the workload runs
+    /// correctly.
+    #[test]
+    fn buckets_are_padded_to_cache_lines() {
+        assert_eq!(std::mem::align_of::<Bucket>(), CACHE_LINE_SIZE);
+        assert_eq!(std::mem::size_of::<Bucket>() % CACHE_LINE_SIZE, 0);
+
+        let allocator = Rulloc::<8>::with_bucket_sizes([16, 32, 64, 128, 256, 512, 1024, 2048]);
+
+        {
+            let internal = allocator.allocator.lock().unwrap();
+            for bucket in internal.buckets.iter().chain([&internal.dyn_bucket]) {
+                assert_eq!((bucket as *const Bucket).addr() % CACHE_LINE_SIZE, 0);
+            }
+        }
+
+        let num_allocs = if cfg!(miri) { 10 } else { 2000 };
+
+        thread::scope(|scope| {
+            for size in [16, 32, 64, 128, 256, 512, 1024, 2048] {
+                let allocator = &allocator;
+                scope.spawn(move || unsafe {
+                    let layout = Layout::array::<u8>(size).unwrap();
+                    let mut live = Vec::with_capacity(num_allocs);
+                    for i in 0..num_allocs {
+                        let address = allocator.allocate(layout).unwrap();
+                        address.as_mut_ptr().write(i as u8);
+                        live.push(address);
+                    }
+                    for (i, address) in live.into_iter().enumerate() {
+                        assert_eq!(address.as_mut_ptr().read(), i as u8);
+                        allocator.deallocate(address.cast(), layout);
+                    }
+                });
+            }
+        });
+
+        verify_no_overlap(&allocator);
+        validate_free_lists(&allocator);
         verify_buckets_are_empty(allocator);
     }
 
@@ -599,6 +6815,366 @@ mod tests {
             }
         });
 
+        verify_no_overlap(&allocator);
+        verify_buckets_are_empty(allocator);
+    }
+
+    /// Allocation that outlives the thread that made it.
+    struct Survivor(NonNull<u8>, Layout);
+
+    unsafe impl Send for Survivor {}
+
+    /// Same as [`multiple_threads_unsynchronized_allocs_and_deallocs`] but
+    /// each thread keeps one allocation alive, so that free lists are not
+    /// empty when we validate them after joining.
+    #[test]
+    fn multiple_threads_leave_valid_free_lists() {
+        let allocator = Rulloc::with_default_config();
+
+        let num_threads = 8;
+
+        let barrier = sync::Barrier::new(num_threads);
+
+        let survivors = thread::scope(|scope| {
+            let handles: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| unsafe {
+                        let layouts =
+                            [16, 256, 1024, 4096].map(|size| Layout::array::<u8>(size).unwrap());
+                        let num_allocs = if cfg!(miri) { 10 } else { 200 };
+
+                        for layout in layouts {
+                            barrier.wait();
+                            for _ in 0..num_allocs {
+                                let addr = allocator.allocate(layout).unwrap().cast::<u8>();
+                                allocator.deallocate(addr, layout);
+                            }
+                        }
+
+                        let layout = layouts[1];
+                        Survivor(allocator.allocate(layout).unwrap().cast(), layout)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        validate_free_lists(&allocator);
+
+        for Survivor(address, layout) in survivors {
+            unsafe { allocator.deallocate(address, layout) };
+        }
+
+        validate_free_lists(&allocator);
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn lenient_interior_pointers() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024])
+            .with_interior_pointer_policy(InteriorPointerPolicy::Lenient);
+
+        unsafe {
+            for size in [48, 512, 4096] {
+                let layout = Layout::array::<u8>(size).unwrap();
+                let address = allocator.allocate(layout).unwrap().cast::<u8>();
+                allocator.deallocate(NonNull::new_unchecked(address.as_ptr().add(16)), layout);
+            }
+
+            // Reallocation moves the whole allocation, not what's after the
+            // interior pointer.
+            let layout = Layout::array::<u8>(512).unwrap();
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            address.as_ptr().write_bytes(69, layout.size());
+            let interior = NonNull::new_unchecked(address.as_ptr().add(16));
+            let new_layout = Layout::array::<u8>(4096).unwrap();
+            let new_address = allocator.grow(interior, layout, new_layout).unwrap();
+            assert!(new_address.as_ref()[..layout.size()]
+                .iter()
+                .all(|byte| *byte == 69));
+            allocator.deallocate(new_address.cast(), new_layout);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn lenient_interior_pointers_with_canary() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024])
+            .with_canary_seed(0x5EED)
+            .with_interior_pointer_policy(InteriorPointerPolicy::Lenient);
+        let layout = Layout::array::<u8>(512).unwrap();
+
+        unsafe {
+            // The canary is checked at the end of the whole allocation, not
+            // 512 bytes after the interior pointer.
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            address.as_ptr().write_bytes(69, layout.size());
+            allocator.deallocate(NonNull::new_unchecked(address.as_ptr().add(16)), layout);
+
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            let interior = NonNull::new_unchecked(address.as_ptr().add(16));
+            let new_layout = Layout::array::<u8>(2048).unwrap();
+            let new_address = allocator.grow(interior, layout, new_layout).unwrap();
+            allocator.deallocate(new_address.cast(), new_layout);
+        }
+
+        #[cfg(feature = "leak-tracking")]
+        assert!(allocator.leak_report().starts_with("0 live allocations"));
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "deallocating interior pointer")]
+    fn interior_pointers_panic() {
+        let allocator = Rulloc::<2>::with_bucket_sizes([64, 1024])
+            .with_interior_pointer_policy(InteriorPointerPolicy::Panic);
+        let layout = Layout::array::<u8>(512).unwrap();
+
+        unsafe {
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            allocator.deallocate(NonNull::new_unchecked(address.as_ptr().add(16)), layout);
+        }
+    }
+
+    #[test]
+    fn verify_no_overlap_detects_overlap() {
+        let allocator = Rulloc::with_default_config();
+        let layout = Layout::array::<u8>(64 * 1024).unwrap();
+
+        unsafe {
+            let first = allocator.allocate(layout).unwrap();
+            let second = allocator.allocate(layout).unwrap();
+
+            {
+                let internal = allocator.allocator.lock().unwrap();
+                internal.verify_no_overlap();
+
+                // Regions are sorted, so stretching the first one until it
+                // reaches the second one makes them overlap.
+                let regions = internal.dyn_bucket.regions();
+                let low = regions.first().unwrap();
+                let high = regions.last().unwrap();
+                let size = low.as_ref().data.size;
+                (*low.as_ptr()).data.size = high.as_ptr().addr() - low.as_ptr().addr();
+
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    internal.verify_no_overlap()
+                }));
+
+                (*low.as_ptr()).data.size = size;
+                assert!(result.is_err());
+            }
+
+            allocator.deallocate(first.cast(), layout);
+            allocator.deallocate(second.cast(), layout);
+        }
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    /// Keeps a fragmented set of live objects of different sizes and then
+    /// allocates and deallocates objects that don't fit in any hole. Without
+    /// pools, every allocation has to skip all the holes in the free list.
+    /// With pools, all blocks have the same size, so any hole works and it's
+    /// the one at the head of the free list. Returns the number of regions
+    /// mapped and the number of free list hits of the first bucket during the
+    /// churn.
+    fn fragmented_churn<const N: usize>(allocator: &Rulloc<N>) -> (usize, usize) {
+        let num_objects = if cfg!(miri) { 20 } else { 1000 };
+        let layouts = (0..2 * num_objects)
+            .map(|i| Layout::array::<u8>(16 + i * 37 % 96).unwrap())
+            .collect::<Vec<_>>();
+        let churn_layout = Layout::array::<u8>(128).unwrap();
+        let mut live = Vec::with_capacity(layouts.len());
+        let mut churn = Vec::with_capacity(num_objects);
+
+        unsafe {
+            for layout in &layouts {
+                live.push(allocator.allocate(*layout).unwrap());
+            }
+
+            // Free every other object so that holes can't be merged.
+            for (address, layout) in live.iter().zip(&layouts).step_by(2) {
+                allocator.deallocate(address.cast(), *layout);
+            }
+
+            let before = allocator.full_stats().buckets[0];
+
+            for _ in 0..10 {
+                for _ in 0..num_objects {
+                    let address = allocator.allocate(churn_layout).unwrap();
+                    assert!(address.len() >= churn_layout.size());
+                    address.as_mut_ptr().write_bytes(69, churn_layout.size());
+                    churn.push(address);
+                }
+                for address in churn.drain(..) {
+                    allocator.deallocate(address.cast(), churn_layout);
+                }
+            }
+
+            let after = allocator.full_stats().buckets[0];
+
+            for (address, layout) in live.iter().zip(&layouts).skip(1).step_by(2) {
+                allocator.deallocate(address.cast(), *layout);
+            }
+
+            (
+                after.region_maps - before.region_maps,
+                after.freelist_hits - before.freelist_hits,
+            )
+        }
+    }
+
+    #[test]
+    fn pool_mode_reuses_holes() {
+        let general = Rulloc::<1>::with_bucket_sizes([128]);
+        let pool = Rulloc::<1>::pool_mode([128]);
+
+        let (general_maps, _) = fragmented_churn(&general);
+        let (pool_maps, pool_hits) = fragmented_churn(&pool);
+
+        validate_free_lists(&general);
+        validate_free_lists(&pool);
+        verify_buckets_are_empty(general);
+
+        // Pools keep their regions until they are dropped.
+        assert!(pool.total_regions() > 0);
+        assert_eq!(pool.poll_stats().bytes_allocated, 0);
+
+        // None of the holes fit the churn without pools, so new regions are
+        // needed. With pools every single allocation reuses a hole.
+        let num_churns = 10 * if cfg!(miri) { 20 } else { 1000 };
+        assert!(general_maps > 0);
+        assert_eq!(pool_maps, 0);
+        assert_eq!(pool_hits, num_churns);
+    }
+
+    /// A monitoring thread polls stats in a tight loop while other threads
+    /// allocate. Polling doesn't need the lock, so nobody should be waiting
+    /// on anybody.
+    #[test]
+    fn poll_stats_while_allocating() {
+        let allocator = Rulloc::with_default_config();
+
+        let num_threads = 4;
+        let num_allocs = if cfg!(miri) { 10 } else { 500 };
+        let done = sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let monitor = scope.spawn(|| {
+                let mut previous = allocator.poll_stats();
+                let mut polls = 0;
+                while !done.load(sync::atomic::Ordering::Relaxed) {
+                    let stats = allocator.poll_stats();
+                    assert!(stats.allocations >= previous.allocations);
+                    assert!(stats.deallocations >= previous.deallocations);
+                    previous = stats;
+                    polls += 1;
+                }
+                polls
+            });
+
+            let workers: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| unsafe {
+                        for i in 0..num_allocs {
+                            let layout = Layout::array::<u8>(16 + i % 2048).unwrap();
+                            let addr = allocator.allocate(layout).unwrap();
+                            allocator.deallocate(addr.cast(), layout);
+                        }
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            done.store(true, sync::atomic::Ordering::Relaxed);
+            assert!(monitor.join().unwrap() > 0);
+        });
+
+        verify_no_overlap(&allocator);
+
+        let stats = allocator.poll_stats();
+        assert_eq!(stats.allocations, num_threads * num_allocs);
+        assert_eq!(stats.deallocations, num_threads * num_allocs);
+        assert_eq!(stats.bytes_allocated, 0);
+        assert_eq!(stats.bytes_mapped, 0);
+
+        verify_buckets_are_empty(allocator);
+    }
+
+    /// Same as above but with [`Rulloc::full_stats`], which has to be
+    /// consistent at every snapshot.
+    #[test]
+    fn full_stats_while_allocating() {
+        let allocator = Rulloc::with_default_config();
+
+        let num_threads = 4;
+        let num_allocs = if cfg!(miri) { 10 } else { 500 };
+        let done = sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let monitor = scope.spawn(|| {
+                let mut snapshots = 0;
+                while !done.load(sync::atomic::Ordering::Relaxed) {
+                    let stats = allocator.full_stats();
+                    assert!(stats.bytes_allocated + stats.bytes_free <= stats.bytes_mapped);
+                    assert_eq!(
+                        stats
+                            .iter()
+                            .map(|bucket| bucket.bytes_mapped)
+                            .sum::<usize>(),
+                        stats.bytes_mapped
+                    );
+                    assert_eq!(
+                        stats.iter().map(|bucket| bucket.free_bytes).sum::<usize>(),
+                        stats.bytes_free
+                    );
+                    snapshots += 1;
+                }
+                snapshots
+            });
+
+            let workers: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|| unsafe {
+                        let mut live = Vec::new();
+                        for i in 0..num_allocs {
+                            let layout = Layout::array::<u8>(16 + i * 7 % 4096).unwrap();
+                            live.push((allocator.allocate(layout).unwrap(), layout));
+                            if live.len() > 8 {
+                                let (addr, layout) = live.swap_remove(i % live.len());
+                                allocator.deallocate(addr.cast(), layout);
+                            }
+                        }
+                        for (addr, layout) in live {
+                            allocator.deallocate(addr.cast(), layout);
+                        }
+                    })
+                })
+                .collect();
+
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            done.store(true, sync::atomic::Ordering::Relaxed);
+            assert!(monitor.join().unwrap() > 0);
+        });
+
+        let stats = allocator.full_stats();
+        assert_eq!(stats.bytes_allocated, 0);
+        assert_eq!(stats.bytes_mapped, 0);
+
         verify_buckets_are_empty(allocator);
     }
 }
@@ -28,6 +28,8 @@ pub(crate) const BLOCK_HEADER_SIZE: usize = mem::size_of::<Header<Block>>();
 /// +----------------------------+          | Block                |
 /// | is free flag (1 byte)      |          |                      |
 /// +----------------------------+          |                      |
+/// | bucket index (2 bytes)     |          |                      |
+/// +----------------------------+          |                      |
 /// | padding (struct alignment) |   <------+                      |
 /// +----------------------------+          <----------------------+
 /// |       Block content        |   <------+
@@ -58,6 +60,35 @@ pub(crate) struct Block {
     pub size: usize,
     /// Whether this block can be used or not.
     pub is_free: bool,
+    /// Whether the pages of this free block were already released by
+    /// [`crate::Rulloc::drain_free_lists`]. Cleared every time the block is
+    /// added to the free list or merged with its neighbour, since then it
+    /// might contain pages that are backed by physical memory again. Fits in
+    /// the padding after [`Self::is_free`] as well.
+    pub purged: bool,
+    /// Index of the bucket that owns the region of this block, so that we can
+    /// deallocate blocks placed with [`crate::Rulloc::allocate_in_bucket`]
+    /// without relying on the layout. It fits in the padding after
+    /// [`Self::is_free`], so it doesn't make headers any bigger.
+    pub bucket: u16,
+    /// Layout given by the user when this block was allocated (or last
+    /// reallocated), `None` if the block is not in use. The size of the block
+    /// is its capacity, the size of this layout is how much of it the user
+    /// actually asked for. Only compiled with the `layout-tracking` feature
+    /// because it makes every header bigger, see
+    /// [`crate::Stats::layout_mismatches`] and [`crate::BucketStats::bytes_used`].
+    #[cfg(feature = "layout-tracking")]
+    pub layout: Option<Layout>,
+    /// Generation of the bucket when this block was allocated, only stored in
+    /// debug builds. See [`crate::generation`].
+    #[cfg(debug_assertions)]
+    pub generation: usize,
+    /// Alignment of the layout this block was allocated with, stored as the
+    /// exponent of the power of 2 and only in debug builds, so that
+    /// deallocating with a different alignment can be caught. Just like
+    /// [`Self::bucket`], it fits in the padding after [`Self::is_free`].
+    #[cfg(debug_assertions)]
+    pub align_log2: u8,
 }
 
 impl Header<Block> {
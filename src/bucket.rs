@@ -1,21 +1,40 @@
 use std::{
     alloc::{AllocError, Layout},
-    mem::ManuallyDrop,
+    ffi::c_int,
+    mem::{self, ManuallyDrop},
     ptr::{self, NonNull},
 };
 
 use crate::{
     alignment,
     block::{Block, BLOCK_HEADER_SIZE, MIN_BLOCK_SIZE},
-    freelist::FreeList,
+    failure::{AllocFailure, UnmapFailures},
+    fit::{FirstFit, FitPolicy, FreeBlocks},
+    freelist::{FreeList, FreeListOrder},
     header::Header,
+    interior::InteriorPointerPolicy,
     list::LinkedList,
-    platform,
+    platform::{self, Protection},
     realloc::{Realloc, ReallocMethod},
-    region::{determine_region_length, Region, REGION_HEADER_SIZE},
+    region::{determine_region_length, Region, RegionInfo, RegionPlacement, REGION_HEADER_SIZE},
+    report::{BlockRecord, BlockReport},
+    sizeclass::SizeClasses,
+    source::MmapSource,
+    stats::BucketStats,
+    tailcache::TailCache,
     AllocResult, Pointer,
 };
 
+/// Minimum number of blocks carved out of each region mapped by a pool. See
+/// [`Bucket::set_pool`].
+pub(crate) const POOL_REGION_BLOCKS: usize = 64;
+
+/// Size of a cache line on most CPUs. Must match the `repr(align)` of
+/// [`Bucket`], which only accepts literals.
+pub(crate) const CACHE_LINE_SIZE: usize = 64;
+
+const _: () = assert!(mem::align_of::<Bucket>() == CACHE_LINE_SIZE);
+
 /// This, on itself, is actually a memory allocator. But we use multiple of
 /// them for optimization purposes. Basically, we can configure different
 /// buckets that will perform allocations of different sizes. So, for example,
@@ -43,1103 +62,3905 @@ use crate::{
 ///     +--- regions.head                         +--- regions.tail
 ///
 /// ```
-pub(crate) struct Bucket {
+///
+/// Which free block is used for each allocation is decided by `P`, see
+/// [`FitPolicy`].
+///
+/// Buckets are stored next to each other in
+/// [`crate::allocator::InternalAllocator`], and the free list head and
+/// counters of one bucket are written on every allocation. Each bucket is
+/// aligned to [`CACHE_LINE_SIZE`] so that the end of one bucket and the
+/// beginning of the next one never share a cache line, otherwise threads
+/// working on different buckets would keep invalidating each other's cache
+/// once buckets can be locked independently:
+///
+/// ```text
+/// +--------------------------------+--------------------------------+
+/// |   buckets[0]   |    Padding    |   buckets[1]   |    Padding    | ...
+/// +--------------------------------+--------------------------------+
+/// ^                                ^
+/// |                                |
+/// +-- Cache line boundary          +-- Cache line boundary
+/// ```
+#[repr(align(64))]
+pub(crate) struct Bucket<P = FirstFit> {
     /// Free list.
     free_blocks: ManuallyDrop<FreeList>,
-    /// All regions mapped by this bucket.
+    /// All regions mapped by this bucket, sorted by address so that adjacent
+    /// regions are also next to each other in the list.
     regions: ManuallyDrop<LinkedList<Region>>,
+    /// Total length of all the regions in [`Self::regions`], including
+    /// headers. We maintain this instead of iterating over regions because
+    /// it's needed after every allocation for [`crate::Stats`].
+    bytes_mapped: usize,
+    /// Maximum value of [`Self::bytes_mapped`]. Requesting a region that
+    /// would go past this fails with [`AllocFailure::LimitExceeded`].
+    limit: usize,
+    /// Why the last allocation failed, if it hasn't been taken yet. See
+    /// [`crate::failure`].
+    failure: Option<AllocFailure>,
+    /// Regions or parts of regions that the kernel refused to unmap since
+    /// the last [`Self::take_unmap_failures`]. See [`crate::failure`].
+    unmap_failures: UnmapFailures,
+    /// Tails of blocks that have been shrunk in place. See [`TailCache`].
+    tail_cache: TailCache,
+    /// Blocks of small requests grouped by size. See [`SizeClasses`].
+    size_classes: SizeClasses,
+    /// How to handle interior pointers on deallocation (debug builds only).
+    interior_pointers: InteriorPointerPolicy,
+    /// Alignment and cache coloring of new regions.
+    placement: RegionPlacement,
+    /// Free block searching strategy.
+    policy: P,
+    /// Position of this bucket in the allocator, stored in every block header.
+    /// See [`Block::bucket`].
+    index: u16,
+    /// Size of every block if this bucket is a pool, see [`Self::set_pool`].
+    pool: Option<usize>,
+    /// Whether shrinking gives away all the bytes the allocation no longer
+    /// needs, see [`Self::set_exact_shrink`].
+    exact_shrink: bool,
+    /// Where freed blocks are inserted in the free list.
+    free_list_order: FreeListOrder,
+    /// Whether block contents are zeroed on deallocation, see
+    /// [`Self::set_sensitive`].
+    sensitive: bool,
+    /// Whether the pages of freed blocks are marked as cold, see
+    /// [`Self::set_cold_free_cache`].
+    cold_free_cache: bool,
+    /// Byte written to every new region, see [`Self::set_scrub_pattern`].
+    scrub_pattern: Option<u8>,
+    /// Byte that fills the block given by the last allocation, if it was
+    /// carved from a region mapped by that same allocation. See
+    /// [`Self::take_fresh`].
+    fresh: Option<u8>,
+    /// Allocations served without mapping a new region, see
+    /// [`BucketStats::hit_rate`].
+    freelist_hits: usize,
+    /// Number of regions mapped since this bucket was created.
+    region_maps: usize,
+    /// Deallocations where the block had a free neighbour, see
+    /// [`BucketStats::coalesced_frees`].
+    coalesced_frees: usize,
+    /// Deallocations where the block had no free neighbour, see
+    /// [`BucketStats::isolated_frees`].
+    isolated_frees: usize,
+    /// Number of deallocations with a layout different from the one recorded
+    /// in the block header. See [`crate::Stats::layout_mismatches`].
+    #[cfg(feature = "layout-tracking")]
+    layout_mismatches: usize,
+    /// Incremented on every deallocation, see [`crate::generation`].
+    #[cfg(debug_assertions)]
+    generation: usize,
 }
 
 impl Bucket {
-    /// Builds a new empty [`Bucket`].
+    /// Builds a new empty [`Bucket`] that uses [`FirstFit`].
     pub const fn new() -> Self {
+        Self::with_policy(FirstFit)
+    }
+}
+
+impl<P: FitPolicy> Bucket<P> {
+    /// Builds a new empty [`Bucket`] that uses the given [`FitPolicy`].
+    pub const fn with_policy(policy: P) -> Self {
         Self {
             free_blocks: ManuallyDrop::new(FreeList::new()),
             regions: ManuallyDrop::new(LinkedList::new()),
+            bytes_mapped: 0,
+            limit: usize::MAX,
+            failure: None,
+            unmap_failures: UnmapFailures::new(),
+            tail_cache: TailCache::new(),
+            size_classes: SizeClasses::new(),
+            interior_pointers: InteriorPointerPolicy::Trust,
+            placement: RegionPlacement::new(),
+            policy,
+            index: 0,
+            pool: None,
+            exact_shrink: false,
+            free_list_order: FreeListOrder::Fifo,
+            sensitive: false,
+            cold_free_cache: false,
+            scrub_pattern: None,
+            fresh: None,
+            freelist_hits: 0,
+            region_maps: 0,
+            coalesced_frees: 0,
+            isolated_frees: 0,
+            #[cfg(feature = "layout-tracking")]
+            layout_mismatches: 0,
+            #[cfg(debug_assertions)]
+            generation: 0,
+        }
+    }
+
+    /// Replaces the [`FitPolicy`] of this bucket. Only used when configuring
+    /// the allocator, the bucket must not contain any regions yet.
+    pub fn replace_policy<Q: FitPolicy>(self, policy: Q) -> Bucket<Q> {
+        debug_assert!(self.regions.len() == 0, "policy replaced after allocating");
+
+        // We can't move fields out of a type that implements Drop, but the
+        // bucket is empty so there's nothing to drop other than the policy.
+        let mut bucket = ManuallyDrop::new(self);
+        unsafe {
+            ptr::drop_in_place(&mut bucket.policy);
+            Bucket {
+                free_blocks: ptr::read(&bucket.free_blocks),
+                regions: ptr::read(&bucket.regions),
+                bytes_mapped: bucket.bytes_mapped,
+                limit: bucket.limit,
+                failure: bucket.failure,
+                unmap_failures: bucket.unmap_failures,
+                tail_cache: ptr::read(&bucket.tail_cache),
+                size_classes: ptr::read(&bucket.size_classes),
+                interior_pointers: bucket.interior_pointers,
+                placement: ptr::read(&bucket.placement),
+                policy,
+                index: bucket.index,
+                pool: bucket.pool,
+                exact_shrink: bucket.exact_shrink,
+                free_list_order: bucket.free_list_order,
+                sensitive: bucket.sensitive,
+                cold_free_cache: bucket.cold_free_cache,
+                scrub_pattern: bucket.scrub_pattern,
+                fresh: bucket.fresh,
+                freelist_hits: bucket.freelist_hits,
+                region_maps: bucket.region_maps,
+                coalesced_frees: bucket.coalesced_frees,
+                isolated_frees: bucket.isolated_frees,
+                #[cfg(feature = "layout-tracking")]
+                layout_mismatches: bucket.layout_mismatches,
+                #[cfg(debug_assertions)]
+                generation: bucket.generation,
+            }
+        }
+    }
+
+    /// Enables or disables the [`TailCache`] of this bucket. If the cache is
+    /// disabled, all the tails it contains are released.
+    pub unsafe fn set_tail_cache(&mut self, enabled: bool) {
+        if !enabled {
+            while let Some(tail) = self.tail_cache.pop() {
+                self.release_tail(tail);
+            }
+        }
+
+        self.tail_cache.set_enabled(enabled);
+    }
+
+    /// Enables or disables [`SizeClasses`] in this bucket. If they are
+    /// disabled, all the blocks they retain are released.
+    pub unsafe fn set_size_classes(&mut self, enabled: bool) {
+        if !enabled {
+            while let Some(block) = self.size_classes.pop_any() {
+                self.release_block(block);
+            }
+        }
+
+        self.size_classes.set_enabled(enabled);
+    }
+
+    /// Sets the position of this bucket in the allocator, see [`Self::index`].
+    pub const fn set_index(&mut self, index: u16) {
+        self.index = index;
+    }
+
+    /// Turns this bucket into a pool of blocks that can hold `size` bytes
+    /// each. A pool never splits or merges blocks after carving them out of a
+    /// new region, so allocating and deallocating is just popping and pushing
+    /// the head of the free list:
+    ///
+    /// ```text
+    ///            allocate() / deallocate()
+    ///                      |
+    ///                      v
+    ///                  +-------+    +-------+    +-------+
+    /// free_blocks ---> | Free  | -> | Free  | -> | Free  |
+    ///                  +-------+    +-------+    +-------+
+    /// ```
+    ///
+    /// The price is memory. Blocks are always as big as the largest request,
+    /// and since free blocks are never merged their regions are never
+    /// returned to the kernel until the bucket is dropped or shrunk, see
+    /// [`Self::shrink_to`]. To give regions a chance to become completely
+    /// free, the blocks of empty regions are moved to the back of the free
+    /// list, so requests are served from regions that are already in use
+    /// first, see [`Self::release_to_pool`]. Only requests aligned to
+    /// [`alignment::POINTER_SIZE`] or less can be served by a pool.
+    pub const fn set_pool(&mut self, size: usize) {
+        self.pool = Some(size);
+    }
+
+    /// Whether this bucket is a pool, see [`Self::set_pool`].
+    #[inline]
+    pub fn is_pool(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// When enabled, shrinking assumes that the allocation won't grow again,
+    /// like a `Vec` converted into a `Box<[T]>`. Normally, if the bytes that
+    /// are no longer needed can't hold a block header plus
+    /// [`MIN_BLOCK_SIZE`] they stay in the shrunk block. With exact shrink,
+    /// those bytes are given to the next block if it's free:
+    ///
+    /// ```text
+    /// Before:
+    /// +--------+-----------------+-------+--------+----------------+
+    /// | Header | Shrunk content  | Slack | Header |   Free block   |
+    /// +--------+-----------------+-------+--------+----------------+
+    ///
+    /// After:
+    /// +--------+-----------------+--------+------------------------+
+    /// | Header | Shrunk content  | Header |       Free block       |
+    /// +--------+-----------------+--------+------------------------+
+    /// ```
+    ///
+    /// The [`TailCache`] is not used for exact shrinks, since there's no
+    /// point in retaining tails for allocations that won't grow.
+    pub fn set_exact_shrink(&mut self, enabled: bool) {
+        self.exact_shrink = enabled;
+    }
+
+    /// Whether exact shrink is enabled, see [`Self::set_exact_shrink`].
+    #[inline]
+    pub fn exact_shrink(&self) -> bool {
+        self.exact_shrink
+    }
+
+    /// Sets the position where freed blocks are inserted in the free list,
+    /// see [`FreeListOrder`]. Blocks that are already free are reinserted so
+    /// that sorted orders hold from now on. Pools always reuse the most
+    /// recently freed block, so they ignore the order.
+    pub unsafe fn set_free_list_order(&mut self, order: FreeListOrder) {
+        self.free_list_order = order;
+
+        // Inserting a block overwrites its node, so the next one has to be
+        // read first.
+        let old = mem::replace(&mut *self.free_blocks, FreeList::new());
+        let mut current = old.first();
+
+        while let Some(node) = current {
+            current = node.as_ref().next;
+            self.insert_free_block(Header::<Block>::from_free_list_node(node));
+        }
+    }
+
+    /// Adds `block` to the free list honoring [`Self::free_list_order`].
+    unsafe fn insert_free_block(&mut self, block: NonNull<Header<Block>>) {
+        match self.pool {
+            Some(_) => self.free_blocks.append_block(block),
+            None => self.free_blocks.insert_block(block, self.free_list_order),
         }
     }
 
-    /// Only used for testing at [`crate::allocator`].
+    /// Sensitive buckets zero the entire content of each block when it's
+    /// deallocated, so secrets don't outlive the allocation that stored them.
+    /// Blocks shrunk in place also zero everything after the new size.
+    /// This happens before the block is added to the free list or retained by
+    /// [`SizeClasses`], so the only bytes that are not zero afterwards are the
+    /// ones overwritten by the free list node or the size class link, which
+    /// only contain pointers to other blocks.
+    pub fn set_sensitive(&mut self, enabled: bool) {
+        self.sensitive = enabled;
+    }
+
+    /// Whether this bucket zeroes blocks on deallocation.
     #[cfg(test)]
-    pub fn regions(&self) -> &LinkedList<Region> {
-        &self.regions
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
     }
 
-    /// Allocates a new block that can fit at least `layout.size()` bytes.
-    /// Because of alignment and headers, it might allocate a bigger block than
-    /// needed. As long as no more than `layout.pad_to_align().size()` bytes are
-    /// written on the content part of the block it should be fine.
-    pub unsafe fn allocate(&mut self, layout: Layout) -> AllocResult {
-        let size = alignment::minimum_block_size_needed_for(layout);
+    /// When enabled, the pages of every block that goes back to the free
+    /// list are marked as cold (see [`Self::advise_cold`]), so the kernel
+    /// reclaims them first under memory pressure. Regions that become empty
+    /// are still returned to the kernel as usual.
+    pub fn set_cold_free_cache(&mut self, enabled: bool) {
+        self.cold_free_cache = enabled;
+    }
 
-        let free_block = match self.find_free_block(size) {
-            Some(block) => block,
-            None => self.request_region(size)?.as_ref().first_block(),
-        };
+    /// Fills every new region with `pattern` before writing any header, so
+    /// allocations served from fresh regions are not zeroed. `None` leaves
+    /// the pages as given by the kernel, which is the default. See
+    /// [`crate::Rulloc::with_scrub_pattern`].
+    pub fn set_scrub_pattern(&mut self, pattern: Option<u8>) {
+        self.scrub_pattern = pattern;
+    }
 
-        self.split_block_if_possible(free_block, size);
-        self.free_blocks.remove_block(free_block);
+    /// Byte written to new regions, see [`Self::set_scrub_pattern`].
+    #[inline]
+    pub fn scrub_pattern(&self) -> Option<u8> {
+        self.scrub_pattern
+    }
 
-        let address = self.add_padding_if_needed(free_block, layout.align());
+    /// Sets the maximum number of bytes this bucket can map, see
+    /// [`Self::limit`].
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
 
-        Ok(address)
+    /// If the block given by the last call to [`Self::allocate`] or
+    /// [`Self::allocate_near`] comes from a region mapped by that call,
+    /// returns the byte that fills its contents, except for the free list
+    /// node written at the beginning. That's the scrub pattern if there is
+    /// one, see [`Self::set_scrub_pattern`], or zero if the region is an
+    /// anonymous mapping, see [`RegionPlacement::map`]. Otherwise nothing is
+    /// known about the contents and this returns `None`. Forgets the value.
+    pub fn take_fresh(&mut self) -> Option<u8> {
+        self.fresh.take()
     }
 
-    /// Deallocates the given pointer. Memory might not be returned to the OS
-    /// if the region where `address` is located still contains used blocks.
-    /// However, the freed block will be reused later if possible.
-    pub unsafe fn deallocate(&mut self, address: NonNull<u8>, layout: Layout) {
-        let mut block = Header::<Block>::from_allocated_pointer(address, layout);
+    /// Returns the reason of the last failed allocation and forgets it.
+    pub fn take_failure(&mut self) -> Option<AllocFailure> {
+        self.failure.take()
+    }
 
-        // This block is now free as it is about to be deallocated.
-        self.free_blocks.append_block(block);
+    /// Returns the unmap failures since the last call and forgets them.
+    pub fn take_unmap_failures(&mut self) -> UnmapFailures {
+        self.unmap_failures.take()
+    }
 
-        // If previous block is merged then the address will change.
-        block = self.merge_surrounding_free_blocks_if_possible(block);
+    /// Remembers `failure` until [`Self::take_failure`] is called and returns
+    /// the error that should be given to the caller.
+    fn fail(&mut self, failure: AllocFailure) -> AllocError {
+        self.failure = Some(failure);
+        AllocError
+    }
 
-        let region = block.as_ref().data.region;
+    /// Sets the [`InteriorPointerPolicy`] of this bucket.
+    pub fn set_interior_pointer_policy(&mut self, policy: InteriorPointerPolicy) {
+        self.interior_pointers = policy;
+    }
 
-        // All blocks have been merged into one, so we can return this region
-        // back to the kernel.
-        if region.as_ref().num_blocks() == 1 {
-            // The only block in this region is no longer valid because the
-            // region is about to be unmapped.
-            self.free_blocks.remove_block(block);
+    /// Sets the alignment of new regions, see [`RegionPlacement`].
+    pub fn set_region_alignment(&mut self, align: usize) {
+        self.placement.set_align(align);
+    }
 
-            // Region has to be removed before unmapping, otherwise seg fault.
-            self.regions.remove(region);
+    /// Enables or disables the huge pages hint for new regions, see
+    /// [`RegionPlacement`].
+    pub fn set_huge_pages(&mut self, enabled: bool) {
+        self.placement.set_huge_pages(enabled);
+    }
 
-            platform::return_memory(region.cast(), region.as_ref().total_size());
-        }
+    /// Sets the extra `mmap` flags of new regions, see [`RegionPlacement`].
+    pub fn set_mmap_flags(&mut self, flags: c_int) {
+        self.placement.set_mmap_flags(flags);
     }
 
-    /// Executes the reallocation specified by `realloc`. When possible,
-    /// reallocation is done in place to avoid copying contents from one block
-    /// to another, but changes in alignment constraints might prevent that.
-    pub unsafe fn reallocate(&mut self, realloc: &Realloc) -> AllocResult {
-        // Reallocation is more complicated than allocation or deallocation,
-        // so study the code in the functions below to understand what's
-        // happening.
-        self.try_reallocate_in_place(realloc)
-            .or_else(|_| self.try_reallocate_on_another_block(realloc))
+    /// Extra `mmap` flags of new regions, see [`RegionPlacement`].
+    pub fn mmap_flags(&self) -> c_int {
+        self.placement.mmap_flags()
+    }
+
+    /// Sets the source of new regions, see [`RegionPlacement`].
+    pub fn set_source(&mut self, source: Option<&'static dyn MmapSource>) {
+        self.placement.set_source(source);
+    }
+
+    /// Sets the length of the guard pages of new regions, see
+    /// [`RegionPlacement`].
+    pub fn set_region_guard(&mut self, bytes: usize) {
+        self.placement.set_guard(bytes);
+    }
+
+    /// Sets the cache coloring stride of new regions, see [`RegionPlacement`].
+    pub fn set_color_stride(&mut self, color_stride: usize) {
+        self.placement.set_color_stride(color_stride);
+    }
+
+    /// Number of free blocks that can fit `layout` right now without
+    /// splitting or mapping new regions. A big free block counts only once
+    /// even if it could be split to fit `layout` multiple times.
+    pub fn available(&self, layout: Layout) -> usize {
+        FreeBlocks::new(&self.free_blocks, layout).count()
+    }
+
+    /// Length of the slice returned by [`Self::allocate`] for `layout` when
+    /// the block is split at the exact size needed. Alignment padding is not
+    /// part of the slice, so for alignments greater than
+    /// [`alignment::POINTER_SIZE`] this is the content without padding.
+    pub fn capacity_hint(&self, layout: Layout) -> usize {
+        if let Some(size) = self.pool {
+            return alignment::minimum_block_size_excluding_padding(unsafe {
+                Layout::from_size_align_unchecked(size, 1)
+            });
+        }
+
+        let layout = self.size_classes.round(layout);
+
+        if layout.align() <= alignment::POINTER_SIZE {
+            alignment::minimum_block_size_needed_for(layout)
+        } else {
+            layout.size() + layout.padding_needed_for(alignment::POINTER_SIZE)
+        }
     }
 
-    /// Returns the first free block in the free list or `None` if we didn't
-    /// find any.
-    unsafe fn find_free_block(&self, size: usize) -> Pointer<Header<Block>> {
+    /// Sum of the content sizes of all blocks in the free list.
+    pub fn free_bytes(&self) -> usize {
         self.free_blocks
-            .iter_blocks()
-            .find(|block| block.as_ref().size() >= size)
+            .iter()
+            .map(|node| unsafe { Header::<Block>::from_free_list_node(node).as_ref().size() })
+            .sum()
     }
 
-    /// This function executes the algorithm described at
-    /// [`alignment::AlignmentBackPointer`]. The caller must guarantee that
-    /// the given block meets the size constraints needed to introduce enough
-    /// padding without overriding other headers. See
-    /// [`alignment::minimum_block_size_needed_for`].
-    unsafe fn add_padding_if_needed(
-        &self,
-        block: NonNull<Header<Block>>,
-        align: usize,
-    ) -> NonNull<[u8]> {
-        let content_address = Header::content_address_of(block);
+    /// Zeroes the counters of [`BucketStats`] that accumulate over time,
+    /// regions and free blocks are not affected.
+    pub fn reset_stats(&mut self) {
+        self.freelist_hits = 0;
+        self.region_maps = 0;
+        self.coalesced_frees = 0;
+        self.isolated_frees = 0;
+        #[cfg(feature = "layout-tracking")]
+        {
+            self.layout_mismatches = 0;
+        }
+    }
 
-        if align <= alignment::POINTER_SIZE {
-            return NonNull::slice_from_raw_parts(content_address, block.as_ref().size());
+    /// Snapshot of this bucket, see [`BucketStats`].
+    pub fn stats(&self) -> BucketStats {
+        BucketStats {
+            regions: self.regions.len(),
+            bytes_mapped: self.bytes_mapped,
+            free_blocks: self.free_blocks.len(),
+            free_bytes: self.free_bytes(),
+            freelist_hits: self.freelist_hits,
+            region_maps: self.region_maps,
+            coalesced_frees: self.coalesced_frees,
+            isolated_frees: self.isolated_frees,
+            #[cfg(feature = "layout-tracking")]
+            layout_mismatches: self.layout_mismatches,
+            #[cfg(feature = "layout-tracking")]
+            bytes_used: self.bytes_used(),
         }
+    }
 
-        let (next_aligned, padding) = alignment::next_aligned(content_address, align);
+    /// Sum of the sizes requested by the user for all the blocks in use,
+    /// see [`BucketStats::bytes_used`].
+    #[cfg(feature = "layout-tracking")]
+    fn bytes_used(&self) -> usize {
+        self.regions
+            .iter()
+            .flat_map(|region| unsafe { region.as_ref().data.blocks.iter() })
+            .filter_map(|block| unsafe { block.as_ref().data.layout })
+            .map(|layout| layout.size())
+            .sum()
+    }
 
-        ptr::write(alignment::back_pointer_of(next_aligned).as_ptr(), block);
+    /// Number of bytes currently mapped by this bucket.
+    #[inline]
+    pub fn bytes_mapped(&self) -> usize {
+        self.bytes_mapped
+    }
 
-        return NonNull::slice_from_raw_parts(next_aligned, block.as_ref().size() - padding);
+    /// Number of deallocations with mismatched layouts in this bucket.
+    #[cfg(feature = "layout-tracking")]
+    #[inline]
+    pub fn layout_mismatches(&self) -> usize {
+        self.layout_mismatches
     }
 
-    /// Requests a new memory region from the kernel where we can fit `size`
-    /// bytes plus headers. See [`determine_region_length`]. The returned region
-    /// will be already initialized with one single free block that takes up all
-    /// the space minus headers:
+    /// Pushes the address and total length of every region to `regions` and
+    /// forgets about them, so they won't be unmapped when this bucket is
+    /// dropped. The bucket is empty afterwards, which means that any pointer
+    /// previously allocated by it must not be deallocated here anymore.
+    pub fn take_regions(&mut self, regions: &mut Vec<(NonNull<u8>, usize)>) {
+        regions.extend(
+            self.regions
+                .iter()
+                .map(|region| unsafe { (region.cast(), region.as_ref().total_size()) }),
+        );
+
+        self.forget_regions();
+    }
+
+    /// Returns all the regions of this bucket to the kernel and leaves it
+    /// empty, just like a new bucket but keeping the configuration. Returns
+    /// the number of blocks that were still in use and the sum of their
+    /// [`Region::requested`] bytes, so the caller can discount them.
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that none of the pointers allocated by this
+    /// bucket will be used again, not even for deallocation.
+    pub unsafe fn clear(&mut self) -> (usize, usize) {
+        let mut not_free = 0;
+        let mut requested = 0usize;
+
+        for region in self.regions.iter() {
+            requested = requested.wrapping_add(region.as_ref().data.requested);
+            not_free += region
+                .as_ref()
+                .data
+                .blocks
+                .iter()
+                .filter(|block| !block.as_ref().is_free())
+                .count();
+        }
+
+        // Blocks retained by the caches are not free, but not in use either.
+        let retained = self.size_classes.total_len() + self.tail_cache.len();
+
+        self.return_all_regions();
+        self.forget_regions();
+
+        (not_free - retained, requested)
+    }
+
+    /// Frees all the blocks of `region` at once without merging them one by
+    /// one. The region ends up with one big free block, just like a freshly
+    /// mapped region, but unlike [`Self::deallocate`] it stays mapped so that
+    /// the next batch of allocations can reuse it:
     ///
     /// ```text
-    /// +--------+-------------------------------------+
-    /// |        | +---------------------------------+ |
-    /// | Region | |          Big Free Block         | |
-    /// |        | +---------------------------------+ |
-    /// +--------+-------------------------------------+
+    /// +--------+-------+-------+-------+-------+     +--------+-----------------------------+
+    /// | Region | Block | Free  | Block | Block | --> | Region |       Big Free Block        |
+    /// +--------+-------+-------+-------+-------+     +--------+-----------------------------+
     /// ```
     ///
-    /// The block must be split if it is too large in order to reduce
-    /// fragmentation.
+    /// Every block of the region is visited once to unlink the free ones from
+    /// the free list, so this is O(blocks in the region), but nothing is
+    /// merged and the content of blocks in use is never touched. Blocks of
+    /// this region retained by [`TailCache`] and [`SizeClasses`] are dropped
+    /// from the caches, blocks of other regions stay cached. Same as
+    /// [`Self::clear`], returns the number of blocks that were still in use
+    /// and the sum of their [`Region::requested`] bytes.
     ///
-    /// # Arguments
+    /// # Safety
     ///
-    /// * `size` - The number of bytes (must be aligned to power of 2) that
-    /// need to be allocated **without including any headers**.
-    unsafe fn request_region(
-        &mut self,
-        size: usize,
-    ) -> Result<NonNull<Header<Region>>, AllocError> {
-        let length = determine_region_length(size);
+    /// `region` must belong to this bucket and none of the pointers allocated
+    /// in it can be used again, not even for deallocation.
+    ///
+    /// # Panics
+    ///
+    /// If this bucket is a pool, see [`Self::set_pool`].
+    pub unsafe fn free_region(&mut self, mut region: NonNull<Header<Region>>) -> (usize, usize) {
+        assert!(
+            self.pool.is_none(),
+            "regions of pools can't be freed at once, their blocks never merge"
+        );
 
-        let address = platform::request_memory(length).ok_or(AllocError)?;
+        // Blocks retained by the caches are not free, but not in use either.
+        let retained = self
+            .size_classes
+            .retain(|block| block.as_ref().data.region != region)
+            + self
+                .tail_cache
+                .retain(|tail| tail.as_ref().data.region != region);
 
-        let mut region = self.regions.append(
-            Region {
-                blocks: LinkedList::new(),
-                size: length - REGION_HEADER_SIZE,
-            },
-            address,
-        );
+        let mut not_free = 0;
+        for block in region.as_ref().data.blocks.iter() {
+            if block.as_ref().is_free() {
+                self.free_blocks.remove_block(block);
+            } else {
+                not_free += 1;
+            }
+        }
 
+        let requested = mem::replace(&mut region.as_mut().data.requested, 0);
+
+        // The first block might not start right after the region header if
+        // cache coloring is enabled, see [`Self::request_region`].
+        let first = region.as_ref().first_block().cast::<u8>();
+        let offset = first
+            .as_ptr()
+            .offset_from(Header::content_address_of(region).as_ptr()) as usize;
+        let size = region.as_ref().size() - BLOCK_HEADER_SIZE - offset;
+
+        if self.sensitive {
+            first.as_ptr().add(BLOCK_HEADER_SIZE).write_bytes(0, size);
+        }
+
+        region.as_mut().data.blocks = LinkedList::new();
         let block = region.as_mut().data.blocks.append(
             Block {
-                size: region.as_ref().size() - BLOCK_HEADER_SIZE,
+                size,
                 is_free: true,
+                purged: false,
+                bucket: self.index,
                 region,
+                #[cfg(feature = "layout-tracking")]
+                layout: None,
+                #[cfg(debug_assertions)]
+                generation: 0,
+                #[cfg(debug_assertions)]
+                align_log2: 0,
             },
-            Header::content_address_of(region),
+            first,
         );
 
-        self.free_blocks.append_block(block);
+        self.insert_free_block(block);
 
-        Ok(region)
+        (not_free - retained, requested)
     }
 
-    /// Block splitting algorithm implementation. Let's say we have a free block
-    /// that can hold 128 bytes and a request to allocate 24 bytes has been
-    /// made. We'll split the free block in two different blocks, like so:
-    ///
-    /// **Before**:
-    ///
-    /// ```text
-    ///         +-->  +-----------+
-    ///         |     |   Header  | <- H bytes (depends on word size and stuff).
-    /// Block   |     +-----------+
-    ///         |     |  Content  | <- 128 bytes.
-    ///         +-->  +-----------+
-    /// ```
-    /// **After**:
+    /// Region of this bucket that starts at `address`, if any.
+    pub unsafe fn region_at(&self, address: NonNull<u8>) -> Pointer<Header<Region>> {
+        self.regions.iter().find(|region| region.cast() == address)
+    }
+
+    /// Number of blocks in the free list.
+    #[inline]
+    pub fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// Pushes free blocks to `blocks` until it's full, it never grows so
+    /// that we don't allocate while holding the lock. See [`Self::purge`].
+    pub fn free_blocks_into(&self, blocks: &mut Vec<NonNull<Header<Block>>>) {
+        let spare = blocks.capacity() - blocks.len();
+        blocks.extend(
+            self.free_blocks
+                .iter()
+                .take(spare)
+                .map(|node| unsafe { Header::<Block>::from_free_list_node(node) }),
+        );
+    }
+
+    /// Tells the kernel that it can reclaim the physical pages of the free
+    /// `block`. Only the pages located entirely inside the block content are
+    /// purged, and we also skip the free list node at the beginning of the
+    /// content because we need it:
     ///
     /// ```text
-    ///         +-->  +-----------+
-    ///         |     |   Header  | <- H bytes.
-    /// Block 1 |     +-----------+
-    ///         |     |  Content  | <- 24 bytes.
-    ///         +-->  +-----------+
-    ///         |     |   Header  | <- H bytes.
-    /// Block 2 |     +-----------+
-    ///         |     |  Content  | <- 128 bytes - 24 bytes - H bytes.
-    ///         +-->  +-----------+
+    /// +--------+------+------------+----------+----------+-----------+
+    /// | Header | Node |   Unused   |  Page 1  |  Page 2  |  Unused   |
+    /// +--------+------+------------+----------+----------+-----------+
+    ///                              ^                     ^
+    ///                              |                     |
+    ///                              +------ Purged -------+
     /// ```
     ///
-    /// The block doesn't necessarily have to be free, it might be in use but
-    /// we want to shrink it. See [`Self::shrink_block`]. This function does not
-    /// touch the contents of the block, it only changes it's header to reflect
-    /// the new size. On the other hand, the new block created in the splitting
-    /// process is automatically added to the free list.
+    /// The block stays in the free list and it can be reused normally, the
+    /// kernel will give us zeroed pages when we write to it again. Returns the
+    /// number of bytes purged, which is 0 if the block was already purged and
+    /// hasn't changed since then, see [`Block::purged`].
     ///
     /// # Safety
     ///
-    /// User content in this block can never exceed `size` bytes, this is only
-    /// relevant for shrinking used blocks. If padding was introduced in this
-    /// block to meet alignment constraints, the caller must guarantee that
-    /// padding is included in `size`.
-    unsafe fn split_block_if_possible(&mut self, mut block: NonNull<Header<Block>>, size: usize) {
-        // If there's not enough space available we can't split the block.
-        if block.as_ref().size() < size + BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE {
-            return;
-        }
-
-        // If we can, the new block will be located `size` bytes after the
-        // initial content address of the current block.
-        let address = Header::content_address_of(block).as_ptr().add(size);
-        let mut region = block.as_ref().data.region;
-
-        // Append new block next to current block.
-        let new_block = region.as_mut().data.blocks.insert_after(
-            block,
-            Block {
-                size: block.as_ref().data.size - size - BLOCK_HEADER_SIZE,
-                is_free: true,
-                region,
-            },
-            NonNull::new_unchecked(address),
-        );
+    /// `block` must be a free block.
+    pub unsafe fn purge(mut block: NonNull<Header<Block>>) -> usize {
+        debug_assert!(block.as_ref().is_free(), "purging a block that is not free");
 
-        self.free_blocks.append_block(new_block);
+        if block.as_ref().data.purged {
+            return 0;
+        }
 
-        // The current block can only hold `size` bytes from now on.
-        block.as_mut().data.size = size;
+        match Self::free_pages_of(block) {
+            Some((start, length)) if platform::purge(start, length) => {
+                block.as_mut().data.purged = true;
+                length
+            }
+            _ => 0,
+        }
     }
 
-    /// This function performs the inverse of [`Self::split_block_if_possible`].
-    /// If surrounding blocks are free, then we'll merge them all into one
-    /// bigger block. This is called block coalescing or block merging.
-    ///
-    /// **Before**:
-    ///
-    /// ```text
-    ///                         +-->  +-----------+
-    ///                         |     |   Header  | <- H bytes.
-    /// Block A, Free           |     +-----------+
-    ///                         |     |  Content  | <- A bytes.
-    ///                         +-->  +-----------+
-    ///                         |     |   Header  | <- H bytes.
-    /// Block B, Recently freed |     +-----------+
-    ///                         |     |  Content  | <- B bytes.
-    ///                         +-->  +-----------+
-    ///                         |     |   Header  | <- H bytes.
-    /// Block C, Free           |     +-----------+
-    ///                         |     |  Content  | <- C bytes.
-    ///                         +-->  +-----------+
-    /// ```
-    ///
-    /// **After**:
-    ///
-    /// ```text
-    ///
-    ///                         +-->  +-----------+
-    ///                         |     |   Header  | <- H bytes.
-    /// Block D, Bigger block   |     +-----------+
-    ///                         |     |  Content  | <- A + B + C + 2H bytes.
-    ///                         +-->  +-----------+
-    /// ```
-    ///
-    /// Note that only one of the surrounding blocks is merged if the other one
-    /// is not free. Also, if the previous block is merged, then the address of
-    /// the current block changes. That's why we have to return a pointer to a
-    /// block.
+    /// Bytes of the free `block` released by a previous [`Self::purge`], 0 if
+    /// it was not purged or it changed since then.
     ///
     /// # Safety
     ///
-    /// Unlike [`Self::split_block_if_possible`], the caller must guarantee that
-    /// `block` is free in this case.
-    #[rustfmt::skip]
-    unsafe fn merge_surrounding_free_blocks_if_possible(
-        &mut self,
-        mut block: NonNull<Header<Block>>,
-    ) -> NonNull<Header<Block>> {
-        if block.as_ref().next.is_some_and(|next| next.as_ref().is_free()) {
-            self.merge_next_adjacent_free_block(block);
+    /// `block` must be a free block.
+    pub unsafe fn purged_bytes(block: NonNull<Header<Block>>) -> usize {
+        match Self::free_pages_of(block) {
+            Some((_, length)) if block.as_ref().data.purged => length,
+            _ => 0,
         }
+    }
 
-        if block.as_ref().prev.is_some_and(|prev| prev.as_ref().is_free()) {
-            block = block.as_ref().prev.unwrap();
-            self.merge_next_adjacent_free_block(block);
+    /// Marks the pages of the free `block` as cold, the same pages that
+    /// [`Self::purge`] would release. Contents are preserved, but under
+    /// memory pressure the kernel reclaims these pages before the ones that
+    /// are in use, which suits free blocks that might not be reused for a
+    /// while. Returns the number of bytes marked.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be a free block.
+    pub unsafe fn advise_cold(block: NonNull<Header<Block>>) -> usize {
+        match Self::free_pages_of(block) {
+            Some((start, length)) if platform::advise_cold(start, length) => length,
+            _ => 0,
         }
+    }
 
-        block
+    /// Start and length of the pages located entirely inside the content of
+    /// the free `block` after its free list node, see [`Self::purge`].
+    unsafe fn free_pages_of(block: NonNull<Header<Block>>) -> Option<(NonNull<u8>, usize)> {
+        let page_size = platform::page_size();
+        let content = Header::content_address_of(block).as_ptr();
+        let start = content.add(MIN_BLOCK_SIZE);
+        let start = start.add(start.align_offset(page_size));
+        let end = content.add(block.as_ref().size());
+        let end = end.sub(end.addr() % page_size);
+
+        (end > start).then(|| (NonNull::new_unchecked(start), end.addr() - start.addr()))
     }
 
-    /// Helper for block merging algorithm. Blocks can only be merged from right
-    /// to left, or from next to current, because the new bigger block address
-    /// should be the address of the first free block. So, given a valid block,
-    /// this function will merge the next adjacent block into the given block
-    /// and update all data structures. This functions also assumes that the
-    /// given block is free and the next block to it is also free and not null.
+    /// Unmaps the pages at the end of each region that are only used by a
+    /// free block. Regions whose blocks are all free are returned to the
+    /// kernel on deallocation, but a large region with a few small blocks at
+    /// the beginning stays mapped entirely. If its last block is free, we can
+    /// cut the region at the first page boundary after the free list node of
+    /// that block and unmap everything else:
     ///
     /// ```text
-    /// +----------------+---------------+
-    /// |    Block A     |   Block B     |
-    /// +----------------+---------------+
-    ///        ^                 |
-    ///        |                 |
-    ///        +-----------------+
-    ///           Merge B into A
+    /// +--------+-------+--------+------+--------+----------+----------+
+    /// | Region | Block | Header | Node | Unused |  Page 1  |  Page 2  |
+    /// +--------+-------+--------+------+--------+----------+----------+
+    ///                                           ^                     ^
+    ///                                           |                     |
+    ///                                           +------ Unmapped -----+
     /// ```
-    unsafe fn merge_next_adjacent_free_block(&mut self, block: NonNull<Header<Block>>) {
-        let next = block.as_ref().next.unwrap();
+    ///
+    /// The free block and the region shrink accordingly, so the region is
+    /// still valid and the block can be reused. Guard pages move to the new
+    /// end of the region, the pages right before the cut are protected and
+    /// the old guard is unmapped with the rest. Pools are skipped because
+    /// their blocks can't be resized, and platforms that can't unmap part of
+    /// a mapping (see [`RegionPlacement::can_coalesce`]) don't shrink
+    /// anything.
+    /// Returns the number of bytes unmapped.
+    pub unsafe fn shrink_regions(&mut self) -> usize {
+        if self.pool.is_some() || !self.placement.can_coalesce() {
+            return 0;
+        }
 
-        // First update free list. The new bigger block will become the
-        // last block, and the 2 old smaller blocks will "dissapear" from the
-        // list.
-        self.free_blocks.remove_block(next);
-        self.free_blocks.remove_block(block);
-        self.free_blocks.append_block(block);
+        let mut unmapped = 0;
+        let mut current = self.regions.first();
 
-        self.expand_block_by_consuming_next(block);
-    }
+        while let Some(region) = current {
+            current = region.as_ref().next;
+            unmapped += self.shrink_region(region);
+        }
 
-    /// This function expands `block` by consuming the block right next to it,
-    /// without modifying the free list.
-    ///
-    /// # Panics
-    ///
-    /// Panics if there is no adjacent block next to the given block.
-    unsafe fn expand_block_by_consuming_next(&mut self, mut block: NonNull<Header<Block>>) {
-        let next = block.as_ref().next.unwrap();
-        // Now this block is bigger.
-        block.as_mut().data.size += next.as_ref().total_size();
-        // Next block doesn't exist anymore.
-        block.as_mut().region_mut().data.blocks.remove(next);
+        unmapped
     }
 
-    /// Optimized in place shrinking. The block can be split in two different
-    /// blocks, creating a free block next to it, and if there was another free
-    /// block next to the original block then they can be merged into one.
-    ///
-    /// Before:
-    ///
-    /// ```text
-    /// +------------------------------------+    +------------+
-    /// |    Used block about to be shrunk   | -> | Free Block |
-    /// +------------------------------------+    +------------+
-    /// ```
+    /// Returns memory retained by this bucket to the kernel until the bytes in
+    /// free blocks drop to `keep_bytes` or nothing else can be unmapped. In
+    /// order:
     ///
-    /// After splitting:
+    /// 1. Blocks retained by [`SizeClasses`] are released, which unmaps their
+    ///    regions if nothing else is using them.
     ///
-    /// ```text
-    /// +--------------+    +----------------+    +------------+
-    /// | Shrunk Block | -> | New Free block | -> | Free Block |
-    /// +--------------+    +----------------+    +------------+
-    /// ```
+    /// 2. Regions whose blocks are all free are unmapped entirely. Regions
+    ///    are normally unmapped as soon as they become empty, but pools never
+    ///    merge blocks so their empty regions stay mapped.
     ///
-    /// After merging:
+    /// 3. The free pages at the end of the remaining regions are unmapped,
+    ///    see [`Self::shrink_regions`].
     ///
-    /// ```text
-    /// +--------------+    +----------------------------------+
-    /// | Shrunk Block | -> |   Resulting bigger free block    |
-    /// +--------------+    +----------------------------------+
-    /// ```
-    unsafe fn shrink_block(&mut self, block: NonNull<Header<Block>>, new_size: usize) {
-        self.split_block_if_possible(block, new_size);
-        if let Some(next) = block.as_ref().next {
-            self.merge_surrounding_free_blocks_if_possible(next);
+    /// Free blocks in the middle of a region can't be unmapped, so the free
+    /// bytes might still be above `keep_bytes` afterwards. Returns the number
+    /// of bytes unmapped.
+    pub unsafe fn shrink_to(&mut self, keep_bytes: usize) -> usize {
+        let mapped = self.bytes_mapped;
+
+        while let Some(block) = self.size_classes.pop_any() {
+            self.release_block(block);
+        }
+
+        let mut free_bytes = self.free_bytes();
+        let mut current = self.regions.first();
+
+        while let Some(region) = current {
+            if free_bytes <= keep_bytes {
+                break;
+            }
+
+            current = region.as_ref().next;
+            free_bytes -= self.return_region_if_empty(region).unwrap_or(0);
+        }
+
+        if self.pool.is_none() && self.placement.can_coalesce() {
+            let mut current = self.regions.first();
+
+            while let Some(region) = current {
+                if free_bytes <= keep_bytes {
+                    break;
+                }
+
+                current = region.as_ref().next;
+                free_bytes -= self.shrink_region(region);
+            }
         }
+
+        mapped - self.bytes_mapped
     }
 
-    /// We will prioritize in place reallocations, this will only fail if the
-    /// new size doesn't fit even after merging surrounding blocks or if
-    /// alignment has increased drastically and we didn't find any aligned
-    /// address in the current block.
-    unsafe fn try_reallocate_in_place(&mut self, realloc: &Realloc) -> AllocResult {
-        match realloc.method {
-            ReallocMethod::Shrink => self.try_shrink_in_place(realloc),
-            ReallocMethod::Grow => self.try_grow_in_place(realloc),
+    /// Unmaps `region` if all of its blocks are free and returns the bytes
+    /// that were in those blocks, or `None` if some block is in use.
+    unsafe fn return_region_if_empty(&mut self, region: NonNull<Header<Region>>) -> Option<usize> {
+        let blocks = &region.as_ref().data.blocks;
+        if blocks.iter().any(|block| !block.as_ref().is_free()) {
+            return None;
+        }
+
+        let mut free_bytes = 0;
+        for block in blocks.iter() {
+            free_bytes += block.as_ref().size();
+            self.free_blocks.remove_block(block);
         }
+
+        self.return_region(region);
+
+        Some(free_bytes)
     }
 
-    /// If everything else fails, just find or create a new block and move
-    /// the contents there.
-    unsafe fn try_reallocate_on_another_block(&mut self, realloc: &Realloc) -> AllocResult {
-        let new_address = self.allocate(realloc.new_layout)?;
-        ptr::copy_nonoverlapping(
-            realloc.address.as_ptr(),
-            new_address.as_mut_ptr(),
-            realloc.count(),
-        );
-        self.deallocate(realloc.address, realloc.old_layout);
+    /// One slice of [`crate::Rulloc::defragment_until`]. Does the same work
+    /// as [`Self::shrink_to`] with nothing to keep, but only for the first
+    /// region whose address is not below `from`, so that the work can be
+    /// split in small steps. Starting at `None` also releases the blocks
+    /// retained by [`SizeClasses`]. Returns the address where the next step
+    /// should start, or `None` if there were no regions left to process.
+    /// Regions are sorted by address, so regions mapped or unmapped between
+    /// steps don't break the cursor.
+    pub unsafe fn defragment_step(&mut self, from: Pointer<u8>) -> Pointer<u8> {
+        if from.is_none() {
+            while let Some(block) = self.size_classes.pop_any() {
+                self.release_block(block);
+            }
+        }
 
-        Ok(new_address)
+        let region = self
+            .regions
+            .iter()
+            .find(|region| from.is_none_or(|from| region.cast() >= from))?;
+
+        // Computed before the region is unmapped.
+        let next = NonNull::new(region.cast::<u8>().as_ptr().wrapping_add(1));
+
+        if self.return_region_if_empty(region).is_none()
+            && self.pool.is_none()
+            && self.placement.can_coalesce()
+        {
+            self.shrink_region(region);
+        }
+
+        next
     }
 
-    /// If possible, this function will attempt to reallocate in place without
-    /// merging adjacent blocks or moving the contents to a new block. This can
-    /// be done especially when alignment changes, because that makes the
-    /// padding change as well.
-    unsafe fn try_reallocate_on_same_block(&mut self, realloc: &Realloc) -> AllocResult {
-        // First let's try to see if we can fit the new layout in this block.
-        let new_size = alignment::minimum_block_size_excluding_padding(realloc.new_layout);
-        let (next_aligned, padding) = alignment::next_aligned(
-            Header::<Block>::content_address_of(realloc.block),
-            realloc.new_layout.align(),
-        );
+    /// Shrinks one single region, see [`Self::shrink_regions`].
+    unsafe fn shrink_region(&mut self, mut region: NonNull<Header<Region>>) -> usize {
+        let mut block = region.as_ref().data.blocks.last().unwrap_unchecked();
 
-        // No luck, we can't use this block.
-        if padding + new_size > realloc.block.as_ref().size() {
-            return Err(AllocError);
+        if !block.as_ref().is_free() {
+            return 0;
         }
 
-        // Now we know for sure that this block can be reused. The code below
-        // deals with many edge cases even though it doesn't look like it does,
-        // so let's break it down.
-        //
-        // We know that `alignment::next_aligned` function will return the first
-        // aligned address after the content address of the block, and we also
-        // know that there's enough space for padding because we've checked
-        // that in the if statement above. So whatever the new alignment is, we
-        // don't care, there's space for it.
-        //
-        // The `next_aligned` address might be located before the current
-        // address (if alignment has decreased) or after the current address
-        // (if alignment has increased), so we'll need to move the contents if
-        // the address wasn't already aligned.
-        //
-        // But now here comes the magic: This code also works for `POINTER_SIZE`
-        // alignment because `alignment::next_aligned` function will return the
-        // content address of the block whenever the alignment is less than or
-        // equal to `POINTER_SIZE`. So if the previous alignment was greater
-        // than `POINTER_SIZE` we'll get rid of all the padding and reduce
-        // fragmentation. If the previous alignment was already `POINTER_SIZE`
-        // then the if statement below won't even run because `next_aligned` is
-        // already equal to the given address!
-        //
-        // The only thing we have to do is write the back pointer if we added
-        // padding, otherwise there's no back pointer. So this covers all the
-        // combinatorics of `old_layout` and `new_layout`, both in terms of
-        // alignment and size.
-        //
-        // Of course, this conclusion was reached after writing dozens of
-        // if-else statements and noticing that the same code is repeated
-        // everywhere. So this is just like simplifying a huge equation!
-        if next_aligned != realloc.address {
-            ptr::copy(
-                realloc.address.as_ptr(),
-                next_aligned.as_ptr(),
-                realloc.count(),
-            );
-            if padding > 0 {
-                ptr::write(
-                    alignment::back_pointer_of(next_aligned).as_ptr(),
-                    realloc.block,
-                );
-            }
+        let page_size = platform::page_size();
+        let content = Header::content_address_of(block).as_ptr();
+        let cut = content.add(MIN_BLOCK_SIZE);
+        let cut = cut.add(cut.align_offset(page_size));
+        let end = content.add(block.as_ref().size());
+
+        if end <= cut {
+            return 0;
         }
 
-        // If we removed padding or size has decreased, maybe we can create
-        // new free blocks next to this one.
-        self.shrink_block(realloc.block, new_size + padding);
+        let length = end.addr() - cut.addr();
+        let guard = region.as_ref().data.guard;
 
-        Ok(NonNull::slice_from_raw_parts(
-            next_aligned,
-            realloc.block.as_ref().size() - padding,
-        ))
+        if guard != 0 {
+            platform::protect(NonNull::new_unchecked(cut), guard, Protection::None);
+        }
+
+        self.unmap_failures.check(platform::return_memory(
+            NonNull::new_unchecked(cut.add(guard)),
+            length,
+        ));
+
+        #[cfg(feature = "logging")]
+        log::debug!(
+            "bucket {}: unmapped {length} bytes at the end of region {region:?}",
+            self.index
+        );
+
+        // Sorted free lists depend on the block size.
+        self.free_blocks.remove_block(block);
+        block.as_mut().data.size -= length;
+        self.insert_free_block(block);
+
+        region.as_mut().data.size -= length;
+        self.bytes_mapped -= length;
+
+        length
     }
 
-    /// Attempts to fit the layout of the new allocation using the surrounding
-    /// free blocks. An error is returned if it's not possible.
+    /// Number of regions currently mapped by this bucket.
     #[inline]
-    unsafe fn try_grow_in_place(&mut self, realloc: &Realloc) -> AllocResult {
-        self.try_reallocate_on_same_block(realloc)
-            .or_else(|_| self.try_grow_by_merging_next_block(realloc))
-            .or_else(|_| self.try_grow_by_merging_prev_block(realloc))
-            .or_else(|_| self.try_grow_by_merging_both_blocks(realloc))
+    pub fn num_regions(&self) -> usize {
+        self.regions.len()
     }
 
-    /// For symmetry with [`Self::try_grow_in_place`].
-    #[inline]
-    unsafe fn try_shrink_in_place(&mut self, realloc: &Realloc) -> AllocResult {
-        self.try_reallocate_on_same_block(realloc)
+    /// Number of blocks in all regions, free or not.
+    pub fn num_blocks(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| unsafe { region.as_ref().num_blocks() })
+            .sum()
     }
 
-    /// As the name suggests, this function attempts to merge the next adjacent
-    /// block to create a bigger one that fits the new layout.
-    ///
-    /// Before:
-    ///
-    /// ```text
-    /// +------------------------------------+    +------------+
-    /// |    Used block that needs to grow   | -> | Free Block |
-    /// +------------------------------------+    +------------+
-    /// ```
-    ///
-    /// After:
-    ///
-    /// ```text
-    /// +------------------------------------------------------+
-    /// |      Resulting block that fits the new layout        |
-    /// +------------------------------------------------------+
-    /// ```
-    unsafe fn try_grow_by_merging_next_block(&mut self, realloc: &Realloc) -> AllocResult {
-        let Realloc { block, .. } = realloc;
-        let next = block.as_ref().next.ok_or(AllocError)?;
-        self.try_grow_by_merging(&[*block, next], realloc)
+    /// Pushes one record per block to `records` until it's full, it never
+    /// grows so that we don't allocate while holding the lock. See
+    /// [`crate::Rulloc::memory_report`].
+    pub fn block_records_into(&self, records: &mut Vec<BlockRecord>) {
+        let spare = records.capacity() - records.len();
+        let blocks = self.regions.iter().flat_map(|region| unsafe {
+            region
+                .as_ref()
+                .data
+                .blocks
+                .iter()
+                .map(move |block| BlockRecord {
+                    bucket: self.index as usize,
+                    region: region.cast(),
+                    region_size: region.as_ref().total_size(),
+                    block: BlockReport {
+                        offset: block.as_ptr().addr() - region.as_ptr().addr(),
+                        size: block.as_ref().size(),
+                        free: block.as_ref().is_free(),
+                    },
+                })
+        });
+
+        records.extend(blocks.take(spare));
     }
 
-    /// Same as [`Self::try_grow_by_merging_next_block`] but using the previous
-    /// block.
-    ///
-    /// Before:
-    ///
-    /// ```text
-    /// +------------+    +------------------------------------+
-    /// | Free Block | -> |    Used block that needs to grow   |
-    /// +------------+    +------------------------------------+
-    /// ```
-    ///
-    /// After:
-    ///
-    /// ```text
-    /// +------------------------------------------------------+
-    /// |      Resulting block that fits the new layout        |
-    /// +------------------------------------------------------+
-    /// ```
-    unsafe fn try_grow_by_merging_prev_block(&mut self, realloc: &Realloc) -> AllocResult {
-        let Realloc { block, .. } = realloc;
-        let prev = block.as_ref().prev.ok_or(AllocError)?;
-        self.try_grow_by_merging(&[prev, *block], realloc)
+    /// See [`FreeList::validate`]. Only used for testing.
+    #[cfg(test)]
+    pub unsafe fn validate_free_list(&self) {
+        self.free_blocks.validate();
     }
 
-    /// If we can't get a big enough block by merging the next or previous, how
-    /// about merging both of them?
-    ///
-    /// Before:
-    ///
-    /// ```text
-    /// +------------+    +----------------------------------+    +------------+
-    /// | Free Block | -> |  Used block that needs to grow   | -> | Free Block |
-    /// +------------+    +----------------------------------+    +------------+
-    /// ```
-    ///
-    /// After:
-    ///
-    /// ```text
-    /// +----------------------------------------------------------------------+
-    /// |              Resulting block that fits the new layout                |
-    /// +----------------------------------------------------------------------+
-    /// ```
-    unsafe fn try_grow_by_merging_both_blocks(&mut self, realloc: &Realloc) -> AllocResult {
-        let Realloc { block, .. } = realloc;
-        let next = block.as_ref().next.ok_or(AllocError)?;
-        let prev = block.as_ref().prev.ok_or(AllocError)?;
-        self.try_grow_by_merging(&[prev, *block, next], realloc)
+    /// All the regions of this bucket in address order. Used for testing and
+    /// leak tracking at [`crate::allocator`].
+    #[cfg(any(test, feature = "leak-tracking"))]
+    pub fn regions(&self) -> &LinkedList<Region> {
+        &self.regions
     }
 
-    /// Automation for merging blocks. `blocks` must contain only adjacent
-    /// blocks, otherwise this will result in a disaster! See
-    /// [`Self::try_grow_in_place`], [`Self::try_grow_by_merging_next_block`],
-    /// [`Self::try_grow_by_merging_prev_block`] and
-    /// [`Self::try_grow_by_merging_both_blocks`].
-    unsafe fn try_grow_by_merging(
-        &mut self,
-        blocks: &[NonNull<Header<Block>>],
-        realloc: &Realloc,
-    ) -> AllocResult {
-        let (starting_block, rest) = blocks.split_first().unwrap();
+    /// Checks that the blocks of each region are in address order, don't
+    /// overlap each other and don't go past the end of their region, then
+    /// appends the range of each region to `ranges` in address order. Only
+    /// used for testing, see [`crate::allocator`].
+    #[cfg(test)]
+    pub unsafe fn verify_no_overlap(&self, ranges: &mut Vec<std::ops::Range<usize>>) {
+        let mut previous_end = 0;
 
-        let new_size = alignment::minimum_block_size_excluding_padding(realloc.new_layout);
-        let padding = alignment::padding_needed_to_align(
-            Header::<Block>::content_address_of(*starting_block),
-            realloc.new_layout.align(),
-        );
+        for region in self.regions.iter() {
+            let start = region.as_ptr().addr();
+            let end = start + region.as_ref().total_size();
+            assert!(
+                previous_end <= start,
+                "regions of bucket {} are not sorted or overlap at {start:#x}",
+                self.index
+            );
+            previous_end = end;
+
+            let mut block_start = start + REGION_HEADER_SIZE;
+            for block in region.as_ref().data.blocks.iter() {
+                let block_end =
+                    Header::content_address_of(block).as_ptr().addr() + block.as_ref().size();
+                assert!(
+                    block_start <= block.as_ptr().addr(),
+                    "block at {:#x} overlaps the previous block or header",
+                    block.as_ptr().addr()
+                );
+                block_start = block_end;
+            }
+            assert!(
+                block_start <= start + REGION_HEADER_SIZE + region.as_ref().size(),
+                "last block of region at {start:#x} ends past the region"
+            );
 
-        let total_size = rest
-            .iter()
-            .fold(starting_block.as_ref().size(), |total, next| {
-                total + next.as_ref().total_size()
-            });
+            ranges.push(start..end);
+        }
+    }
 
-        if new_size + padding > total_size {
-            return Err(AllocError);
+    /// Allocates a new block that can fit at least `layout.size()` bytes.
+    /// Because of alignment and headers, it might allocate a bigger block than
+    /// needed. As long as no more than `layout.pad_to_align().size()` bytes are
+    /// written on the content part of the block it should be fine.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> AllocResult {
+        self.fresh = None;
+
+        if let Some(size) = self.pool {
+            return self.allocate_in_pool(size, layout);
         }
 
-        for block in blocks.iter().rev().skip(1) {
-            // We skipped one, there should always be a next block.
-            let next = block.as_ref().next.unwrap();
-            if next.as_ref().is_free() {
-                self.free_blocks.remove_block(next);
+        let class = self.size_classes.class_of(layout);
+
+        if let Some(block) = class.and_then(|class| self.size_classes.pop(class)) {
+            self.freelist_hits += 1;
+            return Ok(self.allocate_retained(block, layout));
+        }
+
+        // If size classes are enabled the block must be big enough for the
+        // class, not just for the request.
+        let rounded = self.size_classes.round(layout);
+
+        let free_block = match self.find_free_block(rounded) {
+            Some(block) => {
+                self.freelist_hits += 1;
+                block
             }
-            if block.as_ref().is_free() {
-                self.free_blocks.remove_block(*block);
+            None => {
+                let size = alignment::minimum_block_size_needed_for(rounded);
+                self.request_region(size)?.as_ref().first_block()
             }
-            self.expand_block_by_consuming_next(*block);
-        }
+        };
 
-        self.try_reallocate_on_same_block(&realloc.map(*starting_block))
+        Ok(self.allocate_exact(free_block, layout))
     }
-}
 
-impl Drop for Bucket {
-    fn drop(&mut self) {
-        self.regions.iter().for_each(|region| unsafe {
-            platform::return_memory(region.cast(), region.as_ref().total_size());
-        });
-    }
-}
+    /// Same as [`Self::allocate`], but the free blocks of the region that
+    /// contains `hint` are tried first, see [`crate::Rulloc::allocate_near`].
+    /// Pools and size classes already hand out blocks in a fixed order, and
+    /// so does a `hint` outside this bucket, so they fall back to
+    /// [`Self::allocate`].
+    pub unsafe fn allocate_near(&mut self, layout: Layout, hint: NonNull<u8>) -> AllocResult {
+        self.fresh = None;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        alignment::AlignmentBackPointer,
-        platform::{page_size, PAGE_SIZE},
-    };
+        if self.pool.is_some() || self.size_classes.class_of(layout).is_some() {
+            return self.allocate(layout);
+        }
 
-    fn check_mem_corruption(chunk: &[u8], corruption_check: u8) {
-        for value in chunk {
-            assert_eq!(value, &corruption_check);
+        let block = self
+            .find_region_containing(hint)
+            .and_then(|region| self.find_free_block_in_region(region, layout));
+
+        match block {
+            Some(block) => {
+                self.freelist_hits += 1;
+                Ok(self.allocate_exact(block, layout))
+            }
+            None => self.allocate(layout),
         }
     }
 
-    #[test]
-    fn allocs_and_deallocs() {
-        unsafe {
-            let mut bucket = Bucket::new();
+    /// Uses the given free block to allocate `layout`. The block is split
+    /// using the exact size needed given its address, so padding for
+    /// alignment is kept to a minimum. See
+    /// [`alignment::block_size_needed_at`]. If `layout` belongs to one of the
+    /// [`SizeClasses`], the block is split at the size of the class instead.
+    unsafe fn allocate_exact(
+        &mut self,
+        block: NonNull<Header<Block>>,
+        layout: Layout,
+    ) -> NonNull<[u8]> {
+        let size = alignment::block_size_needed_at(
+            Header::content_address_of(block),
+            self.size_classes.round(layout),
+        );
 
-            // Request 1 byte, should call `mmap` with length of PAGE_SIZE.
-            let first_layout = Layout::new::<u8>();
-            let mut first_addr = bucket.allocate(first_layout).unwrap();
+        self.split_block_if_possible(block, size);
+        self.free_blocks.remove_block(block);
 
-            // We'll use this later to check memory corruption. The allocator
-            // should not touch the content of any block.
-            let first_addr_corruption_check = 69;
-            first_addr.as_mut().fill(first_addr_corruption_check);
+        #[cfg(feature = "layout-tracking")]
+        {
+            (*block.as_ptr()).data.layout = Some(layout);
+        }
 
-            // First region should be PAGE_SIZE in length.
-            let first_region = bucket.regions.first().unwrap();
-            assert_eq!(first_region.as_ref().total_size(), PAGE_SIZE);
-            assert_eq!(bucket.regions.len(), 1);
+        #[cfg(debug_assertions)]
+        {
+            (*block.as_ptr()).data.generation = self.generation;
+            (*block.as_ptr()).data.align_log2 = layout.align().trailing_zeros() as u8;
+        }
 
-            // First block should be located after the region header.
-            let first_block = first_region.as_ref().first_block();
-            assert_eq!(
-                first_block.as_ptr() as usize - first_region.as_ptr() as usize,
-                REGION_HEADER_SIZE
-            );
+        self.add_padding_if_needed(block, layout.align())
+    }
 
-            // Size of first block should be minimum size because we've
-            // requested 1 byte only.
-            assert_eq!(first_block.as_ref().size(), MIN_BLOCK_SIZE);
-            assert_eq!(bucket.free_blocks.len(), 1);
+    /// Pops the head of the free list of a pool, see [`Self::set_pool`]. If
+    /// the pool is empty a new region is mapped and carved into blocks of
+    /// `size` bytes, all of them free:
+    ///
+    /// ```text
+    /// +--------+-----------------------------------------------------+
+    /// |        | +------+    +------+    +------+           +------+ |
+    /// | Region | | Free | -> | Free | -> | Free | -> ... -> | Free | |
+    /// |        | +------+    +------+    +------+           +------+ |
+    /// +--------+-----------------------------------------------------+
+    /// ```
+    ///
+    /// The last block takes whatever is left in the region, so it might be
+    /// bigger than the others, but never smaller.
+    unsafe fn allocate_in_pool(&mut self, size: usize, layout: Layout) -> AllocResult {
+        let block_size = alignment::minimum_block_size_excluding_padding(
+            Layout::from_size_align_unchecked(size, 1),
+        );
 
-            // Region should have two blocks, we are using the first one and the
-            // other one is free.
-            assert_eq!(first_region.as_ref().num_blocks(), 2);
+        if layout.align() > alignment::POINTER_SIZE || layout.size() > block_size {
+            return Err(AllocError);
+        }
 
-            // First free block should match this size because there are
-            // two blocks in the region and one of them has minimum size.
-            assert_eq!(
-                bucket.free_blocks.first_free_block().unwrap().size(),
-                PAGE_SIZE - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE * 2 - MIN_BLOCK_SIZE
-            );
+        let block = match self.free_blocks.first() {
+            Some(node) => {
+                self.freelist_hits += 1;
+                Header::<Block>::from_free_list_node(node)
+            }
+            None => self.map_pool_region(block_size)?,
+        };
 
-            // The remaining free block should be split in two when allocating
-            // less size than it can hold.
-            let second_layout = Layout::array::<u8>(PAGE_SIZE / 2).unwrap();
-            let mut second_addr = bucket.allocate(second_layout).unwrap();
+        self.free_blocks.remove_block(block);
+        (*block.as_ref().data.region.as_ptr()).data.used += 1;
 
-            // We'll check corruption later.
-            let second_addr_corruption_check = 42;
-            second_addr.as_mut().fill(second_addr_corruption_check);
+        Ok(self.allocate_retained(block, layout))
+    }
 
-            // There are 3 blocks now, last one is still free.
-            assert_eq!(first_region.as_ref().num_blocks(), 3);
-            assert_eq!(bucket.free_blocks.len(), 1);
+    /// Maps a new pool region and carves it into free blocks of
+    /// `block_size` bytes, see [`Self::allocate_in_pool`]. Returns the first
+    /// block of the region.
+    unsafe fn map_pool_region(
+        &mut self,
+        block_size: usize,
+    ) -> Result<NonNull<Header<Block>>, AllocError> {
+        let region_size = (block_size + BLOCK_HEADER_SIZE)
+            .checked_mul(POOL_REGION_BLOCKS)
+            .ok_or_else(|| self.fail(AllocFailure::SizeOverflow))?
+            - BLOCK_HEADER_SIZE;
+
+        let first = self.request_region(region_size)?.as_ref().first_block();
+
+        // Only split if what's left can hold another block, otherwise the
+        // last block would be too small for the pool.
+        let mut block = first;
+        while block.as_ref().size() >= 2 * block_size + BLOCK_HEADER_SIZE {
+            self.split_block_if_possible(block, block_size);
+            block = block.as_ref().next.unwrap_unchecked();
+        }
 
-            // Lets try to allocate the entire remaining free block.
-            let remaining_size = PAGE_SIZE
-                - REGION_HEADER_SIZE
-                - (BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE) // First Alloc
-                - (BLOCK_HEADER_SIZE + PAGE_SIZE / 2) // Second Alloc
-                - BLOCK_HEADER_SIZE;
-            let third_layout = Layout::array::<u8>(remaining_size).unwrap();
-            let mut third_addr = bucket.allocate(third_layout).unwrap();
+        Ok(first)
+    }
 
-            let third_addr_corruption_check = 107;
-            third_addr.as_mut().fill(third_addr_corruption_check);
+    /// Maps `count` regions up front, each one big enough for at least one
+    /// block of `size` bytes, so that the first allocations are served from
+    /// the free list. See [`crate::Rulloc::with_initial_regions`]. Pool
+    /// regions are carved into blocks right away, just like
+    /// [`Self::allocate_in_pool`] does.
+    pub unsafe fn prewarm(&mut self, count: usize, size: usize) -> Result<(), AllocError> {
+        for _ in 0..count {
+            match self.pool {
+                Some(pool_size) => {
+                    self.map_pool_region(alignment::minimum_block_size_excluding_padding(
+                        Layout::from_size_align_unchecked(pool_size, 1),
+                    ))?;
+                }
+                None => {
+                    self.request_region(alignment::minimum_block_size_needed_for(
+                        Layout::from_size_align_unchecked(size, 1),
+                    ))?;
+                }
+            }
+        }
 
-            // Number of blocks hasn't changed, but we don't have free blocks
-            // anymore.
-            assert_eq!(first_region.as_ref().num_blocks(), 3);
-            assert_eq!(bucket.free_blocks.len(), 0);
+        Ok(())
+    }
 
-            // Time for checking memory corruption
-            check_mem_corruption(first_addr.as_ref(), first_addr_corruption_check);
-            check_mem_corruption(second_addr.as_ref(), second_addr_corruption_check);
-            check_mem_corruption(third_addr.as_ref(), third_addr_corruption_check);
+    /// Pushes `block` to the head of the free list of a pool, so it's the
+    /// next one to be reused. If that leaves its region with no blocks in
+    /// use, all the blocks of the region are moved to the back of the free
+    /// list instead:
+    ///
+    /// ```text
+    ///                  Partially used regions       Empty regions
+    ///                +-----------------------+  +--------------------+
+    ///                |                       |  |                    |
+    ///                +-------+    +-------+     +-------+    +-------+
+    /// free_blocks -> | Free  | -> | Free  | ... | Free  | -> | Free  |
+    ///                +-------+    +-------+     +-------+    +-------+
+    /// ```
+    ///
+    /// Allocations pop the head, so they fill regions that are already in
+    /// use and empty regions stay empty until there's no other choice. Empty
+    /// regions can then be unmapped by [`Self::shrink_to`]. Moving the blocks
+    /// costs one pass over the region, but it only happens once per region
+    /// after all of its blocks were allocated and freed.
+    unsafe fn release_to_pool(&mut self, block: NonNull<Header<Block>>) {
+        self.free_blocks.prepend_block(block);
 
-            // Let's request a bigger chunk so that a new region is used.
-            let fourth_layout = Layout::array::<u8>(PAGE_SIZE * 2 - PAGE_SIZE / 2).unwrap();
-            let mut fourth_addr = bucket.allocate(fourth_layout).unwrap();
+        let region = block.as_ref().data.region;
+        (*region.as_ptr()).data.used -= 1;
 
-            let fourth_addr_corruption_check = 205;
-            fourth_addr.as_mut().fill(fourth_addr_corruption_check);
+        if region.as_ref().data.used == 0 {
+            for block in region.as_ref().data.blocks.iter() {
+                self.free_blocks.remove_block(block);
+                self.free_blocks.append_block(block);
+            }
+        }
+    }
 
-            // We should have a new region and a new free block now.
-            assert_eq!(bucket.regions.len(), 2);
-            assert_eq!(bucket.free_blocks.len(), 1);
+    /// Uses a block retained by [`SizeClasses`] or a pool to allocate
+    /// `layout`. The block already has the right size and `layout` doesn't
+    /// need padding, so there's nothing to split.
+    unsafe fn allocate_retained(
+        &mut self,
+        block: NonNull<Header<Block>>,
+        layout: Layout,
+    ) -> NonNull<[u8]> {
+        #[cfg(feature = "layout-tracking")]
+        {
+            (*block.as_ptr()).data.layout = Some(layout);
+        }
 
-            // Let's play with dealloc.
-            bucket.deallocate(first_addr.cast(), first_layout);
+        #[cfg(not(any(feature = "layout-tracking", debug_assertions)))]
+        let _ = layout;
 
-            // After deallocating the first block, we should have a new free
-            // block but the number of blocks in the region shouldn't change
-            // because no coalescing can happen.
-            assert_eq!(first_region.as_ref().num_blocks(), 3);
-            assert_eq!(bucket.free_blocks.len(), 2);
+        #[cfg(debug_assertions)]
+        {
+            (*block.as_ptr()).data.generation = self.generation;
+            (*block.as_ptr()).data.align_log2 = layout.align().trailing_zeros() as u8;
+        }
 
-            bucket.deallocate(third_addr.cast(), third_layout);
+        NonNull::slice_from_raw_parts(Header::content_address_of(block), block.as_ref().size())
+    }
 
-            // Again, after deallocating the third block we should have a new
-            // free block but the number of block in the region doesn't change.
-            assert_eq!(first_region.as_ref().num_blocks(), 3);
-            assert_eq!(bucket.free_blocks.len(), 3);
+    /// Allocates `count` blocks that can fit `layout` each, all of them carved
+    /// one after another from a single new region:
+    ///
+    /// ```text
+    /// +--------+-----------------------------------------------------------+
+    /// |        | +---------+    +---------+           +---------+    +----+ |
+    /// | Region | | Block 0 | -> | Block 1 | -> ... -> | Block K | -> |Free| |
+    /// |        | +---------+    +---------+           +---------+    +----+ |
+    /// +--------+-----------------------------------------------------------+
+    /// ```
+    ///
+    /// The addresses are pushed to `addresses`, which must have enough
+    /// capacity for `count` elements so that we don't allocate while holding
+    /// the lock. Each block is a normal block, so it can be deallocated or
+    /// reallocated independently.
+    pub unsafe fn allocate_batch(
+        &mut self,
+        layout: Layout,
+        count: usize,
+        addresses: &mut Vec<NonNull<u8>>,
+    ) -> Result<(), AllocError> {
+        debug_assert!(addresses.capacity() - addresses.len() >= count);
+
+        if count == 0 {
+            return Ok(());
+        }
 
-            // Now here comes the magic, if we deallocate second addr all blocks
-            // in region one should be merged and region should be returned to
+        let size = alignment::minimum_block_size_needed_for(self.size_classes.round(layout));
+        let total_size = (size + BLOCK_HEADER_SIZE)
+            .checked_mul(count)
+            .ok_or_else(|| self.fail(AllocFailure::SizeOverflow))?
+            - BLOCK_HEADER_SIZE;
+
+        let region = self.request_region(total_size)?;
+        let mut block = region.as_ref().first_block();
+
+        // Pools carve their own blocks, but they can still be batched.
+        if self.pool.is_some() {
+            (*region.as_ptr()).data.used = count;
+        }
+
+        for i in 0..count {
+            addresses.push(self.allocate_exact(block, layout).cast());
+
+            if i + 1 < count {
+                block = block.as_ref().next.unwrap_unchecked();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deallocates the given pointer. Memory might not be returned to the OS
+    /// if the region where `address` is located still contains used blocks.
+    /// However, the freed block will be reused later if possible.
+    pub unsafe fn deallocate(&mut self, address: NonNull<u8>, layout: Layout) {
+        let block = self.block_to_deallocate(address, layout);
+
+        // Pools never merge, see [`Self::set_pool`].
+        if self.pool.is_some() {
+            self.release_to_pool(block);
+        } else if !self.size_classes.push(block) {
+            self.count_free(block);
+            self.release_block(block);
+        }
+    }
+
+    /// Counts the deallocation of `block` as coalesced or isolated depending
+    /// on whether any of its neighbours is free, see
+    /// [`BucketStats::coalesced_frees`]. Returns whether it can be merged.
+    unsafe fn count_free(&mut self, block: NonNull<Header<Block>>) -> bool {
+        let is_free = |neighbour: Pointer<Header<Block>>| {
+            neighbour.is_some_and(|neighbour| neighbour.as_ref().is_free())
+        };
+
+        let coalescable = is_free(block.as_ref().prev) || is_free(block.as_ref().next);
+
+        if coalescable {
+            self.coalesced_frees += 1;
+        } else {
+            self.isolated_frees += 1;
+        }
+
+        coalescable
+    }
+
+    /// Adds `block` to the free list, merges it with its free neighbours and
+    /// returns the region to the kernel if it's empty.
+    unsafe fn release_block(&mut self, mut block: NonNull<Header<Block>>) {
+        // This block is now free as it is about to be deallocated.
+        self.insert_free_block(block);
+
+        // If previous block is merged then the address will change.
+        block = self.merge_surrounding_free_blocks_if_possible(block);
+
+        let region = block.as_ref().data.region;
+
+        // All blocks have been merged into one, so we can return this region
+        // back to the kernel.
+        if region.as_ref().num_blocks() == 1 {
+            // The only block in this region is no longer valid because the
+            // region is about to be unmapped.
+            self.free_blocks.remove_block(block);
+            self.return_region(region);
+        } else if self.cold_free_cache {
+            Self::advise_cold(block);
+        }
+    }
+
+    /// Removes `region` from this bucket and returns it to the kernel. None
+    /// of its blocks can be in the free list anymore.
+    unsafe fn return_region(&mut self, region: NonNull<Header<Region>>) {
+        // Region has to be removed before unmapping, otherwise seg fault.
+        self.regions.remove(region);
+        self.bytes_mapped -= region.as_ref().total_size();
+
+        #[cfg(feature = "logging")]
+        log::debug!(
+            "bucket {}: unmapped region {region:?} of {} bytes",
+            self.index,
+            region.as_ref().total_size()
+        );
+
+        self.unmap_failures.check(
+            self.placement
+                .unmap(region.cast(), region.as_ref().total_size()),
+        );
+    }
+
+    /// Fast path of [`Self::deallocate`] for object pools. If none of the
+    /// neighbours of the block is free there's nothing to merge and the
+    /// region can't become empty, so the block goes straight to the head of
+    /// the free list. The next allocation that fits reuses this exact address
+    /// while it's still hot in cache:
+    ///
+    /// ```text
+    ///         recycle()                               allocate()
+    /// +------+------+------+      +------+------+------+      +------+------+------+
+    /// | Used | Used | Used | ---> | Used | Free | Used | ---> | Used | Used | Used |
+    /// +------+------+------+      +------+------+------+      +------+------+------+
+    ///                                        ^
+    ///                                        |
+    ///                                        +--- Head of the free list.
+    /// ```
+    ///
+    /// Otherwise we fall back to [`Self::deallocate`]. Skipping the merge when
+    /// a neighbour is free would leave adjacent free blocks behind, and a
+    /// region is only returned to the kernel once all its blocks are merged
+    /// into one, so it would stay mapped forever.
+    pub unsafe fn recycle(&mut self, address: NonNull<u8>, layout: Layout) {
+        let block = self.block_to_deallocate(address, layout);
+
+        if self.pool.is_some() {
+            self.release_to_pool(block);
+            return;
+        }
+
+        if self.size_classes.push(block) {
+            return;
+        }
+
+        let coalescable = self.count_free(block);
+
+        if block.as_ref().data.region.as_ref().num_blocks() > 1 && !coalescable {
+            self.free_blocks.prepend_block(block);
+        } else {
+            self.release_block(block);
+        }
+    }
+
+    /// Work shared by [`Self::deallocate`] and [`Self::recycle`] before the
+    /// block is added to the free list. Returns the header of the block.
+    unsafe fn block_to_deallocate(
+        &mut self,
+        address: NonNull<u8>,
+        layout: Layout,
+    ) -> NonNull<Header<Block>> {
+        #[cfg(debug_assertions)]
+        let address = self.check_interior_pointer(address, layout);
+
+        let block = Header::<Block>::from_allocated_pointer(address, layout);
+
+        #[cfg(feature = "layout-tracking")]
+        {
+            if block.as_ref().data.layout != Some(layout) {
+                self.layout_mismatches += 1;
+            }
+            (*block.as_ptr()).data.layout = None;
+        }
+
+        #[cfg(debug_assertions)]
+        {
+            self.generation = self.generation.wrapping_add(1);
+            // Blocks retained by size classes are not free, so the generation
+            // is the only way to tell that pointers to them are stale.
+            (*block.as_ptr()).data.generation = self.generation;
+        }
+
+        // If we were retaining the tail of this block, it has to be released
+        // now, otherwise it won't be merged and the region will never be
+        // returned to the kernel.
+        self.release_tail_after(block);
+
+        if self.sensitive {
+            Header::content_address_of(block)
+                .as_ptr()
+                .write_bytes(0, block.as_ref().size());
+        }
+
+        block
+    }
+
+    /// Executes the reallocation specified by `realloc`. When possible,
+    /// reallocation is done in place to avoid copying contents from one block
+    /// to another, but changes in alignment constraints might prevent that.
+    ///
+    /// Note that `old_layout` and `new_layout` might have different alignments.
+    /// All the in place algorithms compute the padding needed for
+    /// `new_layout.align()` starting from the content address of the block, so
+    /// if alignment increases the contents are moved forward within the block
+    /// (or merged blocks) to the next properly aligned address. If there's not
+    /// enough space for that, we fall back to a new block, which is allocated
+    /// with `new_layout` and therefore aligned as well.
+    pub unsafe fn reallocate(&mut self, realloc: &Realloc) -> AllocResult {
+        // Reallocation is more complicated than allocation or deallocation,
+        // so study the code in the functions below to understand what's
+        // happening.
+        let address = match self.pool {
+            Some(_) => self.reallocate_in_pool(realloc)?,
+            None => self
+                .try_reallocate_in_place(realloc)
+                .or_else(|_| self.try_reallocate_on_another_block(realloc))?,
+        };
+
+        debug_assert_eq!(
+            address.as_mut_ptr() as usize % realloc.new_layout.align(),
+            0,
+            "reallocation returned an address that doesn't meet the new alignment"
+        );
+
+        // Blocks reallocated in place keep the old layout otherwise.
+        #[cfg(any(feature = "layout-tracking", debug_assertions))]
+        {
+            let mut block =
+                Header::<Block>::from_allocated_pointer(address.cast(), realloc.new_layout);
+            #[cfg(feature = "layout-tracking")]
+            {
+                block.as_mut().data.layout = Some(realloc.new_layout);
+            }
+            #[cfg(debug_assertions)]
+            {
+                block.as_mut().data.align_log2 = realloc.new_layout.align().trailing_zeros() as u8;
+            }
+        }
+
+        Ok(address)
+    }
+
+    /// Returns the free block chosen by [`Self::policy`] among all the blocks
+    /// that can fit `layout`, or `None` if we didn't find any. A block fits if
+    /// it can hold `layout.size()` bytes after moving the content start
+    /// forward to the next address aligned to `layout.align()`, so free blocks
+    /// are reused for stricter alignments whenever that padding fits. See
+    /// [`alignment::block_size_needed_at`].
+    unsafe fn find_free_block(&mut self, layout: Layout) -> Pointer<Header<Block>> {
+        self.policy
+            .choose(FreeBlocks::new(&self.free_blocks, layout), layout)
+            .map(|block| block.header())
+    }
+
+    /// Returns the first free block of `region` that can fit `layout`, or
+    /// `None` if there's no such block. Unlike [`Self::find_free_block`],
+    /// this walks through all the blocks of the region, free or not, so it's
+    /// only used to keep reallocations in the same region, see
+    /// [`Self::try_reallocate_on_another_block`].
+    unsafe fn find_free_block_in_region(
+        &self,
+        region: NonNull<Header<Region>>,
+        layout: Layout,
+    ) -> Pointer<Header<Block>> {
+        region.as_ref().data.blocks.iter().find(|block| {
+            block.as_ref().is_free()
+                && block.as_ref().size()
+                    >= alignment::block_size_needed_at(Header::content_address_of(*block), layout)
+        })
+    }
+
+    /// Returns the region that contains `address`, or `None` if the address
+    /// doesn't belong to this bucket. Regions are sorted by address, so the
+    /// search stops at the first region that starts after `address`.
+    unsafe fn find_region_containing(&self, address: NonNull<u8>) -> Pointer<Header<Region>> {
+        self.regions
+            .iter()
+            .take_while(|region| region.cast() <= address)
+            .last()
+            .filter(|region| region.as_ref().contains(address))
+    }
+
+    /// Whether `address` is between the start of the first region and the end
+    /// of the last one. Regions are sorted by address, so this is O(1), but
+    /// unlike [`Self::region_for`] it can't tell whether `address` falls in
+    /// a gap between two regions.
+    pub unsafe fn spans(&self, address: NonNull<u8>) -> bool {
+        match (self.regions.first(), self.regions.last()) {
+            (Some(first), Some(last)) => {
+                let end = last.as_ptr().addr() + last.as_ref().total_size();
+                (first.as_ptr().addr()..end).contains(&address.as_ptr().addr())
+            }
+            _ => false,
+        }
+    }
+
+    /// Describes the region that contains `address`, see
+    /// [`crate::Rulloc::region_for`].
+    pub unsafe fn region_for(&self, address: NonNull<u8>) -> Option<RegionInfo> {
+        self.find_region_containing(address)
+            .map(|region| RegionInfo {
+                address: region.cast(),
+                length: region.as_ref().total_size(),
+                bucket: self.index as usize,
+                num_blocks: region.as_ref().num_blocks(),
+            })
+    }
+
+    /// Returns the block whose content contains `address`, or `None` if the
+    /// address doesn't belong to any region of this bucket. This has to walk
+    /// through all the blocks of the region, so it's slow.
+    #[cfg(debug_assertions)]
+    unsafe fn find_block_containing(&self, address: NonNull<u8>) -> Pointer<Header<Block>> {
+        let addr = address.as_ptr().addr();
+        let region = self.find_region_containing(address)?;
+
+        region.as_ref().data.blocks.iter().find(|block| {
+            let content = Header::content_address_of(*block).as_ptr().addr();
+            (content..content + block.as_ref().size()).contains(&addr)
+        })
+    }
+
+    /// Generation of the block allocated at `address` with `layout`. See
+    /// [`crate::generation`].
+    #[cfg(debug_assertions)]
+    pub unsafe fn generation_of(&self, address: NonNull<u8>, layout: Layout) -> usize {
+        Header::<Block>::from_allocated_pointer(address, layout)
+            .as_ref()
+            .data
+            .generation
+    }
+
+    /// Returns `true` if `address` no longer belongs to an allocation of the
+    /// given `generation`, which means that it was freed and maybe reused.
+    /// We don't trust the header pointed by `address` because it might not
+    /// exist anymore, so we walk through regions and blocks instead.
+    #[cfg(debug_assertions)]
+    pub unsafe fn is_stale(&self, address: NonNull<u8>, generation: usize) -> bool {
+        match self.find_block_containing(address) {
+            Some(block) => block.as_ref().is_free() || block.as_ref().data.generation != generation,
+            None => true,
+        }
+    }
+
+    /// Alignment that the block whose content contains `address` was
+    /// allocated with, or `None` if there's no such block in use. If `layout`
+    /// is aligned to [`alignment::POINTER_SIZE`] or less, the header should be right
+    /// before `address`, and it's read from there once [`Self::is_block_of`]
+    /// confirms that it's really a block of the region. Otherwise, or if the
+    /// block was allocated with a stricter alignment, there's padding before
+    /// `address` and the header is found by walking through blocks just like
+    /// [`Self::is_stale`] does.
+    #[cfg(debug_assertions)]
+    pub unsafe fn allocated_align(&self, address: NonNull<u8>, layout: Layout) -> Option<usize> {
+        let region = self.find_region_containing(address)?;
+        let header = Header::<Block>::from_content_address(address);
+
+        let block =
+            if layout.align() <= alignment::POINTER_SIZE && Self::is_block_of(region, header) {
+                Some(header)
+            } else {
+                self.find_block_containing(address)
+            };
+
+        block
+            .filter(|block| !block.as_ref().is_free())
+            .map(|block| 1 << block.as_ref().data.align_log2)
+    }
+
+    /// Whether `block` points to the header of a block of `region`, without
+    /// trusting anything read from it until it's known to be inside the
+    /// region. The block must belong to the region and be linked with its
+    /// neighbours, stale headers left in the padding of other blocks are not.
+    #[cfg(debug_assertions)]
+    unsafe fn is_block_of(region: NonNull<Header<Region>>, block: NonNull<Header<Block>>) -> bool {
+        let start = Header::content_address_of(region).as_ptr().addr();
+        let end = start + region.as_ref().size();
+        let inside = |block: NonNull<Header<Block>>| {
+            (start..=end - BLOCK_HEADER_SIZE).contains(&block.as_ptr().addr())
+        };
+
+        if !inside(block) || block.as_ref().data.region != region {
+            return false;
+        }
+
+        match block.as_ref().prev {
+            Some(prev) => inside(prev) && prev.as_ref().next == Some(block),
+            None => region.as_ref().data.blocks.first() == Some(block),
+        }
+    }
+
+    /// Same as [`Self::check_interior_pointer`], but returns `None` if
+    /// `address` is not inside any region of this bucket instead of
+    /// panicking, so that the allocator can find the bucket by address
+    /// before trusting any block header. Also `None` if the policy is
+    /// [`InteriorPointerPolicy::Trust`], which doesn't check anything.
+    #[cfg(debug_assertions)]
+    pub unsafe fn resolve_interior_pointer(
+        &self,
+        address: NonNull<u8>,
+        layout: Layout,
+    ) -> Option<NonNull<u8>> {
+        if self.interior_pointers == InteriorPointerPolicy::Trust {
+            return None;
+        }
+
+        self.find_region_containing(address)?;
+        Some(self.check_interior_pointer(address, layout))
+    }
+
+    /// Checks that `address` is exactly the address returned when `layout`
+    /// was allocated, and handles it according to [`Self::interior_pointers`]
+    /// if it's not. Returns the address that should be deallocated.
+    #[cfg(debug_assertions)]
+    unsafe fn check_interior_pointer(&self, address: NonNull<u8>, layout: Layout) -> NonNull<u8> {
+        if self.interior_pointers == InteriorPointerPolicy::Trust {
+            return address;
+        }
+
+        let Some(block) = self.find_block_containing(address) else {
+            panic!("deallocating {address:?}, which was not allocated by this allocator");
+        };
+
+        let content_address = Header::content_address_of(block);
+        let expected = if layout.align() <= alignment::POINTER_SIZE {
+            content_address
+        } else {
+            alignment::next_aligned(content_address, layout.align()).0
+        };
+
+        if address == expected || block.as_ref().is_free() {
+            // If the block is free this is a double free, not our problem
+            // here.
+            return address;
+        }
+
+        match self.interior_pointers {
+            InteriorPointerPolicy::Lenient => expected,
+            _ => panic!(
+                "deallocating interior pointer {address:?}, the allocation starts at {expected:?} \
+                 ({} bytes before)",
+                address.as_ptr().addr() - expected.as_ptr().addr()
+            ),
+        }
+    }
+
+    /// This function executes the algorithm described at
+    /// [`alignment::AlignmentBackPointer`]. The caller must guarantee that
+    /// the given block meets the size constraints needed to introduce enough
+    /// padding without overriding other headers. See
+    /// [`alignment::minimum_block_size_needed_for`].
+    unsafe fn add_padding_if_needed(
+        &self,
+        block: NonNull<Header<Block>>,
+        align: usize,
+    ) -> NonNull<[u8]> {
+        let content_address = Header::content_address_of(block);
+
+        if align <= alignment::POINTER_SIZE {
+            return NonNull::slice_from_raw_parts(content_address, block.as_ref().size());
+        }
+
+        let (next_aligned, padding) = alignment::next_aligned(content_address, align);
+
+        ptr::write(alignment::back_pointer_of(next_aligned).as_ptr(), block);
+
+        return NonNull::slice_from_raw_parts(next_aligned, block.as_ref().size() - padding);
+    }
+
+    /// Requests a new memory region from the kernel where we can fit `size`
+    /// bytes plus headers. See [`determine_region_length`]. The returned region
+    /// will be already initialized with one single free block that takes up all
+    /// the space minus headers:
+    ///
+    /// ```text
+    /// +--------+-------------------------------------+
+    /// |        | +---------------------------------+ |
+    /// | Region | |          Big Free Block         | |
+    /// |        | +---------------------------------+ |
+    /// +--------+-------------------------------------+
+    /// ```
+    ///
+    /// The block must be split if it is too large in order to reduce
+    /// fragmentation. If cache coloring is enabled, the block doesn't start
+    /// right after the region header, see [`RegionPlacement`].
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The number of bytes (must be aligned to power of 2) that
+    ///   need to be allocated **without including any headers**.
+    unsafe fn request_region(
+        &mut self,
+        size: usize,
+    ) -> Result<NonNull<Header<Region>>, AllocError> {
+        let offset = self.placement.next_color_offset();
+        let guard = self.placement.guard();
+        let (length, mapped) = determine_region_length(size + offset)
+            .and_then(|length| Some((length, length.checked_add(guard)?)))
+            .filter(|(_, mapped)| *mapped <= isize::MAX as usize)
+            .ok_or_else(|| self.fail(AllocFailure::SizeOverflow))?;
+
+        if mapped > self.limit.saturating_sub(self.bytes_mapped) {
+            return Err(self.fail(AllocFailure::LimitExceeded));
+        }
+
+        let address = self
+            .placement
+            .map(mapped)
+            .map_err(|errno| self.fail(AllocFailure::MmapFailed(errno)))?;
+        self.bytes_mapped += mapped;
+        self.region_maps += 1;
+
+        if let Some(pattern) = self.scrub_pattern {
+            address.as_ptr().write_bytes(pattern, length);
+        }
+
+        // Nothing else is written to the region until a block is carved from
+        // it, see [`Self::take_fresh`].
+        self.fresh = self
+            .scrub_pattern
+            .or(self.placement.is_zero_filled().then_some(0));
+
+        #[cfg(feature = "logging")]
+        log::debug!(
+            "bucket {}: mapped region {address:?} of {mapped} bytes",
+            self.index
+        );
+
+        // If the kernel refuses to protect the guard the region still works,
+        // overruns just won't be detected.
+        if guard != 0 {
+            let guard_address = NonNull::new_unchecked(address.as_ptr().add(length));
+            platform::protect(guard_address, guard, Protection::None);
+        }
+
+        // Only a hint, the region is perfectly usable if it's ignored.
+        if self.placement.wants_huge_pages(length) {
+            platform::advise_huge_pages(address, length);
+        }
+
+        let mut region = self.regions.insert_sorted(
+            Region {
+                blocks: LinkedList::new(),
+                size: length - REGION_HEADER_SIZE,
+                guard,
+                used: 0,
+                requested: 0,
+            },
+            address,
+        );
+
+        let block = region.as_mut().data.blocks.append(
+            Block {
+                size: region.as_ref().size() - BLOCK_HEADER_SIZE - offset,
+                is_free: true,
+                purged: false,
+                bucket: self.index,
+                region,
+                #[cfg(feature = "layout-tracking")]
+                layout: None,
+                #[cfg(debug_assertions)]
+                generation: 0,
+                #[cfg(debug_assertions)]
+                align_log2: 0,
+            },
+            NonNull::new_unchecked(Header::content_address_of(region).as_ptr().add(offset)),
+        );
+
+        self.insert_free_block(block);
+
+        Ok(region)
+    }
+
+    /// Block splitting algorithm implementation. Let's say we have a free block
+    /// that can hold 128 bytes and a request to allocate 24 bytes has been
+    /// made. We'll split the free block in two different blocks, like so:
+    ///
+    /// **Before**:
+    ///
+    /// ```text
+    ///         +-->  +-----------+
+    ///         |     |   Header  | <- H bytes (depends on word size and stuff).
+    /// Block   |     +-----------+
+    ///         |     |  Content  | <- 128 bytes.
+    ///         +-->  +-----------+
+    /// ```
+    /// **After**:
+    ///
+    /// ```text
+    ///         +-->  +-----------+
+    ///         |     |   Header  | <- H bytes.
+    /// Block 1 |     +-----------+
+    ///         |     |  Content  | <- 24 bytes.
+    ///         +-->  +-----------+
+    ///         |     |   Header  | <- H bytes.
+    /// Block 2 |     +-----------+
+    ///         |     |  Content  | <- 128 bytes - 24 bytes - H bytes.
+    ///         +-->  +-----------+
+    /// ```
+    ///
+    /// The block doesn't necessarily have to be free, it might be in use but
+    /// we want to shrink it. See [`Self::shrink_block`]. This function does not
+    /// touch the contents of the block, it only changes it's header to reflect
+    /// the new size. On the other hand, the new block created in the splitting
+    /// process is automatically added to the free list.
+    ///
+    /// # Safety
+    ///
+    /// User content in this block can never exceed `size` bytes, this is only
+    /// relevant for shrinking used blocks. If padding was introduced in this
+    /// block to meet alignment constraints, the caller must guarantee that
+    /// padding is included in `size`.
+    unsafe fn split_block_if_possible(&mut self, mut block: NonNull<Header<Block>>, size: usize) {
+        // If there's not enough space available we can't split the block.
+        if block.as_ref().size() < size + BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE {
+            return;
+        }
+
+        // If we can, the new block will be located `size` bytes after the
+        // initial content address of the current block.
+        let address = Header::content_address_of(block).as_ptr().add(size);
+        let mut region = block.as_ref().data.region;
+
+        // Append new block next to current block.
+        let new_block = region.as_mut().data.blocks.insert_after(
+            block,
+            Block {
+                size: block.as_ref().data.size - size - BLOCK_HEADER_SIZE,
+                is_free: true,
+                purged: false,
+                bucket: self.index,
+                region,
+                #[cfg(feature = "layout-tracking")]
+                layout: None,
+                #[cfg(debug_assertions)]
+                generation: 0,
+                #[cfg(debug_assertions)]
+                align_log2: 0,
+            },
+            NonNull::new_unchecked(address),
+        );
+
+        self.insert_free_block(new_block);
+
+        // The current block can only hold `size` bytes from now on.
+        block.as_mut().data.size = size;
+
+        #[cfg(feature = "logging")]
+        log::trace!(
+            "bucket {}: split block {block:?} at {size} bytes, new free block {new_block:?} of {} bytes",
+            self.index,
+            new_block.as_ref().size()
+        );
+    }
+
+    /// This function performs the inverse of [`Self::split_block_if_possible`].
+    /// If surrounding blocks are free, then we'll merge them all into one
+    /// bigger block. This is called block coalescing or block merging.
+    ///
+    /// **Before**:
+    ///
+    /// ```text
+    ///                         +-->  +-----------+
+    ///                         |     |   Header  | <- H bytes.
+    /// Block A, Free           |     +-----------+
+    ///                         |     |  Content  | <- A bytes.
+    ///                         +-->  +-----------+
+    ///                         |     |   Header  | <- H bytes.
+    /// Block B, Recently freed |     +-----------+
+    ///                         |     |  Content  | <- B bytes.
+    ///                         +-->  +-----------+
+    ///                         |     |   Header  | <- H bytes.
+    /// Block C, Free           |     +-----------+
+    ///                         |     |  Content  | <- C bytes.
+    ///                         +-->  +-----------+
+    /// ```
+    ///
+    /// **After**:
+    ///
+    /// ```text
+    ///
+    ///                         +-->  +-----------+
+    ///                         |     |   Header  | <- H bytes.
+    /// Block D, Bigger block   |     +-----------+
+    ///                         |     |  Content  | <- A + B + C + 2H bytes.
+    ///                         +-->  +-----------+
+    /// ```
+    ///
+    /// Note that only one of the surrounding blocks is merged if the other one
+    /// is not free. Also, if the previous block is merged, then the address of
+    /// the current block changes. That's why we have to return a pointer to a
+    /// block.
+    ///
+    /// Each region has its own list of blocks, so the first block of a region
+    /// has no previous block and the last one has no next block, even if
+    /// another region is mapped right before or after it. Blocks are never
+    /// merged across regions, each region is a separate mapping and has its
+    /// own header.
+    ///
+    /// # Safety
+    ///
+    /// Unlike [`Self::split_block_if_possible`], the caller must guarantee that
+    /// `block` is free in this case.
+    #[rustfmt::skip]
+    unsafe fn merge_surrounding_free_blocks_if_possible(
+        &mut self,
+        mut block: NonNull<Header<Block>>,
+    ) -> NonNull<Header<Block>> {
+        if block.as_ref().next.is_some_and(|next| next.as_ref().is_free()) {
+            self.merge_next_adjacent_free_block(block);
+        }
+
+        if block.as_ref().prev.is_some_and(|prev| prev.as_ref().is_free()) {
+            block = block.as_ref().prev.unwrap();
+            self.merge_next_adjacent_free_block(block);
+        }
+
+        block
+    }
+
+    /// Helper for block merging algorithm. Blocks can only be merged from right
+    /// to left, or from next to current, because the new bigger block address
+    /// should be the address of the first free block. So, given a valid block,
+    /// this function will merge the next adjacent block into the given block
+    /// and update all data structures. This functions also assumes that the
+    /// given block is free and the next block to it is also free and not null.
+    ///
+    /// ```text
+    /// +----------------+---------------+
+    /// |    Block A     |   Block B     |
+    /// +----------------+---------------+
+    ///        ^                 |
+    ///        |                 |
+    ///        +-----------------+
+    ///           Merge B into A
+    /// ```
+    unsafe fn merge_next_adjacent_free_block(&mut self, block: NonNull<Header<Block>>) {
+        let next = block.as_ref().next.unwrap();
+
+        #[cfg(feature = "logging")]
+        log::trace!(
+            "bucket {}: merging block {next:?} into {block:?}",
+            self.index
+        );
+
+        // First update free list. The 2 old smaller blocks will "dissapear"
+        // from the list and the new bigger block will be inserted again, at
+        // the end unless the free list is sorted.
+        self.free_blocks.remove_block(next);
+        self.free_blocks.remove_block(block);
+
+        self.expand_block_by_consuming_next(block);
+        self.insert_free_block(block);
+    }
+
+    /// This function expands `block` by consuming the block right next to it,
+    /// without modifying the free list.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no adjacent block next to the given block.
+    unsafe fn expand_block_by_consuming_next(&mut self, mut block: NonNull<Header<Block>>) {
+        let next = block.as_ref().next.unwrap();
+        debug_assert!(
+            next.as_ref().data.region == block.as_ref().data.region
+                && next.cast() == Header::content_address_of(block).add(block.as_ref().size()),
+            "block {next:?} is not right after {block:?} in the same region"
+        );
+        // Now this block is bigger, and the new part was never purged.
+        block.as_mut().data.size += next.as_ref().total_size();
+        block.as_mut().data.purged = false;
+        // Next block doesn't exist anymore.
+        block.as_mut().region_mut().data.blocks.remove(next);
+    }
+
+    /// Optimized in place shrinking. The block can be split in two different
+    /// blocks, creating a free block next to it, and if there was another free
+    /// block next to the original block then they can be merged into one.
+    ///
+    /// Before:
+    ///
+    /// ```text
+    /// +------------------------------------+    +------------+
+    /// |    Used block about to be shrunk   | -> | Free Block |
+    /// +------------------------------------+    +------------+
+    /// ```
+    ///
+    /// After splitting:
+    ///
+    /// ```text
+    /// +--------------+    +----------------+    +------------+
+    /// | Shrunk Block | -> | New Free block | -> | Free Block |
+    /// +--------------+    +----------------+    +------------+
+    /// ```
+    ///
+    /// After merging:
+    ///
+    /// ```text
+    /// +--------------+    +----------------------------------+
+    /// | Shrunk Block | -> |   Resulting bigger free block    |
+    /// +--------------+    +----------------------------------+
+    /// ```
+    ///
+    /// If [`SizeClasses`] are enabled and the new free block is exactly the
+    /// size of one class, it's retained by the class instead of being merged,
+    /// so the next allocation of that class takes it without searching the
+    /// free list. This helps loops that shrink an allocation and then
+    /// allocate the size that was cut off.
+    unsafe fn shrink_block(&mut self, block: NonNull<Header<Block>>, new_size: usize) {
+        let next = block.as_ref().next;
+        self.split_block_if_possible(block, new_size);
+
+        // If the block couldn't be split, its next block might be in use, and
+        // merging used blocks would corrupt the free list.
+        if block.as_ref().next == next {
+            return;
+        }
+
+        // We've just split the block, so there's a new free block for sure.
+        let tail = block.as_ref().next.unwrap();
+
+        if self.size_classes.is_enabled() {
+            self.free_blocks.remove_block(tail);
+            if self.size_classes.push(tail) {
+                return;
+            }
+            self.insert_free_block(tail);
+        }
+
+        self.merge_surrounding_free_blocks_if_possible(tail);
+    }
+
+    /// Same as [`Self::shrink_block`], but if the block can't be split the
+    /// bytes it no longer needs are given to the next block when it's free.
+    /// See [`Self::set_exact_shrink`].
+    unsafe fn shrink_block_exactly(&mut self, mut block: NonNull<Header<Block>>, new_size: usize) {
+        let next = block.as_ref().next;
+        self.shrink_block(block, new_size);
+
+        // If the block was split there's nothing left to give away.
+        let slack = block.as_ref().size() - new_size;
+        if slack == 0 {
+            return;
+        }
+
+        let Some(next) = next.filter(|next| next.as_ref().is_free()) else {
+            return;
+        };
+
+        // Move the header of the next block back so that it starts right
+        // after the new size. Both lists must forget about the old header
+        // before the new one overwrites it.
+        let mut region = block.as_ref().data.region;
+        let size = next.as_ref().size() + slack;
+        self.free_blocks.remove_block(next);
+        region.as_mut().data.blocks.remove(next);
+
+        let moved = region.as_mut().data.blocks.insert_after(
+            block,
+            Block {
+                size,
+                is_free: true,
+                purged: false,
+                bucket: self.index,
+                region,
+                #[cfg(feature = "layout-tracking")]
+                layout: None,
+                #[cfg(debug_assertions)]
+                generation: 0,
+                #[cfg(debug_assertions)]
+                align_log2: 0,
+            },
+            NonNull::new_unchecked(Header::content_address_of(block).as_ptr().add(new_size)),
+        );
+
+        self.insert_free_block(moved);
+        block.as_mut().data.size = new_size;
+    }
+
+    /// Same as [`Self::shrink_block`], but the new free block created after
+    /// `block` is stored in the [`TailCache`] instead of being added to the
+    /// free list or merged with its next block, so that `block` can grow again
+    /// without moving its contents.
+    unsafe fn shrink_block_retaining_tail(
+        &mut self,
+        block: NonNull<Header<Block>>,
+        new_size: usize,
+    ) {
+        let next = block.as_ref().next;
+        self.split_block_if_possible(block, new_size);
+
+        // Block couldn't be split, nothing to retain.
+        if block.as_ref().next == next {
+            return;
+        }
+
+        // We've just split the block, so there's a next block for sure.
+        let tail = block.as_ref().next.unwrap();
+        self.free_blocks.remove_block(tail);
+
+        if let Some(evicted) = self.tail_cache.insert(tail) {
+            self.release_tail(evicted);
+        }
+    }
+
+    /// Moves `tail` from the [`TailCache`] to the free list. The block before
+    /// `tail` is the one that was shrunk and is still in use, so we can only
+    /// merge `tail` with its next block.
+    unsafe fn release_tail(&mut self, tail: NonNull<Header<Block>>) {
+        self.insert_free_block(tail);
+        self.merge_surrounding_free_blocks_if_possible(tail);
+    }
+
+    /// If the block right after `block` is a cached tail, releases it. See
+    /// [`Self::release_tail`].
+    unsafe fn release_tail_after(&mut self, block: NonNull<Header<Block>>) {
+        if !self.tail_cache.is_enabled() {
+            return;
+        }
+
+        if let Some(next) = block.as_ref().next {
+            if self.tail_cache.take(next) {
+                self.release_tail(next);
+            }
+        }
+    }
+
+    /// We will prioritize in place reallocations, this will only fail if the
+    /// new size doesn't fit even after merging surrounding blocks or if
+    /// alignment has increased drastically and we didn't find any aligned
+    /// address in the current block.
+    unsafe fn try_reallocate_in_place(&mut self, realloc: &Realloc) -> AllocResult {
+        match realloc.method {
+            ReallocMethod::Shrink => self.try_shrink_in_place(realloc),
+            ReallocMethod::Grow => self.try_grow_in_place(realloc),
+        }
+    }
+
+    /// Pools can't split or merge blocks, so either the new layout still
+    /// fits in the same block or we need another one.
+    unsafe fn reallocate_in_pool(&mut self, realloc: &Realloc) -> AllocResult {
+        let size = realloc.block.as_ref().size();
+
+        if realloc.new_layout.align() <= alignment::POINTER_SIZE
+            && realloc.new_layout.size() <= size
+        {
+            Ok(NonNull::slice_from_raw_parts(realloc.address, size))
+        } else {
+            self.try_reallocate_on_another_block(realloc)
+        }
+    }
+
+    /// If everything else fails, just find or create a new block and move
+    /// the contents there.
+    ///
+    /// The new block is searched in the region of the current block first,
+    /// even if the [`FitPolicy`] would choose a block in another region:
+    ///
+    /// ```text
+    ///                       +-------------- Preferred -------------+
+    ///                       |                                      |
+    ///                       v                                      |
+    /// +--------+------+-----------+     +--------+-------+------+---------+
+    /// | Region | Free | Allocated |     | Region | Block | Free | Current |
+    /// +--------+------+-----------+     +--------+-------+------+---------+
+    /// ```
+    ///
+    /// That keeps related allocations together and once the current block is
+    /// deallocated the region is still in use, whereas moving to another
+    /// region might leave this one mostly empty but still mapped.
+    unsafe fn try_reallocate_on_another_block(&mut self, realloc: &Realloc) -> AllocResult {
+        let same_region = match self.pool {
+            Some(_) => None,
+            None => self.find_free_block_in_region(
+                realloc.block.as_ref().data.region,
+                self.size_classes.round(realloc.new_layout),
+            ),
+        };
+
+        let new_address = match same_region {
+            Some(block) => self.allocate_exact(block, realloc.new_layout),
+            None => self.allocate(realloc.new_layout)?,
+        };
+        ptr::copy_nonoverlapping(
+            realloc.address.as_ptr(),
+            new_address.as_mut_ptr(),
+            realloc.count(),
+        );
+        self.deallocate(realloc.address, realloc.old_layout);
+
+        Ok(new_address)
+    }
+
+    /// If possible, this function will attempt to reallocate in place without
+    /// merging adjacent blocks or moving the contents to a new block. This can
+    /// be done especially when alignment changes, because that makes the
+    /// padding change as well.
+    unsafe fn try_reallocate_on_same_block(&mut self, realloc: &Realloc) -> AllocResult {
+        // First let's try to see if we can fit the new layout in this block.
+        let new_size = alignment::minimum_block_size_excluding_padding(realloc.new_layout);
+        let (next_aligned, padding) = alignment::next_aligned(
+            Header::<Block>::content_address_of(realloc.block),
+            realloc.new_layout.align(),
+        );
+
+        // No luck, we can't use this block. The size alone might fit, but the
+        // contents only stay in this block if they can be moved to an address
+        // that meets the new alignment, otherwise the caller must move them
+        // elsewhere instead of returning a misaligned pointer.
+        if padding + new_size > realloc.block.as_ref().size() {
+            return Err(AllocError);
+        }
+
+        // Now we know for sure that this block can be reused. The code below
+        // deals with many edge cases even though it doesn't look like it does,
+        // so let's break it down.
+        //
+        // We know that `alignment::next_aligned` function will return the first
+        // aligned address after the content address of the block, and we also
+        // know that there's enough space for padding because we've checked
+        // that in the if statement above. So whatever the new alignment is, we
+        // don't care, there's space for it.
+        //
+        // The `next_aligned` address might be located before the current
+        // address (if alignment has decreased) or after the current address
+        // (if alignment has increased), so we'll need to move the contents if
+        // the address wasn't already aligned.
+        //
+        // But now here comes the magic: This code also works for `POINTER_SIZE`
+        // alignment because `alignment::next_aligned` function will return the
+        // content address of the block whenever the alignment is less than or
+        // equal to `POINTER_SIZE`. So if the previous alignment was greater
+        // than `POINTER_SIZE` we'll get rid of all the padding and reduce
+        // fragmentation. If the previous alignment was already `POINTER_SIZE`
+        // then the if statement below won't even run because `next_aligned` is
+        // already equal to the given address!
+        //
+        // The only thing we have to do is write the back pointer if we added
+        // padding, otherwise there's no back pointer. So this covers all the
+        // combinatorics of `old_layout` and `new_layout`, both in terms of
+        // alignment and size.
+        //
+        // Of course, this conclusion was reached after writing dozens of
+        // if-else statements and noticing that the same code is repeated
+        // everywhere. So this is just like simplifying a huge equation!
+        if next_aligned != realloc.address {
+            ptr::copy(
+                realloc.address.as_ptr(),
+                next_aligned.as_ptr(),
+                realloc.count(),
+            );
+            if padding > 0 {
+                ptr::write(
+                    alignment::back_pointer_of(next_aligned).as_ptr(),
+                    realloc.block,
+                );
+            }
+        }
+
+        // If we removed padding or size has decreased, maybe we can create
+        // new free blocks next to this one. If the user is shrinking, we might
+        // want to keep the new free block for later, see [`TailCache`].
+        let shrink = matches!(realloc.method, ReallocMethod::Shrink);
+
+        // Contents that don't fit in the new layout anymore end up in a free
+        // block or a retained tail, so they're zeroed first just like the
+        // contents of deallocated blocks.
+        if shrink && self.sensitive {
+            let start = next_aligned.as_ptr().add(realloc.new_layout.size());
+            let end = Header::content_address_of(realloc.block)
+                .as_ptr()
+                .add(realloc.block.as_ref().size());
+            start.write_bytes(0, end.offset_from(start) as usize);
+        }
+
+        if shrink && self.exact_shrink {
+            self.shrink_block_exactly(realloc.block, new_size + padding);
+        } else if shrink && self.tail_cache.is_enabled() {
+            self.shrink_block_retaining_tail(realloc.block, new_size + padding);
+        } else {
+            self.shrink_block(realloc.block, new_size + padding);
+        }
+
+        Ok(NonNull::slice_from_raw_parts(
+            next_aligned,
+            realloc.block.as_ref().size() - padding,
+        ))
+    }
+
+    /// Attempts to fit the layout of the new allocation using the surrounding
+    /// free blocks. An error is returned if it's not possible.
+    #[inline]
+    unsafe fn try_grow_in_place(&mut self, realloc: &Realloc) -> AllocResult {
+        // If we retained the tail of this block when it was shrunk, this is
+        // the moment to claim it back.
+        self.release_tail_after(realloc.block);
+
+        self.try_reallocate_on_same_block(realloc)
+            .or_else(|_| self.try_grow_by_merging_next_block(realloc))
+            .or_else(|_| self.try_grow_by_merging_prev_block(realloc))
+            .or_else(|_| self.try_grow_by_merging_both_blocks(realloc))
+    }
+
+    /// For symmetry with [`Self::try_grow_in_place`].
+    #[inline]
+    unsafe fn try_shrink_in_place(&mut self, realloc: &Realloc) -> AllocResult {
+        // If the block is shrunk again the new tail will include the old one.
+        self.release_tail_after(realloc.block);
+
+        self.try_reallocate_on_same_block(realloc)
+    }
+
+    /// As the name suggests, this function attempts to merge the next adjacent
+    /// block to create a bigger one that fits the new layout.
+    ///
+    /// Before:
+    ///
+    /// ```text
+    /// +------------------------------------+    +------------+
+    /// |    Used block that needs to grow   | -> | Free Block |
+    /// +------------------------------------+    +------------+
+    /// ```
+    ///
+    /// After:
+    ///
+    /// ```text
+    /// +------------------------------------------------------+
+    /// |      Resulting block that fits the new layout        |
+    /// +------------------------------------------------------+
+    /// ```
+    unsafe fn try_grow_by_merging_next_block(&mut self, realloc: &Realloc) -> AllocResult {
+        let Realloc { block, .. } = realloc;
+        let next = block.as_ref().next.ok_or(AllocError)?;
+        self.try_grow_by_merging(&[*block, next], realloc)
+    }
+
+    /// Same as [`Self::try_grow_by_merging_next_block`] but using the previous
+    /// block.
+    ///
+    /// Before:
+    ///
+    /// ```text
+    /// +------------+    +------------------------------------+
+    /// | Free Block | -> |    Used block that needs to grow   |
+    /// +------------+    +------------------------------------+
+    /// ```
+    ///
+    /// After:
+    ///
+    /// ```text
+    /// +------------------------------------------------------+
+    /// |      Resulting block that fits the new layout        |
+    /// +------------------------------------------------------+
+    /// ```
+    unsafe fn try_grow_by_merging_prev_block(&mut self, realloc: &Realloc) -> AllocResult {
+        let Realloc { block, .. } = realloc;
+        let prev = block.as_ref().prev.ok_or(AllocError)?;
+        self.try_grow_by_merging(&[prev, *block], realloc)
+    }
+
+    /// If we can't get a big enough block by merging the next or previous, how
+    /// about merging both of them?
+    ///
+    /// Before:
+    ///
+    /// ```text
+    /// +------------+    +----------------------------------+    +------------+
+    /// | Free Block | -> |  Used block that needs to grow   | -> | Free Block |
+    /// +------------+    +----------------------------------+    +------------+
+    /// ```
+    ///
+    /// After:
+    ///
+    /// ```text
+    /// +----------------------------------------------------------------------+
+    /// |              Resulting block that fits the new layout                |
+    /// +----------------------------------------------------------------------+
+    /// ```
+    unsafe fn try_grow_by_merging_both_blocks(&mut self, realloc: &Realloc) -> AllocResult {
+        let Realloc { block, .. } = realloc;
+        let next = block.as_ref().next.ok_or(AllocError)?;
+        let prev = block.as_ref().prev.ok_or(AllocError)?;
+        self.try_grow_by_merging(&[prev, *block, next], realloc)
+    }
+
+    /// Automation for merging blocks. `blocks` must contain only adjacent
+    /// blocks, otherwise this will result in a disaster! See
+    /// [`Self::try_grow_in_place`], [`Self::try_grow_by_merging_next_block`],
+    /// [`Self::try_grow_by_merging_prev_block`] and
+    /// [`Self::try_grow_by_merging_both_blocks`].
+    unsafe fn try_grow_by_merging(
+        &mut self,
+        blocks: &[NonNull<Header<Block>>],
+        realloc: &Realloc,
+    ) -> AllocResult {
+        let (starting_block, rest) = blocks.split_first().unwrap();
+
+        // Only the block we are reallocating can be in use, merging any other
+        // used block would override somebody else's data.
+        if blocks
+            .iter()
+            .any(|block| *block != realloc.block && !block.as_ref().is_free())
+        {
+            return Err(AllocError);
+        }
+
+        let new_size = alignment::minimum_block_size_excluding_padding(realloc.new_layout);
+        let padding = alignment::padding_needed_to_align(
+            Header::<Block>::content_address_of(*starting_block),
+            realloc.new_layout.align(),
+        );
+
+        let total_size = rest
+            .iter()
+            .fold(starting_block.as_ref().size(), |total, next| {
+                total + next.as_ref().total_size()
+            });
+
+        if new_size + padding > total_size {
+            return Err(AllocError);
+        }
+
+        for block in blocks.iter().rev().skip(1) {
+            // We skipped one, there should always be a next block.
+            let next = block.as_ref().next.unwrap();
+            if next.as_ref().is_free() {
+                self.free_blocks.remove_block(next);
+            }
+            if block.as_ref().is_free() {
+                self.free_blocks.remove_block(*block);
+            }
+            self.expand_block_by_consuming_next(*block);
+        }
+
+        self.try_reallocate_on_same_block(&realloc.map(*starting_block))
+    }
+}
+
+impl<P> Bucket<P> {
+    /// Content sizes of the blocks that are still allocated. Blocks retained
+    /// by the [`TailCache`] or [`SizeClasses`] are not free but they are not
+    /// in use either, so they are skipped.
+    #[cfg(feature = "leak-check")]
+    pub unsafe fn live_block_sizes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.regions
+            .iter()
+            .flat_map(|region| region.as_ref().data.blocks.iter())
+            .filter(|block| {
+                !block.as_ref().is_free()
+                    && !self.tail_cache.contains(*block)
+                    && !self.size_classes.contains(*block)
+            })
+            .map(|block| block.as_ref().size())
+    }
+
+    /// Returns all the regions of this bucket to the kernel. Regions are not
+    /// necessarily mapped in address order, but `mmap` tends to place new
+    /// mappings right next to the previous ones, so it's common to have runs
+    /// of virtually adjacent regions:
+    ///
+    /// ```text
+    /// +----------+----------+----------+       +----------+
+    /// | Region 2 | Region 0 | Region 3 |  ...  | Region 1 |
+    /// +----------+----------+----------+       +----------+
+    /// ^                                ^       ^          ^
+    /// |                                |       |          |
+    /// +--------- One munmap -----------+       +- munmap -+
+    /// ```
+    ///
+    /// If the platform supports it we find those runs and return each of them
+    /// with one single call instead of one call per region. Regions are kept
+    /// sorted by address, so each run is just a sequence of consecutive
+    /// regions in the list and checking whether the next one belongs to the
+    /// run is O(1). Regions that become empty while the allocator is alive
+    /// are returned immediately by [`Self::deallocate`], so dropping is the
+    /// only moment where we can find multiple regions to return at once.
+    unsafe fn return_all_regions(&mut self) {
+        if !self.placement.can_coalesce() {
+            for region in self.regions.iter() {
+                let length = region.as_ref().total_size();
+                #[cfg(feature = "logging")]
+                log::debug!(
+                    "bucket {}: unmapped region {region:?} of {length} bytes",
+                    self.index
+                );
+                self.unmap_failures
+                    .check(self.placement.unmap(region.cast(), length));
+            }
+            return;
+        }
+
+        let end_of =
+            |region: NonNull<Header<Region>>| region.as_ptr().addr() + region.as_ref().total_size();
+
+        // Region headers live inside the memory we are returning, so every
+        // region is removed from the list before its run is unmapped,
+        // otherwise the next iteration would read unmapped memory.
+        while let Some(start) = self.regions.first() {
+            self.regions.remove(start);
+            let mut end = end_of(start);
+
+            while let Some(next) = self
+                .regions
+                .first()
+                .filter(|next| next.as_ptr().addr() == end)
+            {
+                self.regions.remove(next);
+                end = end_of(next);
+            }
+
+            let length = end - start.as_ptr().addr();
+            #[cfg(feature = "logging")]
+            log::debug!(
+                "bucket {}: unmapped regions {start:?} of {length} bytes",
+                self.index
+            );
+            self.unmap_failures
+                .check(self.placement.unmap(start.cast(), length));
+        }
+    }
+
+    /// Resets the bucket to its empty state without touching the regions. The
+    /// configuration, including whether the [`TailCache`] and [`SizeClasses`]
+    /// are enabled, is not modified.
+    fn forget_regions(&mut self) {
+        let tail_cache_enabled = self.tail_cache.is_enabled();
+        let size_classes_enabled = self.size_classes.is_enabled();
+
+        *self.regions = LinkedList::new();
+        *self.free_blocks = FreeList::new();
+        self.tail_cache = TailCache::new();
+        self.tail_cache.set_enabled(tail_cache_enabled);
+        self.size_classes = SizeClasses::new();
+        self.size_classes.set_enabled(size_classes_enabled);
+        self.bytes_mapped = 0;
+    }
+}
+
+impl<P> Drop for Bucket<P> {
+    fn drop(&mut self) {
+        // Unmap failures can't be reported anymore, they're only logged.
+        unsafe { self.return_all_regions() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alignment::AlignmentBackPointer,
+        platform::{page_size, PAGE_SIZE},
+        sizeclass::SIZE_CLASSES,
+    };
+
+    /// Region that contains the block allocated at `address` with `layout`.
+    unsafe fn region_of(address: NonNull<[u8]>, layout: Layout) -> NonNull<Header<Region>> {
+        Header::<Block>::from_allocated_pointer(address.cast(), layout)
+            .as_ref()
+            .data
+            .region
+    }
+
+    fn check_mem_corruption(chunk: &[u8], corruption_check: u8) {
+        for value in chunk {
+            assert_eq!(value, &corruption_check);
+        }
+    }
+
+    #[test]
+    fn allocs_and_deallocs() {
+        unsafe {
+            let mut bucket = Bucket::new();
+
+            // Request 1 byte, should call `mmap` with length of PAGE_SIZE.
+            let first_layout = Layout::new::<u8>();
+            let mut first_addr = bucket.allocate(first_layout).unwrap();
+
+            // We'll use this later to check memory corruption. The allocator
+            // should not touch the content of any block.
+            let first_addr_corruption_check = 69;
+            first_addr.as_mut().fill(first_addr_corruption_check);
+
+            // First region should be PAGE_SIZE in length.
+            let first_region = bucket.regions.first().unwrap();
+            assert_eq!(first_region.as_ref().total_size(), PAGE_SIZE);
+            assert_eq!(bucket.regions.len(), 1);
+
+            // First block should be located after the region header.
+            let first_block = first_region.as_ref().first_block();
+            assert_eq!(
+                first_block.as_ptr() as usize - first_region.as_ptr() as usize,
+                REGION_HEADER_SIZE
+            );
+
+            // Size of first block should be minimum size because we've
+            // requested 1 byte only.
+            assert_eq!(first_block.as_ref().size(), MIN_BLOCK_SIZE);
+            assert_eq!(bucket.free_blocks.len(), 1);
+
+            // Region should have two blocks, we are using the first one and the
+            // other one is free.
+            assert_eq!(first_region.as_ref().num_blocks(), 2);
+
+            // First free block should match this size because there are
+            // two blocks in the region and one of them has minimum size.
+            assert_eq!(
+                bucket.free_blocks.first_free_block().unwrap().size(),
+                PAGE_SIZE - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE * 2 - MIN_BLOCK_SIZE
+            );
+
+            // The remaining free block should be split in two when allocating
+            // less size than it can hold.
+            let second_layout = Layout::array::<u8>(PAGE_SIZE / 2).unwrap();
+            let mut second_addr = bucket.allocate(second_layout).unwrap();
+
+            // We'll check corruption later.
+            let second_addr_corruption_check = 42;
+            second_addr.as_mut().fill(second_addr_corruption_check);
+
+            // There are 3 blocks now, last one is still free.
+            assert_eq!(first_region.as_ref().num_blocks(), 3);
+            assert_eq!(bucket.free_blocks.len(), 1);
+
+            // Lets try to allocate the entire remaining free block.
+            let remaining_size = PAGE_SIZE
+                - REGION_HEADER_SIZE
+                - (BLOCK_HEADER_SIZE + MIN_BLOCK_SIZE) // First Alloc
+                - (BLOCK_HEADER_SIZE + PAGE_SIZE / 2) // Second Alloc
+                - BLOCK_HEADER_SIZE;
+            let third_layout = Layout::array::<u8>(remaining_size).unwrap();
+            let mut third_addr = bucket.allocate(third_layout).unwrap();
+
+            let third_addr_corruption_check = 107;
+            third_addr.as_mut().fill(third_addr_corruption_check);
+
+            // Number of blocks hasn't changed, but we don't have free blocks
+            // anymore.
+            assert_eq!(first_region.as_ref().num_blocks(), 3);
+            assert_eq!(bucket.free_blocks.len(), 0);
+
+            // Time for checking memory corruption
+            check_mem_corruption(first_addr.as_ref(), first_addr_corruption_check);
+            check_mem_corruption(second_addr.as_ref(), second_addr_corruption_check);
+            check_mem_corruption(third_addr.as_ref(), third_addr_corruption_check);
+
+            // Let's request a bigger chunk so that a new region is used.
+            let fourth_layout = Layout::array::<u8>(PAGE_SIZE * 2 - PAGE_SIZE / 2).unwrap();
+            let mut fourth_addr = bucket.allocate(fourth_layout).unwrap();
+
+            let fourth_addr_corruption_check = 205;
+            fourth_addr.as_mut().fill(fourth_addr_corruption_check);
+
+            // We should have a new region and a new free block now.
+            assert_eq!(bucket.regions.len(), 2);
+            assert_eq!(bucket.free_blocks.len(), 1);
+
+            // Let's play with dealloc.
+            bucket.deallocate(first_addr.cast(), first_layout);
+
+            // After deallocating the first block, we should have a new free
+            // block but the number of blocks in the region shouldn't change
+            // because no coalescing can happen.
+            assert_eq!(first_region.as_ref().num_blocks(), 3);
+            assert_eq!(bucket.free_blocks.len(), 2);
+
+            bucket.deallocate(third_addr.cast(), third_layout);
+
+            // Again, after deallocating the third block we should have a new
+            // free block but the number of block in the region doesn't change.
+            assert_eq!(first_region.as_ref().num_blocks(), 3);
+            assert_eq!(bucket.free_blocks.len(), 3);
+
+            // Now here comes the magic, if we deallocate second addr all blocks
+            // in region one should be merged and region should be returned to
             // the kernel.
             bucket.deallocate(second_addr.cast(), second_layout);
             assert_eq!(bucket.regions.len(), 1);
             assert_eq!(bucket.free_blocks.len(), 1);
 
-            // Check mem corruption in the last block
-            check_mem_corruption(fourth_addr.as_ref(), fourth_addr_corruption_check);
+            // Check mem corruption in the last block
+            check_mem_corruption(fourth_addr.as_ref(), fourth_addr_corruption_check);
+
+            // Deallocating fourh address should unmap the last region.
+            bucket.deallocate(fourth_addr.cast(), fourth_layout);
+            assert_eq!(bucket.regions.len(), 0);
+            assert_eq!(bucket.free_blocks.len(), 0);
+        }
+    }
+
+    unsafe fn allocate_aligned(
+        bucket: &mut Bucket,
+        size: usize,
+        align: usize,
+        corruption_check: u8,
+    ) -> (NonNull<u8>, Layout) {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let mut addr = bucket.allocate(layout).unwrap();
+
+        // We are not actually performing aligned memory accesses,
+        // but it doesn't matter, we just wanna check that we can
+        // write to the requested memory and we don't seg fault.
+        // We're not writing the entire layout when using Miri
+        // because it's too slow, we'll just write to the addresses
+        // that might cause problems, and Miri will catch bugs or
+        // undefined behaviour.
+        if cfg!(miri) {
+            addr.as_mut()[0] = corruption_check;
+            addr.as_mut()[size / 2] = corruption_check;
+            addr.as_mut()[size - 1] = corruption_check;
+        } else {
+            addr.as_mut().fill(corruption_check);
+        }
+
+        assert_eq!(addr.as_mut_ptr() as usize % align, 0);
+
+        (addr.cast(), layout)
+    }
+
+    unsafe fn deallocate_aligned(
+        bucket: &mut Bucket,
+        aligned_alloc: (NonNull<u8>, Layout),
+        corruption_check: u8,
+    ) {
+        let (addr, layout) = aligned_alloc;
+        let slice = NonNull::slice_from_raw_parts(addr, layout.size());
+        if cfg!(miri) {
+            check_mem_corruption(
+                &[
+                    slice.as_ref()[0],
+                    slice.as_ref()[layout.size() / 2],
+                    slice.as_ref()[layout.size() - 1],
+                ],
+                corruption_check,
+            )
+        } else {
+            check_mem_corruption(slice.as_ref(), corruption_check);
+        }
+        bucket.deallocate(addr, layout);
+    }
+
+    #[test]
+    fn strictly_aligned_allocs_and_deallocs() {
+        unsafe {
+            let mut bucket = Bucket::new();
+
+            let layout = Layout::from_size_align(1, 16).unwrap();
+            let address = bucket.allocate(layout).unwrap().cast::<u8>();
+
+            assert_eq!(address.as_ptr() as usize % 16, 0);
+
+            // Basic back pointer check
+            let first_block = bucket.regions.first().unwrap().as_ref().first_block();
+            let back_ptr = address.cast::<AlignmentBackPointer>().as_ptr().offset(-1);
+            assert_eq!(*back_ptr, first_block);
+
+            bucket.deallocate(address, layout);
+
+            let alignments = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
+            let sizes = [1, 2, 4, 8, 10, 20, 512, 1000, 2048, 4096];
+            let mut allocations = [(NonNull::dangling(), Layout::new::<u8>()); 10];
+            let corruption_check = 69;
+
+            // Multiple allocations of same alignment.
+            for align in alignments {
+                for (i, size) in sizes.iter().enumerate() {
+                    allocations[i] = allocate_aligned(&mut bucket, *size, align, corruption_check);
+                }
+                for allocation in allocations {
+                    deallocate_aligned(&mut bucket, allocation, corruption_check)
+                }
+            }
+            assert_eq!(bucket.regions().len(), 0);
+
+            // Multiple allocations of different alignment.
+            for size in sizes {
+                for (i, align) in alignments.iter().enumerate() {
+                    allocations[i] = allocate_aligned(&mut bucket, size, *align, corruption_check);
+                }
+                for allocation in allocations {
+                    deallocate_aligned(&mut bucket, allocation, corruption_check)
+                }
+            }
+            assert_eq!(bucket.regions().len(), 0);
+        }
+    }
+
+    #[test]
+    fn shrink() {
+        unsafe {
+            let mut corruption_check = 42;
+            let mut bucket = Bucket::new();
+
+            // Allocate entire page.
+            let first_layout =
+                Layout::array::<u8>(page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE).unwrap();
+            let mut first_addr = bucket.allocate(first_layout).unwrap();
+
+            first_addr.as_mut().fill(corruption_check);
+
+            // Now shrink by half
+            let first_layout_shrunk =
+                Layout::from_size_align(first_layout.size() / 2, first_layout.align()).unwrap();
+            let first_addr_shrunk = bucket
+                .reallocate(&Realloc::shrink(
+                    first_addr.cast(),
+                    first_layout,
+                    first_layout_shrunk,
+                ))
+                .unwrap();
+
+            check_mem_corruption(first_addr_shrunk.as_ref(), corruption_check);
+
+            // Shrinking by half should have shrunk the block in place
+            assert_eq!(first_addr.cast::<u8>(), first_addr_shrunk.cast::<u8>());
+            let first_region = bucket.regions().first().unwrap();
+            assert_eq!(
+                first_region.as_ref().first_block().as_ref().size(),
+                first_layout_shrunk
+                    .align_to(alignment::POINTER_SIZE)
+                    .unwrap()
+                    .pad_to_align()
+                    .size()
+            );
+            assert_eq!(first_region.as_ref().num_blocks(), 2);
+            assert_eq!(bucket.free_blocks.len(), 1);
+            assert_eq!(bucket.regions.len(), 1);
+
+            // Let's allocate the remaining block
+            let second_layout = Layout::array::<u8>(
+                page_size()
+                    - REGION_HEADER_SIZE
+                    - 2 * BLOCK_HEADER_SIZE
+                    - first_region.as_ref().first_block().as_ref().size(),
+            )
+            .unwrap();
+
+            let mut second_addr = bucket.allocate(second_layout).unwrap();
+            assert_eq!(second_addr.as_ref().len(), second_layout.size());
+            assert_eq!(first_region.as_ref().num_blocks(), 2);
+            assert_eq!(bucket.free_blocks.len(), 0);
+
+            corruption_check -= 10;
+            second_addr.as_mut().fill(corruption_check);
+
+            // Let's use page size alignment to force reallocation, because
+            // we don't know what address mmap gave us for the region, we only
+            // know that it is aligned to page size. The next address aligned
+            // to page size is at the end of this region, so this should
+            // reallocate.
+            let second_layout_page_aligned =
+                Layout::from_size_align(second_layout.size(), page_size()).unwrap();
+            let second_addr_page_aligned = bucket
+                .reallocate(&Realloc::shrink(
+                    second_addr.cast(),
+                    second_layout,
+                    second_layout_page_aligned,
+                ))
+                .unwrap();
+            let second_region = region_of(second_addr_page_aligned, second_layout_page_aligned);
+            assert_eq!(bucket.regions.len(), 2);
+            assert_eq!(second_region.as_ref().num_blocks(), 2);
+
+            // Last block that we were using was deallocated, and the new one
+            // won't take up all the space in the region.
+            assert_eq!(bucket.free_blocks.len(), 2);
+            assert_ne!(
+                second_addr.as_mut_ptr(),
+                second_addr_page_aligned.as_mut_ptr()
+            );
+            // Padding is exactly what's needed to reach the next page, so the
+            // block is split right after the content.
+            assert_eq!(
+                second_addr_page_aligned.len(),
+                second_layout
+                    .align_to(alignment::POINTER_SIZE)
+                    .unwrap()
+                    .pad_to_align()
+                    .size()
+            );
+            assert_eq!(
+                second_addr_page_aligned.as_mut_ptr() as usize % page_size(),
+                0
+            );
+
+            // We've only filled the memory the allocator gave us the first
+            // time, after forcing reallocation to page size alignment it might
+            // give us a little bit more than we need because of rounding.
+            check_mem_corruption(
+                &second_addr_page_aligned.as_ref()[0..second_addr.len()],
+                corruption_check,
+            );
+
+            // Now let's reduce alignment to force everyhing to be moved
+            // backwards.
+            let previous_block_size = second_region.as_ref().first_block().as_ref().size();
+            let second_layout_half_page_aligned =
+                Layout::from_size_align(second_layout.size(), page_size() / 2).unwrap();
+            let second_addr_half_page_aligned = bucket
+                .reallocate(&Realloc::shrink(
+                    second_addr_page_aligned.cast(),
+                    second_layout_page_aligned,
+                    second_layout_half_page_aligned,
+                ))
+                .unwrap();
+
+            assert!(
+                second_addr_half_page_aligned
+                    .as_mut_ptr()
+                    .offset_from(second_addr_page_aligned.as_mut_ptr())
+                    < 0
+            );
+            // Less padding, so block is smaller.
+            assert!(previous_block_size > second_region.as_ref().first_block().as_ref().size());
+            assert_eq!(
+                second_addr_half_page_aligned.as_mut_ptr() as usize % (page_size() / 2),
+                0
+            );
+            check_mem_corruption(
+                &second_addr_half_page_aligned.as_ref()[0..second_addr.len()],
+                corruption_check,
+            );
+
+            bucket.deallocate(first_addr_shrunk.cast(), first_layout_shrunk);
+            bucket.deallocate(
+                second_addr_half_page_aligned.cast(),
+                second_layout_half_page_aligned,
+            );
+            // No more regions, we've only worked with 2 pointers so far.
+            assert_eq!(bucket.regions.len(), 0);
+
+            // Last but not least, let's try to force everything to be moved
+            // forward instead of backwards. For that, we are going to allocate
+            // an entire page and then increase alignment but decrease size.
+            let third_layout = Layout::from(first_layout);
+            let mut third_addr = bucket.allocate(third_layout).unwrap();
+            corruption_check += 15;
+            third_addr.as_mut().fill(corruption_check);
+
+            let third_layout_aligned_to_half_page =
+                Layout::from_size_align(8, page_size() / 2).unwrap();
+            let third_addr_aligned_to_half_page = bucket
+                .reallocate(&Realloc::shrink(
+                    third_addr.cast(),
+                    third_layout,
+                    third_layout_aligned_to_half_page,
+                ))
+                .unwrap();
+
+            check_mem_corruption(
+                &third_addr_aligned_to_half_page.as_ref()
+                    [..third_layout_aligned_to_half_page.size()],
+                corruption_check,
+            );
+            assert_eq!(bucket.regions.len(), 1);
+            assert_eq!(bucket.free_blocks.len(), 1);
+            assert_eq!(
+                third_addr_aligned_to_half_page.as_mut_ptr() as usize % (page_size() / 2),
+                0
+            );
+            assert!(
+                third_addr_aligned_to_half_page
+                    .as_mut_ptr()
+                    .offset_from(third_addr.as_mut_ptr())
+                    > 0
+            );
+            bucket.deallocate(
+                third_addr_aligned_to_half_page.cast(),
+                third_layout_aligned_to_half_page,
+            );
+        }
+    }
+
+    #[test]
+    fn grow_by_consuming_next_or_prev() {
+        unsafe {
+            let mut bucket = Bucket::new();
+
+            let first_layout = Layout::from_size_align(MIN_BLOCK_SIZE, 4).unwrap();
+            let mut first_addr = bucket.allocate(first_layout).unwrap();
+            let mut corruption_check = 200;
+
+            first_addr.as_mut().fill(corruption_check);
+            let first_region = bucket.regions.first().unwrap();
+
+            // Let's test growth by consuming next block
+            let first_layout_grow_to_40 = Layout::from_size_align(40, 4).unwrap();
+            let first_addr_grow_to_40 = bucket
+                .reallocate(&Realloc::grow(
+                    first_addr.cast(),
+                    first_layout,
+                    first_layout_grow_to_40,
+                ))
+                .unwrap();
+
+            assert_eq!(bucket.regions.len(), 1);
+            assert_eq!(first_region.as_ref().num_blocks(), 2);
+            assert_eq!(bucket.free_blocks.len(), 1);
+            assert_eq!(first_addr_grow_to_40.len(), 40);
+            assert_eq!(first_addr.as_mut_ptr(), first_addr_grow_to_40.as_mut_ptr());
+            check_mem_corruption(
+                &first_addr_grow_to_40.as_ref()[..first_layout.size()],
+                corruption_check,
+            );
+
+            // Now let's try to consume the previous block
+            let second_layout = Layout::array::<u8>(
+                page_size()
+                    - REGION_HEADER_SIZE
+                    - 3 * BLOCK_HEADER_SIZE
+                    - first_region.as_ref().first_block().as_ref().size(),
+            )
+            .unwrap();
+            let mut second_addr = bucket.allocate(second_layout).unwrap();
+
+            corruption_check = 3;
+            second_addr.as_mut().fill(corruption_check);
+
+            // This should set the first block free.
+            bucket.deallocate(first_addr_grow_to_40.cast(), first_layout_grow_to_40);
+            assert_eq!(bucket.free_blocks.len(), 1);
+
+            let second_layout_grow_to_page_size =
+                Layout::array::<u8>(page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE).unwrap();
+
+            let second_addr_grow_to_page_size = bucket
+                .reallocate(&Realloc::grow(
+                    second_addr.cast(),
+                    second_layout,
+                    second_layout_grow_to_page_size,
+                ))
+                .unwrap();
+
+            // Should be the same as the first one because everything is moved
+            // to the first block again.
+            assert_eq!(
+                first_addr.as_mut_ptr(),
+                second_addr_grow_to_page_size.as_mut_ptr()
+            );
+            // We're using all the page, so no free blocks.
+            assert_eq!(bucket.free_blocks.len(), 0);
+            assert_eq!(first_region.as_ref().num_blocks(), 1);
+            check_mem_corruption(
+                &second_addr_grow_to_page_size.as_ref()[..second_addr.as_ref().len()],
+                corruption_check,
+            );
+
+            bucket.deallocate(
+                second_addr_grow_to_page_size.cast(),
+                second_layout_grow_to_page_size,
+            );
+        }
+    }
+
+    #[test]
+    fn grow_does_not_consume_used_blocks() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(64).unwrap();
+
+            let first_addr = bucket.allocate(layout).unwrap();
+            let mut second_addr = bucket.allocate(layout).unwrap();
+            let corruption_check = 7;
+            second_addr.as_mut().fill(corruption_check);
 
-            // Deallocating fourh address should unmap the last region.
-            bucket.deallocate(fourth_addr.cast(), fourth_layout);
+            // Merging the next block would give us enough space, but it's
+            // being used, so the contents have to be moved somewhere else.
+            let grow_layout = Layout::array::<u8>(100).unwrap();
+            let grown = bucket
+                .reallocate(&Realloc::grow(first_addr.cast(), layout, grow_layout))
+                .unwrap();
+            grown.as_mut_ptr().write_bytes(1, grow_layout.size());
+
+            assert_ne!(grown.as_mut_ptr(), first_addr.as_mut_ptr());
+            check_mem_corruption(second_addr.as_ref(), corruption_check);
+
+            bucket.deallocate(grown.cast(), grow_layout);
+            bucket.deallocate(second_addr.cast(), layout);
             assert_eq!(bucket.regions.len(), 0);
-            assert_eq!(bucket.free_blocks.len(), 0);
         }
     }
 
-    unsafe fn allocate_aligned(
-        bucket: &mut Bucket,
-        size: usize,
-        align: usize,
-        corruption_check: u8,
-    ) -> (NonNull<u8>, Layout) {
-        let layout = Layout::from_size_align(size, align).unwrap();
-        let mut addr = bucket.allocate(layout).unwrap();
+    #[test]
+    fn custom_fit_policy() {
+        /// Always picks the largest free block and counts how many times it
+        /// has been called.
+        struct LargestFit(usize);
+
+        impl FitPolicy for LargestFit {
+            fn choose<'a>(
+                &mut self,
+                free_blocks: FreeBlocks<'a>,
+                _: Layout,
+            ) -> Option<crate::FreeBlock<'a>> {
+                self.0 += 1;
+                free_blocks.max_by_key(crate::FreeBlock::size)
+            }
+        }
 
-        // We are not actually performing aligned memory accesses,
-        // but it doesn't matter, we just wanna check that we can
-        // write to the requested memory and we don't seg fault.
-        // We're not writing the entire layout when using Miri
-        // because it's too slow, we'll just write to the addresses
-        // that might cause problems, and Miri will catch bugs or
-        // undefined behaviour.
-        if cfg!(miri) {
-            addr.as_mut()[0] = corruption_check;
-            addr.as_mut()[size / 2] = corruption_check;
-            addr.as_mut()[size - 1] = corruption_check;
-        } else {
-            addr.as_mut().fill(corruption_check);
+        unsafe {
+            let mut bucket = Bucket::with_policy(LargestFit(0));
+            let small = Layout::array::<u8>(64).unwrap();
+            let big = Layout::array::<u8>(256).unwrap();
+
+            let small_addr = bucket.allocate(small).unwrap();
+            let first_separator = bucket.allocate(small).unwrap();
+            let big_addr = bucket.allocate(big).unwrap();
+            let second_separator = bucket.allocate(small).unwrap();
+
+            // Consume the rest of the region so that the only free blocks are
+            // the ones we deallocate below.
+            let rest =
+                Layout::array::<u8>(bucket.free_blocks.first_free_block().unwrap().size()).unwrap();
+            let rest_addr = bucket.allocate(rest).unwrap();
+            assert_eq!(bucket.free_blocks.len(), 0);
+
+            bucket.deallocate(small_addr.cast(), small);
+            bucket.deallocate(big_addr.cast(), big);
+
+            // First fit would return the small block because it comes first in
+            // the free list.
+            let layout = Layout::array::<u8>(32).unwrap();
+            let addr = bucket.allocate(layout).unwrap();
+            assert_eq!(addr.as_mut_ptr(), big_addr.as_mut_ptr());
+            assert_eq!(bucket.policy.0, 6);
+
+            for (addr, layout) in [
+                (addr, layout),
+                (first_separator, small),
+                (second_separator, small),
+                (rest_addr, rest),
+            ] {
+                bucket.deallocate(addr.cast(), layout);
+            }
+            assert_eq!(bucket.regions.len(), 0);
         }
+    }
 
-        assert_eq!(addr.as_mut_ptr() as usize % align, 0);
+    #[cfg(debug_assertions)]
+    #[test]
+    fn lenient_interior_pointer_deallocation() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_interior_pointer_policy(InteriorPointerPolicy::Lenient);
 
-        (addr.cast(), layout)
+            for align in [alignment::POINTER_SIZE, 64] {
+                let layout = Layout::from_size_align(128, align).unwrap();
+                let address = bucket.allocate(layout).unwrap();
+                let interior = NonNull::new_unchecked(address.as_mut_ptr().add(16));
+
+                bucket.deallocate(interior, layout);
+                assert_eq!(bucket.regions.len(), 0);
+            }
+        }
     }
 
-    unsafe fn deallocate_aligned(
-        bucket: &mut Bucket,
-        aligned_alloc: (NonNull<u8>, Layout),
-        corruption_check: u8,
-    ) {
-        let (addr, layout) = aligned_alloc;
-        let slice = NonNull::slice_from_raw_parts(addr, layout.size());
-        if cfg!(miri) {
-            check_mem_corruption(
-                &[
-                    slice.as_ref()[0],
-                    slice.as_ref()[layout.size() / 2],
-                    slice.as_ref()[layout.size() - 1],
-                ],
-                corruption_check,
-            )
-        } else {
-            check_mem_corruption(slice.as_ref(), corruption_check);
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "deallocating interior pointer")]
+    fn interior_pointer_deallocation_panics() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_interior_pointer_policy(InteriorPointerPolicy::Panic);
+
+            let layout = Layout::array::<u8>(128).unwrap();
+            let address = bucket.allocate(layout).unwrap();
+            bucket.deallocate(NonNull::new_unchecked(address.as_mut_ptr().add(8)), layout);
         }
-        bucket.deallocate(addr, layout);
     }
 
     #[test]
-    fn strictly_aligned_allocs_and_deallocs() {
+    fn aligned_and_colored_regions() {
         unsafe {
             let mut bucket = Bucket::new();
+            let align = 64 * 1024;
+            let stride = 64;
+            bucket.set_region_alignment(align);
+            bucket.set_color_stride(stride);
+
+            // Each allocation needs its own region.
+            let layout = Layout::array::<u8>(page_size()).unwrap();
+            let addresses: Vec<_> = (0..3).map(|_| bucket.allocate(layout).unwrap()).collect();
+            assert_eq!(bucket.regions.len(), 3);
+
+            for (i, address) in addresses.iter().enumerate() {
+                let region = region_of(*address, layout);
+                assert_eq!(region.as_ptr() as usize % align, 0);
+                let first_block = region.as_ref().first_block();
+                assert_eq!(
+                    first_block.as_ptr() as usize - region.as_ptr() as usize,
+                    REGION_HEADER_SIZE + i * stride
+                );
+            }
 
-            let layout = Layout::from_size_align(1, 16).unwrap();
-            let address = bucket.allocate(layout).unwrap().cast::<u8>();
+            for address in addresses {
+                address.as_mut_ptr().write_bytes(1, layout.size());
+                bucket.deallocate(address.cast(), layout);
+            }
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            assert_eq!(address.as_ptr() as usize % 16, 0);
+    #[test]
+    fn allocate_batch() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::new::<[u64; 4]>();
+            let count = 64;
 
-            // Basic back pointer check
-            let first_block = bucket.regions.first().unwrap().as_ref().first_block();
-            let back_ptr = address.cast::<AlignmentBackPointer>().as_ptr().offset(-1);
-            assert_eq!(*back_ptr, first_block);
+            let mut addresses = Vec::with_capacity(count);
+            bucket
+                .allocate_batch(layout, count, &mut addresses)
+                .unwrap();
 
-            bucket.deallocate(address, layout);
+            assert_eq!(addresses.len(), count);
+            assert_eq!(bucket.regions.len(), 1);
 
-            let alignments = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192];
-            let sizes = [1, 2, 4, 8, 10, 20, 512, 1000, 2048, 4096];
-            let mut allocations = [(NonNull::dangling(), Layout::new::<u8>()); 10];
-            let corruption_check = 69;
+            let stride = BLOCK_HEADER_SIZE + layout.size();
+            for pair in addresses.windows(2) {
+                assert_eq!(
+                    pair[1].as_ptr() as usize - pair[0].as_ptr() as usize,
+                    stride
+                );
+            }
 
-            // Multiple allocations of same alignment.
-            for align in alignments {
-                for (i, size) in sizes.iter().enumerate() {
-                    allocations[i] = allocate_aligned(&mut bucket, *size, align, corruption_check);
-                }
-                for allocation in allocations {
-                    deallocate_aligned(&mut bucket, allocation, corruption_check)
-                }
+            for address in &addresses {
+                address.as_ptr().write_bytes(5, layout.size());
             }
-            assert_eq!(bucket.regions().len(), 0);
 
-            // Multiple allocations of different alignment.
-            for size in sizes {
-                for (i, align) in alignments.iter().enumerate() {
-                    allocations[i] = allocate_aligned(&mut bucket, size, *align, corruption_check);
-                }
-                for allocation in allocations {
-                    deallocate_aligned(&mut bucket, allocation, corruption_check)
-                }
+            // Every other block first, so that none of them can be merged.
+            for address in addresses.iter().step_by(2) {
+                bucket.deallocate(*address, layout);
             }
-            assert_eq!(bucket.regions().len(), 0);
+            assert_eq!(bucket.free_blocks.len(), count / 2 + 1);
+            bucket.free_blocks.validate();
+
+            for address in addresses.iter().skip(1).step_by(2) {
+                bucket.deallocate(*address, layout);
+            }
+            assert_eq!(bucket.regions.len(), 0);
+            bucket.free_blocks.validate();
         }
     }
 
     #[test]
-    fn shrink() {
+    fn grow_by_merging_prev_without_splitting() {
         unsafe {
-            let mut corruption_check = 42;
             let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(64).unwrap();
+
+            let first_addr = bucket.allocate(layout).unwrap();
+            let second_addr = bucket.allocate(layout).unwrap();
+            let third_addr = bucket.allocate(layout).unwrap();
+            bucket.deallocate(first_addr.cast(), layout);
+
+            // Fits exactly in the first and second blocks merged, so the
+            // resulting block can't be split and the third block (which is in
+            // use) must not be merged with the free block after it.
+            let grow_layout = Layout::array::<u8>(64 + BLOCK_HEADER_SIZE + 64).unwrap();
+            let grown = bucket
+                .reallocate(&Realloc::grow(second_addr.cast(), layout, grow_layout))
+                .unwrap();
+            assert_eq!(grown.as_mut_ptr(), first_addr.as_mut_ptr());
+            assert_eq!(bucket.free_blocks.len(), 1);
+            assert!(!Header::<Block>::from_content_address(third_addr.cast())
+                .as_ref()
+                .is_free());
 
-            // Allocate entire page.
-            let first_layout =
-                Layout::array::<u8>(page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE).unwrap();
-            let mut first_addr = bucket.allocate(first_layout).unwrap();
+            bucket.deallocate(third_addr.cast(), layout);
+            bucket.deallocate(grown.cast(), grow_layout);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            first_addr.as_mut().fill(corruption_check);
+    #[test]
+    fn grow_backwards_when_next_block_is_used() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(128).unwrap();
 
-            // Now shrink by half
-            let first_layout_shrunk =
-                Layout::from_size_align(first_layout.size() / 2, first_layout.align()).unwrap();
-            let first_addr_shrunk = bucket
-                .reallocate(&Realloc::shrink(
-                    first_addr.cast(),
-                    first_layout,
-                    first_layout_shrunk,
-                ))
-                .unwrap();
+            let first_addr = bucket.allocate(layout).unwrap();
+            let mut second_addr = bucket.allocate(layout).unwrap();
+            let third_addr = bucket.allocate(layout).unwrap();
 
-            check_mem_corruption(first_addr_shrunk.as_ref(), corruption_check);
+            let corruption_check = 42;
+            second_addr.as_mut().fill(corruption_check);
+            bucket.deallocate(first_addr.cast(), layout);
+
+            // The next block is in use, so the only way to grow in place is
+            // consuming the previous block and moving the content down.
+            let grow_layout = Layout::array::<u8>(192).unwrap();
+            let grown = bucket
+                .reallocate(&Realloc::grow(second_addr.cast(), layout, grow_layout))
+                .unwrap();
 
-            // Shrinking by half should have shrunk the block in place
-            assert_eq!(first_addr.cast::<u8>(), first_addr_shrunk.cast::<u8>());
-            let first_region = bucket.regions().first().unwrap();
-            assert_eq!(
-                first_region.as_ref().first_block().as_ref().size(),
-                first_layout_shrunk
-                    .align_to(alignment::POINTER_SIZE)
-                    .unwrap()
-                    .pad_to_align()
-                    .size()
-            );
-            assert_eq!(first_region.as_ref().num_blocks(), 2);
-            assert_eq!(bucket.free_blocks.len(), 1);
             assert_eq!(bucket.regions.len(), 1);
+            assert_eq!(grown.as_mut_ptr(), first_addr.as_mut_ptr());
+            assert!(grown.as_mut_ptr() < second_addr.as_mut_ptr());
+            check_mem_corruption(&grown.as_ref()[..layout.size()], corruption_check);
+            bucket.validate_free_list();
 
-            // Let's allocate the remaining block
-            let second_layout = Layout::array::<u8>(
-                page_size()
-                    - REGION_HEADER_SIZE
-                    - 2 * BLOCK_HEADER_SIZE
-                    - first_region.as_ref().first_block().as_ref().size(),
-            )
-            .unwrap();
+            bucket.deallocate(third_addr.cast(), layout);
+            bucket.deallocate(grown.cast(), grow_layout);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            let mut second_addr = bucket.allocate(second_layout).unwrap();
-            assert_eq!(second_addr.as_ref().len(), second_layout.size());
-            assert_eq!(first_region.as_ref().num_blocks(), 2);
+    #[test]
+    fn reuse_free_block_for_stricter_alignment() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let small = Layout::array::<u8>(64).unwrap();
+            let large_aligned = Layout::from_size_align(512, 64).unwrap();
+
+            let first = bucket.allocate(small).unwrap();
+            let freed = bucket.allocate(large_aligned).unwrap();
+            let last = bucket.allocate(small).unwrap();
+
+            // Fill the region so that the only free block left is the one we
+            // are about to deallocate.
+            let rest =
+                Layout::array::<u8>(bucket.free_blocks.first_free_block().unwrap().size()).unwrap();
+            let rest_addr = bucket.allocate(rest).unwrap();
             assert_eq!(bucket.free_blocks.len(), 0);
 
-            corruption_check -= 10;
-            second_addr.as_mut().fill(corruption_check);
+            // The back pointer is overwritten by the free list node once the
+            // block is deallocated, so find the block boundaries first.
+            let freed_block = Header::<Block>::from_allocated_pointer(freed.cast(), large_aligned);
+            let block_start = Header::content_address_of(freed_block).as_ptr();
+            let block_end = block_start.add(freed_block.as_ref().size());
+            bucket.deallocate(freed.cast(), large_aligned);
 
-            // Let's use page size alignment to force reallocation, because
-            // we don't know what address mmap gave us for the region, we only
-            // know that it is aligned to page size. The next address aligned
-            // to page size is at the end of this region, so this should
-            // reallocate.
-            let second_layout_page_aligned =
-                Layout::from_size_align(second_layout.size(), page_size()).unwrap();
-            let second_addr_page_aligned = bucket
-                .reallocate(&Realloc::shrink(
-                    second_addr.cast(),
-                    second_layout,
-                    second_layout_page_aligned,
-                ))
-                .unwrap();
-            let second_region = bucket.regions.last().unwrap();
-            assert_eq!(bucket.regions.len(), 2);
-            assert_eq!(second_region.as_ref().num_blocks(), 2);
+            // Needs more alignment than the freed block content has, but
+            // there's enough space to introduce the padding.
+            let strict = Layout::from_size_align(128, 256).unwrap();
+            let reused = bucket.allocate(strict).unwrap();
 
-            // Last block that we were using was deallocated, and the new one
-            // won't take up all the space in the region.
-            assert_eq!(bucket.free_blocks.len(), 2);
-            assert_ne!(
-                second_addr.as_mut_ptr(),
-                second_addr_page_aligned.as_mut_ptr()
-            );
-            // Account for padding.
-            assert_eq!(
-                second_addr_page_aligned.len(),
-                page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE - second_layout.size()
-            );
-            assert_eq!(
-                second_addr_page_aligned.as_mut_ptr() as usize % page_size(),
-                0
-            );
+            assert_eq!(bucket.regions.len(), 1);
+            assert_eq!(reused.as_mut_ptr() as usize % strict.align(), 0);
+            assert!(reused.as_mut_ptr() > block_start);
+            assert!(reused.as_mut_ptr().add(strict.size()) <= block_end);
+
+            bucket.deallocate(reused.cast(), strict);
+            bucket.deallocate(first.cast(), small);
+            bucket.deallocate(last.cast(), small);
+            bucket.deallocate(rest_addr.cast(), rest);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            // We've only filled the memory the allocator gave us the first
-            // time, but after forcing reallocation to page size alignment it
-            // will give us a little bit more than we need because the minimum
-            // block size has to account for the padding needed in the worst
-            // case.
-            check_mem_corruption(
-                &second_addr_page_aligned.as_ref()[0..second_addr.len()],
-                corruption_check,
+    #[cfg(feature = "logging")]
+    #[test]
+    fn log_region_events() {
+        use std::sync::Mutex;
+
+        struct CapturingLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_index(7);
+            let layout = Layout::array::<u8>(64).unwrap();
+            let address = bucket.allocate(layout).unwrap();
+            bucket.deallocate(address.cast(), layout);
+        }
+
+        // Other tests might be running at the same time, so only look for
+        // the events of our bucket. Each event is matched by its whole
+        // prefix, "unmapped region" also contains "mapped region".
+        let events = LOGGER.0.lock().unwrap();
+        for event in [
+            "mapped region",
+            "split block",
+            "merging block",
+            "unmapped region",
+        ] {
+            let prefix = format!("bucket 7: {event} ");
+            assert!(
+                events.iter().any(|e| e.starts_with(&prefix)),
+                "no \"{prefix}\" event in {events:#?}"
             );
+        }
+    }
 
-            // Now let's reduce alignment to force everyhing to be moved
-            // backwards.
-            let previous_block_size = second_region.as_ref().first_block().as_ref().size();
-            let second_layout_half_page_aligned =
-                Layout::from_size_align(second_layout.size(), page_size() / 2).unwrap();
-            let second_addr_half_page_aligned = bucket
-                .reallocate(&Realloc::shrink(
-                    second_addr_page_aligned.cast(),
-                    second_layout_page_aligned,
-                    second_layout_half_page_aligned,
-                ))
-                .unwrap();
+    #[cfg(all(unix, not(miri)))]
+    #[test]
+    fn region_guard_faults_on_overrun() {
+        use std::{os::unix::process::ExitStatusExt, process::Command};
+
+        const CHILD: &str = "RULLOC_REGION_GUARD_CHILD";
+
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_region_guard(1);
+
+            // Each allocation takes its entire region.
+            let layout =
+                Layout::array::<u8>(4 * page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE)
+                    .unwrap();
+            let mut first = bucket.allocate(layout).unwrap();
+            let mut second = bucket.allocate(layout).unwrap();
+
+            assert_eq!(bucket.regions.len(), 2);
+            assert_eq!(bucket.bytes_mapped, 2 * 5 * page_size());
+
+            let regions = [region_of(first, layout), region_of(second, layout)];
+            let usable_end = |region: NonNull<Header<Region>>| {
+                Header::content_address_of(region)
+                    .as_ptr()
+                    .add(region.as_ref().size())
+            };
+
+            // The usable part of both regions can be written normally.
+            for (address, region) in [&mut first, &mut second].into_iter().zip(&regions) {
+                address.as_mut().fill(69);
+                assert_eq!(address.as_mut_ptr().add(address.len()), usable_end(*region));
+            }
+
+            if std::env::var_os(CHILD).is_some() {
+                // One byte past the usable end of the first region.
+                usable_end(regions[0]).write_volatile(0);
+                unreachable!("write to guard page didn't fault");
+            }
+
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "bucket::tests::region_guard_faults_on_overrun"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap()
+                .status;
 
             assert!(
-                second_addr_half_page_aligned
-                    .as_mut_ptr()
-                    .offset_from(second_addr_page_aligned.as_mut_ptr())
-                    < 0
-            );
-            // Less padding, so block is smaller.
-            assert!(previous_block_size > second_region.as_ref().first_block().as_ref().size());
-            assert_eq!(
-                second_addr_half_page_aligned.as_mut_ptr() as usize % (page_size() / 2),
-                0
-            );
-            check_mem_corruption(
-                &second_addr_half_page_aligned.as_ref()[0..second_addr.len()],
-                corruption_check,
+                matches!(status.signal(), Some(libc::SIGSEGV | libc::SIGBUS)),
+                "child exited with {status:?}"
             );
 
-            bucket.deallocate(first_addr_shrunk.cast(), first_layout_shrunk);
-            bucket.deallocate(
-                second_addr_half_page_aligned.cast(),
-                second_layout_half_page_aligned,
-            );
-            // No more regions, we've only worked with 2 pointers so far.
+            // The next region is unaffected by the guard of the other one.
+            check_mem_corruption(&second.as_ref()[..layout.size()], 69);
+
+            bucket.deallocate(first.cast(), layout);
+            bucket.deallocate(second.cast(), layout);
             assert_eq!(bucket.regions.len(), 0);
+            assert_eq!(bucket.bytes_mapped, 0);
+        }
+    }
 
-            // Last but not least, let's try to force everything to be moved
-            // forward instead of backwards. For that, we are going to allocate
-            // an entire page and then increase alignment but decrease size.
-            let third_layout = Layout::from(first_layout);
-            let mut third_addr = bucket.allocate(third_layout).unwrap();
-            corruption_check += 15;
-            third_addr.as_mut().fill(corruption_check);
+    #[test]
+    fn free_first_and_last_blocks_of_region() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(64).unwrap();
+
+            let first = bucket.allocate(layout).unwrap();
+            let middle = bucket.allocate(layout).unwrap();
+            let region = Header::<Block>::from_allocated_pointer(first.cast(), layout)
+                .as_ref()
+                .data
+                .region;
+
+            // Take the free tail exactly so that the last block is in use.
+            let tail = region.as_ref().data.blocks.last().unwrap();
+            let last_layout = Layout::array::<u8>(tail.as_ref().size()).unwrap();
+            let last = bucket.allocate(last_layout).unwrap();
+            assert_eq!(last.as_mut_ptr(), Header::content_address_of(tail).as_ptr());
+            assert_eq!(region.as_ref().num_blocks(), 3);
 
-            let third_layout_aligned_to_half_page =
-                Layout::from_size_align(8, page_size() / 2).unwrap();
-            let third_addr_aligned_to_half_page = bucket
-                .reallocate(&Realloc::shrink(
-                    third_addr.cast(),
-                    third_layout,
-                    third_layout_aligned_to_half_page,
-                ))
-                .unwrap();
+            // Region is full, so this one goes to a different region, most
+            // likely mapped right next to the first one.
+            let other = bucket.allocate(layout).unwrap();
+            let other_region = Header::<Block>::from_allocated_pointer(other.cast(), layout)
+                .as_ref()
+                .data
+                .region;
+            assert_ne!(region, other_region);
+            let other_blocks = other_region.as_ref().num_blocks();
+            let other_first_size = other_region.as_ref().first_block().as_ref().size();
+
+            // First block has no previous block, it can only become free.
+            bucket.deallocate(first.cast(), layout);
+            let first_block = region.as_ref().first_block();
+            assert!(first_block.as_ref().is_free());
+            assert_eq!(first_block.as_ref().size(), layout.size());
+            assert_eq!(region.as_ref().num_blocks(), 3);
 
-            check_mem_corruption(
-                &third_addr_aligned_to_half_page.as_ref()
-                    [..third_layout_aligned_to_half_page.size()],
-                corruption_check,
-            );
-            assert_eq!(bucket.regions.len(), 1);
-            assert_eq!(bucket.free_blocks.len(), 1);
+            // Same for the last block, which has no next block.
+            bucket.deallocate(last.cast(), last_layout);
+            let last_block = region.as_ref().data.blocks.last().unwrap();
+            assert!(last_block.as_ref().is_free());
+            assert_eq!(last_block.as_ref().size(), last_layout.size());
+            assert_eq!(region.as_ref().num_blocks(), 3);
+
+            // Nothing leaked into the other region.
+            assert_eq!(other_region.as_ref().num_blocks(), other_blocks);
             assert_eq!(
-                third_addr_aligned_to_half_page.as_mut_ptr() as usize % (page_size() / 2),
-                0
-            );
-            assert!(
-                third_addr_aligned_to_half_page
-                    .as_mut_ptr()
-                    .offset_from(third_addr.as_mut_ptr())
-                    > 0
-            );
-            bucket.deallocate(
-                third_addr_aligned_to_half_page.cast(),
-                third_layout_aligned_to_half_page,
+                other_region.as_ref().first_block().as_ref().size(),
+                other_first_size
             );
+            bucket.validate_free_list();
+
+            // Freeing the middle block merges everything back into one block,
+            // so the region is returned.
+            bucket.deallocate(middle.cast(), layout);
+            assert_eq!(bucket.regions.len(), 1);
+
+            bucket.deallocate(other.cast(), layout);
+            assert_eq!(bucket.regions.len(), 0);
         }
     }
 
     #[test]
-    fn grow_by_consuming_next_or_prev() {
+    fn coalesce_adjacent_regions_on_drop() {
         unsafe {
+            // Map 3 pages at once so that we know for sure that the regions
+            // are adjacent, then register them out of order.
+            let length = page_size();
+            let address = platform::request_memory(3 * length, 0).unwrap();
+
             let mut bucket = Bucket::new();
+            for i in [2, 0, 1] {
+                bucket.regions.insert_sorted(
+                    Region {
+                        blocks: LinkedList::new(),
+                        size: length - REGION_HEADER_SIZE,
+                        guard: 0,
+                        used: 0,
+                        requested: 0,
+                    },
+                    NonNull::new_unchecked(address.as_ptr().add(i * length)),
+                );
+            }
 
-            let first_layout = Layout::from_size_align(MIN_BLOCK_SIZE, 4).unwrap();
-            let mut first_addr = bucket.allocate(first_layout).unwrap();
-            let mut corruption_check = 200;
+            let calls = platform::return_memory_calls();
+            drop(bucket);
 
-            first_addr.as_mut().fill(corruption_check);
-            let first_region = bucket.regions.first().unwrap();
+            let expected = if platform::can_coalesce() { 1 } else { 3 };
+            assert_eq!(platform::return_memory_calls() - calls, expected);
+        }
+    }
 
-            // Let's test growth by consuming next block
-            let first_layout_grow_to_40 = Layout::from_size_align(40, 4).unwrap();
-            let first_addr_grow_to_40 = bucket
-                .reallocate(&Realloc::grow(
-                    first_addr.cast(),
-                    first_layout,
-                    first_layout_grow_to_40,
-                ))
+    #[cfg(all(target_os = "linux", not(miri)))]
+    #[test]
+    fn cold_free_cache() {
+        unsafe {
+            let large = Layout::array::<u8>(16 * page_size()).unwrap();
+            let small = Layout::array::<u8>(64).unwrap();
+
+            for enabled in [false, true] {
+                let mut bucket = Bucket::new();
+                bucket.set_cold_free_cache(enabled);
+
+                // The small block keeps the region mapped after the large one
+                // goes back to the free list.
+                let addr = bucket.allocate(large).unwrap();
+                let kept = bucket.allocate(small).unwrap();
+                addr.as_mut_ptr().write_bytes(7, large.size());
+
+                let cold_bytes = platform::cold_bytes();
+                bucket.deallocate(addr.cast(), large);
+                let advised = platform::cold_bytes() - cold_bytes;
+
+                if enabled {
+                    assert!(advised >= 14 * page_size());
+                } else {
+                    assert_eq!(advised, 0);
+                }
+
+                // Cold pages are still mapped and keep their contents.
+                let content = std::slice::from_raw_parts(addr.as_mut_ptr(), large.size());
+                check_mem_corruption(&content[MIN_BLOCK_SIZE..], 7);
+
+                bucket.deallocate(kept.cast(), small);
+                assert_eq!(bucket.regions.len(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_then_grow_with_tail_cache() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_tail_cache(true);
+
+            let corruption_check = 33;
+            let layout =
+                Layout::array::<u8>(page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE).unwrap();
+            let mut addr = bucket.allocate(layout).unwrap();
+            addr.as_mut().fill(corruption_check);
+
+            let shrunk_layout = Layout::array::<u8>(layout.size() / 2).unwrap();
+            let shrunk = bucket
+                .reallocate(&Realloc::shrink(addr.cast(), layout, shrunk_layout))
                 .unwrap();
 
+            // Shrunk in place, but the tail is not in the free list.
+            assert_eq!(shrunk.as_mut_ptr(), addr.as_mut_ptr());
+            assert_eq!(bucket.free_blocks.len(), 0);
+            assert_eq!(bucket.tail_cache.len(), 1);
+
+            // This allocation would have taken the tail, so now it needs a
+            // new region.
+            let other_layout = Layout::array::<u8>(64).unwrap();
+            let other = bucket.allocate(other_layout).unwrap();
+            assert_eq!(bucket.regions.len(), 2);
+
+            // Growing again should reclaim the tail without moving anything.
+            let grown = bucket
+                .reallocate(&Realloc::grow(shrunk.cast(), shrunk_layout, layout))
+                .unwrap();
+            assert_eq!(grown.as_mut_ptr(), addr.as_mut_ptr());
+            assert_eq!(bucket.tail_cache.len(), 0);
+            check_mem_corruption(&grown.as_ref()[..shrunk_layout.size()], corruption_check);
+
+            // Shrink once more and deallocate, tail should be released and
+            // the region returned to the kernel.
+            let shrunk = bucket
+                .reallocate(&Realloc::shrink(grown.cast(), layout, shrunk_layout))
+                .unwrap();
+            assert_eq!(bucket.tail_cache.len(), 1);
+            bucket.deallocate(shrunk.cast(), shrunk_layout);
+            assert_eq!(bucket.tail_cache.len(), 0);
             assert_eq!(bucket.regions.len(), 1);
-            assert_eq!(first_region.as_ref().num_blocks(), 2);
-            assert_eq!(bucket.free_blocks.len(), 1);
-            assert_eq!(first_addr_grow_to_40.len(), 40);
-            assert_eq!(first_addr.as_mut_ptr(), first_addr_grow_to_40.as_mut_ptr());
-            check_mem_corruption(
-                &first_addr_grow_to_40.as_ref()[..first_layout.size()],
-                corruption_check,
-            );
 
-            // Now let's try to consume the previous block
-            let second_layout = Layout::array::<u8>(
-                page_size()
-                    - REGION_HEADER_SIZE
-                    - 3 * BLOCK_HEADER_SIZE
-                    - first_region.as_ref().first_block().as_ref().size(),
-            )
-            .unwrap();
-            let mut second_addr = bucket.allocate(second_layout).unwrap();
+            bucket.deallocate(other.cast(), other_layout);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            corruption_check = 3;
-            second_addr.as_mut().fill(corruption_check);
+    #[test]
+    fn round_requests_to_size_classes() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_size_classes(true);
+
+            let layout = Layout::array::<u8>(20).unwrap();
+            let class = bucket.size_classes.class_of(layout).unwrap();
+            assert_eq!(SIZE_CLASSES[class], 32);
+
+            let first = bucket.allocate(layout).unwrap();
+            assert_eq!(first.len(), 32);
+            let first_block = Header::<Block>::from_allocated_pointer(first.cast(), layout);
+            assert_eq!(first_block.as_ref().size(), 32);
+
+            // Keep the region mapped so that we can see where blocks go.
+            let other_layout = Layout::array::<u8>(100).unwrap();
+            let other = bucket.allocate(other_layout).unwrap();
+
+            // Freed block goes to the list of its class, not the free list.
+            let free_blocks = bucket.free_blocks.len();
+            bucket.deallocate(first.cast(), layout);
+            assert_eq!(bucket.size_classes.len(class), 1);
+            assert_eq!(bucket.free_blocks.len(), free_blocks);
+            assert!(!first_block.as_ref().is_free());
+
+            // Any request of the same class reuses it.
+            let bigger_layout = Layout::array::<u8>(28).unwrap();
+            let second = bucket.allocate(bigger_layout).unwrap();
+            assert_eq!(second.as_mut_ptr(), first.as_mut_ptr());
+            assert_eq!(bucket.size_classes.len(class), 0);
+
+            bucket.deallocate(second.cast(), bigger_layout);
+            assert_eq!(bucket.size_classes.len(class), 1);
+
+            // Disabling size classes releases retained blocks.
+            bucket.set_size_classes(false);
+            assert_eq!(bucket.size_classes.len(class), 0);
+            bucket.validate_free_list();
+
+            bucket.deallocate(other.cast(), other_layout);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
 
-            // This should set the first block free.
-            bucket.deallocate(first_addr_grow_to_40.cast(), first_layout_grow_to_40);
-            assert_eq!(bucket.free_blocks.len(), 1);
+    #[test]
+    fn free_region_keeps_caches_of_other_regions() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_size_classes(true);
 
-            let second_layout_grow_to_page_size =
-                Layout::array::<u8>(page_size() - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE).unwrap();
+            let layout = Layout::array::<u8>(32).unwrap();
+            let class = bucket.size_classes.class_of(layout).unwrap();
 
-            let second_addr_grow_to_page_size = bucket
-                .reallocate(&Realloc::grow(
-                    second_addr.cast(),
-                    second_layout,
-                    second_layout_grow_to_page_size,
-                ))
+            // Fill the first region until blocks go to a second one.
+            let mut addresses = vec![bucket.allocate(layout).unwrap()];
+            let first_region = region_of(addresses[0], layout);
+            while region_of(*addresses.last().unwrap(), layout) == first_region {
+                addresses.push(bucket.allocate(layout).unwrap());
+            }
+            let second = addresses.pop().unwrap();
+
+            // One cached block in each region.
+            bucket.deallocate(addresses[0].cast(), layout);
+            bucket.deallocate(second.cast(), layout);
+            assert_eq!(bucket.size_classes.len(class), 2);
+
+            // Only the block of the second region is still cached.
+            bucket.free_region(first_region);
+            assert_eq!(bucket.size_classes.len(class), 1);
+            let reused = bucket.allocate(layout).unwrap();
+            assert_eq!(reused.as_mut_ptr(), second.as_mut_ptr());
+            bucket.validate_free_list();
+
+            bucket.deallocate(reused.cast(), layout);
+            bucket.set_size_classes(false);
+            bucket.validate_free_list();
+        }
+    }
+
+    #[test]
+    fn shrink_tail_to_size_class() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            bucket.set_size_classes(true);
+
+            // Cut exactly one block of the 160 byte class off the end.
+            let layout = Layout::array::<u8>(512).unwrap();
+            let shrunk_layout = Layout::array::<u8>(512 - 160 - BLOCK_HEADER_SIZE).unwrap();
+            let tail_layout = Layout::array::<u8>(160).unwrap();
+            let class = bucket.size_classes.class_of(tail_layout).unwrap();
+
+            let address = bucket.allocate(layout).unwrap();
+            let shrunk = bucket
+                .reallocate(&Realloc::shrink(address.cast(), layout, shrunk_layout))
                 .unwrap();
+            assert_eq!(shrunk.as_mut_ptr(), address.as_mut_ptr());
+            assert_eq!(bucket.size_classes.len(class), 1);
 
-            // Should be the same as the first one because everything is moved
-            // to the first block again.
+            // Next allocation of the same class takes the tail.
+            let tail = bucket.allocate(tail_layout).unwrap();
             assert_eq!(
-                first_addr.as_mut_ptr(),
-                second_addr_grow_to_page_size.as_mut_ptr()
-            );
-            // We're using all the page, so no free blocks.
-            assert_eq!(bucket.free_blocks.len(), 0);
-            assert_eq!(first_region.as_ref().num_blocks(), 1);
-            check_mem_corruption(
-                &second_addr_grow_to_page_size.as_ref()[..second_addr.as_ref().len()],
-                corruption_check,
+                tail.as_mut_ptr(),
+                shrunk
+                    .as_mut_ptr()
+                    .add(shrunk_layout.size() + BLOCK_HEADER_SIZE)
             );
+            assert_eq!(bucket.size_classes.len(class), 0);
 
-            bucket.deallocate(
-                second_addr_grow_to_page_size.cast(),
-                second_layout_grow_to_page_size,
-            );
+            bucket.deallocate(tail.cast(), tail_layout);
+            bucket.deallocate(shrunk.cast(), shrunk_layout);
+            bucket.set_size_classes(false);
+            bucket.validate_free_list();
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
+
+    #[test]
+    fn grow_with_increasing_alignment() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let corruption_check = 77;
+
+            // Start with pointer size alignment and keep increasing both size
+            // and alignment. Sometimes the block can be reused by moving the
+            // contents forward, sometimes it has to be moved somewhere else,
+            // but the result should always be aligned.
+            let mut layout = Layout::from_size_align(24, 8).unwrap();
+            let mut address = bucket.allocate(layout).unwrap();
+            address.as_mut()[..layout.size()].fill(corruption_check);
+
+            for (size, align) in [(48, 16), (64, 64), (200, 128), (256, 512), (1024, 4096)] {
+                let new_layout = Layout::from_size_align(size, align).unwrap();
+                let new_address = bucket
+                    .reallocate(&Realloc::grow(address.cast(), layout, new_layout))
+                    .unwrap();
+
+                assert_eq!(new_address.as_mut_ptr() as usize % align, 0);
+                assert!(new_address.len() >= size);
+                check_mem_corruption(&new_address.as_ref()[..24], corruption_check);
+
+                // Fill everything so that we check the next reallocation
+                // doesn't corrupt data either.
+                new_address.as_mut_ptr().write_bytes(corruption_check, size);
+
+                layout = new_layout;
+                address = new_address;
+            }
+
+            bucket.deallocate(address.cast(), layout);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
+
+    #[test]
+    fn reallocate_in_place_respects_stricter_alignment() {
+        unsafe {
+            let mut bucket = Bucket::new();
+
+            for (method, size, align) in [
+                (ReallocMethod::Grow, 1024, 4096),
+                (ReallocMethod::Shrink, 256, 2048),
+            ] {
+                let layout = Layout::from_size_align(1024, 8).unwrap();
+                let mut address = bucket.allocate(layout).unwrap();
+                // Used neighbour, so growing can't merge the next block.
+                let neighbour = bucket.allocate(layout).unwrap();
+                address.as_mut()[..size].fill(42);
+
+                // The block is large enough for the size, but the contents
+                // must be moved to an aligned address, within the block if
+                // the padding fits or somewhere else otherwise.
+                let new_layout = Layout::from_size_align(size, align).unwrap();
+                assert_ne!(address.as_mut_ptr() as usize % align, 0);
+                let realloc = match method {
+                    ReallocMethod::Grow => Realloc::grow(address.cast(), layout, new_layout),
+                    ReallocMethod::Shrink => Realloc::shrink(address.cast(), layout, new_layout),
+                };
+                let new_address = bucket.reallocate(&realloc).unwrap();
+
+                assert_eq!(new_address.as_mut_ptr() as usize % align, 0);
+                check_mem_corruption(&new_address.as_ref()[..size], 42);
+
+                bucket.deallocate(new_address.cast(), new_layout);
+                bucket.deallocate(neighbour.cast(), layout);
+            }
+
+            assert_eq!(bucket.regions.len(), 0);
         }
     }
 
@@ -1198,4 +4019,234 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn grow_on_another_block_of_the_same_region() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let small = Layout::array::<u8>(256).unwrap();
+            let medium = Layout::array::<u8>(page_size() / 2).unwrap();
+
+            let current = bucket.allocate(small).unwrap();
+            let next = bucket.allocate(small).unwrap();
+            let region = bucket.regions.first().unwrap();
+
+            // This one needs a new region.
+            let large = Layout::array::<u8>(page_size()).unwrap();
+            let other = bucket.allocate(large).unwrap();
+            assert_eq!(bucket.regions.len(), 2);
+
+            // Leave a hole after `next`, which goes to the end of the free
+            // list, so first fit would choose the other region.
+            let hole = bucket.allocate(medium).unwrap();
+            bucket.deallocate(hole.cast(), medium);
+            let grown_layout = Layout::array::<u8>(1024).unwrap();
+            let first_fit = bucket.find_free_block(grown_layout).unwrap();
+            assert_ne!(first_fit.as_ref().data.region, region);
+
+            current.cast::<u8>().as_ptr().write_bytes(7, small.size());
+            let grown = bucket
+                .reallocate(&Realloc::grow(current.cast(), small, grown_layout))
+                .unwrap();
+
+            let block = Header::<Block>::from_allocated_pointer(grown.cast(), grown_layout);
+            assert_eq!(block.as_ref().data.region, region);
+            assert_ne!(grown.as_mut_ptr(), current.as_mut_ptr());
+            check_mem_corruption(&grown.as_ref()[..small.size()], 7);
+
+            bucket.deallocate(grown.cast(), grown_layout);
+            bucket.deallocate(next.cast(), small);
+            bucket.deallocate(other.cast(), large);
+            assert_eq!(bucket.regions.len(), 0);
+        }
+    }
+
+    #[test]
+    fn fresh_blocks_come_from_regions_mapped_by_the_same_call() {
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(64).unwrap();
+
+            let first = bucket.allocate(layout).unwrap();
+            assert_eq!(bucket.take_fresh(), Some(0));
+            assert_eq!(bucket.take_fresh(), None);
+
+            // Served by the free list.
+            let second = bucket.allocate(layout).unwrap();
+            assert_eq!(bucket.take_fresh(), None);
+
+            // Mapping a region without allocating doesn't make the next
+            // allocation fresh unless that allocation maps one itself.
+            bucket.prewarm(1, 64).unwrap();
+            let third = bucket.allocate(layout).unwrap();
+            assert_eq!(bucket.take_fresh(), None);
+
+            bucket.set_scrub_pattern(Some(0xAA));
+            let large = Layout::array::<u8>(bucket.free_bytes() + page_size()).unwrap();
+            let fourth = bucket.allocate(large).unwrap();
+            assert_eq!(bucket.take_fresh(), Some(0xAA));
+
+            bucket.deallocate(first.cast(), layout);
+            bucket.deallocate(second.cast(), layout);
+            bucket.deallocate(third.cast(), layout);
+            bucket.deallocate(fourth.cast(), large);
+        }
+    }
+
+    #[test]
+    fn regions_sorted_by_address() {
+        use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+        /// Length of each slot of [`OutOfOrder`], enough for any region of
+        /// this test.
+        const SLOT: usize = 1 << 20;
+        /// Order in which [`OutOfOrder`] hands out its slots. The second
+        /// region goes before the first one and the third one between both.
+        const ORDER: [usize; 3] = [2, 0, 1];
+
+        /// Hands out the slots of one single reservation in [`ORDER`], so
+        /// that regions don't end up sorted just because the kernel maps
+        /// them at increasing or decreasing addresses.
+        struct OutOfOrder {
+            reservation: AtomicPtr<u8>,
+            maps: AtomicUsize,
+        }
+
+        unsafe impl MmapSource for OutOfOrder {
+            fn map(&self, length: usize, align: usize) -> Result<NonNull<u8>, i32> {
+                assert!(length <= SLOT && align <= SLOT);
+                let slot = ORDER[self.maps.fetch_add(1, Ordering::Relaxed)];
+                let reservation = self.reservation.load(Ordering::Relaxed);
+                Ok(unsafe { NonNull::new_unchecked(reservation.add(slot * SLOT)) })
+            }
+
+            unsafe fn unmap(&self, _address: NonNull<u8>, _length: usize, _align: usize) {
+                // The whole reservation is returned at the end of the test.
+            }
+        }
+
+        static SOURCE: OutOfOrder = OutOfOrder {
+            reservation: AtomicPtr::new(ptr::null_mut()),
+            maps: AtomicUsize::new(0),
+        };
+
+        unsafe {
+            let mut bucket = Bucket::new();
+            let layout = Layout::array::<u8>(page_size()).unwrap();
+            let is_sorted = |bucket: &Bucket| {
+                let addresses: Vec<usize> =
+                    bucket.regions.iter().map(|r| r.as_ptr().addr()).collect();
+                addresses.windows(2).all(|pair| pair[0] < pair[1])
+            };
+
+            let mut addresses: Vec<_> = (0..8).map(|_| bucket.allocate(layout).unwrap()).collect();
+            assert_eq!(bucket.regions.len(), 8);
+            assert!(is_sorted(&bucket));
+
+            // Unmap every other region so that new ones can be mapped in the
+            // holes, which are not at the end of the address space.
+            for address in addresses.iter().step_by(2) {
+                bucket.deallocate(address.cast(), layout);
+            }
+            addresses = addresses.into_iter().skip(1).step_by(2).collect();
+            assert!(is_sorted(&bucket));
+
+            addresses.extend((0..8).map(|_| bucket.allocate(layout).unwrap()));
+            assert_eq!(bucket.regions.len(), 12);
+            assert!(is_sorted(&bucket));
+
+            for address in addresses {
+                bucket.deallocate(address.cast(), layout);
+            }
+            assert_eq!(bucket.regions.len(), 0);
+
+            // When the last region is mapped the list contains slots 0 and 2,
+            // so slot 1 can only be sorted if it's inserted in the middle.
+            let length = ORDER.len() * SLOT;
+            let reservation = platform::request_aligned_memory(length, SLOT, 0).unwrap();
+            SOURCE
+                .reservation
+                .store(reservation.as_ptr(), Ordering::Relaxed);
+            let slot =
+                |index: usize| NonNull::new_unchecked(reservation.as_ptr().add(index * SLOT));
+
+            let mut bucket = Bucket::new();
+            bucket.set_source(Some(&SOURCE));
+            let addresses: Vec<_> = ORDER.map(|_| bucket.allocate(layout).unwrap()).into();
+            let regions: Vec<_> = bucket.regions.iter().map(|r| r.cast::<u8>()).collect();
+            assert_eq!(regions, [slot(0), slot(1), slot(2)]);
+
+            for address in addresses {
+                bucket.deallocate(address.cast(), layout);
+            }
+            assert_eq!(bucket.regions.len(), 0);
+            platform::return_aligned_memory(reservation, length, SLOT).unwrap();
+        }
+    }
+
+    #[test]
+    fn free_list_order() {
+        let orders = [
+            FreeListOrder::Fifo,
+            FreeListOrder::Lifo,
+            FreeListOrder::AddressOrdered,
+            FreeListOrder::SizeOrdered,
+        ];
+
+        for order in orders {
+            unsafe {
+                let mut bucket = Bucket::new();
+                bucket.set_free_list_order(order);
+
+                // Used blocks between the ones we free so they don't merge.
+                let separator = Layout::array::<u8>(16).unwrap();
+                let layouts = [64, 32, 128, 48, 96].map(|size| Layout::array::<u8>(size).unwrap());
+                let addresses = layouts.map(|layout| {
+                    let address = bucket.allocate(layout).unwrap();
+                    (address, bucket.allocate(separator).unwrap())
+                });
+
+                let header = |index: usize| {
+                    Header::<Block>::from_allocated_pointer(
+                        addresses[index].0.cast(),
+                        layouts[index],
+                    )
+                };
+                let remainder =
+                    Header::<Block>::from_free_list_node(bucket.free_blocks.first().unwrap());
+
+                let freed = [2, 0, 4, 1, 3];
+                for index in freed {
+                    bucket.deallocate(addresses[index].0.cast(), layouts[index]);
+                    bucket.free_blocks.validate();
+                }
+
+                let mut expected = Vec::from([remainder]);
+                expected.extend(freed.map(header));
+                match order {
+                    FreeListOrder::Fifo => {}
+                    FreeListOrder::Lifo => expected.reverse(),
+                    FreeListOrder::AddressOrdered => expected.sort_by_key(|block| block.as_ptr()),
+                    FreeListOrder::SizeOrdered => {
+                        expected.sort_by_key(|block| block.as_ref().size())
+                    }
+                }
+
+                let free_blocks: Vec<_> = bucket
+                    .free_blocks
+                    .iter()
+                    .map(|node| Header::<Block>::from_free_list_node(node))
+                    .collect();
+                assert_eq!(free_blocks, expected, "{order:?}");
+
+                // Everything should be merged back into one block.
+                for (_, separator_address) in addresses {
+                    bucket.deallocate(separator_address.cast(), separator);
+                    bucket.free_blocks.validate();
+                }
+                assert_eq!(bucket.regions.len(), 0, "{order:?}");
+                assert_eq!(bucket.free_blocks.len(), 0, "{order:?}");
+            }
+        }
+    }
 }
@@ -0,0 +1,468 @@
+//! Bump arena for short lived allocations, like everything allocated while
+//! handling one request of a web server. Small allocations are carved
+//! linearly from chunks requested to a parent allocator, there's no free list
+//! and no coalescing, deallocating does nothing at all. Once the request is
+//! done the whole arena is reset at once:
+//!
+//! ```text
+//!                             Cursor                 End
+//!                               |                       |
+//!                               v                       v
+//! +-------+-------+-----+-------+-----------------------+
+//! | Chunk | Alloc | ... | Alloc |         Free          |
+//! +-------+-------+-----+-------+-----------------------+
+//!     |
+//!     |   +-------+-------+-------+-----+-------+----------+
+//!     +-> | Chunk | Alloc | Alloc | ... | Alloc |  Wasted  |
+//!         +-------+-------+-------+-----+-------+----------+
+//! ```
+//!
+//! When the current chunk can't fit an allocation a new one is requested and
+//! the rest of the old one is wasted. Allocations that are too large for a
+//! chunk are forwarded to the parent allocator and must be deallocated like
+//! any other allocation, they are not affected by [`BumpArena::reset`]. That
+//! makes the parent allocator the place for large or long lived data while
+//! the arena takes care of the rest.
+//!
+//! Nested scopes can roll back only what they allocated with
+//! [`BumpArena::checkpoint`] and [`BumpArena::restore`]. A checkpoint is just
+//! the current chunk and cursor, restoring it returns the chunks pushed since
+//! then and moves the cursor back:
+//!
+//! ```text
+//!                 Checkpoint            Cursor
+//!                     |                   |
+//!                     v                   v
+//! +-------+-----+-----+-------+-------+---+---------+
+//! | Chunk | ... | Old | New   | New   |     Free    |
+//! +-------+-----+-----+-------+-------+---+---------+
+//!                     <---- reclaimed ---->
+//! ```
+
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    cell::Cell,
+    marker::PhantomData,
+    mem,
+    ptr::NonNull,
+};
+
+use crate::{AllocResult, Pointer, Rulloc};
+
+/// Default length of each chunk, headers included.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Position of a [`BumpArena`] returned by [`BumpArena::checkpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Chunk that was current, `None` if the arena had no chunks.
+    chunk: Pointer<Chunk>,
+    /// Cursor within that chunk.
+    cursor: usize,
+}
+
+/// Chunk metadata stored at the beginning of the chunk. Chunks form a singly
+/// linked list starting at the current chunk.
+struct Chunk {
+    /// Previous chunk, the one that was current before this one.
+    next: Pointer<Chunk>,
+}
+
+/// Bump allocator layered over a parent [`Allocator`], which is [`Rulloc`]
+/// by default. See the [module documentation](self) for details.
+///
+/// # Examples
+///
+/// ```rust
+/// #![feature(allocator_api)]
+///
+/// use rulloc::{BumpArena, Rulloc};
+///
+/// let rulloc = Rulloc::default();
+/// let mut arena = BumpArena::new(&rulloc);
+///
+/// for request in 0..3 {
+///     let mut headers = Vec::new_in(&arena);
+///     headers.push(("Content-Length", request));
+///     drop(headers);
+///
+///     arena.reset();
+/// }
+/// ```
+///
+/// # Threads
+///
+/// The cursor is not synchronized, so the arena can't be shared between
+/// threads. It can't be sent to another thread either: an arena is meant to
+/// be the scratch space of the thread that handles one request, and keeping
+/// it on that thread guarantees that its chunks are always in the caches of
+/// the core that uses them. Use one arena per thread instead:
+///
+/// ```rust,compile_fail,E0277
+/// use rulloc::{BumpArena, Rulloc};
+///
+/// static RULLOC: Rulloc = Rulloc::with_default_config();
+///
+/// let arena = BumpArena::new(&RULLOC);
+/// std::thread::spawn(move || drop(arena));
+/// ```
+pub struct BumpArena<'a, A: Allocator = Rulloc> {
+    /// Allocator that provides the chunks and the large allocations.
+    allocator: &'a A,
+    /// Layout of every chunk, its size is the length of each chunk.
+    chunk_layout: Layout,
+    /// Current chunk, or `None` if nothing has been allocated yet.
+    current: Cell<Pointer<Chunk>>,
+    /// Address of the next free byte in the current chunk.
+    cursor: Cell<usize>,
+    /// Address right after the last byte of the current chunk.
+    end: Cell<usize>,
+    /// Keeps the arena on its thread, see [`BumpArena`].
+    not_send: PhantomData<*const ()>,
+}
+
+impl<'a, A: Allocator> BumpArena<'a, A> {
+    /// Empty arena with chunks of [`DEFAULT_CHUNK_SIZE`] bytes. Nothing is
+    /// requested to `allocator` until the first allocation.
+    pub fn new(allocator: &'a A) -> Self {
+        Self::with_chunk_size(allocator, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Empty arena with chunks of `chunk_size` bytes. Allocations that take
+    /// more than a quarter of a chunk go straight to `allocator`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` can't fit the chunk metadata plus at least one
+    /// small allocation, or if it's too large to be the size of a [`Layout`]
+    /// (more than `isize::MAX` once rounded up to the chunk alignment).
+    pub fn with_chunk_size(allocator: &'a A, chunk_size: usize) -> Self {
+        assert!(
+            chunk_size >= 4 * mem::size_of::<Chunk>(),
+            "chunk size {chunk_size} is too small"
+        );
+
+        let Ok(chunk_layout) = Layout::from_size_align(chunk_size, mem::align_of::<Chunk>()) else {
+            panic!("chunk size {chunk_size} is too large");
+        };
+
+        Self {
+            allocator,
+            chunk_layout,
+            current: Cell::new(None),
+            cursor: Cell::new(0),
+            end: Cell::new(0),
+            not_send: PhantomData,
+        }
+    }
+
+    /// Length of each chunk, headers included.
+    #[inline]
+    fn chunk_size(&self) -> usize {
+        self.chunk_layout.size()
+    }
+
+    /// Whether `layout` is served from chunks or forwarded to the parent
+    /// allocator. Alignment counts as well because the cursor might need
+    /// `align - 1` bytes of padding, so small layouts always fit in a fresh
+    /// chunk.
+    #[inline]
+    fn is_small(&self, layout: Layout) -> bool {
+        layout.size().saturating_add(layout.align()) <= self.chunk_size() / 4
+    }
+
+    /// Number of chunks currently owned by the arena.
+    pub fn num_chunks(&self) -> usize {
+        let mut count = 0;
+        let mut chunk = self.current.get();
+
+        while let Some(current) = chunk {
+            count += 1;
+            chunk = unsafe { current.as_ref().next };
+        }
+
+        count
+    }
+
+    /// Requests a new chunk to the parent allocator and makes it current.
+    unsafe fn push_chunk(&self) -> Result<(), AllocError> {
+        let chunk = self.allocator.allocate(self.chunk_layout)?.cast::<Chunk>();
+        chunk.as_ptr().write(Chunk {
+            next: self.current.get(),
+        });
+
+        let start = chunk.as_ptr() as usize;
+        self.current.set(Some(chunk));
+        self.cursor.set(start + mem::size_of::<Chunk>());
+        self.end.set(start + self.chunk_size());
+
+        Ok(())
+    }
+
+    /// Carves `layout` out of the current chunk if it fits.
+    fn bump(&self, layout: Layout) -> Pointer<u8> {
+        let start = self.cursor.get().checked_next_multiple_of(layout.align())?;
+        let new_cursor = start.checked_add(layout.size())?;
+
+        if self.current.get().is_none() || new_cursor > self.end.get() {
+            return None;
+        }
+
+        self.cursor.set(new_cursor);
+        NonNull::new(start as *mut u8)
+    }
+
+    /// Remembers the current position of the arena, so that everything
+    /// allocated afterwards can be freed at once with [`Self::restore`]. See
+    /// the [module documentation](self).
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            chunk: self.current.get(),
+            cursor: self.cursor.get(),
+        }
+    }
+
+    /// Frees every small allocation made since `checkpoint` was taken, while
+    /// the ones made before it remain valid. The cursor goes back to where
+    /// it was, so restoring within the same chunk is O(1), otherwise chunks
+    /// pushed since then are returned to the parent allocator one by one.
+    /// Allocations forwarded to the parent allocator are not affected, same
+    /// as [`Self::reset`]. Checkpoints can be restored any number of times,
+    /// and older ones can be restored after newer ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` doesn't belong to this arena, or if its chunk
+    /// was already returned by [`Self::reset`] or by restoring an older
+    /// checkpoint.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    ///
+    /// use rulloc::{BumpArena, Rulloc};
+    ///
+    /// let rulloc = Rulloc::default();
+    /// let mut arena = BumpArena::new(&rulloc);
+    ///
+    /// let config = Box::new_in([1u8; 16], &arena);
+    /// let checkpoint = arena.checkpoint();
+    /// std::mem::forget(Box::new_in([2u8; 512], &arena));
+    ///
+    /// drop(config);
+    /// arena.restore(checkpoint);
+    /// assert_eq!(arena.checkpoint(), checkpoint);
+    /// ```
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        let Some(target) = checkpoint.chunk else {
+            self.release_chunks_until(None);
+            self.current.set(None);
+            self.cursor.set(0);
+            self.end.set(0);
+            return;
+        };
+
+        assert!(
+            self.owns_chunk(target),
+            "checkpoint doesn't belong to this arena"
+        );
+
+        self.release_chunks_until(Some(target));
+        self.current.set(Some(target));
+        self.cursor.set(checkpoint.cursor);
+        self.end.set(target.as_ptr() as usize + self.chunk_size());
+    }
+
+    /// Whether `chunk` is still in the list of chunks owned by the arena.
+    fn owns_chunk(&self, chunk: NonNull<Chunk>) -> bool {
+        let mut current = self.current.get();
+
+        while let Some(candidate) = current {
+            if candidate == chunk {
+                return true;
+            }
+            current = unsafe { candidate.as_ref().next };
+        }
+
+        false
+    }
+
+    /// Returns chunks to the parent allocator starting at the current one
+    /// until `stop` is found, which is not returned.
+    fn release_chunks_until(&self, stop: Pointer<Chunk>) {
+        let mut chunk = self.current.get();
+
+        while chunk != stop {
+            let Some(current) = chunk else {
+                break;
+            };
+
+            unsafe {
+                chunk = current.as_ref().next;
+                self.allocator.deallocate(current.cast(), self.chunk_layout);
+            }
+        }
+    }
+
+    /// Returns all chunks but the current one to the parent allocator and
+    /// rewinds the current one, so it can be reused without requesting
+    /// memory again. Allocations forwarded to the parent allocator are not
+    /// affected. Taking `&mut self` ensures nothing still borrows memory from
+    /// the arena.
+    pub fn reset(&mut self) {
+        let Some(current) = self.current.get() else {
+            return;
+        };
+
+        unsafe {
+            let mut chunk = current.as_ref().next;
+            while let Some(previous) = chunk {
+                chunk = previous.as_ref().next;
+                self.allocator
+                    .deallocate(previous.cast(), self.chunk_layout);
+            }
+
+            (*current.as_ptr()).next = None;
+        }
+
+        self.cursor
+            .set(current.as_ptr() as usize + mem::size_of::<Chunk>());
+    }
+}
+
+unsafe impl<'a, A: Allocator> Allocator for BumpArena<'a, A> {
+    fn allocate(&self, layout: Layout) -> AllocResult {
+        if !self.is_small(layout) {
+            return self.allocator.allocate(layout);
+        }
+
+        let address = match self.bump(layout) {
+            Some(address) => address,
+            None => unsafe {
+                self.push_chunk()?;
+                self.bump(layout).ok_or(AllocError)?
+            },
+        };
+
+        Ok(NonNull::slice_from_raw_parts(address, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, address: NonNull<u8>, layout: Layout) {
+        // Small allocations are only freed by reset() or drop().
+        if !self.is_small(layout) {
+            self.allocator.deallocate(address, layout);
+        }
+    }
+}
+
+impl<'a, A: Allocator> Drop for BumpArena<'a, A> {
+    fn drop(&mut self) {
+        self.reset();
+
+        if let Some(current) = self.current.take() {
+            unsafe { self.allocator.deallocate(current.cast(), self.chunk_layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn chunk_size_too_large() {
+        let rulloc = Rulloc::default();
+        BumpArena::with_chunk_size(&rulloc, usize::MAX);
+    }
+
+    #[test]
+    fn bump_arena() {
+        let rulloc = Rulloc::default();
+        let mut arena = BumpArena::with_chunk_size(&rulloc, 4096);
+        let layout = Layout::new::<u64>();
+
+        let mut addresses = Vec::new();
+        for i in 0..2000 {
+            let address = arena.allocate(layout).unwrap().cast::<u64>();
+            unsafe { address.as_ptr().write(i) };
+            addresses.push(address);
+        }
+
+        // Linear within a chunk and nothing got overwritten.
+        assert_eq!(
+            addresses[1].as_ptr() as usize - addresses[0].as_ptr() as usize,
+            mem::size_of::<u64>()
+        );
+        for (i, address) in addresses.iter().enumerate() {
+            assert_eq!(unsafe { address.as_ptr().read() }, i as u64);
+        }
+        assert!(arena.num_chunks() > 1);
+
+        // Large allocations go to the parent allocator.
+        let large = Layout::array::<u8>(4096).unwrap();
+        let large_address = arena.allocate(large).unwrap();
+        unsafe { arena.deallocate(large_address.cast(), large) };
+
+        arena.reset();
+        assert_eq!(arena.num_chunks(), 1);
+        let mapped = rulloc.total_mapped_bytes();
+
+        // Reusing the arena doesn't request more memory.
+        for _ in 0..3 {
+            let mut vec = Vec::with_capacity_in(64, &arena);
+            vec.extend(0..64u64);
+            assert_eq!(vec.iter().sum::<u64>(), 63 * 64 / 2);
+            drop(vec);
+            arena.reset();
+        }
+        assert_eq!(arena.num_chunks(), 1);
+        assert_eq!(rulloc.total_mapped_bytes(), mapped);
+
+        drop(arena);
+        assert_eq!(rulloc.total_mapped_bytes(), 0);
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let rulloc = Rulloc::default();
+        let mut arena = BumpArena::with_chunk_size(&rulloc, 4096);
+        let layout = Layout::new::<u64>();
+
+        let allocate = |arena: &BumpArena, value: u64| {
+            let address = arena.allocate(layout).unwrap().cast::<u64>();
+            unsafe { address.as_ptr().write(value) };
+            address
+        };
+
+        let earlier: Vec<_> = (0..10).map(|i| allocate(&arena, i)).collect();
+        let checkpoint = arena.checkpoint();
+
+        // Same chunk, only the cursor moves back.
+        let later = allocate(&arena, 100);
+        arena.restore(checkpoint);
+        assert_eq!(arena.checkpoint(), checkpoint);
+        assert_eq!(allocate(&arena, 200), later);
+
+        // Later chunks are returned to the parent allocator.
+        arena.restore(checkpoint);
+        for i in 0..2000 {
+            allocate(&arena, 1000 + i);
+        }
+        assert!(arena.num_chunks() > 1);
+        arena.restore(checkpoint);
+        assert_eq!(arena.num_chunks(), 1);
+        assert_eq!(allocate(&arena, 300), later);
+
+        for (i, address) in earlier.iter().enumerate() {
+            assert_eq!(unsafe { address.as_ptr().read() }, i as u64);
+        }
+
+        // Back to the very beginning.
+        let empty = BumpArena::with_chunk_size(&rulloc, 4096).checkpoint();
+        arena.restore(empty);
+        assert_eq!(arena.num_chunks(), 0);
+        assert_eq!(rulloc.total_mapped_bytes(), 0);
+    }
+}
@@ -0,0 +1,171 @@
+//! Redzone canaries for detecting heap buffer overflows. When enabled, every
+//! allocation gets [`CANARY_SIZE`] extra bytes right after the user content
+//! and we write a known value there:
+//!
+//! ```text
+//! +----------------------------+
+//! |       Block header         |
+//! +----------------------------+
+//! |       User content         | <- Exactly `layout.size()` bytes.
+//! |            ...             |
+//! +----------------------------+
+//! |          Canary            | <- `CANARY_SIZE` bytes, might be unaligned.
+//! +----------------------------+
+//! |  Rest of block (if any)    |
+//! +----------------------------+
+//! ```
+//!
+//! If the value has changed when the user deallocates or reallocates, then
+//! somebody wrote past the end of the allocation and we panic. A fixed magic
+//! value would be predictable to an attacker who can partially overwrite
+//! memory, so the canary is derived from a per allocator seed and the address
+//! of the allocation. That way we don't need to store the expected value
+//! anywhere, we can compute it again when verifying.
+
+use std::{
+    alloc::{AllocError, Layout},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    mem,
+    ptr::NonNull,
+};
+
+/// Size of the redzone placed after user content.
+pub(crate) const CANARY_SIZE: usize = mem::size_of::<usize>();
+
+/// Canary configuration of an allocator. See [`crate::canary`].
+#[derive(Clone, Copy)]
+pub(crate) struct Canary {
+    seed: u64,
+}
+
+impl Canary {
+    /// Canaries derived from the given seed.
+    pub const fn with_seed(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    /// Canaries derived from a random seed. We don't want to depend on any
+    /// crate for random numbers, [`RandomState`] is already randomly seeded.
+    pub fn random() -> Self {
+        Self::with_seed(RandomState::new().build_hasher().finish())
+    }
+
+    /// Expected canary value for the allocation at `address`.
+    #[inline]
+    pub fn value_for(&self, address: NonNull<u8>) -> usize {
+        (self.seed as usize) ^ address.as_ptr().addr()
+    }
+
+    /// Returns the layout that should be used internally to allocate `layout`
+    /// plus its redzone.
+    #[inline]
+    pub fn padded(&self, layout: Layout) -> Result<Layout, AllocError> {
+        let size = layout.size().checked_add(CANARY_SIZE).ok_or(AllocError)?;
+        Layout::from_size_align(size, layout.align()).map_err(|_| AllocError)
+    }
+
+    /// Writes the canary right after `size` bytes starting at `address`.
+    ///
+    /// # Safety
+    ///
+    /// The allocation at `address` must have been made with
+    /// [`Self::padded`].
+    #[inline]
+    pub unsafe fn write(&self, address: NonNull<u8>, size: usize) {
+        let redzone = address.as_ptr().add(size).cast::<usize>();
+        redzone.write_unaligned(self.value_for(address));
+    }
+
+    /// Writes the canary after `size` bytes of `address` and returns the
+    /// slice that the user can actually write to, which doesn't include the
+    /// redzone.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Self::write`].
+    #[inline]
+    pub unsafe fn protect(&self, address: NonNull<[u8]>, size: usize) -> NonNull<[u8]> {
+        let address = address.cast();
+        self.write(address, size);
+        NonNull::slice_from_raw_parts(address, size)
+    }
+
+    /// Whether the canary after `size` bytes starting at `address` has not
+    /// been overwritten. This doesn't panic so that it can be called while
+    /// holding the allocator lock, see [`Self::overflow`].
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Self::write`].
+    #[inline]
+    pub unsafe fn is_intact(&self, address: NonNull<u8>, size: usize) -> bool {
+        let redzone = address.as_ptr().add(size).cast::<usize>();
+        redzone.read_unaligned() == self.value_for(address)
+    }
+
+    /// Reports a canary that [`Self::is_intact`] found overwritten. Must be
+    /// called without holding the allocator lock.
+    #[cold]
+    pub fn overflow(address: NonNull<u8>, size: usize) -> ! {
+        panic!("heap buffer overflow: canary after {size} bytes at {address:?} was overwritten");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{Allocator, Layout};
+
+    use super::*;
+    use crate::Rulloc;
+
+    #[test]
+    fn canaries_depend_on_seed() {
+        let first = Rulloc::with_default_config().with_random_canary_seed();
+        let second = Rulloc::with_default_config().with_random_canary_seed();
+        let address = NonNull::dangling();
+
+        assert_ne!(
+            first.canary.unwrap().value_for(address),
+            second.canary.unwrap().value_for(address)
+        );
+
+        let seeded = Canary::with_seed(0xDEAD_BEEF);
+        assert_eq!(seeded.value_for(address), seeded.value_for(address));
+    }
+
+    #[test]
+    fn untouched_canaries() {
+        let allocator = Rulloc::with_default_config().with_random_canary_seed();
+        let layout = Layout::array::<u8>(13).unwrap();
+        let bigger = Layout::array::<u8>(2000).unwrap();
+
+        unsafe {
+            let mut address = allocator.allocate(layout).unwrap();
+            // Only the requested size is returned, the rest is the redzone.
+            assert_eq!(address.len(), layout.size());
+            address.as_mut().fill(1);
+
+            let mut grown = allocator.grow(address.cast(), layout, bigger).unwrap();
+            assert_eq!(grown.len(), bigger.size());
+            grown.as_mut().fill(2);
+
+            let shrunk = allocator.shrink(grown.cast(), bigger, layout).unwrap();
+            allocator.deallocate(shrunk.cast(), layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "heap buffer overflow")]
+    fn overflow_is_detected() {
+        let allocator = Rulloc::with_default_config().with_random_canary_seed();
+        let layout = Layout::array::<u8>(24).unwrap();
+
+        unsafe {
+            let address = allocator.allocate(layout).unwrap().cast::<u8>();
+            // Off by one.
+            *address.as_ptr().add(layout.size()) ^= 0xFF;
+            allocator.deallocate(address, layout);
+        }
+    }
+}
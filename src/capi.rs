@@ -0,0 +1,307 @@
+//! C ABI exports, only compiled with the `capi` feature on Unix. The wrapper
+//! crate in `capi/` builds them as a `cdylib`, so the shared library can be
+//! injected into any C program with `LD_PRELOAD` and every `malloc` call of
+//! the program ends up in a process global [`Rulloc`]:
+//!
+//! ```text
+//! $ cargo build --release --manifest-path capi/Cargo.toml
+//! $ LD_PRELOAD=capi/target/release/librulloc_capi.so ./program
+//! ```
+//!
+//! The problem is that C only gives us the layout when allocating, `free`
+//! receives nothing but the pointer. So every allocation gets a small prefix
+//! right before the address given to the program where we store the layout:
+//!
+//! ```text
+//! +--------------------+-------------+------------------------------------+
+//! |      Padding       | Size, align |         Given to the program       |
+//! +--------------------+-------------+------------------------------------+
+//! ^                                  ^
+//! |                                  |
+//! Address returned by Rulloc         Address returned by malloc()
+//! ```
+//!
+//! The prefix is [`PREFIX_SIZE`] bytes or the requested alignment, whichever
+//! is larger, so the address given to the program keeps the alignment of the
+//! block. Only the last [`PREFIX_SIZE`] bytes of the prefix are used, the
+//! rest is padding.
+//!
+//! Sizes never exceed `isize::MAX`, so the highest bit of the stored size is
+//! free. We use it to remember that the allocation was made by `calloc`, in
+//! which case growing it with `realloc` zeroes the new bytes as well, so the
+//! whole allocation still reads as zero except for what the program wrote.
+//!
+//! Just like [`crate::leak`], none of this can allocate through the global
+//! allocator, because the global allocator of the Rust code in the shared
+//! library ends up calling our own `malloc`.
+
+use std::{
+    alloc::{Allocator, Layout},
+    ffi::{c_int, c_void},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use crate::Rulloc;
+
+/// Allocator used by all the exported functions.
+static ALLOCATOR: Rulloc = Rulloc::with_default_config();
+
+/// Alignment of `malloc`, large enough for any C type (`max_align_t`).
+const MALLOC_ALIGN: usize = 2 * mem::size_of::<usize>();
+
+/// Bytes used to store the layout of each allocation, see the module
+/// documentation.
+const PREFIX_SIZE: usize = 2 * mem::size_of::<usize>();
+
+/// Bit of the stored size set for allocations made by [`calloc`].
+const ZEROED: usize = 1 << (usize::BITS - 1);
+
+/// Layout of the Rulloc allocation that holds `size` user bytes aligned to
+/// `align`, and the offset of the user address within it.
+fn layout_for(size: usize, align: usize) -> Option<(Layout, usize)> {
+    let offset = align.max(PREFIX_SIZE);
+    let layout =
+        Layout::from_size_align(size.checked_add(offset)?, align.max(MALLOC_ALIGN)).ok()?;
+
+    Some((layout, offset))
+}
+
+/// Reads the size, alignment and [`ZEROED`] flag stored before `address`.
+unsafe fn stored_layout(address: NonNull<u8>) -> (usize, usize, bool) {
+    let prefix = address.as_ptr().sub(PREFIX_SIZE).cast::<usize>();
+    let size = prefix.read();
+
+    (size & !ZEROED, prefix.add(1).read(), size & ZEROED != 0)
+}
+
+/// Address where the Rulloc allocation of `address` starts, its layout, the
+/// alignment requested by the program and whether it was made by [`calloc`].
+unsafe fn block_of(address: NonNull<u8>) -> (NonNull<u8>, Layout, usize, bool) {
+    let (size, align, zeroed) = stored_layout(address);
+    let (layout, offset) = layout_for(size, align).unwrap_unchecked();
+    let base = NonNull::new_unchecked(address.as_ptr().sub(offset));
+
+    (base, layout, align, zeroed)
+}
+
+/// Writes the prefix of the allocation at `base` and returns the address for
+/// the program.
+unsafe fn finish(
+    base: NonNull<[u8]>,
+    offset: usize,
+    size: usize,
+    align: usize,
+    zeroed: bool,
+) -> *mut c_void {
+    let address = base.cast::<u8>().as_ptr().add(offset);
+    let prefix = address.sub(PREFIX_SIZE).cast::<usize>();
+    prefix.write(if zeroed { size | ZEROED } else { size });
+    prefix.add(1).write(align);
+
+    address.cast()
+}
+
+/// Common code for all allocation functions. Returns null on failure.
+unsafe fn allocate(size: usize, align: usize, zeroed: bool) -> *mut c_void {
+    let Some((layout, offset)) = layout_for(size, align) else {
+        return ptr::null_mut();
+    };
+
+    let result = if zeroed {
+        ALLOCATOR.allocate_zeroed(layout)
+    } else {
+        ALLOCATOR.allocate(layout)
+    };
+
+    match result {
+        Ok(base) => finish(base, offset, size, align, zeroed),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Whether `align` is accepted by `posix_memalign`.
+fn is_valid_align(align: usize) -> bool {
+    align.is_power_of_two() && align % mem::size_of::<usize>() == 0
+}
+
+/// C `malloc`. Returns null if the allocation fails.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    allocate(size, MALLOC_ALIGN, false)
+}
+
+/// C `calloc`. Returns null if `count * size` overflows or the allocation
+/// fails.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn calloc(count: usize, size: usize) -> *mut c_void {
+    match count.checked_mul(size) {
+        Some(total) => allocate(total, MALLOC_ALIGN, true),
+        None => ptr::null_mut(),
+    }
+}
+
+/// C `realloc`. Null `address` behaves like [`malloc`] and `size` 0 behaves
+/// like [`free`]. The alignment of the original allocation is preserved, and
+/// so is the [`ZEROED`] flag, which zeroes the bytes added when growing.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn realloc(address: *mut c_void, size: usize) -> *mut c_void {
+    let Some(address) = NonNull::new(address.cast::<u8>()) else {
+        return malloc(size);
+    };
+
+    if size == 0 {
+        free(address.as_ptr().cast());
+        return ptr::null_mut();
+    }
+
+    let (base, old_layout, align, zeroed) = block_of(address);
+    let Some((new_layout, offset)) = layout_for(size, align) else {
+        return ptr::null_mut();
+    };
+
+    // The offset only depends on the alignment, so the prefix moves along
+    // with the contents.
+    let result = if new_layout.size() < old_layout.size() {
+        ALLOCATOR.shrink(base, old_layout, new_layout)
+    } else if zeroed {
+        ALLOCATOR.grow_zeroed(base, old_layout, new_layout)
+    } else {
+        ALLOCATOR.grow(base, old_layout, new_layout)
+    };
+
+    match result {
+        Ok(base) => finish(base, offset, size, align, zeroed),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// C `free`. Null pointers are ignored.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn free(address: *mut c_void) {
+    if let Some(address) = NonNull::new(address.cast::<u8>()) {
+        let (base, layout, ..) = block_of(address);
+        ALLOCATOR.deallocate(base, layout);
+    }
+}
+
+/// POSIX `posix_memalign`. Returns `EINVAL` if `align` is not a power of 2
+/// multiple of the pointer size and `ENOMEM` if the allocation fails.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+    result: *mut *mut c_void,
+    align: usize,
+    size: usize,
+) -> c_int {
+    if !is_valid_align(align) {
+        return libc::EINVAL;
+    }
+
+    let address = allocate(size, align, false);
+    if address.is_null() {
+        return libc::ENOMEM;
+    }
+
+    result.write(address);
+    0
+}
+
+/// C11 `aligned_alloc`. Returns null if `align` is not a power of 2.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn aligned_alloc(align: usize, size: usize) -> *mut c_void {
+    if !align.is_power_of_two() {
+        return ptr::null_mut();
+    }
+
+    allocate(size, align, false)
+}
+
+/// Obsolete `memalign`, same as [`aligned_alloc`]. Exported anyway because
+/// memory allocated by the libc version can't be freed by our [`free`].
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memalign(align: usize, size: usize) -> *mut c_void {
+    aligned_alloc(align, size)
+}
+
+/// GNU `malloc_usable_size`. Returns the size requested when allocating,
+/// which is all the program can use without overwriting anything, or 0 for
+/// null pointers.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn malloc_usable_size(address: *mut c_void) -> usize {
+    match NonNull::new(address.cast::<u8>()) {
+        Some(address) => stored_layout(address).0,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_allocation_functions() {
+        unsafe {
+            let address = malloc(13).cast::<u8>();
+            assert_eq!(address as usize % MALLOC_ALIGN, 0);
+            assert_eq!(malloc_usable_size(address.cast()), 13);
+            address.write_bytes(7, 13);
+
+            let address = realloc(address.cast(), 4096).cast::<u8>();
+            assert_eq!(malloc_usable_size(address.cast()), 4096);
+            assert!((0..13).all(|i| *address.add(i) == 7));
+
+            let address = realloc(address.cast(), 5).cast::<u8>();
+            assert!((0..5).all(|i| *address.add(i) == 7));
+            free(address.cast());
+
+            let zeroed = calloc(16, 8).cast::<u64>();
+            assert!((0..16).all(|i| *zeroed.add(i) == 0));
+            free(zeroed.cast());
+            assert!(calloc(usize::MAX, 2).is_null());
+
+            let mut aligned = ptr::null_mut();
+            assert_eq!(posix_memalign(&mut aligned, 4096, 100), 0);
+            assert_eq!(aligned as usize % 4096, 0);
+            aligned = realloc(aligned, 10_000);
+            assert_eq!(aligned as usize % 4096, 0);
+            free(aligned);
+
+            assert_eq!(posix_memalign(&mut aligned, 24, 100), libc::EINVAL);
+            assert!(aligned_alloc(3, 100).is_null());
+
+            let aligned = memalign(64, 100);
+            assert_eq!(aligned as usize % 64, 0);
+            free(aligned);
+
+            free(ptr::null_mut());
+            assert_eq!(malloc_usable_size(ptr::null_mut()), 0);
+        }
+    }
+
+    #[test]
+    fn calloc_then_realloc_zero_extends() {
+        unsafe {
+            let address = calloc(10, 1).cast::<u8>();
+            address.write_bytes(0xAB, 10);
+
+            // Dirty a block that the allocation can grow into.
+            let dirty = malloc(4096).cast::<u8>();
+            dirty.write_bytes(0xFF, 4096);
+            free(dirty.cast());
+
+            let address = realloc(address.cast(), 4096).cast::<u8>();
+            assert_eq!(malloc_usable_size(address.cast()), 4096);
+            assert!((0..10).all(|i| *address.add(i) == 0xAB));
+            assert!((10..4096).all(|i| *address.add(i) == 0));
+
+            // The flag survives shrinking, so growing again zeroes as well.
+            address.write_bytes(0xCD, 4096);
+            let address = realloc(address.cast(), 5).cast::<u8>();
+            let address = realloc(address.cast(), 100).cast::<u8>();
+            assert!((0..5).all(|i| *address.add(i) == 0xCD));
+            assert!((5..100).all(|i| *address.add(i) == 0));
+
+            free(address.cast());
+        }
+    }
+}
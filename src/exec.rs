@@ -0,0 +1,143 @@
+//! Executable memory for JIT compilers. Executable regions never go through
+//! buckets, each allocation gets its own mapping and follows the W^X
+//! (write xor execute) principle: memory is writable when it's allocated, and
+//! once the code has been written it can be switched to read-execute, at
+//! which point it can't be written anymore.
+//!
+//! We still need to keep track of all the regions so that they are unmapped
+//! when the allocator is dropped, so they are stored in their own linked
+//! list. The problem is that list headers are written every time a neighbour
+//! is added or removed, and we can't write to read-execute memory. That's why
+//! the header lives in a separate page that is never made executable:
+//!
+//! ```text
+//! +-------------------+-------------------------------------------+
+//! |   Header page     |              Code pages                   |
+//! |   (read-write)    |   (read-write, then read-execute)         |
+//! +-------------------+-------------------------------------------+
+//! ^                   ^
+//! |                   |
+//! Region address      Address given to the user
+//! ```
+//!
+//! Wasting one page per allocation is fine because JIT compilers usually
+//! allocate code in large chunks anyway.
+
+use std::{ffi::c_int, io, mem::ManuallyDrop, ptr::NonNull};
+
+use crate::{
+    failure::UnmapFailure,
+    header::Header,
+    list::LinkedList,
+    platform::{self, Protection},
+    Pointer,
+};
+
+/// Executable region metadata stored in the header page.
+pub(crate) struct ExecRegion {
+    /// Length of the code pages in bytes, the header page is not included.
+    length: usize,
+}
+
+/// All the executable regions mapped by an allocator.
+pub(crate) struct ExecRegions {
+    regions: ManuallyDrop<LinkedList<ExecRegion>>,
+}
+
+impl ExecRegions {
+    /// No regions at all.
+    pub const fn new() -> Self {
+        Self {
+            regions: ManuallyDrop::new(LinkedList::new()),
+        }
+    }
+
+    /// Maps a new region where at least `size` bytes of code can be written
+    /// and returns the address where the code starts. `mmap_flags` are ORed
+    /// into the default flags, see [`platform::request_memory`].
+    pub unsafe fn allocate(&mut self, size: usize, mmap_flags: c_int) -> Pointer<u8> {
+        let page_size = platform::page_size();
+        let length = size.checked_next_multiple_of(page_size)?.max(page_size);
+        let address = platform::request_memory(length.checked_add(page_size)?, mmap_flags).ok()?;
+
+        let region = self.regions.append(ExecRegion { length }, address);
+
+        Some(Self::code_address_of(region))
+    }
+
+    /// Number of bytes that can be written at `address`.
+    pub unsafe fn capacity(&self, address: NonNull<u8>) -> usize {
+        Self::region_of(address).as_ref().data.length
+    }
+
+    /// Makes the first `size` bytes at `address` (rounded up to page size)
+    /// read-execute. The code can't be written after this.
+    pub unsafe fn make_executable(&self, address: NonNull<u8>, size: usize) -> io::Result<()> {
+        self.protect(address, size, Protection::ReadExecute)
+    }
+
+    /// Common code for [`Self::make_executable`] and [`Self::make_writable`].
+    unsafe fn protect(
+        &self,
+        address: NonNull<u8>,
+        size: usize,
+        protection: Protection,
+    ) -> io::Result<()> {
+        assert!(
+            size <= self.capacity(address),
+            "making {size} bytes executable at {address:?}, but only {} were allocated",
+            self.capacity(address)
+        );
+
+        let length = size.next_multiple_of(platform::page_size());
+
+        if platform::protect(address, length, protection) {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Makes the first `size` bytes at `address` (rounded up to page size)
+    /// read-write again, so the code can be patched.
+    pub unsafe fn make_writable(&self, address: NonNull<u8>, size: usize) -> io::Result<()> {
+        self.protect(address, size, Protection::ReadWrite)
+    }
+
+    /// Unmaps the region that contains the code at `address`.
+    pub unsafe fn deallocate(&mut self, address: NonNull<u8>) -> Result<(), UnmapFailure> {
+        let region = Self::region_of(address);
+        self.regions.remove(region);
+        Self::unmap(region)
+    }
+
+    /// Header of the region whose code starts at `address`.
+    unsafe fn region_of(address: NonNull<u8>) -> NonNull<Header<ExecRegion>> {
+        NonNull::new_unchecked(address.as_ptr().sub(platform::page_size())).cast()
+    }
+
+    /// Address where the code of `region` starts.
+    unsafe fn code_address_of(region: NonNull<Header<ExecRegion>>) -> NonNull<u8> {
+        NonNull::new_unchecked(region.cast::<u8>().as_ptr().add(platform::page_size()))
+    }
+
+    /// Returns the entire region to the kernel, header page included.
+    unsafe fn unmap(region: NonNull<Header<ExecRegion>>) -> Result<(), UnmapFailure> {
+        let length = region.as_ref().data.length + platform::page_size();
+        platform::return_memory(region.cast(), length)
+    }
+}
+
+impl Drop for ExecRegions {
+    fn drop(&mut self) {
+        // Same as buckets, remove before unmapping because the header is
+        // located in the memory being unmapped. The allocator is going away,
+        // so unmap failures are only logged.
+        while let Some(region) = self.regions.first() {
+            unsafe {
+                self.regions.remove(region);
+                let _ = Self::unmap(region);
+            }
+        }
+    }
+}
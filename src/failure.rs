@@ -0,0 +1,190 @@
+//! Allocation failure diagnostics. [`std::alloc::Allocator`] requires us to
+//! return [`AllocError`], which doesn't say anything about what went wrong,
+//! so we also remember the reason of the last failure and expose it through
+//! [`crate::Rulloc::last_error`]. Most failures are detected by a
+//! [`crate::bucket::Bucket`] while requesting a new region, which happens
+//! with the allocator lock held, so the bucket stores the reason and the
+//! allocator moves it to [`LastFailure`] before releasing the lock. That way
+//! it can be read from any thread without locking, just like
+//! [`crate::Stats`].
+//!
+//! Failures to unmap memory follow the same path. They don't make any
+//! operation fail, so buckets collect them in [`UnmapFailures`] and the
+//! allocator counts them in [`crate::Stats::unmap_failures`] before releasing
+//! the lock. In debug builds the allocator then panics with the details of
+//! the first one, see [`UnmapFailures::report`].
+
+use std::{
+    alloc::AllocError,
+    fmt,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Reason why an allocation failed. See [`crate::Rulloc::last_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocFailure {
+    /// The kernel refused to map a new region. Contains the `errno` value set
+    /// by `mmap` (or the value of `GetLastError` on Windows).
+    MmapFailed(i32),
+    /// Mapping a new region would exceed the limit configured with
+    /// [`crate::Rulloc::with_memory_limit`].
+    LimitExceeded,
+    /// The requested size plus the space needed for headers and alignment
+    /// doesn't fit in `isize`.
+    SizeOverflow,
+}
+
+impl fmt::Display for AllocFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MmapFailed(errno) => write!(f, "failed to map memory (errno {errno})"),
+            Self::LimitExceeded => f.write_str("memory limit exceeded"),
+            Self::SizeOverflow => f.write_str("allocation size overflow"),
+        }
+    }
+}
+
+/// Returned by runtime setters such as [`crate::Rulloc::mark_bucket_sensitive`]
+/// once [`crate::Rulloc::freeze_config`] has been called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConfigFrozen;
+
+impl fmt::Display for ConfigFrozen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("allocator configuration is frozen")
+    }
+}
+
+impl std::error::Error for ConfigFrozen {}
+
+/// Tag stored in the high half of [`LastFailure`], the low half holds the
+/// `errno` of [`AllocFailure::MmapFailed`].
+const NONE: u64 = 0;
+const MMAP_FAILED: u64 = 1;
+const LIMIT_EXCEEDED: u64 = 2;
+const SIZE_OVERFLOW: u64 = 3;
+
+/// Lock-free storage for the last [`AllocFailure`], encoded in one single
+/// atomic so that readers never see the tag of one failure with the `errno`
+/// of another.
+pub(crate) struct LastFailure(AtomicU64);
+
+impl LastFailure {
+    /// No failures yet.
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(NONE))
+    }
+
+    /// Stores `failure` and returns the [`AllocError`] that should be given
+    /// to the caller, which makes it easy to use with [`Result::map_err`].
+    pub fn record(&self, failure: AllocFailure) -> AllocError {
+        let encoded = match failure {
+            AllocFailure::MmapFailed(errno) => MMAP_FAILED << 32 | errno as u32 as u64,
+            AllocFailure::LimitExceeded => LIMIT_EXCEEDED << 32,
+            AllocFailure::SizeOverflow => SIZE_OVERFLOW << 32,
+        };
+        self.0.store(encoded, Ordering::Relaxed);
+
+        AllocError
+    }
+
+    /// Last recorded failure, if any.
+    pub fn get(&self) -> Option<AllocFailure> {
+        let encoded = self.0.load(Ordering::Relaxed);
+
+        match encoded >> 32 {
+            MMAP_FAILED => Some(AllocFailure::MmapFailed(encoded as u32 as i32)),
+            LIMIT_EXCEEDED => Some(AllocFailure::LimitExceeded),
+            SIZE_OVERFLOW => Some(AllocFailure::SizeOverflow),
+            _ => None,
+        }
+    }
+}
+
+/// `munmap` or `VirtualFree` refused to take back `length` bytes at
+/// `address`. Contains the `errno` value set by the kernel (or the value of
+/// `GetLastError` on Windows). See [`crate::platform::return_memory`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UnmapFailure {
+    pub address: NonNull<u8>,
+    pub length: usize,
+    pub errno: i32,
+}
+
+impl fmt::Display for UnmapFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            address,
+            length,
+            errno,
+        } = self;
+        write!(
+            f,
+            "failed to unmap {length} bytes at {address:?} (errno {errno})"
+        )
+    }
+}
+
+impl UnmapFailure {
+    /// Panics with the details of the failure in debug builds, does nothing
+    /// in release builds. Must not be called with the allocator lock held,
+    /// otherwise the panic would poison it.
+    #[track_caller]
+    pub fn report(self) {
+        if cfg!(debug_assertions) {
+            panic!("{self}");
+        }
+    }
+}
+
+/// Unmap failures collected while the allocator lock is held. Only the first
+/// one is kept in full, the rest are just counted, which is enough to know
+/// that something is wrong without allocating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct UnmapFailures {
+    /// Number of failures since the last [`Self::take`].
+    pub count: usize,
+    /// First failure since the last [`Self::take`].
+    pub first: Option<UnmapFailure>,
+}
+
+impl UnmapFailures {
+    /// No failures yet.
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            first: None,
+        }
+    }
+
+    /// Remembers the failure if `result` is one.
+    pub fn check(&mut self, result: Result<(), UnmapFailure>) {
+        if let Err(failure) = result {
+            self.count += 1;
+            self.first.get_or_insert(failure);
+        }
+    }
+
+    /// Adds the failures of `other` to these ones.
+    pub fn merge(&mut self, other: Self) {
+        self.count += other.count;
+        if self.first.is_none() {
+            self.first = other.first;
+        }
+    }
+
+    /// Returns the failures collected so far and forgets them.
+    pub fn take(&mut self) -> Self {
+        std::mem::replace(self, Self::new())
+    }
+
+    /// Reports the first failure, see [`UnmapFailure::report`]. In release
+    /// builds failures are only counted.
+    #[track_caller]
+    pub fn report(self) {
+        if let Some(failure) = self.first {
+            failure.report();
+        }
+    }
+}
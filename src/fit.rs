@@ -0,0 +1,143 @@
+use std::{alloc::Layout, marker::PhantomData, ptr::NonNull};
+
+use crate::{alignment, block::Block, freelist::FreeList, header::Header, list::Iter};
+
+/// Strategy used by [`crate::bucket::Bucket`] to choose which free block
+/// should serve an allocation request. By default we use [`FirstFit`], which
+/// is the fastest one, but depending on the workload other strategies might
+/// result in less fragmentation. Consider this free list:
+///
+/// ```text
+/// +--------+----------------------------------------------------------------+
+/// |        | +----------+    +-------+    +---------+    +-------------+    |
+/// | Region | | Free 512 | -> | Block | -> | Free 64 | -> |  Free 4096  |    |
+/// |        | +----------+    +-------+    +---------+    +-------------+    |
+/// +--------+----------------------------------------------------------------+
+/// ```
+///
+/// If we get a request for 48 bytes, [`FirstFit`] would split the 512 byte
+/// block, [`BestFit`] would use the 64 byte block and a policy that chooses
+/// the largest block would split the 4096 byte block. The policy only decides
+/// *which* block, splitting and updating the free list is still handled by
+/// the bucket, so a policy can't corrupt the allocator. Any type that
+/// implements this trait can be used with [`crate::Rulloc::with_fit_policy`].
+///
+/// Each bucket owns its own instance of the policy, so stateful policies (for
+/// example, a policy that counts requests or remembers where the last search
+/// ended) don't have to care about different buckets sharing state.
+pub trait FitPolicy {
+    /// Chooses one of the `free_blocks` to allocate `layout`. All the blocks
+    /// yielded by the iterator are large enough for `layout`, including any
+    /// padding needed for alignment, and they are yielded in free list order.
+    /// Returning `None` means that none of the blocks should be used, which
+    /// forces the bucket to request a new region from the kernel.
+    fn choose<'a>(&mut self, free_blocks: FreeBlocks<'a>, layout: Layout) -> Option<FreeBlock<'a>>;
+}
+
+/// Free block that can be chosen by a [`FitPolicy`]. It can only be obtained
+/// through [`FreeBlocks`] and it's only valid while the policy is choosing.
+///
+/// The policy runs while the thread that's allocating holds the allocator
+/// lock, so free blocks can't be sent to other threads, which would be able
+/// to read them after the lock is released:
+///
+/// ```rust,compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+///
+/// assert_send::<rulloc::FreeBlock<'static>>();
+/// ```
+#[derive(Clone, Copy)]
+pub struct FreeBlock<'a> {
+    block: NonNull<Header<Block>>,
+    /// Borrows the free list and can't cross threads, see above.
+    marker: PhantomData<(&'a FreeList, *const ())>,
+}
+
+impl FreeBlock<'_> {
+    /// Size of the block content in bytes. Might be greater than the size
+    /// that was requested.
+    #[inline]
+    pub fn size(&self) -> usize {
+        unsafe { self.block.as_ref().size() }
+    }
+
+    /// Address where the content of this block starts. The block is free, so
+    /// this address must not be written or read, it's only useful for
+    /// policies that care about locality.
+    #[inline]
+    pub fn address(&self) -> NonNull<u8> {
+        unsafe { Header::content_address_of(self.block) }
+    }
+
+    /// Block header, used by the bucket once the block has been chosen.
+    #[inline]
+    pub(crate) fn header(&self) -> NonNull<Header<Block>> {
+        self.block
+    }
+}
+
+/// Iterator over the free blocks of a bucket that can fit a given layout. See
+/// [`FitPolicy`]. Same as [`FreeBlock`], it can't be sent to other threads:
+///
+/// ```rust,compile_fail,E0277
+/// fn assert_send<T: Send>() {}
+///
+/// assert_send::<rulloc::FreeBlocks<'static>>();
+/// ```
+pub struct FreeBlocks<'a> {
+    nodes: Iter<()>,
+    layout: Layout,
+    /// Borrows the free list and can't cross threads, see [`FreeBlock`].
+    marker: PhantomData<(&'a FreeList, *const ())>,
+}
+
+impl<'a> FreeBlocks<'a> {
+    /// Free blocks in `free_list` that can fit `layout`. See
+    /// [`alignment::block_size_needed_at`].
+    pub(crate) fn new(free_list: &'a FreeList, layout: Layout) -> Self {
+        Self {
+            nodes: free_list.iter(),
+            layout,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a> Iterator for FreeBlocks<'a> {
+    type Item = FreeBlock<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.nodes.find_map(|node| unsafe {
+            let block = Header::<Block>::from_free_list_node(node);
+            let needed = alignment::block_size_needed_at(node.cast(), self.layout);
+            (block.as_ref().size() >= needed).then_some(FreeBlock {
+                block,
+                marker: PhantomData,
+            })
+        })
+    }
+}
+
+/// Chooses the first block in the free list that can fit the request. This is
+/// the default policy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstFit;
+
+impl FitPolicy for FirstFit {
+    #[inline]
+    fn choose<'a>(&mut self, mut free_blocks: FreeBlocks<'a>, _: Layout) -> Option<FreeBlock<'a>> {
+        free_blocks.next()
+    }
+}
+
+/// Chooses the smallest block that can fit the request. This has to traverse
+/// the entire free list for each allocation, but it leaves larger blocks
+/// available for larger requests.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BestFit;
+
+impl FitPolicy for BestFit {
+    fn choose<'a>(&mut self, free_blocks: FreeBlocks<'a>, _: Layout) -> Option<FreeBlock<'a>> {
+        free_blocks.min_by_key(FreeBlock::size)
+    }
+}
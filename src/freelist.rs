@@ -1,5 +1,7 @@
 use std::ptr::NonNull;
 
+#[cfg(test)]
+use crate::block::MIN_BLOCK_SIZE;
 use crate::{
     block::Block,
     header::Header,
@@ -56,8 +58,9 @@ use crate::{
 /// 1. We get a new region from the kernel 4096 bytes in length.
 ///
 /// 2. We create one single block in this region where we can allocate a maximum
-/// of 4096 - R - B bytes, where R = [`crate::region::REGION_HEADER_SIZE`] and
-/// B = [`crate::block::BLOCK_HEADER_SIZE`]. This would be the current state:
+///    of 4096 - R - B bytes, where R = [`crate::region::REGION_HEADER_SIZE`]
+///    and B = [`crate::block::BLOCK_HEADER_SIZE`]. This would be the current
+///    state:
 ///
 /// ```text
 ///
@@ -73,8 +76,8 @@ use crate::{
 /// ```
 ///
 /// 3. The block takes up all the space, if a subsequent allocation is smaller
-/// than the block size, then the block will be split in two different blocks
-/// and the second block will become the first and only free block.
+///    than the block size, then the block will be split in two different blocks
+///    and the second block will become the first and only free block.
 ///
 /// ```text
 /// +--------+-------------------------------------+
@@ -88,7 +91,7 @@ use crate::{
 /// ```
 ///
 /// 4. If the user makes another allocation that is smaller than our free block,
-/// the splitting algorithm does its job again:
+///    the splitting algorithm does its job again:
 ///
 /// ```text
 /// +--------+-------------------------------------+
@@ -170,12 +173,91 @@ pub(crate) type FreeListNode = Node<()>;
 /// See [`FreeListNode`].
 pub(crate) type FreeList = LinkedList<()>;
 
+/// Position where blocks are inserted when they are added to the free list,
+/// which determines the block that [`crate::FirstFit`] finds first. See
+/// [`crate::Rulloc::with_free_list_order`].
+///
+/// ```text
+///                  +---------+    +---------+    +---------+
+/// Fifo:            | Freed 1 | -> | Freed 2 | -> | Freed 3 |
+///                  +---------+    +---------+    +---------+
+///
+///                  +---------+    +---------+    +---------+
+/// Lifo:            | Freed 3 | -> | Freed 2 | -> | Freed 1 |
+///                  +---------+    +---------+    +---------+
+///
+///                  +---------+    +---------+    +---------+
+/// AddressOrdered:  | 0x1000  | -> | 0x2000  | -> | 0x3000  |
+///                  +---------+    +---------+    +---------+
+///
+///                  +---------+    +---------+    +---------+
+/// SizeOrdered:     |   32    | -> |   64    | -> |   128   |
+///                  +---------+    +---------+    +---------+
+/// ```
+///
+/// [`FreeListOrder::Fifo`] and [`FreeListOrder::Lifo`] insert in O(1), the
+/// other two have to walk through the free list on every insertion, but
+/// they usually pay off: address order makes first fit reuse the lowest
+/// addresses and leave the rest of the region free, which reduces
+/// fragmentation, and with size order first fit becomes best fit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FreeListOrder {
+    /// Freed blocks go to the end of the list. This is the default.
+    #[default]
+    Fifo,
+    /// Freed blocks go to the beginning of the list, so the most recently
+    /// freed block is reused first while it's still in the CPU cache.
+    Lifo,
+    /// Blocks are sorted by address.
+    AddressOrdered,
+    /// Blocks are sorted by size, smallest first.
+    SizeOrdered,
+}
+
 impl FreeList {
     /// Helper function for adding blocks to the free list. `block` must be
     /// valid.
     pub unsafe fn append_block(&mut self, mut block: NonNull<Header<Block>>) {
         self.append((), Header::content_address_of(block));
         block.as_mut().data.is_free = true;
+        block.as_mut().data.purged = false;
+    }
+
+    /// Same as [`Self::append_block`] but `block` becomes the head of the
+    /// free list, so it's the first one found by [`crate::FirstFit`].
+    pub unsafe fn prepend_block(&mut self, mut block: NonNull<Header<Block>>) {
+        self.prepend((), Header::content_address_of(block));
+        block.as_mut().data.is_free = true;
+        block.as_mut().data.purged = false;
+    }
+
+    /// Adds `block` to the free list at the position given by `order`.
+    /// Sorted orders assume that the list is already sorted. `block` must be
+    /// valid.
+    pub unsafe fn insert_block(&mut self, mut block: NonNull<Header<Block>>, order: FreeListOrder) {
+        let address = Header::content_address_of(block);
+        let size = block.as_ref().size();
+
+        let prev = match order {
+            FreeListOrder::Fifo => self.last(),
+            FreeListOrder::Lifo => None,
+            FreeListOrder::AddressOrdered => {
+                self.iter().take_while(|node| node.cast() < address).last()
+            }
+            FreeListOrder::SizeOrdered => self
+                .iter()
+                .take_while(|node| {
+                    Header::<Block>::from_free_list_node(*node).as_ref().size() < size
+                })
+                .last(),
+        };
+
+        match prev {
+            Some(prev) => self.insert_after(prev, (), address),
+            None => self.prepend((), address),
+        };
+        block.as_mut().data.is_free = true;
+        block.as_mut().data.purged = false;
     }
 
     /// Removes `block` from the free list. `block` must be valid.
@@ -184,6 +266,57 @@ impl FreeList {
         block.as_mut().data.is_free = false;
     }
 
+    /// Panics if the free list is not well formed. This checks that:
+    ///
+    /// - Following `next` pointers from the head reaches the tail after
+    ///   exactly `len` nodes, so there are no cycles or lost nodes.
+    /// - Every node is the `prev` of its `next`.
+    /// - Every block in the list is marked as free and can hold at least
+    ///   [`MIN_BLOCK_SIZE`] bytes, otherwise it couldn't store the node.
+    ///
+    /// Order depends on [`FreeListOrder`], so it's not checked. Only used for
+    /// testing.
+    #[cfg(test)]
+    pub unsafe fn validate(&self) {
+        let mut prev = None;
+        let mut current = self.first();
+        let mut count = 0;
+
+        while let Some(node) = current {
+            count += 1;
+            assert!(
+                count <= self.len(),
+                "free list has a cycle or more than {} nodes",
+                self.len()
+            );
+            assert_eq!(
+                node.as_ref().prev,
+                prev,
+                "free list node {node:?} has a wrong prev pointer"
+            );
+
+            let block = Header::<Block>::from_free_list_node(node);
+            assert!(
+                block.as_ref().is_free(),
+                "block {block:?} is in the free list but is not free"
+            );
+            assert!(
+                block.as_ref().size() >= MIN_BLOCK_SIZE,
+                "free block {block:?} is too small to hold a free list node"
+            );
+
+            prev = current;
+            current = node.as_ref().next;
+        }
+
+        assert_eq!(
+            count,
+            self.len(),
+            "free list length doesn't match the number of nodes"
+        );
+        assert_eq!(prev, self.last(), "free list tail is not the last node");
+    }
+
     /// Returns a reference to the block header of the first block in the free
     /// list. Not used internally, for now we only need it for testing.
     #[cfg(test)]
@@ -193,11 +326,4 @@ impl FreeList {
             block.as_ref()
         })
     }
-
-    /// Free list nodes are a little bit harder to iterate because they don't
-    /// point to block headers, so let's make it easier.
-    pub unsafe fn iter_blocks(&self) -> impl Iterator<Item = NonNull<Header<Block>>> {
-        self.iter()
-            .map(|node| Header::<Block>::from_free_list_node(node))
-    }
 }
@@ -0,0 +1,62 @@
+//! Stale pointer detection. Every block header carries a generation number
+//! in debug builds. Each bucket increments its generation whenever a block is
+//! freed, and blocks are stamped with the current generation of the bucket
+//! when they are allocated. So if a block is freed and then reused by another
+//! allocation, the new owner gets a different generation than the old one:
+//!
+//! ```text
+//!  allocate_checked()    deallocate_checked()   allocate_checked()
+//!  +---------------+     +---------------+      +---------------+
+//!  | 0x1000, gen 3 | --> |     Free      | ---> | 0x1000, gen 4 |
+//!  +---------------+     +---------------+      +---------------+
+//!          ^                                            ^
+//!          |                                            |
+//!    Stale handle                               Current handle
+//! ```
+//!
+//! A plain pointer doesn't remember its generation, so the checks only work
+//! through [`CheckedPtr`], which is returned by
+//! [`crate::Rulloc::allocate_checked`] and consumed by
+//! [`crate::Rulloc::deallocate_checked`]. Deallocating a stale handle panics
+//! instead of freeing somebody else's block. Just like interior pointer
+//! detection, this only happens in debug builds. Release builds don't store
+//! generations, so checked handles behave like normal pointers.
+
+use std::{alloc::Layout, ptr::NonNull};
+
+/// Pointer returned by [`crate::Rulloc::allocate_checked`] that remembers the
+/// generation of its block. See [`crate::generation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckedPtr {
+    /// Address given to the user.
+    pub(crate) address: NonNull<u8>,
+    /// Layout used to allocate the pointer.
+    pub(crate) layout: Layout,
+    /// Generation of the block when it was allocated, 0 in release builds.
+    pub(crate) generation: usize,
+}
+
+// A checked pointer owns its allocation just like a Box, and the allocator
+// can deallocate it from any thread.
+unsafe impl Send for CheckedPtr {}
+unsafe impl Sync for CheckedPtr {}
+
+impl CheckedPtr {
+    /// Address where the allocated memory starts.
+    #[inline]
+    pub fn as_non_null(&self) -> NonNull<u8> {
+        self.address
+    }
+
+    /// Same as [`Self::as_non_null`] but as a raw pointer.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.address.as_ptr()
+    }
+
+    /// Layout used to allocate this pointer.
+    #[inline]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
@@ -60,9 +60,9 @@ impl<T> Header<T> {
     /// # Notes
     ///
     /// - We are using this function as `Header::content_address_of(header)`
-    /// instead of `header.content_address()` because we want to avoid creating
-    /// intermediary references to `self` to keep Miri happy. See
-    /// [Stacked Borrows](https://github.com/rust-lang/unsafe-code-guidelines/blob/master/wip/stacked-borrows.md).
+    ///   instead of `header.content_address()` because we want to avoid
+    ///   creating intermediary references to `self` to keep Miri happy. See
+    ///   [Stacked Borrows](https://github.com/rust-lang/unsafe-code-guidelines/blob/master/wip/stacked-borrows.md).
     #[inline]
     pub unsafe fn content_address_of(header: NonNull<Self>) -> NonNull<u8> {
         NonNull::new_unchecked(header.as_ptr().offset(1)).cast()
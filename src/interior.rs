@@ -0,0 +1,31 @@
+/// What to do when [`std::alloc::Allocator::deallocate`] receives a pointer
+/// that doesn't point to the start of an allocation, but somewhere inside of
+/// it. This is a common bug when doing pointer arithmetic manually:
+///
+/// ```text
+/// +--------------+----------------------------+
+/// | Block header |        Block content       |
+/// +--------------+----------------------------+
+///                ^          ^
+///                |          |
+///                |          +--- Interior pointer, passed to deallocate.
+///                |
+///                +--- Pointer returned by allocate.
+/// ```
+///
+/// Normally we trust the pointer and compute the block header address from
+/// it, which will read garbage and corrupt the allocator. Detecting interior
+/// pointers requires walking the regions and blocks of the bucket to find the
+/// block that contains the address, so the check only runs in debug builds.
+/// In release builds the policy is ignored and pointers are always trusted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InteriorPointerPolicy {
+    /// Don't check anything, same behaviour as release builds.
+    #[default]
+    Trust,
+    /// Panic with a message that includes the address of the containing block.
+    Panic,
+    /// Deallocate the block that contains the address as if the pointer
+    /// returned by the allocator had been given to us.
+    Lenient,
+}
@@ -0,0 +1,169 @@
+//! Leak hunting support, only compiled with the `leak-tracking` feature. When
+//! enabled, every allocation captures a [`Backtrace`] that is kept until the
+//! allocation is freed, so at any point (usually right before the program
+//! exits) we can dump the stacks of all the allocations that are still alive.
+//! This is really slow, don't use it in production.
+//!
+//! # Reentrancy
+//!
+//! Capturing backtraces and storing them needs memory. Storage is requested
+//! from [`System`] so that it never goes through our own allocator, but
+//! [`Backtrace`] itself uses the global allocator, which might be us. If that's
+//! the case, the allocations made while capturing a backtrace would try to
+//! capture another backtrace, and so on. We avoid that with a thread local
+//! flag that disables tracking while we are already tracking something.
+
+use std::{alloc::System, backtrace::Backtrace, cell::Cell, fmt::Write, ptr::NonNull, sync::Mutex};
+
+thread_local! {
+    /// Whether the current thread is already running tracking code.
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with tracking disabled for the current thread. Returns `None`
+/// if tracking was already disabled, which means we've been called from
+/// within tracking code.
+fn untracked<R>(f: impl FnOnce() -> R) -> Option<R> {
+    let already_tracking = TRACKING
+        .try_with(|tracking| tracking.replace(true))
+        .unwrap_or(true);
+
+    if already_tracking {
+        return None;
+    }
+
+    let result = f();
+    let _ = TRACKING.try_with(|tracking| tracking.set(false));
+
+    Some(result)
+}
+
+/// Allocation that has not been freed yet.
+struct LiveAllocation {
+    /// Address returned to the user.
+    address: NonNull<u8>,
+    /// Size of the layout used for the allocation.
+    size: usize,
+    /// Stack at the moment of the allocation.
+    backtrace: Backtrace,
+}
+
+/// Stores a [`LiveAllocation`] for every pointer we've given out and not
+/// received back yet. Linear searches are fine here, this is a debugging tool.
+pub(crate) struct LeakTracker {
+    live: Mutex<Vec<LiveAllocation, System>>,
+}
+
+// Pointers in `live` are only used as keys, never dereferenced.
+unsafe impl Send for LeakTracker {}
+unsafe impl Sync for LeakTracker {}
+
+impl LeakTracker {
+    /// Creates an empty tracker.
+    pub const fn new() -> Self {
+        Self {
+            live: Mutex::new(Vec::new_in(System)),
+        }
+    }
+
+    /// Captures the current stack and associates it with `address`.
+    pub fn track(&self, address: NonNull<u8>, size: usize) {
+        untracked(|| {
+            let backtrace = Backtrace::force_capture();
+            if let Ok(mut live) = self.live.lock() {
+                live.push(LiveAllocation {
+                    address,
+                    size,
+                    backtrace,
+                });
+            }
+        });
+    }
+
+    /// Forgets the allocation at `address` because it has been freed.
+    pub fn untrack(&self, address: NonNull<u8>) {
+        untracked(|| {
+            if let Ok(mut live) = self.live.lock() {
+                if let Some(index) = live.iter().position(|alloc| alloc.address == address) {
+                    live.swap_remove(index);
+                }
+            }
+        });
+    }
+
+    /// Forgets all the allocations located in `length` bytes starting from
+    /// `start`, see [`crate::Rulloc::free_region`].
+    pub fn untrack_range(&self, start: NonNull<u8>, length: usize) {
+        let range = start.as_ptr().addr()..start.as_ptr().addr() + length;
+
+        untracked(|| {
+            if let Ok(mut live) = self.live.lock() {
+                live.retain(|alloc| !range.contains(&alloc.address.as_ptr().addr()));
+            }
+        });
+    }
+
+    /// Reallocations might move the user contents, but we still want to keep
+    /// the original backtrace because that's where the memory was requested.
+    pub fn retrack(&self, old_address: NonNull<u8>, new_address: NonNull<u8>, new_size: usize) {
+        untracked(|| {
+            if let Ok(mut live) = self.live.lock() {
+                if let Some(alloc) = live.iter_mut().find(|alloc| alloc.address == old_address) {
+                    alloc.address = new_address;
+                    alloc.size = new_size;
+                }
+            }
+        });
+    }
+
+    /// Human readable report of all live allocations and their backtraces.
+    pub fn report(&self) -> String {
+        untracked(|| {
+            let mut report = String::new();
+            if let Ok(live) = self.live.lock() {
+                let total: usize = live.iter().map(|alloc| alloc.size).sum();
+                let _ = writeln!(
+                    report,
+                    "{} live allocations, {total} bytes in total",
+                    live.len()
+                );
+                for alloc in live.iter() {
+                    let _ = writeln!(
+                        report,
+                        "\n{} bytes at {:?} allocated at:\n{}",
+                        alloc.size, alloc.address, alloc.backtrace
+                    );
+                }
+            }
+            report
+        })
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{Allocator, Layout};
+
+    use crate::Rulloc;
+
+    #[test]
+    fn leak_report_includes_allocation_site() {
+        let allocator = Rulloc::with_default_config();
+        let freed_layout = Layout::array::<u8>(32).unwrap();
+        let leaked_layout = Layout::array::<u8>(100).unwrap();
+
+        unsafe {
+            let freed = allocator.allocate(freed_layout).unwrap();
+            let leaked = allocator.allocate(leaked_layout).unwrap();
+            allocator.deallocate(freed.cast(), freed_layout);
+
+            let report = allocator.leak_report();
+            assert!(report.starts_with("1 live allocations, 100 bytes in total"));
+            assert!(report.contains("leak_report_includes_allocation_site"));
+
+            allocator.deallocate(leaked.cast(), leaked_layout);
+            assert!(allocator.leak_report().starts_with("0 live allocations"));
+        }
+    }
+}
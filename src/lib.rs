@@ -54,19 +54,46 @@
 #![feature(nonnull_slice_from_raw_parts)]
 #![feature(strict_provenance)]
 #![feature(slice_ptr_get)]
+#![cfg_attr(all(feature = "tracy", not(test)), feature(linkage))]
 
 use std::{alloc::AllocError, ptr::NonNull};
 
+mod adaptive;
 mod alignment;
 mod allocator;
 mod block;
 mod bucket;
+mod bump;
+mod canary;
+#[cfg(all(feature = "capi", unix))]
+mod capi;
+mod exec;
+mod failure;
+mod fit;
 mod freelist;
+mod generation;
 mod header;
+mod interior;
+#[cfg(feature = "leak-tracking")]
+mod leak;
 mod list;
+mod lock;
 mod platform;
 mod realloc;
 mod region;
+mod report;
+mod secure;
+mod sizeclass;
+mod slab;
+mod source;
+mod stats;
+mod tailcache;
+#[cfg(feature = "timing")]
+mod timing;
+#[cfg(feature = "tracy")]
+mod tracy;
+#[cfg(feature = "timing")]
+mod window;
 
 /// Non-null pointer to `T`. We use this in most cases instead of `*mut T`
 /// because the compiler will yell at us if we don't write code for the `None`
@@ -78,3 +105,19 @@ pub(crate) type Pointer<T> = Option<NonNull<T>>;
 pub(crate) type AllocResult = Result<NonNull<[u8]>, AllocError>;
 
 pub use allocator::Rulloc;
+pub use bump::{BumpArena, Checkpoint, DEFAULT_CHUNK_SIZE};
+pub use failure::{AllocFailure, ConfigFrozen};
+pub use fit::{BestFit, FirstFit, FitPolicy, FreeBlock, FreeBlocks};
+pub use freelist::FreeListOrder;
+pub use generation::CheckedPtr;
+pub use interior::InteriorPointerPolicy;
+pub use realloc::ShrinkPolicy;
+pub use region::RegionInfo;
+pub use report::{BlockReport, BucketReport, HeapReport, RegionReport};
+pub use secure::SecureBuffer;
+pub use source::MmapSource;
+pub use stats::{BucketStats, FullStats, Stats};
+#[cfg(feature = "timing")]
+pub use timing::{LatencyHistogram, LATENCY_BUCKETS};
+#[cfg(feature = "timing")]
+pub use window::{Window, WindowedStats, NUM_WINDOWS};
@@ -52,8 +52,7 @@ impl<T> LinkedList<T> {
         self.head
     }
 
-    /// Last element in the list. For now it's only used in tests.
-    #[cfg(test)]
+    /// Last element in the list.
     #[inline]
     pub fn last(&self) -> Pointer<Header<T>> {
         self.tail
@@ -72,7 +71,7 @@ impl<T> LinkedList<T> {
     /// * `data` - The data that the new node will hold.
     ///
     /// * `address` - Memory address where the new node will be written. Must
-    /// be valid and non null.
+    ///   be valid and non null.
     pub unsafe fn append(&mut self, data: T, address: NonNull<u8>) -> NonNull<Header<T>> {
         let node = address.cast::<Node<T>>();
 
@@ -94,6 +93,52 @@ impl<T> LinkedList<T> {
         node
     }
 
+    /// Same as [`Self::append`] but the new node becomes the head of the list.
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that `address` is valid.
+    pub unsafe fn prepend(&mut self, data: T, address: NonNull<u8>) -> NonNull<Header<T>> {
+        let node = address.cast::<Node<T>>();
+
+        node.as_ptr().write(Node {
+            prev: None,
+            next: self.head,
+            data,
+        });
+
+        if let Some(mut head) = self.head {
+            head.as_mut().prev = Some(node);
+        } else {
+            self.tail = Some(node);
+        }
+
+        self.head = Some(node);
+        self.len += 1;
+
+        node
+    }
+
+    /// Inserts a new node at `address` keeping the list sorted by address,
+    /// assuming that it already was. The list is traversed from the head to
+    /// find the insertion point, so this is O(n), which is fine for short
+    /// lists like the regions of a bucket.
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that `address` is valid.
+    pub unsafe fn insert_sorted(&mut self, data: T, address: NonNull<u8>) -> NonNull<Header<T>> {
+        let prev = self
+            .iter()
+            .take_while(|node| node.cast::<u8>() < address)
+            .last();
+
+        match prev {
+            Some(prev) => self.insert_after(prev, data, address),
+            None => self.prepend(data, address),
+        }
+    }
+
     /// Inserts a new node with the given `data` right after the given `node`.
     /// New node will be written to `address`, so address must be valid and
     /// non-null.
@@ -200,7 +245,7 @@ mod tests {
     fn linked_list_operations() {
         unsafe {
             let mut list: LinkedList<u8> = LinkedList::new();
-            let region = platform::request_memory(platform::page_size()).unwrap();
+            let region = platform::request_memory(platform::page_size(), 0).unwrap();
             let size = mem::size_of::<Node<u8>>();
 
             // N1 <-> N2 <-> N3
@@ -284,7 +329,7 @@ mod tests {
             assert_eq!(list.head, None);
             assert_eq!(list.len, 0);
 
-            platform::return_memory(region, platform::page_size());
+            platform::return_memory(region, platform::page_size()).unwrap();
         }
     }
 }
@@ -0,0 +1,120 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// Lock that protects [`crate::Rulloc`] internals. By default it's just a
+/// [`Mutex`], which is the cheapest option when most operations modify the
+/// allocator (allocations, deallocations and reallocations do). However, if
+/// the user calls introspection methods very frequently, for example from
+/// monitoring threads, a [`RwLock`] allows all of them to read at the same
+/// time while allocations still get exclusive access.
+///
+/// The API mirrors [`Mutex`] so that switching between both options doesn't
+/// change the code that uses the lock: [`Lock::lock`] always gives exclusive
+/// access and [`Lock::read`] gives shared access if the underlying lock
+/// supports it, or exclusive access otherwise.
+pub(crate) enum Lock<T> {
+    /// One thread at a time, readers included.
+    Exclusive(Mutex<T>),
+    /// Multiple readers or one writer.
+    Shared(RwLock<T>),
+}
+
+/// Guard returned by [`Lock::lock`].
+pub(crate) enum WriteGuard<'a, T> {
+    Mutex(MutexGuard<'a, T>),
+    RwLock(RwLockWriteGuard<'a, T>),
+}
+
+/// Guard returned by [`Lock::read`].
+pub(crate) enum ReadGuard<'a, T> {
+    Mutex(MutexGuard<'a, T>),
+    RwLock(RwLockReadGuard<'a, T>),
+}
+
+/// Converts the guard inside of a [`LockResult`] without losing the poisoned
+/// state.
+fn map_result<G, R>(result: LockResult<G>, map: impl FnOnce(G) -> R) -> LockResult<R> {
+    match result {
+        Ok(guard) => Ok(map(guard)),
+        Err(poisoned) => Err(PoisonError::new(map(poisoned.into_inner()))),
+    }
+}
+
+impl<T> Lock<T> {
+    /// Builds a new [`Lock::Exclusive`].
+    pub const fn new(value: T) -> Self {
+        Self::Exclusive(Mutex::new(value))
+    }
+
+    /// Converts this lock into [`Lock::Shared`], keeping the protected value.
+    pub fn into_shared(self) -> Self {
+        Self::Shared(RwLock::new(
+            self.into_inner().unwrap_or_else(PoisonError::into_inner),
+        ))
+    }
+
+    /// Acquires exclusive access.
+    pub fn lock(&self) -> LockResult<WriteGuard<'_, T>> {
+        match self {
+            Self::Exclusive(mutex) => map_result(mutex.lock(), WriteGuard::Mutex),
+            Self::Shared(rwlock) => map_result(rwlock.write(), WriteGuard::RwLock),
+        }
+    }
+
+    /// Acquires shared access if possible.
+    pub fn read(&self) -> LockResult<ReadGuard<'_, T>> {
+        match self {
+            Self::Exclusive(mutex) => map_result(mutex.lock(), ReadGuard::Mutex),
+            Self::Shared(rwlock) => map_result(rwlock.read(), ReadGuard::RwLock),
+        }
+    }
+
+    /// Mutable access without locking, see [`Mutex::get_mut`].
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        match self {
+            Self::Exclusive(mutex) => mutex.get_mut(),
+            Self::Shared(rwlock) => rwlock.get_mut(),
+        }
+    }
+
+    /// Consumes the lock, see [`Mutex::into_inner`].
+    pub fn into_inner(self) -> LockResult<T> {
+        match self {
+            Self::Exclusive(mutex) => mutex.into_inner(),
+            Self::Shared(rwlock) => rwlock.into_inner(),
+        }
+    }
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Mutex(guard) => guard,
+            Self::RwLock(guard) => guard,
+        }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Self::Mutex(guard) => guard,
+            Self::RwLock(guard) => guard,
+        }
+    }
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Mutex(guard) => guard,
+            Self::RwLock(guard) => guard,
+        }
+    }
+}
@@ -1,20 +1,96 @@
-use std::ptr::NonNull;
-
-use crate::Pointer;
+use std::{ffi::c_int, io, ptr::NonNull};
+
+use crate::{failure::UnmapFailure, Pointer};
+
+/// Memory protection modes supported by [`PlatformSpecificMemory::protect`].
+/// All memory is mapped as [`Protection::ReadWrite`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protection {
+    /// Readable and writable, not executable.
+    ReadWrite,
+    /// Readable only.
+    ReadOnly,
+    /// Readable and executable, not writable.
+    ReadExecute,
+    /// Any access faults. Used for guard pages.
+    None,
+}
 
 /// Abstraction for platform specific memory handling. The allocator only needs
 /// to request pages of memory and return them back when they are no longer in
 /// use, but it doesn't care about the APIs offered by the underlying kernel or
 /// libraries.
 trait PlatformSpecificMemory {
+    /// Whether [`Self::return_memory`] can release a range that spans
+    /// multiple adjacent regions obtained with different requests.
+    const CAN_COALESCE: bool;
+
     /// Requests a memory region from the kernel where `length` bytes can be
-    /// written safely.
-    unsafe fn request_memory(length: usize) -> Pointer<u8>;
+    /// written safely. `extra_flags` are ORed into the flags given to `mmap`
+    /// on Unix and ignored everywhere else, see
+    /// [`crate::Rulloc::with_mmap_flags`].
+    unsafe fn request_memory(length: usize, extra_flags: c_int) -> Pointer<u8>;
+
+    /// Same as [`Self::request_memory`] but the returned address is aligned
+    /// to `align`, which must be a power of 2 greater than the page size.
+    /// The whole region must be returned with a single call to
+    /// [`Self::return_memory`].
+    unsafe fn request_aligned_memory(
+        length: usize,
+        align: usize,
+        extra_flags: c_int,
+    ) -> Pointer<u8>;
 
     /// Attempts to return `length` bytes starting from `address` to the
     /// underlying kernel. This function will usually be called to discard
     /// entire regions of memory, so length will equal the size of the region.
-    unsafe fn return_memory(address: NonNull<u8>, length: usize);
+    /// Returns whether the kernel accepted the request, see
+    /// [`check_returned`].
+    unsafe fn return_memory(address: NonNull<u8>, length: usize) -> bool;
+
+    /// Returns a region obtained from [`Self::request_aligned_memory`]. Most
+    /// kernels don't care about alignment when unmapping memory, so by
+    /// default this is the same as [`Self::return_memory`].
+    unsafe fn return_aligned_memory(address: NonNull<u8>, length: usize, _align: usize) -> bool {
+        Self::return_memory(address, length)
+    }
+
+    /// Tells the kernel that `length` bytes starting from `address` would
+    /// benefit from being backed by huge pages. This is only a hint, so it
+    /// returns whether the kernel accepted it but nothing breaks if it
+    /// didn't. By default it's not supported at all.
+    unsafe fn advise_huge_pages(_address: NonNull<u8>, _length: usize) -> bool {
+        false
+    }
+
+    /// Tells the kernel that `length` bytes starting from `address`, which
+    /// must be page aligned, won't be used for a while, so their physical
+    /// pages should be reclaimed before others under memory pressure. Unlike
+    /// [`Self::purge`], the contents are preserved and nothing happens if
+    /// there's no pressure. Only a hint, by default it's not supported.
+    unsafe fn advise_cold(_address: NonNull<u8>, _length: usize) -> bool {
+        false
+    }
+
+    /// Tells the kernel that the contents of `length` bytes starting from
+    /// `address`, which must be page aligned, are not needed anymore, so the
+    /// physical pages can be reclaimed. The memory is still mapped and can be
+    /// used again, but its contents are undefined. Returns whether the kernel
+    /// accepted the request.
+    unsafe fn purge(address: NonNull<u8>, length: usize) -> bool;
+
+    /// Changes the protection of `length` bytes starting from `address`,
+    /// which must be page aligned. Returns whether it succeeded.
+    unsafe fn protect(address: NonNull<u8>, length: usize, protection: Protection) -> bool;
+
+    /// Locks `length` bytes starting from `address`, which must be page
+    /// aligned, in physical memory so that they are never written to swap.
+    /// Returns whether it succeeded, the kernel usually limits how much
+    /// memory a process can lock.
+    unsafe fn lock_memory(address: NonNull<u8>, length: usize) -> bool;
+
+    /// Inverse of [`Self::lock_memory`].
+    unsafe fn unlock_memory(address: NonNull<u8>, length: usize) -> bool;
 
     /// Virtual memory page size in bytes.
     unsafe fn page_size() -> usize;
@@ -40,69 +116,372 @@ pub(crate) fn page_size() -> usize {
     }
 }
 
-/// Convinience wrapper for [`PlatformSpecificMemory::request_memory`].
+/// See [`PlatformSpecificMemory::CAN_COALESCE`].
+#[inline]
+pub(crate) const fn can_coalesce() -> bool {
+    Platform::CAN_COALESCE
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of times memory has been returned to the kernel by the current
+    /// thread. Only used for testing.
+    static RETURN_MEMORY_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Number of calls to [`return_memory`] and [`return_aligned_memory`] made
+/// by the current thread.
+#[cfg(test)]
+pub(crate) fn return_memory_calls() -> usize {
+    RETURN_MEMORY_CALLS.with(|calls| calls.get())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of bytes passed to [`advise_cold`] by the current thread,
+    /// whether the kernel accepted the advice or not. Only used for testing.
+    static COLD_BYTES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Number of bytes that the current thread asked to mark as cold.
+#[cfg(test)]
+pub(crate) fn cold_bytes() -> usize {
+    COLD_BYTES.with(|bytes| bytes.get())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Error code that the next call to [`return_memory`] or
+    /// [`return_aligned_memory`] made by the current thread will report as a
+    /// failure. Only used for testing.
+    static INJECTED_RETURN_ERROR: std::cell::Cell<Option<i32>> = const { std::cell::Cell::new(None) };
+}
+
+/// Makes the next call to [`return_memory`] or [`return_aligned_memory`] on
+/// the current thread fail with `errno`. The memory is still returned to the
+/// kernel so that tests don't leak it, only the result is faked.
+#[cfg(test)]
+pub(crate) fn fail_next_return_memory(errno: i32) {
+    INJECTED_RETURN_ERROR.with(|error| error.set(Some(errno)));
+}
+
+/// Called when `munmap` or `VirtualFree` fails. We always give them lengths
+/// and addresses that we computed ourselves, so a failure means that we
+/// computed something wrong (or the address space is so fragmented that the
+/// kernel can't split a mapping, which is rare enough). The memory is still
+/// mapped, so the only thing we can do is make some noise: the failure is
+/// logged with the details of the request if the `logging` feature is
+/// enabled and returned to the caller, which usually runs with the allocator
+/// locked. The allocator counts it in [`crate::Stats::unmap_failures`] and
+/// panics in debug builds once the lock is released, see
+/// [`crate::failure::UnmapFailures`].
+#[cold]
+fn unmap_failed(address: NonNull<u8>, length: usize, errno: i32) -> Result<(), UnmapFailure> {
+    let failure = UnmapFailure {
+        address,
+        length,
+        errno,
+    };
+
+    #[cfg(feature = "logging")]
+    log::error!("{failure}");
+
+    Err(failure)
+}
+
+/// Reports the failure if `returned` is false, see [`unmap_failed`]. Must be
+/// called right after returning memory so that the error code is still the
+/// one set by the kernel.
+#[inline]
+fn check_returned(returned: bool, address: NonNull<u8>, length: usize) -> Result<(), UnmapFailure> {
+    #[cfg(test)]
+    if let Some(errno) = INJECTED_RETURN_ERROR.with(|error| error.take()) {
+        return unmap_failed(address, length, errno);
+    }
+
+    if returned {
+        Ok(())
+    } else {
+        unmap_failed(address, length, last_os_error())
+    }
+}
+
+/// Code of the last OS error on this thread, which is what `mmap` and
+/// `VirtualAlloc` set when they fail. Must be called right after the failed
+/// call, before anything else can overwrite it.
+fn last_os_error() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::request_memory`]. If the
+/// request fails, the error code set by the kernel is returned instead.
+#[inline]
+pub(crate) unsafe fn request_memory(length: usize, extra_flags: c_int) -> Result<NonNull<u8>, i32> {
+    Platform::request_memory(length, extra_flags).ok_or_else(last_os_error)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::request_aligned_memory`].
+/// If `align` is not greater than the page size any region will do.
+#[inline]
+pub(crate) unsafe fn request_aligned_memory(
+    length: usize,
+    align: usize,
+    extra_flags: c_int,
+) -> Result<NonNull<u8>, i32> {
+    if align <= page_size() {
+        request_memory(length, extra_flags)
+    } else {
+        Platform::request_aligned_memory(length, align, extra_flags).ok_or_else(last_os_error)
+    }
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::return_memory`]. If the
+/// kernel refuses to take the memory back, the failure is returned, see
+/// [`unmap_failed`].
 #[inline]
-pub(crate) unsafe fn request_memory(length: usize) -> Pointer<u8> {
-    Platform::request_memory(length)
+pub(crate) unsafe fn return_memory(
+    address: NonNull<u8>,
+    length: usize,
+) -> Result<(), UnmapFailure> {
+    #[cfg(test)]
+    RETURN_MEMORY_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+    check_returned(Platform::return_memory(address, length), address, length)
 }
 
-/// Convinience wrapper for [`PlatformSpecificMemory::return_memory`].
+/// Convinience wrapper for [`PlatformSpecificMemory::advise_huge_pages`].
 #[inline]
-pub(crate) unsafe fn return_memory(address: NonNull<u8>, length: usize) {
-    Platform::return_memory(address, length)
+pub(crate) unsafe fn advise_huge_pages(address: NonNull<u8>, length: usize) -> bool {
+    Platform::advise_huge_pages(address, length)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::advise_cold`].
+#[inline]
+pub(crate) unsafe fn advise_cold(address: NonNull<u8>, length: usize) -> bool {
+    #[cfg(test)]
+    COLD_BYTES.with(|bytes| bytes.set(bytes.get() + length));
+
+    Platform::advise_cold(address, length)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::purge`].
+#[inline]
+pub(crate) unsafe fn purge(address: NonNull<u8>, length: usize) -> bool {
+    Platform::purge(address, length)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::protect`].
+#[inline]
+pub(crate) unsafe fn protect(address: NonNull<u8>, length: usize, protection: Protection) -> bool {
+    Platform::protect(address, length, protection)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::lock_memory`].
+#[inline]
+pub(crate) unsafe fn lock_memory(address: NonNull<u8>, length: usize) -> Result<(), i32> {
+    match Platform::lock_memory(address, length) {
+        true => Ok(()),
+        false => Err(last_os_error()),
+    }
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::unlock_memory`].
+#[inline]
+pub(crate) unsafe fn unlock_memory(address: NonNull<u8>, length: usize) -> bool {
+    Platform::unlock_memory(address, length)
+}
+
+/// Convinience wrapper for [`PlatformSpecificMemory::return_aligned_memory`].
+/// `align` must be the same value given to [`request_aligned_memory`].
+#[inline]
+pub(crate) unsafe fn return_aligned_memory(
+    address: NonNull<u8>,
+    length: usize,
+    align: usize,
+) -> Result<(), UnmapFailure> {
+    if align <= page_size() {
+        return_memory(address, length)
+    } else {
+        #[cfg(test)]
+        RETURN_MEMORY_CALLS.with(|calls| calls.set(calls.get() + 1));
+
+        check_returned(
+            Platform::return_aligned_memory(address, length, align),
+            address,
+            length,
+        )
+    }
 }
 
 #[cfg(unix)]
 #[cfg(not(miri))]
 mod unix {
-    use std::ptr::{self, NonNull};
+    use std::{
+        ffi::{c_int, c_void},
+        ptr::{self, NonNull},
+    };
 
     use libc;
 
-    use super::{Platform, PlatformSpecificMemory};
+    use super::{check_returned, page_size, Platform, PlatformSpecificMemory, Protection};
     use crate::Pointer;
 
+    /// Translates the return value of `mmap` into an address. Unlike
+    /// `malloc`, `mmap` signals failure with `MAP_FAILED`, which is `-1` and
+    /// not null, so checking for null alone would hand out an address right
+    /// at the end of the address space. Null is never returned when no
+    /// address hint is given, but we reject it anyway instead of building a
+    /// `NonNull` out of it. Any other address is valid, no matter how low.
+    #[inline]
+    fn mapped_address(address: *mut c_void) -> Pointer<u8> {
+        if address == libc::MAP_FAILED {
+            return None;
+        }
+
+        NonNull::new(address.cast())
+    }
+
     impl PlatformSpecificMemory for Platform {
-        unsafe fn request_memory(length: usize) -> Pointer<u8> {
+        // munmap works on any range of pages, no matter how they were mapped.
+        const CAN_COALESCE: bool = true;
+
+        unsafe fn request_memory(length: usize, extra_flags: c_int) -> Pointer<u8> {
             // Memory protection. Read-Write only.
             let protection = libc::PROT_READ | libc::PROT_WRITE;
 
-            // Memory should be private to our process and not mapped to any file.
-            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            // Memory should be private to our process and not mapped to any
+            // file, plus whatever the user asked for.
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | extra_flags;
 
             // For all the configuration options that `mmap` accepts see
             // https://man7.org/linux/man-pages/man2/mmap.2.html
-            match libc::mmap(ptr::null_mut(), length, protection, flags, -1, 0) {
-                libc::MAP_FAILED => None,
-                address => Some(NonNull::new_unchecked(address).cast()),
-            }
+            mapped_address(libc::mmap(
+                ptr::null_mut(),
+                length,
+                protection,
+                flags,
+                -1,
+                0,
+            ))
         }
 
-        unsafe fn return_memory(address: NonNull<u8>, length: usize) {
-            if libc::munmap(address.cast().as_ptr(), length) != 0 {
-                // TODO: What should we do here? Panic? Memory region is still
-                // valid here, it wasn't unmapped.
+        unsafe fn request_aligned_memory(
+            length: usize,
+            align: usize,
+            extra_flags: c_int,
+        ) -> Pointer<u8> {
+            // mmap doesn't support alignment, but it gives us page aligned
+            // addresses, so if we map align - page_size extra bytes there
+            // must be an aligned address somewhere. Then we unmap what we
+            // don't need at both ends:
+            //
+            // +---------+------------------------------+----------+
+            // | Leading |  Aligned region (length)     | Trailing |
+            // +---------+------------------------------+----------+
+            let mapped = length + align - page_size();
+            let address = Self::request_memory(mapped, extra_flags)?;
+            let leading = address.as_ptr().align_offset(align);
+            let trailing = mapped - leading - length;
+
+            // Failing to trim the ends only wastes address space, the
+            // aligned region is still usable, so the failures are logged but
+            // not reported to the caller.
+            if leading != 0 {
+                let _ = check_returned(Self::return_memory(address, leading), address, leading);
+            }
+
+            let aligned = NonNull::new_unchecked(address.as_ptr().add(leading));
+
+            if trailing != 0 {
+                let end = NonNull::new_unchecked(aligned.as_ptr().add(length));
+                let _ = check_returned(Self::return_memory(end, trailing), end, trailing);
             }
+
+            Some(aligned)
+        }
+
+        unsafe fn return_memory(address: NonNull<u8>, length: usize) -> bool {
+            libc::munmap(address.cast().as_ptr(), length) == 0
+        }
+
+        unsafe fn purge(address: NonNull<u8>, length: usize) -> bool {
+            libc::madvise(address.cast().as_ptr(), length, libc::MADV_DONTNEED) == 0
+        }
+
+        unsafe fn protect(address: NonNull<u8>, length: usize, protection: Protection) -> bool {
+            let protection = match protection {
+                Protection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+                Protection::ReadOnly => libc::PROT_READ,
+                Protection::ReadExecute => libc::PROT_READ | libc::PROT_EXEC,
+                Protection::None => libc::PROT_NONE,
+            };
+
+            libc::mprotect(address.cast().as_ptr(), length, protection) == 0
+        }
+
+        unsafe fn lock_memory(address: NonNull<u8>, length: usize) -> bool {
+            libc::mlock(address.cast().as_ptr(), length) == 0
+        }
+
+        unsafe fn unlock_memory(address: NonNull<u8>, length: usize) -> bool {
+            libc::munlock(address.cast().as_ptr(), length) == 0
+        }
+
+        // Transparent huge pages are Linux specific. Unlike MAP_HUGETLB they
+        // don't need huge pages reserved in advance, the kernel will use them
+        // opportunistically if THP is enabled in "madvise" or "always" mode.
+        #[cfg(target_os = "linux")]
+        unsafe fn advise_huge_pages(address: NonNull<u8>, length: usize) -> bool {
+            libc::madvise(address.cast().as_ptr(), length, libc::MADV_HUGEPAGE) == 0
+        }
+
+        // MADV_COLD needs Linux 5.4, older kernels reject it with EINVAL.
+        #[cfg(target_os = "linux")]
+        unsafe fn advise_cold(address: NonNull<u8>, length: usize) -> bool {
+            libc::madvise(address.cast().as_ptr(), length, libc::MADV_COLD) == 0
         }
 
         unsafe fn page_size() -> usize {
             libc::sysconf(libc::_SC_PAGE_SIZE) as usize
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mapped_address_rejects_map_failed() {
+            assert_eq!(mapped_address(libc::MAP_FAILED), None);
+            assert_eq!(mapped_address(ptr::null_mut()), None);
+
+            // Low addresses are fine, only the sentinels are errors.
+            let low = page_size() as *mut c_void;
+            assert_eq!(mapped_address(low).map(NonNull::as_ptr), Some(low.cast()));
+
+            // And the allocator turns the failure into AllocError.
+            let rulloc = crate::Rulloc::default();
+            let layout = std::alloc::Layout::array::<u8>(isize::MAX as usize / 2).unwrap();
+            assert!(std::alloc::Allocator::allocate(&rulloc, layout).is_err());
+        }
+    }
 }
 
 #[cfg(windows)]
 #[cfg(not(miri))]
 mod windows {
-    use std::{mem::MaybeUninit, ptr::NonNull};
+    use std::{ffi::c_int, mem::MaybeUninit, ptr::NonNull};
 
     use windows::Win32::System::{Memory, SystemInformation};
 
-    use super::{Platform, PlatformSpecificMemory};
+    use super::{check_returned, Platform, PlatformSpecificMemory, Protection};
     use crate::Pointer;
 
     impl PlatformSpecificMemory for Platform {
-        unsafe fn request_memory(length: usize) -> Pointer<u8> {
+        // VirtualFree can only release entire reservations.
+        const CAN_COALESCE: bool = false;
+
+        unsafe fn request_memory(length: usize, _extra_flags: c_int) -> Pointer<u8> {
             // Similar to mmap on Linux, Read-Write only.
             let protection = Memory::PAGE_READWRITE;
 
@@ -118,7 +497,49 @@ mod windows {
             NonNull::new(address.cast())
         }
 
-        unsafe fn return_memory(address: NonNull<u8>, _length: usize) {
+        unsafe fn request_aligned_memory(
+            length: usize,
+            align: usize,
+            _extra_flags: c_int,
+        ) -> Pointer<u8> {
+            // VirtualFree can't release part of a reservation, so we can't
+            // trim like we do with munmap. Instead, reserve enough space to
+            // find an aligned address, release everything and reserve again
+            // at the aligned address. Another thread might steal the address
+            // in the meantime, so retry a few times.
+            for _ in 0..8 {
+                let reserved = Memory::VirtualAlloc(
+                    None,
+                    length + align,
+                    Memory::MEM_RESERVE,
+                    Memory::PAGE_NOACCESS,
+                );
+
+                let reserved = NonNull::new(reserved.cast::<u8>())?;
+                let aligned = reserved.as_ptr().add(reserved.as_ptr().align_offset(align));
+                // Same as the trimming on Unix, only logged.
+                let _ = check_returned(
+                    Self::return_memory(reserved, length + align),
+                    reserved,
+                    length + align,
+                );
+
+                let address = Memory::VirtualAlloc(
+                    Some(aligned.cast()),
+                    length,
+                    Memory::MEM_RESERVE | Memory::MEM_COMMIT,
+                    Memory::PAGE_READWRITE,
+                );
+
+                if let Some(address) = NonNull::new(address.cast()) {
+                    return Some(address);
+                }
+            }
+
+            None
+        }
+
+        unsafe fn return_memory(address: NonNull<u8>, _length: usize) -> bool {
             // Again, we have to decommit memory first and then release it. We
             // can skip decommitting by specifying length of 0 and MEM_RELEASE
             // flag. See the docs for details:
@@ -127,10 +548,46 @@ mod windows {
             let length = 0;
             let flags = Memory::MEM_RELEASE;
 
-            if !Memory::VirtualFree(address, length, flags).as_bool() {
-                // TODO: Release failed, don't know what to do here yet. Same
-                // problem as munmap on Linux.
-            }
+            Memory::VirtualFree(address, length, flags).as_bool()
+        }
+
+        unsafe fn purge(address: NonNull<u8>, length: usize) -> bool {
+            // MEM_RESET is the closest thing to MADV_DONTNEED, pages are not
+            // written to the paging file and their contents are discarded.
+            let address = Memory::VirtualAlloc(
+                Some(address.cast().as_ptr()),
+                length,
+                Memory::MEM_RESET,
+                Memory::PAGE_READWRITE,
+            );
+
+            !address.is_null()
+        }
+
+        unsafe fn protect(address: NonNull<u8>, length: usize, protection: Protection) -> bool {
+            let protection = match protection {
+                Protection::ReadWrite => Memory::PAGE_READWRITE,
+                Protection::ReadOnly => Memory::PAGE_READONLY,
+                Protection::ReadExecute => Memory::PAGE_EXECUTE_READ,
+                Protection::None => Memory::PAGE_NOACCESS,
+            };
+            let mut old_protection = Memory::PAGE_PROTECTION_FLAGS::default();
+
+            Memory::VirtualProtect(
+                address.cast().as_ptr(),
+                length,
+                protection,
+                &mut old_protection,
+            )
+            .as_bool()
+        }
+
+        unsafe fn lock_memory(address: NonNull<u8>, length: usize) -> bool {
+            Memory::VirtualLock(address.cast().as_ptr(), length).as_bool()
+        }
+
+        unsafe fn unlock_memory(address: NonNull<u8>, length: usize) -> bool {
+            Memory::VirtualUnlock(address.cast().as_ptr(), length).as_bool()
         }
 
         unsafe fn page_size() -> usize {
@@ -150,22 +607,62 @@ mod miri {
     //! memory leaks in our own allocator (regions that are not returned back to
     //! the kernel).
 
-    use std::{alloc, ptr::NonNull};
+    use std::{alloc, ffi::c_int, ptr::NonNull};
 
-    use super::{page_size, Platform, PlatformSpecificMemory};
+    use super::{page_size, Platform, PlatformSpecificMemory, Protection};
     use crate::Pointer;
 
-    fn to_layout(length: usize) -> alloc::Layout {
-        alloc::Layout::from_size_align(length, page_size()).unwrap()
+    fn to_layout(length: usize, align: usize) -> alloc::Layout {
+        alloc::Layout::from_size_align(length, align).unwrap()
     }
 
     impl PlatformSpecificMemory for Platform {
-        unsafe fn request_memory(length: usize) -> Pointer<u8> {
-            NonNull::new(alloc::alloc(to_layout(length)))
+        // Every allocation must be deallocated separately.
+        const CAN_COALESCE: bool = false;
+
+        // Zeroed just like pages given by the kernel, see
+        // [`crate::Rulloc::allocate_zeroed`].
+        unsafe fn request_memory(length: usize, _extra_flags: c_int) -> Pointer<u8> {
+            NonNull::new(alloc::alloc_zeroed(to_layout(length, page_size())))
+        }
+
+        unsafe fn request_aligned_memory(
+            length: usize,
+            align: usize,
+            _extra_flags: c_int,
+        ) -> Pointer<u8> {
+            NonNull::new(alloc::alloc_zeroed(to_layout(length, align)))
+        }
+
+        unsafe fn return_memory(address: NonNull<u8>, length: usize) -> bool {
+            alloc::dealloc(address.as_ptr(), to_layout(length, page_size()));
+            true
+        }
+
+        unsafe fn return_aligned_memory(address: NonNull<u8>, length: usize, align: usize) -> bool {
+            // Miri checks that the layout matches the one used to allocate.
+            alloc::dealloc(address.as_ptr(), to_layout(length, align));
+            true
+        }
+
+        unsafe fn purge(_address: NonNull<u8>, _length: usize) -> bool {
+            // Nothing to release, memory comes from the global allocator.
+            false
+        }
+
+        unsafe fn protect(_address: NonNull<u8>, _length: usize, _protection: Protection) -> bool {
+            // Memory from the global allocator can't be executed or made
+            // read only anyway.
+            true
+        }
+
+        unsafe fn lock_memory(_address: NonNull<u8>, _length: usize) -> bool {
+            // Miri doesn't swap anything.
+            true
         }
 
-        unsafe fn return_memory(address: NonNull<u8>, length: usize) {
-            alloc::dealloc(address.as_ptr(), to_layout(length));
+        unsafe fn unlock_memory(_address: NonNull<u8>, _length: usize) -> bool {
+            true
         }
 
         unsafe fn page_size() -> usize {
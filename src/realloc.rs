@@ -17,6 +17,10 @@ pub(crate) struct Realloc {
     pub old_layout: Layout,
     /// For now, whether shrink or grow.
     pub method: ReallocMethod,
+    /// Size recorded in the source block when it was allocated, see
+    /// [`Block::layout`].
+    #[cfg(feature = "layout-tracking")]
+    pub requested: Option<usize>,
 }
 
 /// The reallocation either attempts to shrink or grow, but there are some
@@ -31,6 +35,30 @@ pub(crate) enum ReallocMethod {
     Grow,
 }
 
+/// What to do when an allocation shrinks below the size of its bucket. See
+/// [`crate::Rulloc::with_shrink_policy`]. With bucket sizes `[128, 1024]`,
+/// shrinking a 900 byte allocation to 100 bytes does this:
+///
+/// ```text
+///             +---------+                            +---------+
+/// Migrate:    | 1024: A | -- copy to new block -->   | 128: A' |
+///             +---------+                            +---------+
+///
+///             +---------+                            +---------+------+
+/// Stable:     | 1024: A | -- split in place ----->   | 1024: A | Free |
+///             +---------+                            +---------+------+
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShrinkPolicy {
+    /// Move the contents to the bucket where the new layout would normally
+    /// go, which keeps every bucket for its own sizes. This is the default.
+    #[default]
+    Migrate,
+    /// Never change buckets when shrinking, the pointer stays the same and
+    /// the block is split in place if possible.
+    Stable,
+}
+
 impl Realloc {
     /// Builds a new [`Realloc`] with the given parameters. Caller must
     /// ensure that `address` is valid because we'll attempt to obtain the
@@ -49,6 +77,19 @@ impl Realloc {
             new_layout,
             old_layout,
             method,
+            #[cfg(feature = "layout-tracking")]
+            requested: None,
+        }
+    }
+
+    /// Same [`Realloc`] with [`Self::requested`] read from the source block.
+    /// This is not done in [`Self::new`] because addresses given by
+    /// [`crate::slab`] have no block header to read.
+    #[cfg(feature = "layout-tracking")]
+    pub unsafe fn with_requested_size(&self) -> Self {
+        Self {
+            requested: self.block.as_ref().data.layout.map(|layout| layout.size()),
+            ..*self
         }
     }
 
@@ -64,12 +105,21 @@ impl Realloc {
 
     /// Number of bytes that should be copied from the previous allocation. If
     /// we are shrinking, we only need to copy enough bytes to fill the new
-    /// layout, otherwise we'll copy everything from the previous layout.
+    /// layout, otherwise we'll copy everything from the previous layout. If
+    /// we know the size that was actually requested for the source block we
+    /// never copy more than that, even if `old_layout` says otherwise.
     pub fn count(&self) -> usize {
-        match self.method {
+        let count = match self.method {
             ReallocMethod::Shrink => self.new_layout.size(),
             ReallocMethod::Grow => self.old_layout.size(),
+        };
+
+        #[cfg(feature = "layout-tracking")]
+        if let Some(requested) = self.requested {
+            return count.min(requested);
         }
+
+        count
     }
 
     /// Maps this [`Realloc`] to a [`Realloc`] on a new block. This is usefull
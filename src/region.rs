@@ -1,15 +1,43 @@
-use std::{alloc::Layout, mem, ptr::NonNull};
+use std::{alloc::Layout, ffi::c_int, mem, ptr::NonNull};
 
 use crate::{
+    alignment::POINTER_SIZE,
     block::{Block, BLOCK_HEADER_SIZE, MIN_BLOCK_SIZE},
+    failure::UnmapFailure,
     header::Header,
     list::LinkedList,
     platform,
+    source::MmapSource,
 };
 
+/// Regions of at least this many bytes are hinted to be backed by huge pages
+/// if [`RegionPlacement`] says so. This is the huge page size on x86-64 and
+/// most ARM64 configurations, smaller regions can't contain a huge page.
+pub(crate) const HUGE_PAGE_THRESHOLD: usize = 2 * 1024 * 1024;
+
 /// Region header size in bytes. See [`Header<T>`] and [`Region`].
 pub(crate) const REGION_HEADER_SIZE: usize = mem::size_of::<Header<Region>>();
 
+/// Public description of a region, returned by [`crate::Rulloc::region_for`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// Address where the mapping starts, which is also the address of the
+    /// region header.
+    pub address: NonNull<u8>,
+    /// Length of the whole mapping, including the region header and guard
+    /// pages.
+    pub length: usize,
+    /// Index of the bucket that owns the region. Index `N` is the dynamic
+    /// bucket.
+    pub bucket: usize,
+    /// Number of blocks in the region, free or not.
+    pub num_blocks: usize,
+}
+
+// The address is only a description of the mapping, we never dereference it.
+unsafe impl Send for RegionInfo {}
+unsafe impl Sync for RegionInfo {}
+
 /// Memory region specific data. All headers are also linked lists nodes, see
 /// [`Header<T>`] and [`Block`]. In this case, a complete region header would be
 /// [`Header<Region>`].
@@ -31,8 +59,20 @@ pub(crate) const REGION_HEADER_SIZE: usize = mem::size_of::<Header<Region>>();
 pub(crate) struct Region {
     /// Blocks contained within this memory region.
     pub blocks: LinkedList<Block>,
-    /// Size of the region excluding [`Header<Region>`] size.
+    /// Size of the region excluding [`Header<Region>`] size and guard pages.
     pub size: usize,
+    /// Length of the guard pages mapped right after the usable part of the
+    /// region, 0 if there are none. See [`RegionPlacement`].
+    pub guard: usize,
+    /// Number of blocks in use. Only pools keep track of it, see
+    /// [`crate::bucket::Bucket::set_pool`].
+    pub used: usize,
+    /// Sum of the sizes requested for the blocks in use, so that blocks
+    /// dropped without being deallocated can still be discounted from
+    /// [`crate::Stats::bytes_allocated`]. Updated by the allocator, not by
+    /// the bucket, see [`crate::Rulloc::clear_bucket`]. It wraps around just
+    /// like the counter if a deallocation reports a different size.
+    pub requested: usize,
 }
 
 impl Header<Region> {
@@ -46,16 +86,17 @@ impl Header<Region> {
         self.data.blocks.first().unwrap_unchecked()
     }
 
-    /// Region size excluding [`REGION_HEADER_SIZE`].
+    /// Region size excluding [`REGION_HEADER_SIZE`] and guard pages.
     #[inline]
     pub fn size(&self) -> usize {
         self.data.size
     }
 
-    /// Region size including [`REGION_HEADER_SIZE`].
+    /// Length of the whole mapping, including [`REGION_HEADER_SIZE`] and
+    /// guard pages.
     #[inline]
     pub fn total_size(&self) -> usize {
-        REGION_HEADER_SIZE + self.data.size
+        REGION_HEADER_SIZE + self.data.size + self.data.guard
     }
 
     /// Number of blocks in this region.
@@ -63,6 +104,14 @@ impl Header<Region> {
     pub fn num_blocks(&self) -> usize {
         self.data.blocks.len()
     }
+
+    /// Whether `address` points anywhere inside the mapping of this region,
+    /// headers and guard pages included.
+    #[inline]
+    pub fn contains(&self, address: NonNull<u8>) -> bool {
+        let start = (self as *const Self).addr();
+        (start..start + self.total_size()).contains(&address.as_ptr().addr())
+    }
 }
 
 /// Calculates the length in bytes that we should call `mmap` with if we
@@ -71,18 +120,20 @@ impl Header<Region> {
 /// # Arguments
 ///
 /// * `size` - Amount of bytes that need to be allocated without including
-/// any header. This value must be **already aligned**.
+///   any header. This value must be **already aligned**.
 ///
-pub(crate) fn determine_region_length(size: usize) -> usize {
+/// Returns `None` if the length doesn't fit in `isize`, which is the maximum
+/// size of any allocation.
+pub(crate) fn determine_region_length(size: usize) -> Option<usize> {
     // We'll store at least one block in this region, so we need space for
     // region header, block header and user content.
-    let total_size = REGION_HEADER_SIZE + BLOCK_HEADER_SIZE + size;
+    let total_size = size.checked_add(REGION_HEADER_SIZE + BLOCK_HEADER_SIZE)?;
 
     // Align up to page size. If we want to store 4104 bytes and page size is
     // 4096 bytes, then we'll request a region that's 2 pages in length
     // (8192 bytes).
     let mut length = Layout::from_size_align(total_size, platform::page_size())
-        .unwrap()
+        .ok()?
         .pad_to_align()
         .size();
 
@@ -136,7 +187,220 @@ pub(crate) fn determine_region_length(size: usize) -> usize {
         length += platform::page_size();
     }
 
-    length
+    (length <= isize::MAX as usize).then_some(length)
+}
+
+/// Controls where new regions are placed in memory and where their first
+/// block starts. By default regions are only aligned to page size (that's
+/// what `mmap` gives us) and the first block is located right after the
+/// region header. Both things can be changed:
+///
+/// - **Alignment**: region base addresses are aligned to a custom power of 2
+///   boundary, for example 64KB. To do so, we map `length + align - page_size`
+///   bytes and return the unaligned parts back to the kernel, see
+///   [`platform::request_aligned_memory`].
+///
+/// - **Cache coloring**: each new region shifts the start of its first block
+///   by an additional `color_stride` bytes, wrapping around at page size.
+///   Without coloring, hot blocks located at the beginning of different regions
+///   have the same offset relative to a page boundary, so they compete for the
+///   same cache sets:
+///
+/// ```text
+/// +--------+----------------------+
+/// | Region | Block ...            |  <- Offset 0
+/// +--------+----------------------+
+///
+/// +--------+--------+----------------------+
+/// | Region | Stride | Block ...            |  <- Offset color_stride
+/// +--------+--------+----------------------+
+///
+/// +--------+-----------------+----------------------+
+/// | Region |   2 * Stride    | Block ...            |  <- Offset 2 * color_stride
+/// +--------+-----------------+----------------------+
+/// ```
+///
+/// The bytes skipped are wasted until the region is unmapped, so the stride
+/// should be small, usually the cache line size.
+///
+/// - **Huge pages**: regions of at least [`HUGE_PAGE_THRESHOLD`] bytes can be
+///   hinted to be backed by transparent huge pages, see
+///   [`platform::advise_huge_pages`].
+///
+/// - **Guard pages**: each region is followed by some pages that can't be
+///   read or written, so writing past the end of the last block of a region
+///   faults right away instead of silently corrupting whatever is mapped
+///   after it, which might be another region:
+///
+/// ```text
+/// +--------+------------------------+-------+      +--------+---------
+/// | Region | Block ...      | Block | Guard |      | Region | Block ...
+/// +--------+------------------------+-------+      +--------+---------
+///                                       ^
+///                                       |
+///                                       +--- PROT_NONE
+/// ```
+///
+/// Guard pages are part of the mapping, so they are unmapped together with
+/// the region, but they are not part of [`Region::size`] so blocks never
+/// include them. Overruns within the same region are not detected.
+///
+/// - **Extra `mmap` flags**: anything the options above don't cover, see
+///   [`crate::Rulloc::with_mmap_flags`].
+///
+/// - **Source**: regions can be requested to a custom [`MmapSource`] instead
+///   of the kernel, see [`crate::Rulloc::with_bucket_source`]. Sources only
+///   know about whole mappings, so regions of custom sources are never
+///   coalesced nor partially unmapped, and the extra `mmap` flags don't apply.
+pub(crate) struct RegionPlacement {
+    /// Alignment of region base addresses. 0 means page size.
+    align: usize,
+    /// Offset added to the content start of each new region.
+    color_stride: usize,
+    /// Offset that will be used for the next region.
+    next_color: usize,
+    /// Whether large regions should be backed by huge pages.
+    huge_pages: bool,
+    /// Length of the guard pages mapped after each region, 0 disables them.
+    guard: usize,
+    /// Flags ORed into the default `mmap` flags.
+    mmap_flags: c_int,
+    /// Custom source of regions, `None` means anonymous `mmap`.
+    source: Option<&'static dyn MmapSource>,
+}
+
+impl RegionPlacement {
+    /// Default placement, page aligned regions without coloring.
+    pub const fn new() -> Self {
+        Self {
+            align: 0,
+            color_stride: 0,
+            next_color: 0,
+            huge_pages: false,
+            guard: 0,
+            mmap_flags: 0,
+            source: None,
+        }
+    }
+
+    /// Alignment of region base addresses.
+    #[inline]
+    pub fn align(&self) -> usize {
+        self.align.max(platform::page_size())
+    }
+
+    /// Sets the alignment of region base addresses, see [`RegionPlacement`].
+    pub fn set_align(&mut self, align: usize) {
+        assert!(
+            align.is_power_of_two(),
+            "region alignment must be a power of 2"
+        );
+        self.align = align;
+    }
+
+    /// Sets the cache coloring stride, 0 disables coloring.
+    pub fn set_color_stride(&mut self, color_stride: usize) {
+        assert!(
+            color_stride % POINTER_SIZE == 0 && color_stride < platform::page_size(),
+            "cache coloring stride must be a multiple of the pointer size and less than page size"
+        );
+        self.color_stride = color_stride;
+        self.next_color = 0;
+    }
+
+    /// Length of the guard pages of each region, already a multiple of the
+    /// page size.
+    #[inline]
+    pub fn guard(&self) -> usize {
+        self.guard
+    }
+
+    /// Sets the length of the guard pages of each region. The length is
+    /// rounded up to the page size, 0 disables guard pages.
+    pub fn set_guard(&mut self, bytes: usize) {
+        self.guard = bytes.next_multiple_of(platform::page_size());
+    }
+
+    /// Flags ORed into the default `mmap` flags, see
+    /// [`platform::request_memory`].
+    #[inline]
+    pub fn mmap_flags(&self) -> c_int {
+        self.mmap_flags
+    }
+
+    /// Sets the extra `mmap` flags of new regions.
+    pub fn set_mmap_flags(&mut self, flags: c_int) {
+        self.mmap_flags = flags;
+    }
+
+    /// Sets the source of new regions, `None` restores anonymous `mmap`.
+    pub fn set_source(&mut self, source: Option<&'static dyn MmapSource>) {
+        self.source = source;
+    }
+
+    /// Whether consecutive regions can be unmapped with one single call and
+    /// regions can be partially unmapped, see [`platform::can_coalesce`].
+    /// Custom sources only take back whole mappings.
+    #[inline]
+    pub fn can_coalesce(&self) -> bool {
+        self.source.is_none() && platform::can_coalesce()
+    }
+
+    /// Maps `length` bytes for a new region, aligned to [`Self::align`].
+    ///
+    /// Anonymous mappings are always zero filled by the kernel, so buckets
+    /// assume that a region they just mapped only contains zeroes and skip
+    /// zeroing it again, see [`crate::bucket::Bucket::take_fresh`]. That
+    /// only holds without a custom source, see [`Self::is_zero_filled`].
+    pub unsafe fn map(&self, length: usize) -> Result<NonNull<u8>, i32> {
+        match self.source {
+            Some(source) => source.map(length, self.align()),
+            None => platform::request_aligned_memory(length, self.align(), self.mmap_flags),
+        }
+    }
+
+    /// Whether the memory given by [`Self::map`] is known to be zero filled,
+    /// which is only guaranteed for anonymous mappings.
+    #[inline]
+    pub fn is_zero_filled(&self) -> bool {
+        self.source.is_none()
+    }
+
+    /// Returns a mapping obtained from [`Self::map`]. Without a custom source
+    /// it can also be a run of coalesced regions. Custom sources handle their
+    /// own errors, so only the kernel can report an [`UnmapFailure`].
+    pub unsafe fn unmap(&self, address: NonNull<u8>, length: usize) -> Result<(), UnmapFailure> {
+        match self.source {
+            Some(source) => {
+                source.unmap(address, length, self.align());
+                Ok(())
+            }
+            None => platform::return_aligned_memory(address, length, self.align()),
+        }
+    }
+
+    /// Enables or disables the huge pages hint for large regions.
+    pub fn set_huge_pages(&mut self, enabled: bool) {
+        self.huge_pages = enabled;
+    }
+
+    /// Whether a region of `length` bytes should be hinted to use huge pages.
+    #[inline]
+    pub fn wants_huge_pages(&self, length: usize) -> bool {
+        self.huge_pages && length >= HUGE_PAGE_THRESHOLD
+    }
+
+    /// Returns the offset from the end of the region header where the first
+    /// block of the next region should be placed.
+    pub fn next_color_offset(&mut self) -> usize {
+        let offset = self.next_color;
+
+        if self.color_stride != 0 {
+            self.next_color = (self.next_color + self.color_stride) % platform::page_size();
+        }
+
+        offset
+    }
 }
 
 #[cfg(test)]
@@ -148,21 +412,30 @@ mod tests {
     fn region_length() {
         unsafe {
             // Basic checks.
-            assert_eq!(determine_region_length(POINTER_SIZE), platform::page_size());
-            assert_eq!(determine_region_length(PAGE_SIZE / 2), PAGE_SIZE);
+            assert_eq!(
+                determine_region_length(POINTER_SIZE),
+                Some(platform::page_size())
+            );
+            assert_eq!(determine_region_length(PAGE_SIZE / 2), Some(PAGE_SIZE));
             for i in 1..=100 {
-                assert_eq!(determine_region_length(PAGE_SIZE * i), PAGE_SIZE * (i + 1));
+                assert_eq!(
+                    determine_region_length(PAGE_SIZE * i),
+                    Some(PAGE_SIZE * (i + 1))
+                );
             }
 
             // Some corner cases.
             let exact_remaining_space = PAGE_SIZE - REGION_HEADER_SIZE - BLOCK_HEADER_SIZE;
-            assert_eq!(determine_region_length(exact_remaining_space), PAGE_SIZE);
+            assert_eq!(
+                determine_region_length(exact_remaining_space),
+                Some(PAGE_SIZE)
+            );
 
             let enough_space_for_minimum_block_at_the_end =
                 PAGE_SIZE - REGION_HEADER_SIZE - 2 * BLOCK_HEADER_SIZE - MIN_BLOCK_SIZE;
             assert_eq!(
                 determine_region_length(enough_space_for_minimum_block_at_the_end),
-                PAGE_SIZE
+                Some(PAGE_SIZE)
             );
 
             let not_enough_space_for_minimum_block_at_the_end =
@@ -170,8 +443,11 @@ mod tests {
                     + POINTER_SIZE;
             assert_eq!(
                 determine_region_length(not_enough_space_for_minimum_block_at_the_end),
-                2 * PAGE_SIZE
+                Some(2 * PAGE_SIZE)
             );
+
+            // Nothing can be larger than isize::MAX.
+            assert_eq!(determine_region_length(isize::MAX as usize), None);
         }
     }
 }
@@ -0,0 +1,116 @@
+//! Structured description of the heap returned by
+//! [`crate::Rulloc::memory_report`]. It mirrors the way memory is organized
+//! internally, so it can be used to write custom visualizers or to check the
+//! exact layout of blocks in tests:
+//!
+//! ```text
+//! HeapReport
+//!   |
+//!   +-> BucketReport (size 128)
+//!   |     |
+//!   |     +-> RegionReport (base, size)
+//!   |     |     |
+//!   |     |     +-> BlockReport (offset, size, free)
+//!   |     |     +-> BlockReport (offset, size, free)
+//!   |     |
+//!   |     +-> RegionReport ...
+//!   |
+//!   +-> BucketReport (size usize::MAX, dynamic bucket)
+//! ```
+//!
+//! Building the tree needs memory, but the allocator can't allocate while
+//! holding its lock (see [`crate::Rulloc`]), so the blocks are first copied
+//! into a flat list of [`BlockRecord`] with enough capacity reserved
+//! beforehand. The tree is built from that list once the lock is released.
+
+use std::ptr::NonNull;
+
+/// Snapshot of every bucket, region and block of an allocator. Slab slots
+/// are not blocks, so they are not included, see [`crate::Rulloc::with_slab`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapReport {
+    /// Fixed size buckets in order, followed by the dynamic bucket.
+    pub buckets: Vec<BucketReport>,
+}
+
+/// One bucket of [`HeapReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketReport {
+    /// Size of the bucket, `usize::MAX` for the dynamic bucket just like
+    /// [`crate::FullStats::classes`].
+    pub size: usize,
+    /// Regions sorted by address.
+    pub regions: Vec<RegionReport>,
+}
+
+/// One region of [`BucketReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionReport {
+    /// Address where the mapping starts, same as [`crate::RegionInfo::address`].
+    pub base: NonNull<u8>,
+    /// Length of the whole mapping, same as [`crate::RegionInfo::length`].
+    pub size: usize,
+    /// Blocks in address order.
+    pub blocks: Vec<BlockReport>,
+}
+
+// Same as RegionInfo, the base address is never dereferenced, so reports can
+// be sent to another thread to be rendered or stored.
+unsafe impl Send for RegionReport {}
+unsafe impl Sync for RegionReport {}
+
+/// One block of [`RegionReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockReport {
+    /// Distance in bytes from [`RegionReport::base`] to the block header.
+    pub offset: usize,
+    /// Content size of the block, excluding its header.
+    pub size: usize,
+    /// Whether the block is free. Blocks retained by the tail cache or size
+    /// classes are not free, even though nobody is using them.
+    pub free: bool,
+}
+
+/// Flat description of one block, see the [module documentation](self).
+pub(crate) struct BlockRecord {
+    /// Index of the bucket that owns the block.
+    pub bucket: usize,
+    /// Region that contains the block.
+    pub region: NonNull<u8>,
+    /// Length of the region mapping.
+    pub region_size: usize,
+    /// The block itself.
+    pub block: BlockReport,
+}
+
+impl HeapReport {
+    /// Builds the tree out of `records`, which must be grouped by bucket and
+    /// region in the same order as the allocator stores them. `sizes` gives
+    /// the size of each bucket, buckets without records are still reported.
+    pub(crate) fn from_records(
+        sizes: impl Iterator<Item = usize>,
+        records: Vec<BlockRecord>,
+    ) -> Self {
+        let mut buckets: Vec<BucketReport> = sizes
+            .map(|size| BucketReport {
+                size,
+                regions: Vec::new(),
+            })
+            .collect();
+
+        for record in records {
+            let regions = &mut buckets[record.bucket].regions;
+
+            match regions.last_mut() {
+                Some(region) if region.base == record.region => region.blocks.push(record.block),
+                _ => regions.push(RegionReport {
+                    base: record.region,
+                    size: record.region_size,
+                    blocks: vec![record.block],
+                }),
+            }
+        }
+
+        Self { buckets }
+    }
+}
@@ -0,0 +1,150 @@
+//! Buffers for secrets such as key material. Just like executable memory (see
+//! [`crate::exec`]), secure buffers never go through buckets, each one gets
+//! its own mapping so that it can be locked in physical memory without
+//! locking anybody else's data:
+//!
+//! ```text
+//!   allocate_secure()                                           drop()
+//!          |                                                      |
+//!          v                                                      v
+//! +-----------------+     +-----------------+     +---------------------------+
+//! | mmap and mlock  | --> |  Zeroed pages   | --> | Zero, munlock and munmap  |
+//! +-----------------+     +-----------------+     +---------------------------+
+//! ```
+//!
+//! Locked pages are never written to swap, so secrets can't end up on disk,
+//! and the contents are zeroed before the pages are unlocked and returned to
+//! the kernel. The zeroing is done with volatile writes so that the compiler
+//! can't remove it even though nobody reads the memory afterwards.
+
+use std::{
+    ffi::c_int,
+    io,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+use crate::platform;
+
+/// Page aligned buffer locked in physical memory and zeroed when dropped.
+/// Returned by [`crate::Rulloc::allocate_secure`]. The buffer owns its
+/// mapping, so it can outlive the allocator that created it.
+pub struct SecureBuffer {
+    /// First page of the mapping.
+    address: NonNull<u8>,
+    /// Number of bytes requested by the user.
+    size: usize,
+    /// Length of the mapping, `size` rounded up to page size.
+    length: usize,
+}
+
+unsafe impl Send for SecureBuffer {}
+unsafe impl Sync for SecureBuffer {}
+
+impl SecureBuffer {
+    /// Maps, locks and zeroes enough pages to hold `size` bytes. If the pages
+    /// can't be locked (the kernel usually limits how much memory a process
+    /// can lock) they are unmapped and the error is returned. `mmap_flags`
+    /// are ORed into the default flags, see [`platform::request_memory`].
+    pub(crate) fn new(size: usize, mmap_flags: c_int) -> io::Result<Self> {
+        let page_size = platform::page_size();
+        let length = size
+            .checked_next_multiple_of(page_size)
+            .filter(|length| *length <= isize::MAX as usize)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::OutOfMemory))?
+            .max(page_size);
+
+        unsafe {
+            let address = platform::request_memory(length, mmap_flags)
+                .map_err(io::Error::from_raw_os_error)?;
+
+            if let Err(errno) = platform::lock_memory(address, length) {
+                if let Err(failure) = platform::return_memory(address, length) {
+                    failure.report();
+                }
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+
+            // Fresh mappings are already zeroed on most platforms, but the
+            // global allocator used under Miri doesn't guarantee it.
+            address.as_ptr().write_bytes(0, length);
+
+            Ok(Self {
+                address,
+                size,
+                length,
+            })
+        }
+    }
+
+    /// Address of the first byte, always page aligned.
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.address
+    }
+
+    /// Overwrites the whole mapping with zeroes in a way that can't be
+    /// optimized away.
+    fn zeroize(&mut self) {
+        for offset in 0..self.length {
+            unsafe { self.address.as_ptr().add(offset).write_volatile(0) };
+        }
+    }
+}
+
+impl Deref for SecureBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address.as_ptr(), self.size) }
+    }
+}
+
+impl DerefMut for SecureBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.address.as_ptr(), self.size) }
+    }
+}
+
+impl Drop for SecureBuffer {
+    fn drop(&mut self) {
+        self.zeroize();
+
+        unsafe {
+            // Unmapping unlocks the pages anyway, this is only explicit so
+            // that the order is obvious.
+            platform::unlock_memory(self.address, self.length);
+            if let Err(failure) = platform::return_memory(self.address, self.length) {
+                failure.report();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secure_buffer() {
+        let page_size = platform::page_size();
+        let mut buffer = SecureBuffer::new(100, 0).unwrap();
+
+        assert_eq!(buffer.len(), 100);
+        assert_eq!(buffer.as_ptr().as_ptr() as usize % page_size, 0);
+        assert!(buffer.iter().all(|byte| *byte == 0));
+
+        buffer.fill(0xAB);
+        assert!(buffer.iter().all(|byte| *byte == 0xAB));
+
+        // Zeroing happens before unmapping, so we can observe it while the
+        // buffer is still alive.
+        buffer.zeroize();
+        assert!(buffer.iter().all(|byte| *byte == 0));
+
+        buffer.fill(0xCD);
+        let calls = platform::return_memory_calls();
+        drop(buffer);
+        assert_eq!(platform::return_memory_calls(), calls + 1);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{alloc::Layout, ptr::NonNull};
+
+use crate::{alignment::POINTER_SIZE, block::Block, header::Header, Pointer};
+
+/// Sizes that small requests are rounded up to when size classes are enabled.
+/// There's no class smaller than 16 bytes because on 64 bit machines blocks
+/// can't be smaller than that anyway, they need space for the free list node.
+/// See [`crate::block::MIN_BLOCK_SIZE`].
+pub(crate) const SIZE_CLASSES: [usize; 16] = [
+    16, 32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512,
+];
+
+/// Number of entries in [`SIZE_CLASSES`].
+pub(crate) const NUM_SIZE_CLASSES: usize = SIZE_CLASSES.len();
+
+/// Maximum number of blocks retained by each class of [`SizeClasses`].
+pub(crate) const SIZE_CLASS_CAPACITY: usize = 32;
+
+/// Without size classes, a bucket splits free blocks at the exact size needed
+/// for each request, so a 20 byte request gets a 24 byte block and a 28 byte
+/// request gets a 32 byte block. Freed blocks of slightly different sizes
+/// end up mixed in the free list and the bucket has to search and split
+/// again on every allocation. With size classes, requests are rounded up to
+/// the nearest entry of [`SIZE_CLASSES`] and freed blocks are kept in one
+/// list per class:
+///
+/// ```text
+///             +-------+    +-------+    +-------+
+/// Class 16:   | Block | -> | Block | -> | Block |
+///             +-------+    +-------+    +-------+
+///
+///             +----------+
+/// Class 32:   |  Block   |
+///             +----------+
+///
+///             +--------------+    +--------------+
+/// Class 48:   |    Block     | -> |    Block     |
+///             +--------------+    +--------------+
+/// ```
+///
+/// An allocation that rounds up to a class with cached blocks pops the head
+/// of the list, which is O(1), no searching or splitting involved. Only when
+/// the list is empty do we go through the normal free list, which still
+/// splits the block at the class size so that it can be cached once freed.
+///
+/// Just like [`crate::tailcache::TailCache`], cached blocks are neither free
+/// nor in use. They are not in the free list and their `is_free` flag is
+/// `false`, so they won't be merged with their neighbours and they keep their
+/// region mapped until they are released. That's why each class retains at
+/// most [`SIZE_CLASS_CAPACITY`] blocks, the rest go to the free list as usual.
+/// The link to the next block of the class is stored in the content of the
+/// block, which is not used by anyone while the block is cached.
+///
+/// Only requests aligned to [`POINTER_SIZE`] or less are rounded, stricter
+/// alignments need padding at different offsets depending on the address of
+/// the block, so blocks of those requests can't be reused interchangeably.
+pub(crate) struct SizeClasses {
+    /// Whether requests should be rounded and blocks retained.
+    enabled: bool,
+    /// First cached block of each class.
+    heads: [Pointer<Header<Block>>; NUM_SIZE_CLASSES],
+    /// Number of cached blocks of each class.
+    lengths: [usize; NUM_SIZE_CLASSES],
+}
+
+impl SizeClasses {
+    /// Builds a new disabled and empty set of classes.
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            heads: [None; NUM_SIZE_CLASSES],
+            lengths: [0; NUM_SIZE_CLASSES],
+        }
+    }
+
+    /// Whether requests should be rounded and blocks retained.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enables or disables the classes. Caller must release all cached blocks
+    /// before disabling them, otherwise they'll be leaked until the region is
+    /// dropped.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Index in [`SIZE_CLASSES`] of the class that `layout` rounds up to, or
+    /// `None` if `layout` is not handled by size classes.
+    pub fn class_of(&self, layout: Layout) -> Option<usize> {
+        if !self.enabled || layout.align() > POINTER_SIZE {
+            return None;
+        }
+
+        let class = SIZE_CLASSES.partition_point(|size| *size < layout.size());
+
+        (class < NUM_SIZE_CLASSES).then_some(class)
+    }
+
+    /// Returns `layout` with its size rounded up to its class, or `layout`
+    /// itself if it's not handled by size classes.
+    pub fn round(&self, layout: Layout) -> Layout {
+        match self.class_of(layout) {
+            Some(class) => unsafe {
+                Layout::from_size_align_unchecked(SIZE_CLASSES[class], layout.align())
+            },
+            None => layout,
+        }
+    }
+
+    /// Class whose size is exactly `size`, which is the only kind of block
+    /// that can be retained.
+    fn class_of_block(&self, size: usize) -> Option<usize> {
+        if !self.enabled {
+            return None;
+        }
+
+        SIZE_CLASSES.binary_search(&size).ok()
+    }
+
+    /// Retains `block` in the list of its class. Returns `false` if the block
+    /// size doesn't match any class or the class is full, in which case the
+    /// caller must release the block.
+    pub unsafe fn push(&mut self, block: NonNull<Header<Block>>) -> bool {
+        let Some(class) = self.class_of_block(block.as_ref().size()) else {
+            return false;
+        };
+
+        if self.lengths[class] == SIZE_CLASS_CAPACITY {
+            return false;
+        }
+
+        Self::link_of(block).write(self.heads[class]);
+        self.heads[class] = Some(block);
+        self.lengths[class] += 1;
+
+        true
+    }
+
+    /// Removes and returns the head of the given class, or `None` if the class
+    /// is empty.
+    pub unsafe fn pop(&mut self, class: usize) -> Pointer<Header<Block>> {
+        let block = self.heads[class]?;
+        self.heads[class] = Self::link_of(block).read();
+        self.lengths[class] -= 1;
+
+        Some(block)
+    }
+
+    /// Removes and returns any cached block, or `None` if all classes are
+    /// empty.
+    pub unsafe fn pop_any(&mut self) -> Pointer<Header<Block>> {
+        let class = self.lengths.iter().position(|length| *length > 0)?;
+        self.pop(class)
+    }
+
+    /// Forgets every cached block for which `keep` returns `false` and
+    /// returns how many were forgotten. The blocks are neither released nor
+    /// touched, only unlinked from their class.
+    pub unsafe fn retain(&mut self, mut keep: impl FnMut(NonNull<Header<Block>>) -> bool) -> usize {
+        let total = self.total_len();
+
+        for class in 0..NUM_SIZE_CLASSES {
+            let mut link = &mut self.heads[class] as *mut Pointer<Header<Block>>;
+            while let Some(block) = *link {
+                if keep(block) {
+                    link = Self::link_of(block);
+                } else {
+                    *link = Self::link_of(block).read();
+                    self.lengths[class] -= 1;
+                }
+            }
+        }
+
+        total - self.total_len()
+    }
+
+    /// Whether `block` is retained by its class.
+    #[cfg(feature = "leak-check")]
+    pub unsafe fn contains(&self, block: NonNull<Header<Block>>) -> bool {
+        let Some(class) = self.class_of_block(block.as_ref().size()) else {
+            return false;
+        };
+
+        let mut current = self.heads[class];
+        while let Some(cached) = current {
+            if cached == block {
+                return true;
+            }
+            current = Self::link_of(cached).read();
+        }
+
+        false
+    }
+
+    /// Number of cached blocks in all classes.
+    pub fn total_len(&self) -> usize {
+        self.lengths.iter().sum()
+    }
+
+    /// Number of cached blocks in the given class.
+    #[cfg(test)]
+    pub fn len(&self, class: usize) -> usize {
+        self.lengths[class]
+    }
+
+    /// Address where the link to the next cached block is stored.
+    #[inline]
+    unsafe fn link_of(block: NonNull<Header<Block>>) -> *mut Pointer<Header<Block>> {
+        Header::content_address_of(block).cast().as_ptr()
+    }
+}
@@ -0,0 +1,417 @@
+//! Headerless slab for the smallest allocations. Every block managed by a
+//! [`crate::bucket::Bucket`] carries a [`crate::block::Block`] header, which
+//! is several times larger than an 8 byte allocation, so a bucket full of
+//! tiny objects is mostly metadata. A slab region is instead divided into
+//! slots of one fixed size, and the only metadata is one bit per slot stored
+//! at the beginning of the region:
+//!
+//! ```text
+//! +--------+--------------+------+------+------+------+------+-------+------+
+//! | Header | Free bitmap  | Slot | Slot | Slot | Slot | Slot |  ...  | Slot |
+//! +--------+--------------+------+------+------+------+------+-------+------+
+//!          ^                     ^
+//!          |                     |
+//!          +-- 1 bit per slot    +-- Address given to the user
+//! ```
+//!
+//! Slots don't store anything about themselves, so deallocation finds the
+//! region that contains the address and computes the slot index from the
+//! offset. Regions have a fixed length and only live while at least one of
+//! their slots is in use, just like regions of buckets. This is enabled with
+//! [`crate::Rulloc::with_slab`] and replaces the first bucket for requests
+//! that fit in one slot.
+
+use std::{alloc::Layout, ffi::c_int, mem, mem::ManuallyDrop, ptr::NonNull};
+
+use crate::{
+    alignment::POINTER_SIZE,
+    failure::{AllocFailure, UnmapFailures},
+    header::Header,
+    list::LinkedList,
+    platform, Pointer,
+};
+
+/// Length of each slab region before rounding up to page size.
+pub(crate) const SLAB_REGION_SIZE: usize = 64 * 1024;
+
+/// Largest slot size supported. Slabs only make sense for objects much
+/// smaller than a block header.
+const MAX_SLOT_SIZE: usize = 64;
+
+/// Number of slots that fit in one bitmap word.
+const BITS: usize = u64::BITS as usize;
+
+/// Slab region metadata stored at the beginning of the region. The bitmap
+/// starts right after the header and the slots start at `slots_offset`.
+pub(crate) struct SlabRegion {
+    /// Total length of the region, header included.
+    length: usize,
+    /// Number of slots in the region.
+    num_slots: usize,
+    /// Number of slots currently in use.
+    used: usize,
+    /// Offset from the region address where the first slot starts.
+    slots_offset: usize,
+}
+
+/// All the slab regions of an allocator.
+pub(crate) struct Slab {
+    /// Size of each slot, or 0 if the slab is disabled.
+    slot_size: usize,
+    /// Regions that belong to this slab.
+    regions: ManuallyDrop<LinkedList<SlabRegion>>,
+    /// Sum of the lengths of all regions.
+    bytes_mapped: usize,
+    /// Byte written to the slots of new regions, see
+    /// [`crate::bucket::Bucket::set_scrub_pattern`].
+    scrub_pattern: Option<u8>,
+    /// Flags ORed into the default `mmap` flags, see
+    /// [`crate::Rulloc::with_mmap_flags`].
+    mmap_flags: c_int,
+    /// Whether freed slots are zeroed, see
+    /// [`crate::bucket::Bucket::set_sensitive`].
+    sensitive: bool,
+    /// Reason of the last failed allocation, see
+    /// [`crate::bucket::Bucket::take_failure`].
+    failure: Option<AllocFailure>,
+    /// See [`crate::bucket::Bucket::take_unmap_failures`].
+    unmap_failures: UnmapFailures,
+}
+
+impl Slab {
+    /// Disabled slab with no regions.
+    pub const fn new() -> Self {
+        Self {
+            slot_size: 0,
+            regions: ManuallyDrop::new(LinkedList::new()),
+            bytes_mapped: 0,
+            scrub_pattern: None,
+            mmap_flags: 0,
+            sensitive: false,
+            failure: None,
+            unmap_failures: UnmapFailures::new(),
+        }
+    }
+
+    /// Sets the extra `mmap` flags of new regions.
+    pub fn set_mmap_flags(&mut self, flags: c_int) {
+        self.mmap_flags = flags;
+    }
+
+    /// Fills the slots of new regions with `pattern`, or leaves them as given
+    /// by the kernel if `None`.
+    pub fn set_scrub_pattern(&mut self, pattern: Option<u8>) {
+        self.scrub_pattern = pattern;
+    }
+
+    /// Zeroes slots when they are freed. The slab replaces the first bucket,
+    /// so it follows [`crate::Rulloc::mark_bucket_sensitive`] for index 0.
+    pub fn set_sensitive(&mut self, enabled: bool) {
+        self.sensitive = enabled;
+    }
+
+    /// Returns the reason of the last failed allocation and forgets it.
+    pub fn take_failure(&mut self) -> Option<AllocFailure> {
+        self.failure.take()
+    }
+
+    /// Returns the unmap failures since the last call and forgets them.
+    pub fn take_unmap_failures(&mut self) -> UnmapFailures {
+        self.unmap_failures.take()
+    }
+
+    /// Enables the slab with slots of `slot_size` bytes, which is rounded up
+    /// to pointer size, or disables it if `slot_size` is 0. Can only be
+    /// changed while there are no regions.
+    pub fn set_slot_size(&mut self, slot_size: usize) {
+        assert!(
+            slot_size <= MAX_SLOT_SIZE,
+            "slab slots can't be larger than {MAX_SLOT_SIZE} bytes, got {slot_size}"
+        );
+        debug_assert_eq!(self.regions.len(), 0);
+
+        self.slot_size = slot_size.next_multiple_of(POINTER_SIZE);
+    }
+
+    /// Size of each slot, 0 if disabled.
+    #[inline]
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Whether an allocation of `layout` can be placed in one slot. Slots are
+    /// only aligned to pointer size. Nothing fits in a disabled slab, not
+    /// even zero size layouts.
+    #[inline]
+    pub fn fits(&self, layout: Layout) -> bool {
+        self.slot_size != 0 && layout.size() <= self.slot_size && layout.align() <= POINTER_SIZE
+    }
+
+    /// Total number of bytes mapped by the slab.
+    #[inline]
+    pub fn bytes_mapped(&self) -> usize {
+        self.bytes_mapped
+    }
+
+    /// Number of regions mapped by the slab.
+    #[inline]
+    pub fn num_regions(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Number of content bytes in free slots of all regions.
+    pub fn free_bytes(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| unsafe {
+                let region = &region.as_ref().data;
+                (region.num_slots - region.used) * self.slot_size
+            })
+            .sum()
+    }
+
+    /// Number of slots in use in all regions.
+    #[cfg(feature = "leak-check")]
+    pub fn live_slots(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|region| unsafe { region.as_ref().data.used })
+            .sum()
+    }
+
+    /// Returns the address of a free slot, mapping a new region if all of
+    /// them are full and `limit` allows mapping one more. `limit` is the
+    /// number of bytes the slab can have mapped in total.
+    pub unsafe fn allocate(&mut self, limit: usize) -> Pointer<u8> {
+        let region = match self
+            .regions
+            .iter()
+            .find(|region| region.as_ref().data.used < region.as_ref().data.num_slots)
+        {
+            Some(region) => region,
+            None => self.map_region(limit)?,
+        };
+
+        let SlabRegion {
+            num_slots,
+            slots_offset,
+            ..
+        } = region.as_ref().data;
+        let bitmap = Self::bitmap_of(region);
+
+        let mut index = 0;
+        while index < num_slots {
+            let word = &mut *bitmap.add(index / BITS);
+
+            if *word == u64::MAX {
+                index += BITS;
+                continue;
+            }
+
+            index += word.trailing_ones() as usize;
+            if index >= num_slots {
+                break;
+            }
+
+            *word |= 1 << (index % BITS);
+            (*region.as_ptr()).data.used += 1;
+
+            let offset = slots_offset + index * self.slot_size;
+            return Some(NonNull::new_unchecked(
+                region.cast::<u8>().as_ptr().add(offset),
+            ));
+        }
+
+        unreachable!("slab region with free slots has no zero bit in its bitmap")
+    }
+
+    /// Frees the slot at `address`, unmapping its region if it was the last
+    /// slot in use.
+    pub unsafe fn deallocate(&mut self, address: NonNull<u8>) {
+        let region = self
+            .region_of(address)
+            .expect("address doesn't belong to the slab");
+        let index = self.slot_index(region, address);
+        let word = &mut *Self::bitmap_of(region).add(index / BITS);
+
+        debug_assert!(*word & (1 << (index % BITS)) != 0, "double free in slab");
+
+        if self.sensitive {
+            address.as_ptr().write_bytes(0, self.slot_size);
+        }
+
+        *word &= !(1 << (index % BITS));
+        (*region.as_ptr()).data.used -= 1;
+
+        if region.as_ref().data.used == 0 {
+            self.unmap_region(region);
+        }
+    }
+
+    /// Whether `address` points to a slot of any region of this slab.
+    #[inline]
+    pub fn contains(&self, address: NonNull<u8>) -> bool {
+        self.slot_size != 0 && self.region_of(address).is_some()
+    }
+
+    /// Whether the slot at `address` is currently in use. The address must
+    /// belong to the slab, see [`Self::contains`].
+    pub unsafe fn is_allocated(&self, address: NonNull<u8>) -> bool {
+        let Some(region) = self.region_of(address) else {
+            return false;
+        };
+        let index = self.slot_index(region, address);
+
+        *Self::bitmap_of(region).add(index / BITS) & (1 << (index % BITS)) != 0
+    }
+
+    /// Region whose slots contain `address`.
+    fn region_of(&self, address: NonNull<u8>) -> Pointer<Header<SlabRegion>> {
+        let address = address.as_ptr() as usize;
+
+        self.regions.iter().find(|region| unsafe {
+            let start = region.as_ptr() as usize;
+            let SlabRegion {
+                length,
+                slots_offset,
+                ..
+            } = region.as_ref().data;
+            (start + slots_offset..start + length).contains(&address)
+        })
+    }
+
+    /// Index of the slot at `address` inside `region`.
+    unsafe fn slot_index(
+        &self,
+        region: NonNull<Header<SlabRegion>>,
+        address: NonNull<u8>,
+    ) -> usize {
+        let offset = address.as_ptr() as usize - region.as_ptr() as usize;
+        (offset - region.as_ref().data.slots_offset) / self.slot_size
+    }
+
+    /// First word of the bitmap of `region`.
+    #[inline]
+    unsafe fn bitmap_of(region: NonNull<Header<SlabRegion>>) -> *mut u64 {
+        Header::content_address_of(region).cast().as_ptr()
+    }
+
+    /// Maps an empty region and adds it to the front of the list so that it's
+    /// found first by the next allocations.
+    unsafe fn map_region(&mut self, limit: usize) -> Pointer<Header<SlabRegion>> {
+        let length = SLAB_REGION_SIZE.next_multiple_of(platform::page_size());
+
+        if self.bytes_mapped + length > limit {
+            self.failure = Some(AllocFailure::LimitExceeded);
+            return None;
+        }
+
+        // Each slot costs its size plus one bit of the bitmap. Start from
+        // the ideal count and remove slots until the rounded up bitmap fits.
+        let available = length - mem::size_of::<Header<SlabRegion>>();
+        let mut num_slots = available * 8 / (self.slot_size * 8 + 1);
+        let slots_offset = |num_slots: usize| {
+            mem::size_of::<Header<SlabRegion>>() + num_slots.div_ceil(BITS) * mem::size_of::<u64>()
+        };
+        while slots_offset(num_slots) + num_slots * self.slot_size > length {
+            num_slots -= 1;
+        }
+
+        let address = match platform::request_memory(length, self.mmap_flags) {
+            Ok(address) => address,
+            Err(errno) => {
+                self.failure = Some(AllocFailure::MmapFailed(errno));
+                return None;
+            }
+        };
+
+        if let Some(pattern) = self.scrub_pattern {
+            address.as_ptr().write_bytes(pattern, length);
+        }
+
+        // Fresh mappings are already zeroed on most platforms, but the
+        // global allocator used under Miri doesn't guarantee it.
+        address.as_ptr().write_bytes(0, slots_offset(num_slots));
+
+        let region = self.regions.prepend(
+            SlabRegion {
+                length,
+                num_slots,
+                used: 0,
+                slots_offset: slots_offset(num_slots),
+            },
+            address,
+        );
+        self.bytes_mapped += length;
+
+        Some(region)
+    }
+
+    /// Removes `region` from the list and returns it to the kernel.
+    unsafe fn unmap_region(&mut self, region: NonNull<Header<SlabRegion>>) {
+        let length = region.as_ref().data.length;
+        self.regions.remove(region);
+        self.bytes_mapped -= length;
+        self.unmap_failures
+            .check(platform::return_memory(region.cast(), length));
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        // Same as buckets, remove before unmapping because the header is
+        // located in the memory being unmapped.
+        while let Some(region) = self.regions.first() {
+            unsafe { self.unmap_region(region) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slab_slots() {
+        let mut slab = Slab::new();
+        slab.set_slot_size(8);
+        let layout = Layout::new::<u64>();
+        assert!(slab.fits(layout));
+        assert!(!slab.fits(Layout::new::<u128>()));
+        assert!(slab.fits(Layout::new::<()>()));
+        assert!(!Slab::new().fits(Layout::new::<()>()));
+
+        unsafe {
+            let first = slab.allocate(usize::MAX).unwrap();
+            let second = slab.allocate(usize::MAX).unwrap();
+            assert_eq!(second.as_ptr() as usize - first.as_ptr() as usize, 8);
+            assert_eq!(slab.num_regions(), 1);
+            assert!(slab.contains(first) && slab.is_allocated(first));
+
+            // Freed slots are reused.
+            slab.deallocate(first);
+            assert!(!slab.is_allocated(first));
+            assert_eq!(slab.allocate(usize::MAX), Some(first));
+
+            // Region is unmapped once all its slots are free.
+            slab.deallocate(first);
+            slab.deallocate(second);
+            assert_eq!(slab.num_regions(), 0);
+            assert_eq!(slab.bytes_mapped(), 0);
+
+            // Limit is respected and the reason is remembered.
+            assert_eq!(slab.allocate(0), None);
+            assert_eq!(slab.take_failure(), Some(AllocFailure::LimitExceeded));
+            assert_eq!(slab.take_failure(), None);
+
+            // Sensitive slabs zero slots when they are freed.
+            slab.set_sensitive(true);
+            let kept = slab.allocate(usize::MAX).unwrap();
+            let freed = slab.allocate(usize::MAX).unwrap();
+            freed.as_ptr().write_bytes(0xFF, 8);
+            slab.deallocate(freed);
+            assert_eq!(freed.cast::<u64>().as_ptr().read(), 0);
+            slab.deallocate(kept);
+        }
+    }
+}
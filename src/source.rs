@@ -0,0 +1,110 @@
+use std::ptr::NonNull;
+
+/// Where the regions of a bucket come from. By default every region is an
+/// anonymous private mapping requested to the kernel, but systems with
+/// different kinds of memory might want to place some buckets somewhere else.
+/// For example, on a tiered memory machine small and hot objects could live in
+/// fast DRAM while large buffers go to a file mapped on persistent memory:
+///
+/// ```text
+/// +----------+   +----------+   +----------+   +-------------+
+/// | Bucket 0 |   | Bucket 1 |   | Bucket 2 |   | Dyn Bucket  |
+/// +----------+   +----------+   +----------+   +-------------+
+///      |              |              |                |
+///      +--------------+--------------+                |
+///                     |                               |
+///                     v                               v
+///          +---------------------+         +---------------------+
+///          | Anonymous mmap      |         | File on /mnt/pmem   |
+///          | (default)           |         | (custom source)     |
+///          +---------------------+         +---------------------+
+/// ```
+///
+/// Each bucket can be given its own source with
+/// [`crate::Rulloc::with_bucket_source`]. The bucket still manages blocks
+/// exactly the same way, the source only decides which memory backs the
+/// regions. Regions are always mapped and unmapped while the allocator lock
+/// is held, so sources must not allocate using the same [`crate::Rulloc`]
+/// instance, and they are shared between all threads that use the allocator.
+///
+/// # Safety
+///
+/// The allocator trusts the source blindly. Memory returned by
+/// [`MmapSource::map`] must be readable, writable, aligned as requested and
+/// not used by anything else until it's given back to
+/// [`MmapSource::unmap`]. It must also be page aligned at least, since guard
+/// pages and hints like [`crate::Rulloc::with_thp_hint`] work with whole
+/// pages.
+///
+/// The memory doesn't have to be zeroed, a source can hand out recycled
+/// memory with any contents. Unlike anonymous mappings, regions of custom
+/// sources are never assumed to be zero filled, so
+/// [`std::alloc::Allocator::allocate_zeroed`] always writes the zeroes.
+///
+/// # Examples
+///
+/// ```rust
+/// #![feature(allocator_api)]
+///
+/// use std::{
+///     alloc::{Allocator, Layout},
+///     ptr::{self, NonNull},
+///     sync::atomic::{AtomicUsize, Ordering},
+/// };
+///
+/// use rulloc::{MmapSource, Rulloc};
+///
+/// /// Maps shared memory with `mmap` and counts the regions. Only page
+/// /// alignment is supported, which is all a bucket asks for unless
+/// /// configured otherwise.
+/// struct Counted(AtomicUsize);
+///
+/// unsafe impl MmapSource for Counted {
+///     fn map(&self, length: usize, align: usize) -> Result<NonNull<u8>, i32> {
+///         if align > unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize {
+///             return Err(libc::EINVAL);
+///         }
+///
+///         let protection = libc::PROT_READ | libc::PROT_WRITE;
+///         let flags = libc::MAP_SHARED | libc::MAP_ANONYMOUS;
+///         let address = unsafe { libc::mmap(ptr::null_mut(), length, protection, flags, -1, 0) };
+///         if address == libc::MAP_FAILED {
+///             return Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(0));
+///         }
+///
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///         Ok(NonNull::new(address.cast()).unwrap())
+///     }
+///
+///     unsafe fn unmap(&self, address: NonNull<u8>, length: usize, _align: usize) {
+///         libc::munmap(address.as_ptr().cast(), length);
+///     }
+/// }
+///
+/// static SOURCE: Counted = Counted(AtomicUsize::new(0));
+///
+/// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]).with_bucket_source(3, &SOURCE);
+///
+/// let layout = Layout::array::<u8>(64 * 1024).unwrap();
+/// let address = rulloc.allocate(layout).unwrap();
+/// assert_eq!(SOURCE.0.load(Ordering::Relaxed), 1);
+///
+/// unsafe { rulloc.deallocate(address.cast(), layout) };
+/// ```
+pub unsafe trait MmapSource: Sync {
+    /// Maps `length` bytes aligned to `align`, which is a power of 2 not
+    /// smaller than the page size. `length` is always a multiple of the page
+    /// size. On failure, returns an error code that is reported through
+    /// [`crate::AllocFailure::MmapFailed`].
+    fn map(&self, length: usize, align: usize) -> Result<NonNull<u8>, i32>;
+
+    /// Gives back a mapping returned by [`MmapSource::map`]. `length` and
+    /// `align` are the same values used to map it. Mappings are never split
+    /// or merged, each one is returned exactly once and as a whole.
+    ///
+    /// # Safety
+    ///
+    /// The allocator only calls this with mappings obtained from this same
+    /// source that nobody is using anymore.
+    unsafe fn unmap(&self, address: NonNull<u8>, length: usize, align: usize);
+}
@@ -0,0 +1,326 @@
+//! Allocator statistics. Some counters are maintained with atomics so that
+//! they can be read from any thread without acquiring the allocator lock, which
+//! is useful for monitoring threads that poll the allocator frequently and
+//! don't want to perturb the workload.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Snapshot of the allocator counters returned by [`crate::Rulloc::poll_stats`].
+/// Each field is read independently, so if other threads are allocating at
+/// the same time the snapshot might not be perfectly consistent, but every
+/// value was true at some point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// Total bytes requested from the kernel, including headers.
+    pub bytes_mapped: usize,
+    /// Number of regions currently mapped by all buckets.
+    pub regions: usize,
+    /// Bytes currently allocated by the user. This is the sum of the sizes of
+    /// all the layouts that have not been deallocated yet.
+    pub bytes_allocated: usize,
+    /// Number of allocations that have not been deallocated yet. Unlike
+    /// `allocations - deallocations` this is not affected by
+    /// [`crate::Rulloc::reset_stats`].
+    pub live_blocks: usize,
+    /// Number of successful allocations since the allocator was created.
+    pub allocations: usize,
+    /// Number of deallocations since the allocator was created.
+    pub deallocations: usize,
+    /// Number of deallocations whose layout was different from the layout
+    /// used to allocate (or last reallocate) the same pointer. That's
+    /// undefined behaviour according to [`std::alloc::Allocator`], but it
+    /// might go unnoticed if the size is the same, since we would still find
+    /// the correct bucket. A different alignment however can break the
+    /// recovery of the block header, see [`crate::alignment`], so debug
+    /// builds panic before counting it. Only available with the
+    /// `layout-tracking` feature.
+    #[cfg(feature = "layout-tracking")]
+    pub layout_mismatches: usize,
+    /// Number of times the kernel refused to unmap memory returned by this
+    /// allocator, which should never happen. The memory stays mapped, so a
+    /// growing value means that the process is leaking address space. Debug
+    /// builds panic with the address, length and `errno` of the failure
+    /// right after counting it, so this is mostly useful in release builds.
+    /// Buffers from [`crate::Rulloc::allocate_secure`] are not counted, they
+    /// can outlive the allocator. With the `logging` feature, each failure is
+    /// also logged as an error.
+    pub unmap_failures: usize,
+}
+
+/// Snapshot of a single bucket, see [`FullStats`]. Unlike [`Stats`], these
+/// values are read with the allocator lock held, so they are consistent with
+/// each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BucketStats {
+    /// Number of regions mapped by this bucket.
+    pub regions: usize,
+    /// Total length of the regions mapped by this bucket, including headers.
+    pub bytes_mapped: usize,
+    /// Number of blocks in the free list of this bucket.
+    pub free_blocks: usize,
+    /// Sum of the content sizes of all the free blocks of this bucket.
+    pub free_bytes: usize,
+    /// Number of allocations served by blocks that were already free, either
+    /// in the free list or retained by size classes.
+    pub freelist_hits: usize,
+    /// Number of regions mapped by this bucket since it was created,
+    /// including the ones that were already returned to the kernel.
+    pub region_maps: usize,
+    /// Number of deallocations where the block had at least one free
+    /// neighbour to merge with. Deallocations that end up in object pools or
+    /// size classes don't merge at all, so they are not counted here nor in
+    /// [`Self::isolated_frees`].
+    pub coalesced_frees: usize,
+    /// Number of deallocations where neither neighbour of the block was
+    /// free, so the block stayed on its own in the free list. If most frees
+    /// are isolated, objects with different lifetimes are interleaved in
+    /// memory and the bucket fragments. See [`Self::coalesce_rate`].
+    pub isolated_frees: usize,
+    /// Same as [`Stats::layout_mismatches`] but only for this bucket.
+    #[cfg(feature = "layout-tracking")]
+    pub layout_mismatches: usize,
+    /// Sum of the sizes requested by the user for all the blocks of this
+    /// bucket that are in use. Blocks are usually bigger than requested
+    /// because of rounding, alignment and blocks that can't be split, so
+    /// comparing this value to the capacity of the blocks tells how much
+    /// memory is wasted inside them. Only available with the
+    /// `layout-tracking` feature.
+    #[cfg(feature = "layout-tracking")]
+    pub bytes_used: usize,
+}
+
+/// Per bucket snapshot returned by [`crate::Rulloc::full_stats`]. The
+/// dynamic bucket, which serves requests larger than the last fixed size,
+/// has its own field so that code iterating over `buckets` can't forget
+/// about it by accident:
+///
+/// ```text
+/// buckets[0]   -> sizes[0]
+/// buckets[1]   -> sizes[1]
+/// ...
+/// buckets[N-1] -> sizes[N - 1]
+/// dyn_bucket   -> everything else
+/// ```
+///
+/// Everything, including the totals, is read within one acquisition of the
+/// allocator lock, so even if other threads are allocating at the same time
+/// the buckets and the totals describe the same moment. Note that
+/// `bytes_overhead` is not measured, it's whatever is mapped but neither
+/// allocated nor free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullStats<const N: usize> {
+    /// Fixed size buckets, in the same order as the sizes given to
+    /// [`crate::Rulloc::with_bucket_sizes`].
+    pub buckets: [BucketStats; N],
+    /// The dynamic bucket.
+    pub dyn_bucket: BucketStats,
+    /// Size of each fixed bucket, `sizes[i]` is the size of `buckets[i]`.
+    pub sizes: [usize; N],
+    /// Same as [`Stats::bytes_mapped`] at the moment of the snapshot. Also
+    /// counts the slab, see [`crate::Rulloc::with_slab`].
+    pub bytes_mapped: usize,
+    /// Same as [`Stats::bytes_allocated`] at the moment of the snapshot.
+    pub bytes_allocated: usize,
+    /// Same as [`crate::Rulloc::free_bytes`] at the moment of the snapshot.
+    pub bytes_free: usize,
+    /// Same as [`crate::Rulloc::bytes_overhead`] at the moment of the
+    /// snapshot.
+    pub bytes_overhead: usize,
+}
+
+impl BucketStats {
+    /// Fraction of allocations that didn't need a new region, from 0 to 1.
+    /// Once the bucket has warmed up this should be close to 1, a low value
+    /// means that regions are mapped and unmapped all the time, either
+    /// because the bucket size doesn't match the workload or because memory
+    /// is leaking. Returns 0 if nothing has been allocated yet.
+    pub fn hit_rate(&self) -> f64 {
+        match self.freelist_hits + self.region_maps {
+            0 => 0.0,
+            total => self.freelist_hits as f64 / total as f64,
+        }
+    }
+
+    /// Fraction of deallocations that could merge with a free neighbour,
+    /// from 0 to 1. A low value means that frees leave holes between blocks
+    /// that are still in use:
+    ///
+    /// ```text
+    /// +------+------+------+------+------+------+
+    /// | Used | Free | Used | Free | Used | Free |
+    /// +------+------+------+------+------+------+
+    /// ```
+    ///
+    /// Returns 0 if nothing has been deallocated yet.
+    pub fn coalesce_rate(&self) -> f64 {
+        match self.coalesced_frees + self.isolated_frees {
+            0 => 0.0,
+            total => self.coalesced_frees as f64 / total as f64,
+        }
+    }
+}
+
+impl<const N: usize> FullStats<N> {
+    /// Iterator over all buckets, the dynamic one last.
+    pub fn iter(&self) -> impl Iterator<Item = &BucketStats> {
+        self.buckets.iter().chain(std::iter::once(&self.dyn_bucket))
+    }
+
+    /// Iterator over all buckets paired with their size, the dynamic one
+    /// last with size `usize::MAX` since it has no upper limit. Useful for
+    /// exporting one metric per size class with a single loop:
+    ///
+    /// ```rust
+    /// use rulloc::Rulloc;
+    ///
+    /// let rulloc = Rulloc::<3>::with_bucket_sizes([128, 1024, 8192]);
+    ///
+    /// for (size, stats) in rulloc.full_stats().classes() {
+    ///     let label = match size {
+    ///         usize::MAX => String::from("+Inf"),
+    ///         size => size.to_string(),
+    ///     };
+    ///     println!("rulloc_bytes_mapped{{size=\"{label}\"}} {}", stats.bytes_mapped);
+    /// }
+    /// ```
+    pub fn classes(&self) -> impl Iterator<Item = (usize, BucketStats)> + '_ {
+        self.sizes
+            .iter()
+            .copied()
+            .chain(std::iter::once(usize::MAX))
+            .zip(self.iter().copied())
+    }
+}
+
+/// Lock-free counters backing [`Stats`]. Writes happen while the allocator
+/// lock is held, so we only need atomics to make reads safe from other
+/// threads, that's why all operations are [`Ordering::Relaxed`].
+pub(crate) struct Counters {
+    bytes_mapped: AtomicUsize,
+    regions: AtomicUsize,
+    bytes_allocated: AtomicUsize,
+    live_blocks: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    #[cfg(feature = "layout-tracking")]
+    layout_mismatches: AtomicUsize,
+    unmap_failures: AtomicUsize,
+}
+
+impl Counters {
+    /// All counters start at 0.
+    pub const fn new() -> Self {
+        Self {
+            bytes_mapped: AtomicUsize::new(0),
+            regions: AtomicUsize::new(0),
+            bytes_allocated: AtomicUsize::new(0),
+            live_blocks: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            #[cfg(feature = "layout-tracking")]
+            layout_mismatches: AtomicUsize::new(0),
+            unmap_failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reads all the counters without locking.
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            bytes_mapped: self.bytes_mapped(),
+            regions: self.regions(),
+            bytes_allocated: self.bytes_allocated.load(Ordering::Relaxed),
+            live_blocks: self.live_blocks(),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            #[cfg(feature = "layout-tracking")]
+            layout_mismatches: self.layout_mismatches.load(Ordering::Relaxed),
+            unmap_failures: self.unmap_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zeroes the counters that accumulate over the lifetime of the
+    /// allocator. The ones that describe the current state of the heap, like
+    /// [`Stats::bytes_allocated`], are left alone, and so is
+    /// [`Stats::unmap_failures`], which counts address space that is still
+    /// leaked.
+    pub fn reset_lifetime(&self) {
+        self.allocations.store(0, Ordering::Relaxed);
+        self.deallocations.store(0, Ordering::Relaxed);
+        #[cfg(feature = "layout-tracking")]
+        self.layout_mismatches.store(0, Ordering::Relaxed);
+    }
+
+    /// See [`Stats::bytes_mapped`].
+    #[inline]
+    pub fn bytes_mapped(&self) -> usize {
+        self.bytes_mapped.load(Ordering::Relaxed)
+    }
+
+    /// See [`Stats::regions`].
+    #[inline]
+    pub fn regions(&self) -> usize {
+        self.regions.load(Ordering::Relaxed)
+    }
+
+    /// See [`Stats::live_blocks`].
+    #[inline]
+    pub fn live_blocks(&self) -> usize {
+        self.live_blocks.load(Ordering::Relaxed)
+    }
+
+    /// Regions are mapped and unmapped by buckets, so we just store whatever
+    /// the totals are after each operation.
+    #[inline]
+    pub fn set_mapped(&self, bytes: usize, regions: usize) {
+        self.bytes_mapped.store(bytes, Ordering::Relaxed);
+        self.regions.store(regions, Ordering::Relaxed);
+    }
+
+    /// Mismatches are detected by buckets, so this works just like
+    /// [`Self::set_mapped`].
+    #[cfg(feature = "layout-tracking")]
+    #[inline]
+    pub fn set_layout_mismatches(&self, mismatches: usize) {
+        self.layout_mismatches.store(mismatches, Ordering::Relaxed);
+    }
+
+    /// Records a successful allocation of `size` bytes.
+    #[inline]
+    pub fn record_allocation(&self, size: usize) {
+        self.bytes_allocated.fetch_add(size, Ordering::Relaxed);
+        self.live_blocks.fetch_add(1, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a deallocation of `size` bytes.
+    #[inline]
+    pub fn record_deallocation(&self, size: usize) {
+        self.bytes_allocated.fetch_sub(size, Ordering::Relaxed);
+        self.live_blocks.fetch_sub(1, Ordering::Relaxed);
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records `count` blocks adding up to `size` bytes that were dropped all
+    /// at once without being deallocated, see [`crate::Rulloc::clear_bucket`]
+    /// and [`crate::Rulloc::free_region`].
+    #[inline]
+    pub fn record_dropped(&self, count: usize, size: usize) {
+        self.bytes_allocated.fetch_sub(size, Ordering::Relaxed);
+        self.live_blocks.fetch_sub(count, Ordering::Relaxed);
+        self.deallocations.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records `count` regions or parts of regions that the kernel refused to
+    /// unmap, see [`crate::failure::UnmapFailures`].
+    #[inline]
+    pub fn record_unmap_failures(&self, count: usize) {
+        self.unmap_failures.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records a successful reallocation from `old_size` to `new_size`.
+    #[inline]
+    pub fn record_reallocation(&self, old_size: usize, new_size: usize) {
+        self.bytes_allocated.fetch_sub(old_size, Ordering::Relaxed);
+        self.bytes_allocated.fetch_add(new_size, Ordering::Relaxed);
+    }
+}
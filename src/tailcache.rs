@@ -0,0 +1,115 @@
+use std::ptr::NonNull;
+
+use crate::{block::Block, header::Header, Pointer};
+
+/// Maximum number of tails retained by a single [`TailCache`].
+pub(crate) const TAIL_CACHE_CAPACITY: usize = 4;
+
+/// When a block is shrunk in place, the bytes it no longer needs become a new
+/// free block right after it, which we call the "tail":
+///
+/// ```text
+/// +--------------+    +----------------+
+/// | Shrunk Block | -> |      Tail      |
+/// +--------------+    +----------------+
+/// ```
+///
+/// Normally the tail goes straight to the free list, so any other allocation
+/// can take it. That's a problem for the typical `Vec` usage pattern where
+/// the user shrinks and then grows again, because if the tail was taken by
+/// somebody else in the meantime the contents have to be copied somewhere
+/// else. If the tail cache is enabled, the tail is not added to the free list,
+/// it's stored here instead so that nobody else can use it and the shrunk
+/// block can reclaim it without copying. The tail is released back to the free
+/// list when:
+///
+/// - The block right before it grows or is deallocated.
+/// - The cache is full and the tail is the oldest entry.
+///
+/// Cached tails are neither free nor in use. They are not in the free list and
+/// their `is_free` flag is `false`, so they won't be merged with any other
+/// block until they are released. See [`crate::bucket::Bucket`] for the code
+/// that actually moves tails in and out of the cache.
+pub(crate) struct TailCache {
+    /// Whether tails should be retained at all.
+    enabled: bool,
+    /// Cached tails, `None` means empty slot.
+    tails: [Pointer<Header<Block>>; TAIL_CACHE_CAPACITY],
+    /// Next slot that will be overwritten. Since it's a ring buffer this is
+    /// also the oldest entry when the cache is full.
+    next: usize,
+}
+
+impl TailCache {
+    /// Builds a new disabled and empty cache.
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            tails: [None; TAIL_CACHE_CAPACITY],
+            next: 0,
+        }
+    }
+
+    /// Whether tails should be retained.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enables or disables the cache. Caller must release all tails before
+    /// disabling it, otherwise they'll be leaked until the region is dropped.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Stores `tail` in the cache. If the cache is full, the oldest tail is
+    /// evicted and returned so that the caller can release it.
+    pub fn insert(&mut self, tail: NonNull<Header<Block>>) -> Pointer<Header<Block>> {
+        let evicted = self.tails[self.next].replace(tail);
+        self.next = (self.next + 1) % TAIL_CACHE_CAPACITY;
+
+        evicted
+    }
+
+    /// Removes `tail` from the cache if it's there. Returns whether it was
+    /// removed.
+    pub fn take(&mut self, tail: NonNull<Header<Block>>) -> bool {
+        match self.tails.iter_mut().find(|slot| **slot == Some(tail)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `tail` is in the cache.
+    #[cfg(feature = "leak-check")]
+    pub fn contains(&self, tail: NonNull<Header<Block>>) -> bool {
+        self.tails.contains(&Some(tail))
+    }
+
+    /// Forgets every cached tail for which `keep` returns `false` and
+    /// returns how many were forgotten.
+    pub fn retain(&mut self, mut keep: impl FnMut(NonNull<Header<Block>>) -> bool) -> usize {
+        let total = self.len();
+
+        for slot in &mut self.tails {
+            if slot.is_some_and(|tail| !keep(tail)) {
+                *slot = None;
+            }
+        }
+
+        total - self.len()
+    }
+
+    /// Removes and returns any tail from the cache, or `None` if it's empty.
+    pub fn pop(&mut self) -> Pointer<Header<Block>> {
+        self.tails.iter_mut().find_map(|slot| slot.take())
+    }
+
+    /// Number of cached tails.
+    pub fn len(&self) -> usize {
+        self.tails.iter().filter(|slot| slot.is_some()).count()
+    }
+}
@@ -0,0 +1,95 @@
+//! Latency tracking, only compiled with the `timing` feature. When enabled,
+//! every [`std::alloc::Allocator::allocate`] and
+//! [`std::alloc::Allocator::deallocate`] call is timed with a monotonic clock
+//! ([`Instant`]) and the result is stored in a histogram with logarithmic
+//! scale:
+//!
+//! ```text
+//!  Index      0       1       2       3       4              31
+//!         +-------+-------+-------+-------+-------+       +-------+
+//!  Count  |   0   |   0   |   0   |   0   |  12   |  ...  |   0   |
+//!         +-------+-------+-------+-------+-------+       +-------+
+//!  Nanos   0..2    2..4    4..8    8..16  16..32           2^31..
+//! ```
+//!
+//! Reusing a block from the free list takes a few dozen nanoseconds, but
+//! requesting a new region from the kernel takes a few microseconds, so both
+//! end up in different buckets and tail latency spikes caused by syscalls are
+//! easy to spot. Without the feature nothing is measured at all.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Number of buckets in a [`LatencyHistogram`]. The last one counts anything
+/// that took 2^31 nanoseconds (about 2 seconds) or more.
+pub const LATENCY_BUCKETS: usize = 32;
+
+/// Snapshot of the latency histogram returned by
+/// [`crate::Rulloc::latency_histogram`]. Just like [`crate::Stats`], each
+/// bucket is read independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// `counts[i]` is the number of operations that took at least `2^i`
+    /// nanoseconds and less than `2^(i + 1)`, except for `counts[0]`, which
+    /// also includes operations that took 0 nanoseconds, and the last bucket,
+    /// which has no upper bound.
+    pub counts: [usize; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// Total number of operations recorded.
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Index of the bucket where an operation that took `latency` is stored.
+    pub fn bucket_of(latency: Duration) -> usize {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let log2 = (u64::BITS - 1 - (nanos | 1).leading_zeros()) as usize;
+
+        log2.min(LATENCY_BUCKETS - 1)
+    }
+}
+
+/// Lock-free histogram backing [`LatencyHistogram`]. Operations are timed
+/// outside of the allocator lock, so unlike [`crate::stats::Counters`] writes
+/// can happen from multiple threads at the same time, but we still don't need
+/// any ordering between buckets.
+pub(crate) struct LatencyRecorder {
+    counts: [AtomicUsize; LATENCY_BUCKETS],
+}
+
+impl LatencyRecorder {
+    /// Empty histogram.
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicUsize::new(0) }; LATENCY_BUCKETS],
+        }
+    }
+
+    /// Records an operation that started at `start` and just finished.
+    #[inline]
+    pub fn record_since(&self, start: Instant) {
+        let bucket = LatencyHistogram::bucket_of(start.elapsed());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Empties the histogram.
+    pub fn reset(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads all the buckets.
+    pub fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            counts: self
+                .counts
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+}
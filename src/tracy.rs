@@ -0,0 +1,162 @@
+//! [Tracy](https://github.com/wolfpld/tracy) profiler integration, only
+//! compiled with the `tracy` feature. Every allocation and deallocation is
+//! reported as a memory event, so Tracy's memory view shows what the
+//! allocator is doing while the program runs. Events are reported to one
+//! named pool per bucket, so each bucket can be inspected on its own:
+//!
+//! ```text
+//! rulloc slab        <- Slots of the slab, see `Rulloc::with_slab`.
+//! rulloc bucket 0    <- Fixed size buckets.
+//! ...
+//! rulloc bucket N    <- Dynamic bucket.
+//! ```
+//!
+//! The Tracy client functions are linked weakly, so we don't depend on the
+//! Tracy crates. If the program links the Tracy client (for example through
+//! `tracy-client`) events are sent, otherwise the functions are null and
+//! nothing happens at all. Weak linkage is not supported by every target,
+//! ELF and Mach-O ones work fine.
+//!
+//! Events are emitted while the allocator lock is held. Otherwise another
+//! thread could reuse an address before its deallocation is reported, and
+//! Tracy would see the same address allocated twice. The Tracy client has
+//! its own internal allocator, so it never calls us back.
+
+use std::ffi::{c_void, CStr};
+
+/// Tracy C API, see `TracyC.h`. Tests replace it with [`mock`].
+#[cfg(not(test))]
+#[allow(non_upper_case_globals)]
+mod ffi {
+    use std::ffi::{c_char, c_int, c_void};
+
+    extern "C" {
+        #[linkage = "extern_weak"]
+        pub static ___tracy_emit_memory_alloc_named:
+            Option<unsafe extern "C" fn(*const c_void, usize, c_int, *const c_char)>;
+
+        #[linkage = "extern_weak"]
+        pub static ___tracy_emit_memory_free_named:
+            Option<unsafe extern "C" fn(*const c_void, c_int, *const c_char)>;
+    }
+}
+
+#[cfg(test)]
+use mock as ffi;
+
+/// Names of the Tracy memory pools. Tracy identifies pools by the address of
+/// the name, so they must be static. Buckets past the end of the table share
+/// the last pool.
+const POOLS: [&CStr; 18] = [
+    c"rulloc bucket 0",
+    c"rulloc bucket 1",
+    c"rulloc bucket 2",
+    c"rulloc bucket 3",
+    c"rulloc bucket 4",
+    c"rulloc bucket 5",
+    c"rulloc bucket 6",
+    c"rulloc bucket 7",
+    c"rulloc bucket 8",
+    c"rulloc bucket 9",
+    c"rulloc bucket 10",
+    c"rulloc bucket 11",
+    c"rulloc bucket 12",
+    c"rulloc bucket 13",
+    c"rulloc bucket 14",
+    c"rulloc bucket 15",
+    c"rulloc bucket 16+",
+    c"rulloc slab",
+];
+
+/// Pool of `bucket`, or the slab pool if `None`.
+fn pool(bucket: Option<usize>) -> &'static CStr {
+    match bucket {
+        Some(index) => POOLS[index.min(POOLS.len() - 2)],
+        None => POOLS[POOLS.len() - 1],
+    }
+}
+
+/// Reports that `size` bytes at `address` were allocated from `bucket`,
+/// `None` meaning the slab.
+#[inline]
+pub(crate) fn emit_alloc(address: *const u8, size: usize, bucket: Option<usize>) {
+    unsafe {
+        if let Some(emit) = ffi::___tracy_emit_memory_alloc_named {
+            emit(address.cast::<c_void>(), size, 0, pool(bucket).as_ptr());
+        }
+    }
+}
+
+/// Reports that `address` was deallocated from `bucket`, see
+/// [`emit_alloc`].
+#[inline]
+pub(crate) fn emit_free(address: *const u8, bucket: Option<usize>) {
+    unsafe {
+        if let Some(emit) = ffi::___tracy_emit_memory_free_named {
+            emit(address.cast::<c_void>(), 0, pool(bucket).as_ptr());
+        }
+    }
+}
+
+/// Fake Tracy client that records the events of the current thread, so tests
+/// don't need a real Tracy server.
+#[cfg(test)]
+#[allow(non_upper_case_globals)]
+pub(crate) mod mock {
+    use std::{
+        cell::RefCell,
+        ffi::{c_char, c_int, c_void, CStr},
+    };
+
+    /// Event received by the fake client.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) enum Event {
+        Alloc {
+            address: usize,
+            size: usize,
+            pool: String,
+        },
+        Free {
+            address: usize,
+            pool: String,
+        },
+    }
+
+    thread_local! {
+        static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Takes all the events recorded by the current thread.
+    pub(crate) fn take_events() -> Vec<Event> {
+        EVENTS.with(|events| events.take())
+    }
+
+    unsafe fn name(pool: *const c_char) -> String {
+        CStr::from_ptr(pool).to_string_lossy().into_owned()
+    }
+
+    unsafe extern "C" fn alloc(address: *const c_void, size: usize, _: c_int, pool: *const c_char) {
+        let event = Event::Alloc {
+            address: address.addr(),
+            size,
+            pool: name(pool),
+        };
+        EVENTS.with(|events| events.borrow_mut().push(event));
+    }
+
+    unsafe extern "C" fn free(address: *const c_void, _: c_int, pool: *const c_char) {
+        let event = Event::Free {
+            address: address.addr(),
+            pool: name(pool),
+        };
+        EVENTS.with(|events| events.borrow_mut().push(event));
+    }
+
+    pub static ___tracy_emit_memory_alloc_named: Option<
+        unsafe extern "C" fn(*const c_void, usize, c_int, *const c_char),
+    > = Some(alloc);
+
+    pub static ___tracy_emit_memory_free_named: Option<
+        unsafe extern "C" fn(*const c_void, c_int, *const c_char),
+    > = Some(free);
+}
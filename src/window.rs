@@ -0,0 +1,162 @@
+//! Allocation rate tracking, only compiled with the `timing` feature. Counts
+//! are stored in a ring of one second windows indexed by the current second
+//! modulo [`NUM_WINDOWS`]:
+//!
+//! ```text
+//!  Slot        0       1       2             58      59
+//!         +-------+-------+-------+       +-------+-------+
+//!  Second |  120  |  121  |   62  |  ...  |  118  |  119  |
+//!         +-------+-------+-------+       +-------+-------+
+//!                             ^
+//!                             |
+//!                             +-- Stale, reset when second 122 starts
+//! ```
+//!
+//! Each slot remembers which second it belongs to, so the first operation
+//! recorded in a new second finds a stale slot and resets it before counting,
+//! there's no background thread rolling the windows. Recording is one clock
+//! read plus one atomic add, and one extra compare and swap once per second.
+//! The clock can be replaced with [`crate::Rulloc::with_stats_clock`], which
+//! is mostly useful for testing.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+/// Number of windows kept by [`WindowedStats`], one per second.
+pub const NUM_WINDOWS: usize = 60;
+
+/// Allocations and deallocations recorded during one second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Window {
+    /// Number of allocations that started during this second.
+    pub allocations: usize,
+    /// Number of deallocations that started during this second.
+    pub deallocations: usize,
+}
+
+/// Snapshot returned by [`crate::Rulloc::windowed_stats`]. Just like
+/// [`crate::Stats`], each window is read independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowedStats {
+    /// Last [`NUM_WINDOWS`] seconds, oldest first. The last window is the
+    /// current second, which is still in progress.
+    pub windows: [Window; NUM_WINDOWS],
+}
+
+impl WindowedStats {
+    /// Allocations per second averaged over all windows.
+    pub fn allocation_rate(&self) -> f64 {
+        let total: usize = self.windows.iter().map(|window| window.allocations).sum();
+        total as f64 / NUM_WINDOWS as f64
+    }
+}
+
+/// Default clock, seconds elapsed since the first time it was called.
+pub(crate) fn monotonic_seconds() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs()
+}
+
+/// One slot of the ring, see the module documentation.
+struct Slot {
+    /// Second this slot belongs to.
+    second: AtomicU64,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+}
+
+/// Lock-free ring backing [`WindowedStats`]. Like
+/// [`crate::timing::LatencyRecorder`], writes happen outside of the allocator
+/// lock. If one thread resets a slot while another one is counting in it, one
+/// of the operations might be lost, which is fine for rate graphs.
+pub(crate) struct WindowRecorder {
+    slots: [Slot; NUM_WINDOWS],
+    clock: fn() -> u64,
+}
+
+impl WindowRecorder {
+    /// Empty ring that reads the time from `clock`, which must return the
+    /// number of whole seconds elapsed since any fixed point in time and must
+    /// never go backwards.
+    pub const fn new(clock: fn() -> u64) -> Self {
+        Self {
+            slots: [const {
+                Slot {
+                    second: AtomicU64::new(0),
+                    allocations: AtomicUsize::new(0),
+                    deallocations: AtomicUsize::new(0),
+                }
+            }; NUM_WINDOWS],
+            clock,
+        }
+    }
+
+    /// Records one allocation in the current window.
+    #[inline]
+    pub fn record_allocation(&self) {
+        self.current().allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records one deallocation in the current window.
+    #[inline]
+    pub fn record_deallocation(&self) {
+        self.current().deallocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Empties every window. Slots keep their second, so whatever is
+    /// recorded afterwards is still counted in the right window.
+    pub fn reset(&self) {
+        for slot in &self.slots {
+            slot.allocations.store(0, Ordering::Relaxed);
+            slot.deallocations.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Slot of the current second, reset first if it's stale.
+    fn current(&self) -> &Slot {
+        let now = (self.clock)();
+        let slot = &self.slots[now as usize % NUM_WINDOWS];
+        let second = slot.second.load(Ordering::Relaxed);
+
+        if second != now
+            && slot
+                .second
+                .compare_exchange(second, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            slot.allocations.store(0, Ordering::Relaxed);
+            slot.deallocations.store(0, Ordering::Relaxed);
+        }
+
+        slot
+    }
+
+    /// Reads the last [`NUM_WINDOWS`] seconds. Slots that haven't been
+    /// written since their second started are read as empty.
+    pub fn snapshot(&self) -> WindowedStats {
+        let now = (self.clock)();
+        let mut windows = [Window::default(); NUM_WINDOWS];
+
+        for (i, window) in windows.iter_mut().enumerate() {
+            let Some(second) = (now + 1 + i as u64).checked_sub(NUM_WINDOWS as u64) else {
+                continue;
+            };
+
+            let slot = &self.slots[second as usize % NUM_WINDOWS];
+
+            if slot.second.load(Ordering::Relaxed) == second {
+                *window = Window {
+                    allocations: slot.allocations.load(Ordering::Relaxed),
+                    deallocations: slot.deallocations.load(Ordering::Relaxed),
+                };
+            }
+        }
+
+        WindowedStats { windows }
+    }
+}
@@ -0,0 +1,44 @@
+#![feature(allocator_api)]
+
+use std::thread;
+
+use rulloc::Rulloc;
+
+#[global_allocator]
+static GLOBAL: Rulloc = Rulloc::with_default_config();
+
+/// Uses [`GLOBAL`] both implicitly (global allocations) and explicitly
+/// (`Vec::new_in`) from multiple threads at the same time. If the allocator
+/// allocated while holding its lock, this would deadlock.
+#[test]
+fn global_and_explicit_allocations() {
+    let num_threads = 4;
+
+    thread::scope(|scope| {
+        for t in 0..num_threads {
+            scope.spawn(move || {
+                let mut explicit: Vec<usize, &'static Rulloc> = Vec::new_in(&GLOBAL);
+                let mut boxes = Vec::new();
+                let mut strings = Vec::new();
+
+                for i in 0..2000 {
+                    explicit.push(t * i);
+                    boxes.push(Box::new(t + i));
+
+                    if i % 100 == 0 {
+                        strings.push(format!("{t} {i}"));
+                        explicit.shrink_to_fit();
+                    }
+                }
+
+                for (i, (n, b)) in explicit.iter().zip(&boxes).enumerate() {
+                    assert_eq!(*n, t * i);
+                    assert_eq!(**b, t + i);
+                }
+                for (i, string) in strings.iter().enumerate() {
+                    assert_eq!(*string, format!("{t} {}", i * 100));
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,26 @@
+use rulloc::Rulloc;
+
+#[global_allocator]
+static GLOBAL: Rulloc = Rulloc::with_default_config();
+
+/// `vec![0; n]` goes through [`std::alloc::GlobalAlloc::alloc_zeroed`], which
+/// only zeroes the beginning of the block when it comes from a new region.
+/// Blocks that are reused must be zeroed entirely.
+#[test]
+fn global_alloc_zeroed() {
+    // Larger than any bucket, so it needs a new region.
+    let large = vec![0u8; 1 << 20];
+    assert!(large.iter().all(|byte| *byte == 0));
+    drop(large);
+
+    for _ in 0..16 {
+        // Keep the region mapped while the block is reused.
+        let keep = vec![1u8; 16];
+        let dirty = vec![0xFFu8; 512];
+        drop(dirty);
+
+        let zeroed = vec![0u8; 512];
+        assert!(zeroed.iter().all(|byte| *byte == 0));
+        drop(keep);
+    }
+}